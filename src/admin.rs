@@ -0,0 +1,514 @@
+use crate::{
+    budget,
+    check_config::{self, Setting},
+    diagnostics, fail_fast,
+    handoff::HandedOff,
+    lockdown::{Lockdown, Status as LockdownStatus},
+    maintenance::Notice,
+    path::{normalize_path, ratelimit_path},
+    prewarm::inject_bucket,
+    probe::Report,
+    tenant::{Counts, Tenant},
+    State,
+};
+use http::{header::CONTENT_TYPE, HeaderMap, Method, Response, StatusCode};
+use hyper::{Body, Request};
+use ring::constant_time::verify_slices_are_equal;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+use twilight_http_ratelimiting::{Method as RatelimitMethod, Path};
+
+/// Path prefix of all endpoints handled by the proxy itself.
+pub const PREFIX: &str = "/__proxy/";
+
+/// Header carrying the `ADMIN_TOKEN`.
+pub const TOKEN_HEADER: &str = "x-proxy-admin-token";
+
+/// Time to wait for requests queued before a bucket injection to be sent.
+const INJECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bucket state set via the admin API, `remaining` defaults to the limit.
+#[derive(Deserialize)]
+struct BucketInjection {
+    limit: u64,
+    remaining: Option<u64>,
+    reset_after_ms: u64,
+}
+
+#[derive(Serialize)]
+struct BucketState {
+    path: String,
+    limit: Option<u64>,
+    remaining: Option<u64>,
+    reset_after_ms: Option<u128>,
+    time_remaining_ms: Option<u128>,
+    /// Unix timestamp in milliseconds at which the bucket resets.
+    reset_at_ms: Option<u128>,
+    queued: usize,
+    backoff_factor: Option<f64>,
+    oldest_queued_ms: Option<u128>,
+}
+
+/// Configuration a running instance uses.
+#[derive(Serialize)]
+struct EffectiveConfig {
+    version: &'static str,
+    features: Vec<&'static str>,
+    /// Hash of the current default token, which may have been rotated since
+    /// startup.
+    default_token: Option<String>,
+    settings: Vec<Setting>,
+}
+
+#[derive(Serialize)]
+struct DailyBudget {
+    limit: u64,
+    used: u64,
+}
+
+#[derive(Serialize)]
+struct Estimate {
+    queued: usize,
+    /// Lower bound of the time until a request sent now would be forwarded.
+    estimated_wait_ms: u128,
+    paused: bool,
+}
+
+#[derive(Serialize)]
+struct Liveness {
+    alive: bool,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    probe: Option<Report>,
+    lockdown: Option<LockdownStatus>,
+}
+
+#[derive(Serialize)]
+struct TenantUsage {
+    hash: String,
+    requests: Counts,
+    ratelimited: Counts,
+    shared_ratelimited: Counts,
+    queue_depth: usize,
+    buckets: Vec<BucketState>,
+    daily_budget: Option<DailyBudget>,
+}
+
+pub async fn handle(state: &State, request: Request<Body>) -> Response<Body> {
+    let path = &request.uri().path()[PREFIX.len()..];
+    let segments = path.trim_end_matches('/').split('/').collect::<Vec<_>>();
+
+    // Health checks don't know the admin token
+    let public = matches!(segments.as_slice(), ["live"] | ["ready"]);
+
+    if !public && !is_authorized(state.admin_token.as_deref(), request.headers()) {
+        warn!("Rejecting admin request without a valid admin token");
+
+        return error(StatusCode::UNAUTHORIZED);
+    }
+
+    match (request.method(), segments.as_slice()) {
+        (&Method::GET, ["api-versions"]) => json(&state.api_versions.usage()),
+        (&Method::GET, ["config"]) => json(&effective_config(state)),
+        (&Method::GET, ["diagnostics"]) => Response::builder()
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(Body::from(diagnostics::snapshot(state)))
+            .expect("response is valid"),
+        (&Method::POST, ["handoff"]) => receive_handoff(state, request).await,
+        (&Method::GET, ["live"]) => json(&Liveness { alive: true }),
+        (&Method::GET, ["lockdown"]) => match &state.lockdown {
+            Some(lockdown) => json(&lockdown.status()),
+            None => error(StatusCode::NOT_FOUND),
+        },
+        (&Method::DELETE, ["lockdown"]) => match &state.lockdown {
+            Some(lockdown) => {
+                if lockdown.lift() {
+                    warn!("Lockdown was lifted early");
+                }
+
+                json(&lockdown.status())
+            }
+            None => error(StatusCode::NOT_FOUND),
+        },
+        (&Method::GET, ["major-parameters"]) => json(&state.major_parameters.top()),
+        (&Method::GET, ["maintenance"]) => json(&state.maintenance.routes()),
+        (&Method::PUT, ["maintenance", route]) => {
+            let route = route.to_string();
+
+            start_maintenance(state, &route, request).await
+        }
+        (&Method::DELETE, ["maintenance", route]) => {
+            if state.maintenance.end(route) {
+                info!("Maintenance of {} ended", route);
+
+                json(&state.maintenance.routes())
+            } else {
+                error(StatusCode::NOT_FOUND)
+            }
+        }
+        (&Method::GET, ["oauth2", "token"]) => oauth2_token(state).await,
+        (&Method::POST, ["pause"]) => {
+            state.pause.pause();
+            warn!("Traffic to Discord is paused");
+
+            json(&state.pause.status())
+        }
+        (&Method::GET, ["ratelimits"]) => json(&state.ratelimit_log.entries()),
+        (&Method::GET, ["ready"]) => ready(state),
+        (&Method::GET, ["requests"]) => json(&state.request_log.overview()),
+        (&Method::POST, ["resume"]) => {
+            state.pause.resume();
+            info!("Traffic to Discord is resumed");
+
+            json(&state.pause.status())
+        }
+        (&Method::GET, ["slos"]) => json(&state.slos.summaries()),
+        (&Method::GET, ["tags"]) => json(&state.tags.counts()),
+        (&Method::GET, ["tenants", hash, "buckets"]) => {
+            match state.ratelimiter_map.get_by_hash(hash) {
+                Some(tenant) => json(&bucket_states(&tenant).await),
+                None => error(StatusCode::NOT_FOUND),
+            }
+        }
+        (&Method::POST, ["tenants", hash, "buckets", method, path @ ..]) => {
+            let (hash, method, path) = (hash.to_string(), method.to_string(), path.join("/"));
+
+            inject(state, &hash, &method, &path, request).await
+        }
+        (&Method::GET, ["tenants", hash, "estimate", method, path @ ..]) => {
+            estimate(state, hash, method, &path.join("/")).await
+        }
+        (&Method::GET, ["tenants", hash, "usage"]) => tenant_usage(state, hash).await,
+        (
+            _,
+            ["api-versions"]
+            | ["config"]
+            | ["diagnostics"]
+            | ["live"]
+            | ["lockdown"]
+            | ["maintenance", ..]
+            | ["major-parameters"]
+            | ["oauth2", "token"]
+            | ["pause"]
+            | ["ratelimits"]
+            | ["ready"]
+            | ["requests"]
+            | ["resume"]
+            | ["slos"]
+            | ["tags"]
+            | ["tenants", _, "buckets" | "estimate" | "usage", ..],
+        ) => error(StatusCode::METHOD_NOT_ALLOWED),
+        _ => error(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Whether a request may use the admin API, which is open to everyone unless
+/// an admin token is configured.
+fn is_authorized(admin_token: Option<&str>, headers: &HeaderMap) -> bool {
+    let admin_token = match admin_token {
+        Some(admin_token) => admin_token,
+        None => return true,
+    };
+
+    headers.get(TOKEN_HEADER).is_some_and(|token| {
+        verify_slices_are_equal(token.as_bytes(), admin_token.as_bytes()).is_ok()
+    })
+}
+
+fn effective_config(state: &State) -> EffectiveConfig {
+    EffectiveConfig {
+        version: env!("CARGO_PKG_VERSION"),
+        features: vec![
+            #[cfg(feature = "expose-metrics")]
+            "expose-metrics",
+            #[cfg(feature = "tokio-console")]
+            "tokio-console",
+        ],
+        default_token: state
+            .ratelimiter_map
+            .get_or_insert(None)
+            .map(|(tenant, _)| tenant.usage.hash().to_string()),
+        settings: check_config::settings(),
+    }
+}
+
+/// Store the bucket states handed off by a replica that is shutting down.
+async fn receive_handoff(state: &State, request: Request<Body>) -> Response<Body> {
+    let buckets = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => match serde_json::from_slice::<Vec<HandedOff>>(&body) {
+            Ok(buckets) => buckets,
+            Err(_) => return error(StatusCode::BAD_REQUEST),
+        },
+        Err(_) => return error(StatusCode::BAD_REQUEST),
+    };
+
+    info!("Received {} bucket states from a peer", buckets.len());
+    state.handoff.receive(buckets);
+
+    Response::new(Body::empty())
+}
+
+/// Put a route under maintenance with the notice in the request body, or the
+/// default notice if the body is empty.
+async fn start_maintenance(state: &State, route: &str, request: Request<Body>) -> Response<Body> {
+    let body = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => body,
+        Err(_) => return error(StatusCode::BAD_REQUEST),
+    };
+
+    let notice = if body.is_empty() {
+        Notice::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(notice) => notice,
+            Err(_) => return error(StatusCode::BAD_REQUEST),
+        }
+    };
+
+    state.maintenance.start(route, notice);
+    warn!("{} is under maintenance", route);
+
+    json(&state.maintenance.routes())
+}
+
+/// A bearer token of the application, if client credentials and an admin
+/// token are configured.
+async fn oauth2_token(state: &State) -> Response<Body> {
+    let credentials = match &state.client_credentials {
+        Some(credentials) if state.admin_token.is_some() => credentials,
+        _ => return error(StatusCode::NOT_FOUND),
+    };
+
+    match credentials
+        .token(state.clients.get(&state.upstream), &state.upstream)
+        .await
+    {
+        Ok(token) => json(&token),
+        Err(e) => {
+            warn!("Failed to obtain an OAuth2 token: {}", e);
+
+            error(StatusCode::BAD_GATEWAY)
+        }
+    }
+}
+
+/// Whether the proxy can reach Discord, according to the latest probe.
+///
+/// Always ready if probing is disabled.
+fn ready(state: &State) -> Response<Body> {
+    let (ready, probe) = match &state.probe {
+        Some(probe) => (probe.is_ready(), probe.report()),
+        None => (true, None),
+    };
+    let locked_down = state
+        .lockdown
+        .as_ref()
+        .is_some_and(|lockdown| lockdown.remaining().is_some());
+    let ready = ready && !locked_down;
+
+    let mut response = json(&Readiness {
+        ready,
+        probe,
+        lockdown: state.lockdown.as_ref().map(Lockdown::status),
+    });
+
+    if !ready {
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+
+    response
+}
+
+/// State of the buckets a tenant made requests to recently or has requests
+/// waiting for, sorted by path.
+async fn bucket_states(tenant: &Tenant) -> Vec<BucketState> {
+    let mut buckets = Vec::new();
+    let queue_ages = tenant.usage.queue_ages();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let mut paths = tenant.usage.paths();
+
+    for (path, _) in &queue_ages {
+        if !paths.contains(path) {
+            paths.push(path.clone());
+        }
+    }
+
+    for path in paths {
+        match tenant.ratelimiter.bucket(&path).await {
+            Ok(Some(bucket)) => {
+                // Buckets which have not received headers yet use the
+                // maximum value as a placeholder
+                let known = bucket.limit() != u64::MAX;
+                let time_remaining = bucket.time_remaining();
+
+                buckets.push(BucketState {
+                    path: format!("{:?}", path),
+                    limit: known.then(|| bucket.limit()),
+                    remaining: known.then(|| bucket.remaining()),
+                    reset_after_ms: known.then(|| bucket.reset_after().as_millis()),
+                    time_remaining_ms: time_remaining.map(|left| left.as_millis()),
+                    reset_at_ms: time_remaining.map(|left| (now + left).as_millis()),
+                    queued: tenant.usage.queued(&path),
+                    backoff_factor: tenant.backoff.factor(&path),
+                    oldest_queued_ms: queue_ages
+                        .iter()
+                        .find(|(queued, _)| *queued == path)
+                        .map(|(_, age)| age.as_millis()),
+                });
+            }
+            // The ratelimiter drops buckets that were idle for a while,
+            // stop reporting them as well
+            Ok(None) => tenant.usage.forget_path(&path),
+            Err(_) => {}
+        }
+    }
+
+    buckets.sort_by(|a, b| a.path.cmp(&b.path));
+
+    buckets
+}
+
+async fn tenant_usage(state: &State, hash: &str) -> Response<Body> {
+    let tenant = match state.ratelimiter_map.get_by_hash(hash) {
+        Some(tenant) => tenant,
+        None => return error(StatusCode::NOT_FOUND),
+    };
+
+    let buckets = bucket_states(&tenant).await;
+
+    let daily_budget = state
+        .budgets
+        .limit(tenant.usage.hash())
+        .map(|limit| DailyBudget {
+            limit,
+            used: tenant.usage.daily(budget::today()),
+        });
+
+    json(&TenantUsage {
+        hash: tenant.usage.hash().to_string(),
+        requests: tenant.usage.requests(),
+        ratelimited: tenant.usage.ratelimited(),
+        shared_ratelimited: tenant.usage.shared_ratelimited(),
+        queue_depth: tenant.usage.queue_depth(),
+        buckets,
+        daily_budget,
+    })
+}
+
+/// Parse the ratelimit path of a method and an API path given in an endpoint.
+fn parse_path(method: &str, path: &str) -> Option<Path> {
+    let method = match method.to_ascii_uppercase().as_str() {
+        "DELETE" => RatelimitMethod::Delete,
+        "GET" | "HEAD" => RatelimitMethod::Get,
+        "PATCH" => RatelimitMethod::Patch,
+        "POST" => RatelimitMethod::Post,
+        "PUT" => RatelimitMethod::Put,
+        _ => return None,
+    };
+
+    ratelimit_path(method, &normalize_path(path).path).ok()
+}
+
+/// Overwrite the state of a tenant's bucket with the state in the request
+/// body.
+async fn inject(
+    state: &State,
+    hash: &str,
+    method: &str,
+    path: &str,
+    request: Request<Body>,
+) -> Response<Body> {
+    let tenant = match state.ratelimiter_map.get_by_hash(hash) {
+        Some(tenant) => tenant,
+        None => return error(StatusCode::NOT_FOUND),
+    };
+
+    let path = match parse_path(method, path) {
+        Some(path) => path,
+        None => return error(StatusCode::BAD_REQUEST),
+    };
+
+    let injection = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => match serde_json::from_slice::<BucketInjection>(&body) {
+            Ok(injection) => injection,
+            Err(_) => return error(StatusCode::BAD_REQUEST),
+        },
+        Err(_) => return error(StatusCode::BAD_REQUEST),
+    };
+    let remaining = injection.remaining.unwrap_or(injection.limit);
+
+    let injected = tokio::time::timeout(
+        INJECT_TIMEOUT,
+        inject_bucket(
+            &tenant,
+            &path,
+            injection.limit,
+            remaining,
+            injection.reset_after_ms,
+        ),
+    )
+    .await;
+
+    match injected {
+        Ok(true) => {
+            warn!(
+                "Injected bucket state of {:?} for tenant {}: {}/{} remaining, resetting in {}ms",
+                path, hash, remaining, injection.limit, injection.reset_after_ms
+            );
+
+            Response::new(Body::empty())
+        }
+        Ok(false) => error(StatusCode::INTERNAL_SERVER_ERROR),
+        // The requests queued before did not drain in time
+        Err(_) => error(StatusCode::SERVICE_UNAVAILABLE),
+    }
+}
+
+/// Estimate how long a request to a path would wait for its ratelimit.
+async fn estimate(state: &State, hash: &str, method: &str, path: &str) -> Response<Body> {
+    let tenant = match state.ratelimiter_map.get_by_hash(hash) {
+        Some(tenant) => tenant,
+        None => return error(StatusCode::NOT_FOUND),
+    };
+
+    let path = match parse_path(method, path) {
+        Some(path) => path,
+        None => return error(StatusCode::BAD_REQUEST),
+    };
+
+    let queued = tenant.usage.queued(&path);
+
+    json(&Estimate {
+        queued,
+        estimated_wait_ms: fail_fast::estimated_wait(&tenant, &path, queued)
+            .await
+            .as_millis(),
+        paused: state.pause.is_paused(),
+    })
+}
+
+fn json<T: Serialize>(value: &T) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::to_vec(value).expect("admin responses are serializable"),
+        ))
+        .unwrap()
+}
+
+fn error(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(format!(
+            "http-proxy: {}",
+            status.canonical_reason().unwrap_or_default()
+        )))
+        .unwrap()
+}
@@ -0,0 +1,122 @@
+//! Usage of the Discord API versions clients request, to plan client upgrades
+//! before Discord sunsets a version.
+
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    sync::Mutex,
+};
+use tracing::warn;
+
+/// Label of requests without a version, which Discord answers with its
+/// default version.
+const UNVERSIONED: &str = "unversioned";
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct VersionUsage {
+    requests: u64,
+    deprecated: bool,
+    /// Requests per token hash.
+    tenants: BTreeMap<String, u64>,
+}
+
+/// Configured via `DEPRECATED_API_VERSIONS`.
+#[derive(Default)]
+pub struct ApiVersions {
+    deprecated: BTreeSet<u8>,
+    usage: Mutex<BTreeMap<String, VersionUsage>>,
+}
+
+impl ApiVersions {
+    pub fn from_env() -> Self {
+        match env::var("DEPRECATED_API_VERSIONS") {
+            Ok(value) => Self::new(&value),
+            Err(_) => Self::default(),
+        }
+    }
+
+    /// Parse a comma-separated list of deprecated versions, with or without
+    /// a leading `v`.
+    fn new(deprecated: &str) -> Self {
+        let mut versions = BTreeSet::new();
+
+        for entry in deprecated
+            .split(',')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            match entry.trim_start_matches(['v', 'V']).parse() {
+                Ok(version) => {
+                    versions.insert(version);
+                }
+                Err(_) => warn!("Ignoring invalid DEPRECATED_API_VERSIONS entry {:?}", entry),
+            }
+        }
+
+        Self {
+            deprecated: versions,
+            usage: Mutex::default(),
+        }
+    }
+
+    /// Count a request of a token to a version.
+    ///
+    /// The first request of each token to a deprecated version is logged.
+    pub fn record(&self, version: Option<u8>, hash: &str) {
+        let label = version.map_or_else(|| UNVERSIONED.to_string(), |v| format!("v{}", v));
+        let deprecated = version.is_some_and(|version| self.deprecated.contains(&version));
+
+        let mut usage = self.usage.lock().expect("api versions poisoned");
+        let entry = usage.entry(label.clone()).or_default();
+        entry.requests += 1;
+        entry.deprecated = deprecated;
+
+        let requests = entry.tenants.entry(hash.to_string()).or_default();
+        *requests += 1;
+
+        if deprecated && *requests == 1 {
+            warn!(
+                "Token {} uses deprecated API version {}, upgrade its client",
+                hash, label
+            );
+        }
+
+        drop(usage);
+
+        #[cfg(feature = "expose-metrics")]
+        metrics::increment_counter!(
+            format!("{}_api_version_requests_total", crate::METRIC_KEY.as_str()),
+            "version" => label
+        );
+    }
+
+    /// Usage of all versions since the proxy started.
+    pub fn usage(&self) -> BTreeMap<String, VersionUsage> {
+        self.usage.lock().expect("api versions poisoned").clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ApiVersions;
+
+    #[test]
+    fn test_record() {
+        let versions = ApiVersions::new("v8, 9,invalid");
+        assert_eq!(versions.deprecated.len(), 2);
+
+        versions.record(Some(10), "a");
+        versions.record(Some(10), "b");
+        versions.record(Some(9), "a");
+        versions.record(None, "a");
+
+        let usage = versions.usage();
+        assert_eq!(usage.len(), 3);
+        assert_eq!(usage["v10"].requests, 2);
+        assert!(!usage["v10"].deprecated);
+        assert_eq!(usage["v10"].tenants["b"], 1);
+        assert!(usage["v9"].deprecated);
+        assert_eq!(usage["unversioned"].requests, 1);
+    }
+}
@@ -0,0 +1,119 @@
+//! Backends creating the ratelimiter of every tenant, chosen on startup with
+//! `RATELIMITER_BACKEND`.
+
+use crate::redis_ratelimiter::RedisRatelimiter;
+use std::{env, error::Error, sync::Arc};
+use tracing::warn;
+use twilight_http_ratelimiting::{
+    ticket, GetBucketFuture, GetTicketFuture, HasBucketFuture, InMemoryRatelimiter,
+    IsGloballyLockedFuture, Path, Ratelimiter,
+};
+
+pub trait Backend: Send + Sync {
+    /// Create the ratelimiter of a token, identified by its hash.
+    fn ratelimiter(&self, hash: &str) -> Arc<dyn Ratelimiter>;
+}
+
+/// Buckets kept by each replica on its own.
+pub struct InMemory;
+
+impl Backend for InMemory {
+    fn ratelimiter(&self, _: &str) -> Arc<dyn Ratelimiter> {
+        Arc::new(InMemoryRatelimiter::new())
+    }
+}
+
+/// Buckets shared by all replicas using the same Redis.
+impl Backend for RedisRatelimiter {
+    fn ratelimiter(&self, hash: &str) -> Arc<dyn Ratelimiter> {
+        Arc::new(self.for_token(hash))
+    }
+}
+
+/// No ratelimiting at all, e.g. if another layer in front of Discord already
+/// enforces the limits.
+pub struct Disabled;
+
+impl Backend for Disabled {
+    fn ratelimiter(&self, _: &str) -> Arc<dyn Ratelimiter> {
+        Arc::new(NoopRatelimiter)
+    }
+}
+
+/// Ratelimiter granting every ticket immediately.
+#[derive(Debug)]
+struct NoopRatelimiter;
+
+impl Ratelimiter for NoopRatelimiter {
+    fn bucket(&self, _: &Path) -> GetBucketFuture {
+        Box::pin(async { Ok(None) })
+    }
+
+    fn is_globally_locked(&self) -> IsGloballyLockedFuture {
+        Box::pin(async { Ok(false) })
+    }
+
+    fn has(&self, _: &Path) -> HasBucketFuture {
+        Box::pin(async { Ok(false) })
+    }
+
+    fn ticket(&self, _: Path) -> GetTicketFuture {
+        let (notifier, receiver) = ticket::channel();
+
+        // The headers are discarded, but must be received so sending them
+        // doesn't fail
+        if let Some(headers) = notifier.available() {
+            tokio::spawn(headers);
+        }
+
+        Box::pin(async { Ok(receiver) })
+    }
+}
+
+/// The backend configured with `RATELIMITER_BACKEND`, which is `memory`,
+/// `redis` or `none`.
+///
+/// Fails if Redis can't be reached.
+pub async fn from_env() -> Result<Box<dyn Backend>, Box<dyn Error>> {
+    match env::var("RATELIMITER_BACKEND").as_deref() {
+        Err(_) | Ok("memory") => Ok(Box::new(InMemory)),
+        Ok("redis") => {
+            let url = env::var("REDIS_URL")
+                .map_err(|_| "RATELIMITER_BACKEND is redis, but REDIS_URL is not set")?;
+            let ratelimiter = RedisRatelimiter::connect(&url, env::var("REDIS_KEY_PREFIX").ok())
+                .await
+                .map_err(|e| format!("failed to connect to Redis: {}", e))?;
+
+            Ok(Box::new(ratelimiter))
+        }
+        Ok("none") => {
+            warn!("RATELIMITER_BACKEND is none, requests are not ratelimited");
+
+            Ok(Box::new(Disabled))
+        }
+        Ok(other) => Err(format!(
+            "RATELIMITER_BACKEND {:?} is neither memory, redis nor none",
+            other
+        )
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backend, Disabled};
+    use twilight_http_ratelimiting::{Path, RatelimitHeaders};
+
+    #[tokio::test]
+    async fn test_disabled() {
+        let ratelimiter = Disabled.ratelimiter("abc");
+
+        for _ in 0..10 {
+            let sender = ratelimiter.wait_for_ticket(Path::Gateway).await.unwrap();
+            assert!(sender.headers(Some(RatelimitHeaders::None)).is_ok());
+        }
+
+        assert!(ratelimiter.bucket(&Path::Gateway).await.unwrap().is_none());
+        assert!(!ratelimiter.is_globally_locked().await.unwrap());
+    }
+}
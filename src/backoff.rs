@@ -0,0 +1,219 @@
+//! Adaptive backoff for buckets that keep returning 429s although requests
+//! follow their ratelimit headers, e.g. because of clock skew or limits
+//! Discord doesn't advertise.
+//!
+//! After repeated 429s, requests to the bucket are paced locally at its
+//! advertised rate slowed down by a safety factor, which decays over time.
+
+use http::StatusCode;
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, warn};
+use twilight_http_ratelimiting::{headers::RatelimitHeaders, Path};
+
+/// Consecutive 429s of a bucket after which it is backed off.
+const THRESHOLD: u32 = 2;
+
+/// Maximum safety factor, reached after four consecutive 429s.
+const MAX_FACTOR: f64 = 8.0;
+
+/// Time after which the part of a factor above 1 is halved.
+const HALF_LIFE: Duration = Duration::from_secs(30);
+
+/// Factor below which a bucket is no longer backed off.
+const MIN_FACTOR: f64 = 1.05;
+
+/// Maximum amount of buckets tracked before pruning recovered ones.
+const PRUNE_THRESHOLD: usize = 64;
+
+struct Bucket {
+    /// Amount of consecutive 429s.
+    streak: u32,
+    /// Safety factor at `updated`.
+    factor: f64,
+    updated: Instant,
+    /// Time between requests at the highest rate the bucket advertised.
+    interval: Duration,
+    /// Earliest time the next request may be sent.
+    next: Instant,
+}
+
+impl Bucket {
+    /// Safety factor after decaying until `now`.
+    fn factor(&self, now: Instant) -> f64 {
+        let half_lives = now.duration_since(self.updated).as_secs_f64() / HALF_LIFE.as_secs_f64();
+
+        1.0 + (self.factor - 1.0) * 0.5_f64.powf(half_lives)
+    }
+}
+
+/// Per-token safety factors of buckets.
+#[derive(Default)]
+pub struct Backoff {
+    buckets: Mutex<HashMap<Path, Bucket>>,
+}
+
+impl Backoff {
+    /// Record a response from Discord, except for 429s with a shared scope.
+    pub fn record(&self, path: &Path, status: StatusCode, headers: Option<&RatelimitHeaders>) {
+        let present = match headers {
+            Some(RatelimitHeaders::Present(present)) => Some(present),
+            _ => None,
+        };
+        let interval = present.map(|present| {
+            Duration::from_millis(present.reset_after()) / present.limit().max(1) as u32
+        });
+
+        self.record_at(
+            path,
+            status == StatusCode::TOO_MANY_REQUESTS,
+            interval,
+            Instant::now(),
+        );
+    }
+
+    fn record_at(&self, path: &Path, ratelimited: bool, interval: Option<Duration>, now: Instant) {
+        let mut buckets = self.buckets.lock().expect("backoff poisoned");
+
+        if !ratelimited {
+            if let Some(bucket) = buckets.get_mut(path) {
+                bucket.streak = 0;
+                bucket.interval = bucket.interval.max(interval.unwrap_or_default());
+
+                if bucket.factor(now) < MIN_FACTOR {
+                    buckets.remove(path);
+                }
+            }
+
+            return;
+        }
+
+        // Global 429s and ones without headers are left to the ratelimiter
+        let interval = match interval {
+            Some(interval) => interval,
+            None => return,
+        };
+
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| {
+                bucket.factor(now) >= MIN_FACTOR || now.duration_since(bucket.updated) < HALF_LIFE
+            });
+        }
+
+        let bucket = buckets.entry(path.clone()).or_insert_with(|| Bucket {
+            streak: 0,
+            factor: 1.0,
+            updated: now,
+            interval,
+            next: now,
+        });
+
+        bucket.streak += 1;
+        bucket.interval = bucket.interval.max(interval);
+        bucket.factor = bucket.factor(now);
+        bucket.updated = now;
+
+        if bucket.streak >= THRESHOLD {
+            if bucket.factor < MIN_FACTOR {
+                warn!("Bucket of {:?} keeps returning 429s, backing off", path);
+            }
+
+            bucket.factor = (bucket.factor.max(1.0) * 2.0).min(MAX_FACTOR);
+        }
+    }
+
+    /// Safety factor of the bucket of a path, if it is backed off.
+    pub fn factor(&self, path: &Path) -> Option<f64> {
+        let buckets = self.buckets.lock().expect("backoff poisoned");
+
+        buckets
+            .get(path)
+            .map(|bucket| bucket.factor(Instant::now()))
+            .filter(|factor| *factor >= MIN_FACTOR)
+    }
+
+    /// Wait until a request to a backed off bucket may be sent.
+    pub async fn wait(&self, path: &Path) {
+        if let Some(at) = self.reserve(path, Instant::now()) {
+            if at > Instant::now() {
+                debug!("Backing off request to {:?}", path);
+                sleep_until(at).await;
+            }
+        }
+    }
+
+    /// Reserve the earliest time at which a request to a backed off bucket
+    /// may be sent.
+    fn reserve(&self, path: &Path, now: Instant) -> Option<Instant> {
+        let mut buckets = self.buckets.lock().expect("backoff poisoned");
+        let bucket = buckets.get_mut(path)?;
+        let factor = bucket.factor(now);
+
+        if factor < MIN_FACTOR {
+            return None;
+        }
+
+        let at = bucket.next.max(now);
+        bucket.next = at + bucket.interval.mul_f64(factor);
+
+        Some(at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backoff, HALF_LIFE};
+    use std::time::Duration;
+    use tokio::time::Instant;
+    use twilight_http_ratelimiting::Path;
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff::default();
+        let path = Path::ChannelsIdMessages(1);
+        let interval = Some(Duration::from_millis(100));
+        let start = Instant::now();
+
+        // A single 429 is left to the ratelimiter
+        backoff.record_at(&path, true, interval, start);
+        assert_eq!(backoff.reserve(&path, start), None);
+
+        backoff.record_at(&path, true, interval, start);
+        assert_eq!(backoff.reserve(&path, start), Some(start));
+        assert_eq!(
+            backoff.reserve(&path, start),
+            Some(start + Duration::from_millis(200))
+        );
+
+        // Successes end the streak but don't reset the factor
+        backoff.record_at(&path, false, interval, start);
+        backoff.record_at(&path, true, interval, start);
+        assert_eq!(
+            backoff.reserve(&path, start),
+            Some(start + Duration::from_millis(400))
+        );
+
+        // Further 429s increase the factor up to the maximum
+        for _ in 0..5 {
+            backoff.record_at(&path, true, interval, start);
+        }
+
+        assert_eq!(
+            backoff.reserve(&path, start),
+            Some(start + Duration::from_millis(600))
+        );
+        assert_eq!(
+            backoff.reserve(&path, start),
+            Some(start + Duration::from_millis(1400))
+        );
+
+        // The factor decays until the bucket is no longer backed off
+        let later = start + HALF_LIFE * 10;
+        assert_eq!(backoff.reserve(&path, later), None);
+        backoff.record_at(&path, false, interval, later);
+        assert!(backoff.buckets.lock().unwrap().is_empty());
+
+        // Other buckets are not affected
+        assert_eq!(backoff.reserve(&Path::ChannelsIdMessages(2), start), None);
+    }
+}
@@ -0,0 +1,261 @@
+//! Checks on request bodies that need them buffered before forwarding.
+
+use crate::{
+    error::RequestError,
+    limits::{self, LimitExceeded},
+    multipart,
+    sublimit::Rule,
+    State,
+};
+use http::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    HeaderValue,
+};
+use hyper::{
+    body::{Bytes, HttpBody},
+    Body, Request,
+};
+use tracing::{debug, error};
+use twilight_http_ratelimiting::{Method, Path};
+
+/// Name of the multipart part containing the JSON payload of a request with
+/// attachments.
+const PAYLOAD_JSON: &str = "payload_json";
+
+/// Run the configured checks on a request body, buffering it if needed.
+///
+/// Returns the sublimit applying to the request, which is `None` if the rule
+/// found for the route doesn't apply to the request's body.
+///
+/// Messages lacking `allowed_mentions` get the default of the
+/// [`MentionPolicy`] added.
+///
+/// [`MentionPolicy`]: crate::mentions::MentionPolicy
+pub async fn inspect<'a>(
+    state: &State,
+    hash: &str,
+    method: Method,
+    path: &Path,
+    request: &mut Request<Body>,
+    sublimit: Option<&'a Rule>,
+) -> Result<Option<&'a Rule>, RequestError> {
+    let mutating = matches!(method, Method::Patch | Method::Post | Method::Put);
+    let enforce_limits = state.enforce_payload_limits && mutating;
+    let check_message = state.enforce_payload_limits && limits::is_message_route(method, path);
+    let validate_json = state.validate_json && mutating;
+    let mention_policy = state
+        .mention_policy
+        .as_ref()
+        .filter(|policy| policy.applies(hash, method, path));
+
+    if enforce_limits {
+        let length = request
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+
+        // Reject large bodies before receiving them
+        if let Some(length) = length {
+            check_size(state, hash, length)?;
+        }
+    }
+
+    let boundary = match multipart::boundary(request.headers()) {
+        Some(Ok(boundary)) => Some(boundary),
+        Some(Err(e)) if state.validate_multipart => {
+            debug!("Rejecting multipart request: {}", e);
+            return Err(RequestError::InvalidMultipart { source: e });
+        }
+        _ => None,
+    };
+    let json = is_json(request);
+
+    let needs_body = sublimit.is_some_and(|rule| rule.fields().is_some())
+        || (state.validate_multipart && boundary.is_some())
+        || ((enforce_limits || validate_json || mention_policy.is_some())
+            && (json || boundary.is_some()));
+
+    if !needs_body {
+        return Ok(sublimit);
+    }
+
+    let body = read(state, hash, request.body_mut()).await?;
+
+    let parts = match boundary.map(|boundary| multipart::parse(&body, &boundary)) {
+        Some(Ok(parts)) => parts,
+        Some(Err(e)) if state.validate_multipart => {
+            debug!("Rejecting multipart request: {}", e);
+            return Err(RequestError::InvalidMultipart { source: e });
+        }
+        _ => Vec::new(),
+    };
+
+    let payload = if parts.is_empty() {
+        Some(&body[..])
+    } else {
+        parts
+            .iter()
+            .find(|part| part.name == PAYLOAD_JSON)
+            .map(|part| part.content)
+    };
+
+    if validate_json && (json || !parts.is_empty()) {
+        if let Some(payload) = payload.filter(|payload| !payload.is_empty()) {
+            if let Err(e) = serde_json::from_slice::<serde::de::IgnoredAny>(payload) {
+                let offset = error_offset(payload, &e);
+                debug!("Rejecting request with invalid JSON at {}: {}", offset, e);
+
+                return Err(RequestError::InvalidJson { source: e, offset });
+            }
+        }
+    }
+
+    if check_message {
+        if let Some(Err(e)) = payload.map(limits::check_message) {
+            debug!("Rejecting request exceeding payload limits: {}", e);
+            return Err(RequestError::LimitExceeded { source: e });
+        }
+    }
+
+    let sublimit =
+        sublimit.filter(|rule| payload.is_some_and(|payload| rule.matches_body(payload)));
+
+    let rewritten = mention_policy.zip(payload).and_then(|(policy, payload)| {
+        let replacement = policy.apply(path, payload)?;
+
+        // The payload is either the whole body or a part of it
+        let start = payload.as_ptr() as usize - body.as_ptr() as usize;
+        let end = start + payload.len();
+
+        Some([&body[..start], &replacement, &body[end..]].concat())
+    });
+
+    match rewritten {
+        Some(rewritten) => {
+            debug!("Adding the default allowed_mentions to the message");
+
+            request
+                .headers_mut()
+                .insert(CONTENT_LENGTH, HeaderValue::from(rewritten.len()));
+            *request.body_mut() = Body::from(rewritten);
+        }
+        None => *request.body_mut() = Body::from(body),
+    }
+
+    Ok(sublimit)
+}
+
+/// Read a request body into memory, stopping as soon as it exceeds the
+/// tenant's upload limit if payload limits are enforced.
+///
+/// Bodies sent without a `Content-Length`, such as chunked uploads, can't be
+/// rejected before receiving them, so this keeps them from making the proxy
+/// buffer an arbitrary amount of data.
+pub async fn read(state: &State, hash: &str, body: &mut Body) -> Result<Bytes, RequestError> {
+    let max = if state.enforce_payload_limits {
+        state.payload_limits.upload_limit(hash)
+    } else {
+        u64::MAX
+    };
+
+    read_capped(body, max).await.inspect_err(|e| {
+        if let RequestError::LimitExceeded { source } = e {
+            debug!("Rejecting request exceeding payload limits: {}", source);
+        }
+    })
+}
+
+async fn read_capped(body: &mut Body, max: u64) -> Result<Bytes, RequestError> {
+    let mut buffer = Vec::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| {
+            error!("Failed to read request body: {:?}", e);
+
+            RequestError::InvalidBody { source: e }
+        })?;
+        buffer.extend_from_slice(&chunk);
+
+        let size = buffer.len() as u64;
+
+        if size > max {
+            return Err(RequestError::LimitExceeded {
+                source: LimitExceeded::Upload { size, max },
+            });
+        }
+    }
+
+    Ok(Bytes::from(buffer))
+}
+
+fn check_size(state: &State, hash: &str, size: u64) -> Result<(), RequestError> {
+    state.payload_limits.check_size(hash, size).map_err(|e| {
+        debug!("Rejecting request exceeding payload limits: {}", e);
+
+        RequestError::LimitExceeded { source: e }
+    })
+}
+
+/// Byte offset of a JSON syntax error within the payload.
+fn error_offset(payload: &[u8], error: &serde_json::Error) -> usize {
+    if error.is_eof() {
+        return payload.len();
+    }
+
+    let line_start = payload
+        .split_inclusive(|byte| *byte == b'\n')
+        .take(error.line().saturating_sub(1))
+        .map(<[u8]>::len)
+        .sum::<usize>();
+
+    (line_start + error.column().saturating_sub(1)).min(payload.len())
+}
+
+fn is_json(request: &Request<Body>) -> bool {
+    request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{error_offset, read_capped};
+    use crate::{error::RequestError, limits::LimitExceeded};
+    use hyper::{body::Bytes, Body};
+
+    fn offset(payload: &str) -> usize {
+        let error = serde_json::from_str::<serde::de::IgnoredAny>(payload).unwrap_err();
+
+        error_offset(payload.as_bytes(), &error)
+    }
+
+    #[test]
+    fn test_error_offset() {
+        assert_eq!(offset(r#"{"content": "a",}"#), 16);
+        assert_eq!(offset("{\n  \"content\": x\n}"), 15);
+        assert_eq!(offset(r#"{"content": "a""#), 15);
+    }
+
+    #[tokio::test]
+    async fn test_read_capped() {
+        let chunked = || {
+            let chunks = (0..4).map(|_| Ok::<_, std::io::Error>(Bytes::from_static(&[0; 10])));
+
+            Body::wrap_stream(futures_util::stream::iter(chunks))
+        };
+
+        assert_eq!(read_capped(&mut chunked(), 40).await.unwrap().len(), 40);
+
+        // Reading stops at the chunk exceeding the limit
+        assert!(matches!(
+            read_capped(&mut chunked(), 15).await,
+            Err(RequestError::LimitExceeded {
+                source: LimitExceeded::Upload { size: 20, max: 15 }
+            })
+        ));
+    }
+}
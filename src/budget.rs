@@ -0,0 +1,220 @@
+use crate::{
+    parse_env,
+    tenant::{parse_tenant_values, Usage},
+};
+use std::{
+    collections::HashMap,
+    env, mem,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+use twilight_http_ratelimiting::Method;
+
+const SECONDS_PER_DAY: u64 = 86400;
+
+/// Daily request budgets of tenants.
+///
+/// Budgets reset at midnight UTC. Once a tenant used up its budget, only
+/// requests with essential methods are forwarded until the next reset.
+pub struct Budgets {
+    default: Option<u64>,
+    essential: Vec<Method>,
+    tenants: HashMap<String, u64>,
+}
+
+impl Budgets {
+    pub fn from_env() -> Self {
+        let tenants = env::var("DAILY_BUDGETS")
+            .map(|value| parse_tenant_values(&value, "daily budget"))
+            .unwrap_or_default();
+
+        let essential = env::var("DAILY_BUDGET_ESSENTIAL_METHODS")
+            .map(|value| parse_methods(&value))
+            .unwrap_or_else(|_| vec![Method::Get]);
+
+        Self {
+            default: parse_env("DEFAULT_DAILY_BUDGET"),
+            essential,
+            tenants,
+        }
+    }
+
+    /// The daily budget of a tenant, if any.
+    pub fn limit(&self, hash: &str) -> Option<u64> {
+        self.tenants.get(hash).copied().or(self.default)
+    }
+
+    /// Count a request against the tenant's budget.
+    ///
+    /// Returns the amount of seconds until the budget resets if the request
+    /// must be rejected. The request is taken back from the budget if the
+    /// returned reservation is dropped before it is sent.
+    pub fn admit<'a>(
+        &self,
+        usage: &'a Usage,
+        method: Method,
+    ) -> Result<Option<Reservation<'a>>, u64> {
+        let limit = match self.limit(usage.hash()) {
+            Some(limit) => limit,
+            None => return Ok(None),
+        };
+
+        // Essential requests are counted, but never rejected
+        let limit = if self.essential.contains(&method) {
+            u64::MAX
+        } else {
+            limit
+        };
+
+        let now = unix_seconds();
+        let day = now / SECONDS_PER_DAY;
+
+        if !usage.count_daily(day, limit) {
+            return Err(SECONDS_PER_DAY - now % SECONDS_PER_DAY);
+        }
+
+        Ok(Some(Reservation { usage, day }))
+    }
+}
+
+/// A request counted against a budget, taken back if the request is dropped
+/// before it is sent, e.g. because it timed out waiting for its ratelimit
+/// ticket.
+pub struct Reservation<'a> {
+    usage: &'a Usage,
+    day: u64,
+}
+
+impl Reservation<'_> {
+    /// Keep the request counted, as it is sent.
+    pub fn keep(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.usage.uncount_daily(self.day);
+    }
+}
+
+/// Current UTC day, as used by [`Usage::daily`].
+pub fn today() -> u64 {
+    unix_seconds() / SECONDS_PER_DAY
+}
+
+fn unix_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default()
+}
+
+fn parse_methods(value: &str) -> Vec<Method> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|method| !method.is_empty())
+        .filter_map(|method| match method.to_ascii_uppercase().as_str() {
+            "DELETE" => Some(Method::Delete),
+            "GET" => Some(Method::Get),
+            "PATCH" => Some(Method::Patch),
+            "POST" => Some(Method::Post),
+            "PUT" => Some(Method::Put),
+            _ => {
+                warn!("Ignoring unknown essential method {:?}", method);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_methods, today, Budgets};
+    use crate::tenant::Tenant;
+    use std::{collections::HashMap, thread};
+    use twilight_http_ratelimiting::Method;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse_methods("get,POST,head"),
+            vec![Method::Get, Method::Post]
+        );
+    }
+
+    #[test]
+    fn test_admit() {
+        let tenant = Tenant::new("Bot abc");
+        let mut tenants = HashMap::new();
+        tenants.insert(tenant.usage.hash().to_string(), 2);
+
+        let budgets = Budgets {
+            default: None,
+            essential: vec![Method::Get],
+            tenants,
+        };
+
+        let first = budgets.admit(&tenant.usage, Method::Post).unwrap();
+        let second = budgets.admit(&tenant.usage, Method::Post).unwrap();
+        assert!(budgets.admit(&tenant.usage, Method::Post).is_err());
+
+        // Rejected requests don't count, essential ones do
+        assert_eq!(tenant.usage.daily(today()), 2);
+        budgets
+            .admit(&tenant.usage, Method::Get)
+            .unwrap()
+            .unwrap()
+            .keep();
+        assert_eq!(tenant.usage.daily(today()), 3);
+
+        // Requests that weren't sent are taken back
+        first.unwrap().keep();
+        drop(second);
+        assert_eq!(tenant.usage.daily(today()), 2);
+
+        // Tenants without budget are never limited
+        let other = Tenant::new("Bot def");
+        for _ in 0..5 {
+            assert!(budgets.admit(&other.usage, Method::Post).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_admit_concurrent() {
+        let tenant = Tenant::new("Bot abc");
+        let mut tenants = HashMap::new();
+        tenants.insert(tenant.usage.hash().to_string(), 10);
+
+        let budgets = Budgets {
+            default: None,
+            essential: Vec::new(),
+            tenants,
+        };
+
+        let admitted = thread::scope(|scope| {
+            let handles = (0..50)
+                .map(|_| {
+                    scope.spawn(|| match budgets.admit(&tenant.usage, Method::Post) {
+                        Ok(reservation) => {
+                            reservation.expect("tenant has a budget").keep();
+
+                            true
+                        }
+                        Err(_) => false,
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .filter(|admitted| *admitted)
+                .count()
+        });
+
+        assert_eq!(admitted, 10);
+        assert_eq!(tenant.usage.daily(today()), 10);
+    }
+}
@@ -0,0 +1,352 @@
+//! Banning many users of a guild at once, e.g. after a raid, with
+//! `POST /__proxy/bulk-ban/guilds/{id}`.
+//!
+//! The users are banned with Discord's bulk bans of up to 200 users each, or
+//! one by one on API versions before bulk bans were added. Every request goes
+//! through the proxy's own pipeline with the client's token, so it is paced by
+//! the ratelimits like any other request.
+
+use crate::{
+    error::RequestError, forwarded::ClientAddr, handle_request, path::normalize_path,
+    tenant::Tenant, State,
+};
+use http::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    HeaderMap, HeaderValue, Method, Request, Response, StatusCode,
+};
+use hyper::{body::Bytes, Body};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{debug, info};
+
+/// Path prefix of the bulk ban endpoint.
+pub const PREFIX: &str = "/__proxy/bulk-ban/";
+
+/// Maximum amount of users Discord bans with a single bulk ban.
+const CHUNK_SIZE: usize = 200;
+
+/// API version used if the request doesn't name one.
+const DEFAULT_VERSION: u8 = 10;
+
+/// First API version with bulk bans.
+const BULK_BAN_VERSION: u8 = 10;
+
+#[derive(Deserialize)]
+struct BulkBan {
+    /// IDs as strings or numbers.
+    user_ids: Vec<Value>,
+    delete_message_seconds: Option<u64>,
+}
+
+/// Response of Discord's bulk ban endpoint.
+#[derive(Deserialize)]
+struct BulkBanResponse {
+    banned_users: Vec<String>,
+    failed_users: Vec<String>,
+}
+
+/// Outcome of all bans, returned to the client.
+#[derive(Default, Serialize)]
+struct Report {
+    banned_users: Vec<String>,
+    failed_users: Vec<String>,
+    /// Requests Discord didn't answer successfully.
+    errors: Vec<Failure>,
+}
+
+#[derive(Serialize)]
+struct Failure {
+    user_ids: Vec<String>,
+    status: u16,
+    /// Discord's error message, if any.
+    message: Option<String>,
+}
+
+/// Sends the ban requests of a bulk ban.
+struct Banner<'a> {
+    state: &'a State,
+    tenant: Tenant,
+    token: String,
+    headers: HeaderMap,
+    client: Option<ClientAddr>,
+    /// API prefix including the version.
+    api: String,
+    guild_id: String,
+    delete_message_seconds: Option<u64>,
+}
+
+impl Banner<'_> {
+    /// Send a request with a JSON body, returning the status and body of the
+    /// response.
+    async fn send(&self, method: Method, path: &str, body: &Value) -> (StatusCode, Bytes) {
+        let mut request = Request::new(Body::from(body.to_string()));
+        *request.method_mut() = method;
+        *request.headers_mut() = self.headers.clone();
+
+        match format!("{}{}", self.api, path).parse() {
+            Ok(uri) => *request.uri_mut() = uri,
+            Err(_) => return (StatusCode::BAD_REQUEST, Bytes::new()),
+        }
+
+        if let Some(client) = self.client {
+            request.extensions_mut().insert(client);
+        }
+
+        let response = match handle_request(
+            self.state,
+            self.tenant.clone(),
+            Some(self.token.clone()),
+            request,
+        )
+        .await
+        {
+            Ok(response) => response,
+            Err(e) => e.as_response(),
+        };
+
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+
+        (parts.status, body)
+    }
+
+    /// Ban the users with bulk bans of up to 200 users.
+    async fn bulk(&self, user_ids: Vec<String>, report: &mut Report) {
+        let mut chunks = user_ids.chunks(CHUNK_SIZE);
+
+        while let Some(chunk) = chunks.next() {
+            let mut body = json!({ "user_ids": chunk });
+
+            if let Some(seconds) = self.delete_message_seconds {
+                body["delete_message_seconds"] = seconds.into();
+            }
+
+            let path = format!("/guilds/{}/bulk-ban", self.guild_id);
+            let (status, response) = self.send(Method::POST, &path, &body).await;
+
+            let parsed = status
+                .is_success()
+                .then(|| serde_json::from_slice::<BulkBanResponse>(&response).ok())
+                .flatten();
+
+            if let Some(parsed) = parsed {
+                report.banned_users.extend(parsed.banned_users);
+                report.failed_users.extend(parsed.failed_users);
+
+                continue;
+            }
+
+            let mut failed = chunk.to_vec();
+
+            // Without the permissions, the remaining bans fail as well
+            if is_fatal(status) {
+                failed.extend(chunks.by_ref().flatten().cloned());
+            }
+
+            report.fail(failed, status, &response);
+        }
+    }
+
+    /// Ban the users one by one.
+    async fn sequential(&self, user_ids: Vec<String>, report: &mut Report) {
+        let body = self.delete_message_seconds.map_or_else(
+            || json!({}),
+            |seconds| json!({ "delete_message_seconds": seconds }),
+        );
+
+        let mut user_ids = user_ids.into_iter();
+
+        while let Some(user_id) = user_ids.next() {
+            let path = format!("/guilds/{}/bans/{}", self.guild_id, user_id);
+            let (status, response) = self.send(Method::PUT, &path, &body).await;
+
+            if status.is_success() {
+                report.banned_users.push(user_id);
+
+                continue;
+            }
+
+            let mut failed = vec![user_id];
+
+            if is_fatal(status) {
+                failed.extend(user_ids.by_ref());
+            }
+
+            report.fail(failed, status, &response);
+        }
+    }
+}
+
+impl Report {
+    fn fail(&mut self, user_ids: Vec<String>, status: StatusCode, response: &[u8]) {
+        let message = serde_json::from_slice::<Value>(response)
+            .ok()
+            .and_then(|error| error.get("message")?.as_str().map(str::to_string))
+            .or_else(|| {
+                // Errors of the proxy itself are plain text
+                std::str::from_utf8(response)
+                    .ok()
+                    .filter(|text| !text.is_empty())
+                    .map(str::to_string)
+            });
+
+        self.failed_users.extend(user_ids.iter().cloned());
+        self.errors.push(Failure {
+            user_ids,
+            status: status.as_u16(),
+            message,
+        });
+    }
+}
+
+/// Whether a failed request means the remaining ones would fail too.
+fn is_fatal(status: StatusCode) -> bool {
+    matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+}
+
+/// Parse the user IDs of a bulk ban, which Discord accepts as strings or
+/// numbers.
+fn parse_ids(user_ids: &[Value]) -> Option<Vec<String>> {
+    user_ids
+        .iter()
+        .map(|id| match id {
+            Value::String(id) if id.parse::<u64>().is_ok() => Some(id.clone()),
+            Value::Number(id) => id.as_u64().map(|id| id.to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Handle a request to the bulk ban endpoint.
+pub async fn handle(state: &State, request: Request<Body>) -> Response<Body> {
+    ban(state, request)
+        .await
+        .unwrap_or_else(|e| e.as_response())
+}
+
+async fn ban(state: &State, request: Request<Body>) -> Result<Response<Body>, RequestError> {
+    // Keep the leading slash
+    let normalized = normalize_path(&request.uri().path()[PREFIX.len() - 1..]);
+    let segments = normalized.path.split('/').collect::<Vec<_>>();
+
+    let guild_id = match segments.as_slice() {
+        ["", "guilds", id] if request.method() == Method::POST && id.parse::<u64>().is_ok() => {
+            id.to_string()
+        }
+        _ => {
+            debug!(
+                "Rejecting bulk ban to {} {}",
+                request.method(),
+                normalized.path
+            );
+            return Err(RequestError::InvalidBulkBan);
+        }
+    };
+
+    let token = request
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok());
+
+    let (tenant, token) = match state.ratelimiter_map.get_or_insert(token) {
+        Some(tenant) => tenant,
+        None => {
+            debug!("Rejecting bulk ban without Authorization header");
+            return Err(RequestError::MissingToken);
+        }
+    };
+
+    let (mut parts, body) = request.into_parts();
+    let body = hyper::body::to_bytes(body)
+        .await
+        .map_err(|e| RequestError::InvalidBody { source: e })?;
+
+    let bulk_ban = serde_json::from_slice::<BulkBan>(&body).map_err(|e| {
+        debug!("Rejecting invalid bulk ban: {}", e);
+        RequestError::InvalidBulkBan
+    })?;
+    let user_ids = parse_ids(&bulk_ban.user_ids).ok_or(RequestError::InvalidBulkBan)?;
+
+    // Keeps headers like `X-Audit-Log-Reason` for every request
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+    let version = normalized.version().unwrap_or(DEFAULT_VERSION);
+    let banner = Banner {
+        state,
+        tenant,
+        token,
+        headers: parts.headers,
+        client: parts.extensions.get::<ClientAddr>().copied(),
+        api: format!("/api/v{}", version),
+        guild_id,
+        delete_message_seconds: bulk_ban.delete_message_seconds,
+    };
+
+    let mut report = Report::default();
+
+    if version >= BULK_BAN_VERSION {
+        banner.bulk(user_ids, &mut report).await;
+    } else {
+        banner.sequential(user_ids, &mut report).await;
+    }
+
+    info!(
+        "Bulk ban in guild {}: {} users banned, {} failed",
+        banner.guild_id,
+        report.banned_users.len(),
+        report.failed_users.len()
+    );
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::to_vec(&report).expect("reports are serializable"),
+        ))
+        .expect("response is valid"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_ids, Report};
+    use http::StatusCode;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_ids() {
+        assert_eq!(
+            parse_ids(&[json!("1"), json!(2)]),
+            Some(vec!["1".to_string(), "2".to_string()])
+        );
+        assert_eq!(parse_ids(&[json!("a")]), None);
+        assert_eq!(parse_ids(&[json!(-1)]), None);
+    }
+
+    #[test]
+    fn test_fail() {
+        let mut report = Report::default();
+
+        report.fail(
+            vec!["1".to_string()],
+            StatusCode::FORBIDDEN,
+            br#"{"message": "Missing Permissions", "code": 50013}"#,
+        );
+        report.fail(
+            vec!["2".to_string()],
+            StatusCode::SERVICE_UNAVAILABLE,
+            b"http-proxy: Traffic is paused",
+        );
+
+        assert_eq!(report.failed_users, ["1", "2"]);
+        assert_eq!(
+            report.errors[0].message.as_deref(),
+            Some("Missing Permissions")
+        );
+        assert_eq!(
+            report.errors[1].message.as_deref(),
+            Some("http-proxy: Traffic is paused")
+        );
+    }
+}
@@ -0,0 +1,251 @@
+//! Routing of a fraction of traffic to a canary instance of the proxy, e.g. a
+//! new version, to compare it against this one before upgrading all replicas.
+//!
+//! Requests are assigned by their token, so every token's buckets live on a
+//! single instance and the two never exceed a ratelimit together.
+
+use crate::{
+    error::RequestError,
+    forwarded::ClientAddr,
+    headers::remove_hop_by_hop,
+    parse_env,
+    upstream::{Upstream, UpstreamError},
+};
+use http::{
+    header::{AUTHORIZATION, CONTENT_LENGTH, HOST},
+    HeaderValue, Request, Response,
+};
+use hyper::{client::HttpConnector, Body, Client};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use std::{
+    collections::hash_map::DefaultHasher,
+    convert::TryFrom,
+    env,
+    hash::{Hash, Hasher},
+};
+use tracing::{debug, warn};
+
+#[cfg(feature = "expose-metrics")]
+use http::StatusCode;
+#[cfg(feature = "expose-metrics")]
+use tokio::time::{Duration, Instant};
+
+/// Header marking requests sent by another proxy, which are never routed to a
+/// canary again and not forwarded to Discord.
+pub const CANARY_HEADER: &str = "x-proxy-canary";
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Instance that handled a request, used as the `build` label of metrics.
+#[cfg(feature = "expose-metrics")]
+#[derive(Clone, Copy)]
+pub enum Build {
+    Primary,
+    Canary,
+}
+
+#[cfg(feature = "expose-metrics")]
+impl Build {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Primary => "primary",
+            Self::Canary => "canary",
+        }
+    }
+}
+
+/// Configured via `CANARY_URL` and `CANARY_PERCENT`.
+pub struct Canary {
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    /// Percentage of tokens routed to the canary.
+    percent: f64,
+    upstream: Upstream,
+}
+
+impl Canary {
+    /// Returns `None` if no canary is configured.
+    pub fn from_env() -> Result<Option<Self>, UpstreamError> {
+        let upstream = match env::var("CANARY_URL") {
+            Ok(url) => Upstream::new(&url)?,
+            Err(_) => return Ok(None),
+        };
+
+        let mut percent = parse_env("CANARY_PERCENT").unwrap_or(10.0);
+
+        if !(0.0..=100.0).contains(&percent) {
+            warn!("CANARY_PERCENT must be between 0 and 100, using 10");
+            percent = 10.0;
+        }
+
+        let connector = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        Ok(Some(Self {
+            client: Client::builder().build(connector),
+            percent,
+            upstream,
+        }))
+    }
+
+    /// Whether requests with the given `Authorization` value, or path if they
+    /// have none, are routed to the canary.
+    fn sample(&self, key: &[u8]) -> bool {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+
+        ((hasher.finish() % 10_000) as f64) < self.percent * 100.0
+    }
+
+    /// Send a request to the canary if its token is routed there.
+    ///
+    /// Returns the request if it should be handled by this instance instead,
+    /// including if the canary can't be connected to.
+    pub async fn forward(&self, request: Request<Body>) -> Result<Response<Body>, Request<Body>> {
+        let routed = match request.headers().get(AUTHORIZATION) {
+            Some(token) => self.sample(token.as_bytes()),
+            None => self.sample(request.uri().path().as_bytes()),
+        };
+
+        if !routed {
+            return Err(request);
+        }
+
+        let path_and_query = request
+            .uri()
+            .path_and_query()
+            .map_or("/", |path_and_query| path_and_query.as_str());
+
+        let uri = match self.upstream.uri("", path_and_query, None) {
+            Ok(uri) => uri,
+            Err(e) => {
+                debug!("Failed to create URI for the canary: {:?}", e);
+
+                return Err(request);
+            }
+        };
+
+        // Buffered, so the request can still be handled here if the canary
+        // is down
+        let (parts, body) = request.into_parts();
+        let body = match hyper::body::to_bytes(body).await {
+            Ok(body) => body,
+            Err(e) => {
+                debug!("Failed to read request body for the canary: {:?}", e);
+
+                return Err(Request::from_parts(parts, Body::empty()));
+            }
+        };
+
+        let mut canary_request = Request::new(Body::from(body.clone()));
+        *canary_request.method_mut() = parts.method.clone();
+        *canary_request.uri_mut() = uri;
+        *canary_request.headers_mut() = parts.headers.clone();
+
+        let headers = canary_request.headers_mut();
+        remove_hop_by_hop(headers);
+        headers.remove(CONTENT_LENGTH);
+        headers.insert(HOST, self.upstream.host().clone());
+        headers.insert(CANARY_HEADER, HeaderValue::from_static("1"));
+
+        if let Some(ClientAddr(client)) = parts.extensions.get::<ClientAddr>() {
+            let forwarded_for = match headers.get(X_FORWARDED_FOR).map(HeaderValue::to_str) {
+                Some(Ok(previous)) => format!("{}, {}", previous, client),
+                _ => client.to_string(),
+            };
+
+            if let Ok(value) = HeaderValue::try_from(forwarded_for) {
+                headers.insert(X_FORWARDED_FOR, value);
+            }
+        }
+
+        #[cfg(feature = "expose-metrics")]
+        let start = Instant::now();
+
+        match self.client.request(canary_request).await {
+            Ok(response) => {
+                #[cfg(feature = "expose-metrics")]
+                record(Build::Canary, response.status(), start.elapsed());
+
+                Ok(response)
+            }
+            Err(e) if e.is_connect() => {
+                warn!(
+                    "Failed to connect to the canary, handling the request here: {}",
+                    e
+                );
+
+                Err(Request::from_parts(parts, Body::from(body)))
+            }
+            Err(e) => {
+                warn!("Error when requesting the canary: {}", e);
+
+                #[cfg(feature = "expose-metrics")]
+                record(Build::Canary, StatusCode::BAD_GATEWAY, start.elapsed());
+
+                Ok(RequestError::RequestIssue { source: e }.as_response())
+            }
+        }
+    }
+}
+
+/// Record the status and duration of a request handled by an instance.
+#[cfg(feature = "expose-metrics")]
+pub fn record(build: Build, status: StatusCode, duration: Duration) {
+    metrics::histogram!(
+        format!("{}_canary_duration_seconds", crate::METRIC_KEY.as_str()),
+        duration,
+        "build" => build.name(),
+        "status" => status.as_str().to_string()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Canary;
+    use crate::upstream::Upstream;
+    use hyper::Client;
+    use hyper_rustls::HttpsConnectorBuilder;
+
+    fn canary(percent: f64) -> Canary {
+        let connector = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        Canary {
+            client: Client::builder().build(connector),
+            percent,
+            upstream: Upstream::new("http://127.0.0.1:1").unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_sample() {
+        let tokens = (0..1000)
+            .map(|token| format!("Bot {}", token))
+            .collect::<Vec<_>>();
+
+        assert!(tokens
+            .iter()
+            .all(|token| canary(100.0).sample(token.as_bytes())));
+        assert!(tokens
+            .iter()
+            .all(|token| !canary(0.0).sample(token.as_bytes())));
+
+        let canary = canary(10.0);
+        let routed = tokens
+            .iter()
+            .filter(|token| canary.sample(token.as_bytes()))
+            .count();
+        assert!((50..150).contains(&routed));
+
+        // Tokens always stay on the same instance
+        assert!(tokens
+            .iter()
+            .all(|token| canary.sample(token.as_bytes()) == canary.sample(token.as_bytes())));
+    }
+}
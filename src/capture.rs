@@ -0,0 +1,236 @@
+//! Capturing of proxied traffic into a replayable file.
+//!
+//! Every exchange is written as a single line of JSON. Tokens in the
+//! `Authorization` header are never written, exchanges are attributed to the
+//! token's hash instead.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::{header::AUTHORIZATION, HeaderMap};
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    io::{Error as IoError, ErrorKind},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::{
+    fs::OpenOptions,
+    io::AsyncWriteExt,
+    sync::mpsc::{self, error::TrySendError, Sender},
+};
+use tracing::{error, warn};
+
+/// Exchanges waiting to be written, further ones are dropped so a slow disk
+/// can't make the proxy buffer an unbounded amount of them.
+const BUFFER: usize = 1024;
+
+/// A request body or response body.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Text(String),
+    /// Base64-encoded bytes of bodies that aren't valid UTF-8.
+    Base64(String),
+}
+
+impl Payload {
+    pub fn new(bytes: &[u8]) -> Option<Self> {
+        if bytes.is_empty() {
+            return None;
+        }
+
+        Some(match std::str::from_utf8(bytes) {
+            Ok(text) => Self::Text(text.to_string()),
+            Err(_) => Self::Base64(STANDARD.encode(bytes)),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, IoError> {
+        match self {
+            Self::Text(text) => Ok(text.clone().into_bytes()),
+            Self::Base64(encoded) => STANDARD
+                .decode(encoded)
+                .map_err(|source| IoError::new(ErrorKind::InvalidData, source)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Payload>,
+}
+
+/// A request forwarded to Discord and, if captured, its response.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Exchange {
+    /// Hash of the token the request was sent with.
+    pub tenant: String,
+    pub method: String,
+    /// Path and query, as forwarded to Discord.
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Payload>,
+    pub response: Option<RecordedResponse>,
+}
+
+/// Convert headers into the captured representation, leaving out the token.
+pub fn capture_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter(|(name, _)| *name != AUTHORIZATION)
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect()
+}
+
+/// Writer of captured exchanges.
+pub struct Capture {
+    responses: bool,
+    sender: Sender<Exchange>,
+    /// Exchanges dropped because the writer fell behind.
+    dropped: AtomicU64,
+}
+
+impl Capture {
+    /// Start capturing into the file at `CAPTURE_FILE`, if set.
+    ///
+    /// Captured exchanges are appended to the file.
+    pub async fn from_env() -> Result<Option<Self>, IoError> {
+        let path = match env::var("CAPTURE_FILE") {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .await?;
+
+        let (sender, mut receiver) = mpsc::channel::<Exchange>(BUFFER);
+
+        // Write from a separate task, so requests never wait for the disk
+        tokio::spawn(async move {
+            while let Some(exchange) = receiver.recv().await {
+                let mut line = serde_json::to_vec(&exchange).expect("exchanges are serializable");
+                line.push(b'\n');
+
+                if let Err(e) = file.write_all(&line).await {
+                    error!("Failed to write to capture file {}: {}", path, e);
+                }
+            }
+        });
+
+        Ok(Some(Self {
+            responses: env::var("CAPTURE_RESPONSES").is_ok(),
+            sender,
+            dropped: AtomicU64::new(0),
+        }))
+    }
+
+    /// Whether responses are captured as well.
+    pub const fn responses(&self) -> bool {
+        self.responses
+    }
+
+    /// Queue an exchange for writing, dropping it if the writer fell behind.
+    pub fn record(&self, exchange: Exchange) {
+        // The writer only stops once the capture is dropped
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(exchange) {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+
+            #[cfg(feature = "expose-metrics")]
+            metrics::increment_counter!(format!(
+                "{}_captures_dropped_total",
+                crate::METRIC_KEY.as_str()
+            ));
+
+            // Warn less often the more are dropped
+            if dropped.is_power_of_two() {
+                warn!(
+                    "Dropped {} captured exchanges, writing the capture file can't keep up",
+                    dropped
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{capture_headers, Capture, Exchange, Payload, BUFFER};
+    use http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderMap, HeaderValue,
+    };
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_payload() {
+        assert_eq!(Payload::new(b""), None);
+        assert_eq!(Payload::new(b"{}"), Some(Payload::Text("{}".to_string())));
+
+        let binary = Payload::new(&[0x89, b'P', b'N', b'G']).unwrap();
+        assert_eq!(binary, Payload::Base64("iVBORw==".to_string()));
+        assert_eq!(binary.to_bytes().unwrap(), [0x89, b'P', b'N', b'G']);
+    }
+
+    #[test]
+    fn test_capture_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bot secret"));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+        assert_eq!(
+            capture_headers(&headers),
+            vec![("content-type".to_string(), "application/json".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let exchange = Exchange {
+            tenant: "0123456789abcdef".to_string(),
+            method: "POST".to_string(),
+            path: "/api/v10/channels/1/messages?foo=bar".to_string(),
+            headers: Vec::new(),
+            body: Some(Payload::Text(r#"{"content":"hi"}"#.to_string())),
+            response: None,
+        };
+
+        let line = serde_json::to_string(&exchange).unwrap();
+
+        assert!(!line.contains('\n'));
+        assert_eq!(serde_json::from_str::<Exchange>(&line).unwrap(), exchange);
+    }
+
+    #[test]
+    fn test_record_full() {
+        let (sender, _receiver) = mpsc::channel(BUFFER);
+        let capture = Capture {
+            responses: false,
+            sender,
+            dropped: AtomicU64::new(0),
+        };
+        let exchange = Exchange {
+            tenant: "0123456789abcdef".to_string(),
+            method: "GET".to_string(),
+            path: "/api/v10/gateway".to_string(),
+            headers: Vec::new(),
+            body: None,
+            response: None,
+        };
+
+        for _ in 0..BUFFER + 2 {
+            capture.record(exchange.clone());
+        }
+
+        assert_eq!(capture.dropped.load(Ordering::Relaxed), 2);
+    }
+}
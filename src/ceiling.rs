@@ -0,0 +1,80 @@
+//! A proxy-wide cap on the rate of requests sent to Discord, independent of
+//! its buckets and the amount of tokens in use.
+
+use crate::parse_env;
+use std::sync::Mutex;
+use tokio::time::{sleep_until, Duration, Instant};
+use tracing::{debug, warn};
+
+pub struct RateCeiling {
+    /// Time between two requests at the maximum rate.
+    interval: Duration,
+    /// Earliest time the next request may be sent.
+    next: Mutex<Instant>,
+}
+
+impl RateCeiling {
+    /// Load the rate from `MAX_REQUESTS_PER_SECOND`.
+    ///
+    /// Returns `None` if no ceiling is configured.
+    pub fn from_env() -> Option<Self> {
+        let rate = parse_env::<f64>("MAX_REQUESTS_PER_SECOND")?;
+
+        if !rate.is_finite() || rate <= 0.0 {
+            warn!("MAX_REQUESTS_PER_SECOND must be a positive number, ignoring it");
+
+            return None;
+        }
+
+        Some(Self::new(rate))
+    }
+
+    fn new(rate: f64) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1.0 / rate),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Reserve the next slot for a request and wait until it is reached.
+    pub async fn wait(&self) {
+        let at = self.reserve(Instant::now());
+
+        if at > Instant::now() {
+            debug!("Delaying request to stay below MAX_REQUESTS_PER_SECOND");
+            sleep_until(at).await;
+        }
+    }
+
+    /// Reserve the earliest time at which a request may be sent.
+    ///
+    /// Requests are spread out evenly, so no burst exceeds the rate either.
+    fn reserve(&self, now: Instant) -> Instant {
+        let mut next = self.next.lock().expect("ceiling poisoned");
+        let at = (*next).max(now);
+        *next = at + self.interval;
+
+        at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateCeiling;
+    use tokio::time::{Duration, Instant};
+
+    #[test]
+    fn test_reserve() {
+        let ceiling = RateCeiling::new(4.0);
+        let start = Instant::now();
+
+        assert_eq!(ceiling.reserve(start), start);
+        assert_eq!(ceiling.reserve(start), start + Duration::from_millis(250));
+        assert_eq!(ceiling.reserve(start), start + Duration::from_millis(500));
+
+        // Idle time is not saved up for bursts
+        let later = start + Duration::from_secs(10);
+        assert_eq!(ceiling.reserve(later), later);
+        assert_eq!(ceiling.reserve(later), later + Duration::from_millis(250));
+    }
+}
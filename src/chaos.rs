@@ -0,0 +1,237 @@
+//! Injection of artificial failures, for testing how clients handle them.
+//!
+//! Injected responses never reach Discord and don't affect the ratelimiter.
+
+use crate::sublimit::route_name;
+use http::{
+    header::{CONTENT_TYPE, RETRY_AFTER},
+    Response, StatusCode,
+};
+use hyper::Body;
+use std::{
+    env,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+use twilight_http_ratelimiting::Path;
+
+/// Header added to injected responses, so they can be told apart from real
+/// ones.
+pub const CHAOS_HEADER: &str = "x-proxy-chaos";
+
+/// Statuses of injected server errors.
+const ERROR_STATUSES: [StatusCode; 3] = [
+    StatusCode::INTERNAL_SERVER_ERROR,
+    StatusCode::BAD_GATEWAY,
+    StatusCode::SERVICE_UNAVAILABLE,
+];
+
+/// What to do with a request.
+#[derive(Debug)]
+pub enum Injection {
+    /// Delay the request before forwarding it.
+    Latency(Duration),
+    /// Respond with an injected response instead of forwarding the request.
+    Respond(Response<Body>),
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Chaos {
+    /// Probability of delaying a request and the maximum delay.
+    latency: Option<(f64, Duration)>,
+    /// Probability of responding with a 429.
+    ratelimit: f64,
+    /// Probability of responding with a 5xx.
+    error: f64,
+    /// Route names chaos applies to, all routes if empty.
+    routes: Vec<String>,
+}
+
+impl Chaos {
+    /// Load the configuration from `CHAOS` and `CHAOS_ROUTES`.
+    ///
+    /// Returns `None` if chaos is not enabled.
+    pub fn from_env() -> Option<Self> {
+        let mut chaos = parse_chaos(&env::var("CHAOS").ok()?);
+
+        if let Ok(routes) = env::var("CHAOS_ROUTES") {
+            chaos.routes = routes
+                .split(',')
+                .map(str::trim)
+                .filter(|route| !route.is_empty())
+                .map(ToString::to_string)
+                .collect();
+        }
+
+        Some(chaos)
+    }
+
+    /// Decide whether to inject a failure into a request.
+    pub fn inject(&self, path: &Path) -> Option<Injection> {
+        if !self.routes.is_empty() && !self.routes.contains(&route_name(path)) {
+            return None;
+        }
+
+        let roll = fastrand::f64();
+
+        if roll < self.ratelimit {
+            return Some(Injection::Respond(ratelimited(fastrand::u64(500..5000))));
+        }
+
+        if roll < self.ratelimit + self.error {
+            let status = ERROR_STATUSES[fastrand::usize(..ERROR_STATUSES.len())];
+
+            return Some(Injection::Respond(server_error(status)));
+        }
+
+        match self.latency {
+            Some((probability, max)) if fastrand::f64() < probability => {
+                Some(Injection::Latency(max.mul_f64(fastrand::f64())))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A 429 resembling one of Discord's, with a fake bucket.
+fn ratelimited(retry_after_ms: u64) -> Response<Body> {
+    let retry_after = retry_after_ms as f64 / 1000.0;
+    let reset = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+        + retry_after;
+    let body = serde_json::json!({
+        "message": "You are being rate limited.",
+        "retry_after": retry_after,
+        "global": false,
+    });
+
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header(CONTENT_TYPE, "application/json")
+        .header(RETRY_AFTER, retry_after_ms.div_ceil(1000))
+        .header("x-ratelimit-bucket", "chaos")
+        .header("x-ratelimit-limit", 1)
+        .header("x-ratelimit-remaining", 0)
+        .header("x-ratelimit-reset", format!("{:.3}", reset))
+        .header("x-ratelimit-reset-after", retry_after.to_string())
+        .header("x-ratelimit-scope", "user")
+        .header(CHAOS_HEADER, "ratelimit")
+        .body(Body::from(body.to_string()))
+        .expect("response is valid")
+}
+
+fn server_error(status: StatusCode) -> Response<Body> {
+    let body = serde_json::json!({
+        "message": status.canonical_reason().unwrap_or_default(),
+        "code": 0,
+    });
+
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .header(CHAOS_HEADER, "error")
+        .body(Body::from(body.to_string()))
+        .expect("response is valid")
+}
+
+/// Parse a configuration in the format
+/// `latency=probability/max_ms,ratelimit=probability,error=probability`.
+fn parse_chaos(value: &str) -> Chaos {
+    let mut chaos = Chaos::default();
+
+    for entry in value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let parsed = entry.split_once('=').and_then(|(kind, setting)| {
+            let (probability, max) = setting
+                .split_once('/')
+                .map_or((setting, None), |(probability, max)| {
+                    (probability, Some(max))
+                });
+            let probability = probability
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|probability| (0.0..=1.0).contains(probability))?;
+
+            match (kind.trim(), max) {
+                ("latency", Some(max)) => {
+                    let max = Duration::from_millis(max.trim().parse().ok()?);
+                    chaos.latency = Some((probability, max));
+                }
+                ("ratelimit", None) => chaos.ratelimit = probability,
+                ("error", None) => chaos.error = probability,
+                _ => return None,
+            }
+
+            Some(())
+        });
+
+        if parsed.is_none() {
+            warn!("Ignoring invalid chaos setting {:?}", entry);
+        }
+    }
+
+    chaos
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_chaos, ratelimited, Chaos, Injection};
+    use crate::ratelimiter_map::ratelimit_headers;
+    use http::StatusCode;
+    use std::time::Duration;
+    use twilight_http_ratelimiting::Path;
+
+    #[test]
+    fn test_parse_chaos() {
+        assert_eq!(
+            parse_chaos("latency=0.5/200, ratelimit=0.1,error=0.05,error=2,foo=1"),
+            Chaos {
+                latency: Some((0.5, Duration::from_millis(200))),
+                ratelimit: 0.1,
+                error: 0.05,
+                routes: Vec::new(),
+            }
+        );
+        assert_eq!(parse_chaos("latency=0.5"), Chaos::default());
+    }
+
+    #[test]
+    fn test_inject() {
+        let chaos = Chaos {
+            ratelimit: 1.0,
+            routes: vec!["ChannelsIdMessages".to_string()],
+            ..Chaos::default()
+        };
+
+        assert!(matches!(
+            chaos.inject(&Path::ChannelsIdMessages(1)),
+            Some(Injection::Respond(response)) if response.status() == StatusCode::TOO_MANY_REQUESTS
+        ));
+        assert!(chaos.inject(&Path::ChannelsId(1)).is_none());
+
+        let chaos = Chaos {
+            latency: Some((1.0, Duration::from_millis(100))),
+            ..Chaos::default()
+        };
+
+        assert!(matches!(
+            chaos.inject(&Path::ChannelsId(1)),
+            Some(Injection::Latency(latency)) if latency <= Duration::from_millis(100)
+        ));
+        assert!(Chaos::default().inject(&Path::ChannelsId(1)).is_none());
+    }
+
+    #[test]
+    fn test_ratelimited_headers() {
+        let response = ratelimited(1500);
+
+        assert_eq!(response.headers()["retry-after"], "2");
+        assert!(ratelimit_headers(response.headers()).is_some());
+    }
+}
@@ -0,0 +1,575 @@
+//! The `check-config` subcommand, validating the configuration before a
+//! deployment.
+
+use crate::{
+    api_versions::ApiVersions,
+    backend,
+    budget::Budgets,
+    canary::Canary,
+    ceiling::RateCeiling,
+    chaos::Chaos,
+    concurrency::ConcurrencyLimits,
+    cors::Cors,
+    edges::{self, EdgeResolver, Edges},
+    fair::FairQueue,
+    forwarded::TrustedProxies,
+    gateway::GatewayUrl,
+    global_limit::GlobalLimits,
+    handoff::Handoff,
+    http_version::HttpVersions,
+    limits::PayloadLimits,
+    lockdown::Lockdown,
+    memory::MemoryPressure,
+    mentions::MentionPolicy,
+    mirror::Mirror,
+    oauth::ClientCredentials,
+    parse_env,
+    probe::Probe,
+    queue_limits::QueueLimits,
+    queue_timeout::QueueTimeout,
+    ratelimiter_map::with_prefix,
+    schema::ResponseValidation,
+    services::Services,
+    session::SessionGuard,
+    slo::Slos,
+    sublimit::Sublimits,
+    tenant::hash_token,
+    token_check::{self, Verification},
+    token_file,
+    traffic::TrafficClasses,
+    upstream::{Upstream, DEFAULT_UPSTREAM},
+};
+use http::HeaderValue;
+use hyper::{
+    client::{connect::dns::Name, HttpConnector},
+    service::Service,
+    Body, Client,
+};
+use hyper_rustls::{HttpsConnectorBuilder, MaybeHttpsStream};
+use serde::Serialize;
+use std::{
+    env,
+    error::Error,
+    fmt::{Debug, Write},
+    net::IpAddr,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use tokio::time::{timeout, Duration};
+use tracing::{
+    field::{Field, Visit},
+    Dispatch, Event, Level, Subscriber,
+};
+use tracing_subscriber::{
+    layer::{Context, Layer, SubscriberExt},
+    registry,
+};
+
+/// How long to wait for the upstream to respond when probing it.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Settings read from the environment and their defaults, in the order they
+/// are printed.
+const SETTINGS: &[(&str, Option<&str>)] = &[
+    ("HOST", Some("0.0.0.0")),
+    ("PORT", Some("80")),
+    ("WORKER_PROCESSES", None),
+    ("WORKER_THREADS", None),
+    ("MAX_BLOCKING_THREADS", Some("512")),
+    ("BLOCKING_THREAD_KEEP_ALIVE", Some("10")),
+    ("UPSTREAM_URL", Some(DEFAULT_UPSTREAM)),
+    ("UPSTREAM_ADDRS", None),
+    ("DISCORD_TOKEN", None),
+    ("DISCORD_TOKEN_FILE", None),
+    ("VERIFY_TOKEN", None),
+    ("ADMIN_TOKEN", None),
+    ("OAUTH2_CLIENT_ID", None),
+    ("OAUTH2_CLIENT_SECRET", None),
+    ("OAUTH2_SCOPES", Some("identify")),
+    ("TRUSTED_PROXIES", None),
+    ("PROXY_PROTOCOL", None),
+    ("CORS_ORIGINS", None),
+    ("GATEWAY_URL", None),
+    ("SERVICES", None),
+    ("SESSION_START_RESERVE", None),
+    ("DISABLE_HTTP2", None),
+    ("UPSTREAM_HTTP_VERSIONS", None),
+    ("RATELIMITER_BACKEND", Some("memory")),
+    ("REDIS_URL", None),
+    ("REDIS_KEY_PREFIX", Some("twilight-http-proxy")),
+    ("DRY_RUN", None),
+    ("CLIENT_DECAY_TIMEOUT", Some("3600")),
+    ("CLIENT_CACHE_MAX_SIZE", None),
+    ("DEFAULT_DEADLINE_MS", None),
+    ("MAX_QUEUE_WAIT_MS", None),
+    ("QUEUE_TIMEOUT_STATUS", Some("429")),
+    ("MAX_QUEUED_PER_BUCKET", None),
+    ("MAX_QUEUED", None),
+    ("VALIDATE_RESPONSES", None),
+    ("MAX_QUERY_LENGTH", Some("2048")),
+    ("MAX_HEADER_COUNT", Some("100")),
+    ("MAX_HEADER_SIZE", Some("32768")),
+    ("ENCODE_AUDIT_LOG_REASON", None),
+    ("VALIDATE_JSON", None),
+    ("VALIDATE_MULTIPART", None),
+    ("ENFORCE_PAYLOAD_LIMITS", None),
+    ("DEFAULT_ALLOWED_MENTIONS", None),
+    ("ALLOWED_MENTIONS_OPT_OUT", None),
+    ("MAX_UPLOAD_SIZE", Some("26214400")),
+    ("MAX_RESPONSE_SIZE", None),
+    ("RESPONSE_HEADER_ALLOWLIST", None),
+    ("UPLOAD_LIMITS", None),
+    ("DAILY_BUDGETS", None),
+    ("DEFAULT_DAILY_BUDGET", None),
+    ("DAILY_BUDGET_ESSENTIAL_METHODS", Some("GET")),
+    ("SUBLIMITS", None),
+    ("MAX_SUBLIMIT_WAIT_MS", Some("10000")),
+    ("REACTION_BATCHING", None),
+    ("CONCURRENCY_LIMITS", None),
+    ("MAX_REQUESTS_PER_SECOND", None),
+    ("FAIL_FAST_RATELIMITS", None),
+    ("GLOBAL_RATELIMIT_PER_SECOND", None),
+    ("GLOBAL_RATELIMITS", None),
+    ("FAIR_SHARE_SLOTS", None),
+    ("TOKEN_WEIGHTS", None),
+    ("LATENCY_SLOS", None),
+    ("MAX_TAGS", Some("50")),
+    ("DEPRECATED_API_VERSIONS", None),
+    ("RATELIMIT_LOG_SIZE", Some("100")),
+    ("REQUEST_LOG_SIZE", Some("1000")),
+    ("REQUEST_LOG_WINDOW", Some("300")),
+    ("TOP_MAJOR_PARAMETERS", Some("100")),
+    ("BULK_ROUTES", None),
+    ("DISPATCH_WEIGHTS", Some("1:0")),
+    ("CHAOS", None),
+    ("CHAOS_ROUTES", None),
+    ("CAPTURE_FILE", None),
+    ("BUCKET_LIMITS_FILE", None),
+    ("HANDOFF_URL", None),
+    ("SIGNING_KEY", None),
+    ("SHUTDOWN_REPORT_FILE", None),
+    ("CAPTURE_RESPONSES", None),
+    ("MIRROR_URL", None),
+    ("MIRROR_PERCENT", Some("100")),
+    ("MIRROR_METHODS", Some("GET,HEAD")),
+    ("MIRROR_MAX_IN_FLIGHT", Some("100")),
+    ("CANARY_URL", None),
+    ("CANARY_PERCENT", Some("10")),
+    ("PROBE_INTERVAL", None),
+    ("PROBE_PATH", Some("/api/v10/gateway/bot")),
+    ("PAUSE_QUEUE_LIMIT", Some("10000")),
+    ("MEMORY_WATERMARK", None),
+    ("STARVATION_THRESHOLD", Some("60")),
+    ("LOCKDOWN_COOLDOWN", None),
+    #[cfg(feature = "expose-metrics")]
+    ("METRIC_KEY", Some("twilight_http_proxy")),
+    #[cfg(feature = "expose-metrics")]
+    ("METRIC_TIMEOUT", Some("300")),
+    #[cfg(feature = "expose-metrics")]
+    ("METRIC_LABELS", None),
+    #[cfg(feature = "expose-metrics")]
+    ("METRIC_DIMENSIONS", Some("method,route,status,scope")),
+];
+
+/// Value of a setting, with secrets redacted.
+#[derive(Serialize)]
+pub struct Setting {
+    name: &'static str,
+    /// `None` if the setting is not set and has no default.
+    value: Option<String>,
+    default: bool,
+}
+
+/// The effective value of every setting.
+///
+/// Tokens are replaced by their hash and `SIGNING_KEY` is redacted.
+pub fn settings() -> Vec<Setting> {
+    SETTINGS
+        .iter()
+        .map(|&(name, default)| match env::var(name) {
+            Ok(value) => Setting {
+                name,
+                value: Some(match name {
+                    "DISCORD_TOKEN" => {
+                        format!("<redacted, hash {}>", hash_token(&with_prefix(value)))
+                    }
+                    // Redis URLs may contain a password
+                    "ADMIN_TOKEN" | "OAUTH2_CLIENT_SECRET" | "REDIS_URL" | "SIGNING_KEY" => {
+                        "<redacted>".to_string()
+                    }
+                    _ => value,
+                }),
+                default: false,
+            },
+            Err(_) => Setting {
+                name,
+                value: default.map(str::to_string),
+                default: default.is_some(),
+            },
+        })
+        .collect()
+}
+
+/// Print the effective configuration and fail if any setting is invalid.
+///
+/// With `--probe`, the upstream is resolved, connected to and requested as
+/// well, failing if it can't be reached. `--verify-token` additionally
+/// requests the current user with the default token, failing if Discord
+/// rejects it.
+pub async fn run(probe: bool, verify_token: bool) -> Result<(), Box<dyn Error>> {
+    for setting in settings() {
+        match setting.value {
+            Some(value) if setting.default => println!("{}={} (default)", setting.name, value),
+            Some(value) => println!("{}={}", setting.name, value),
+            None => println!("{} is not set", setting.name),
+        }
+    }
+
+    let mut problems = Vec::new();
+
+    if let Ok(host) = env::var("HOST") {
+        if IpAddr::from_str(&host).is_err() {
+            problems.push(format!("HOST {:?} is not an IP address", host));
+        }
+    }
+
+    if let Ok(port) = env::var("PORT") {
+        if port.parse::<u16>().is_err() {
+            problems.push(format!("PORT {:?} is not a port number", port));
+        }
+    }
+
+    let upstream =
+        Upstream::new(&env::var("UPSTREAM_URL").unwrap_or_else(|_| DEFAULT_UPSTREAM.into()));
+
+    let upstream = match upstream {
+        Ok(upstream) => Some(upstream),
+        Err(e) => {
+            problems.push(format!("UPSTREAM_URL: {}", e));
+
+            None
+        }
+    };
+
+    let token = match token_file::default_token() {
+        Ok(token) => token.map(with_prefix),
+        Err(e) => {
+            problems.push(e.to_string());
+
+            None
+        }
+    };
+
+    if let Some(token) = &token {
+        if let Err(problem) = check_token(token) {
+            problems.push(format!("default token {}", problem));
+        }
+    }
+
+    if let Ok(path) = env::var("CAPTURE_FILE") {
+        if let Err(problem) = check_capture_file(Path::new(&path)) {
+            problems.push(format!("CAPTURE_FILE {:?} {}", path, problem));
+        }
+    }
+
+    // The settings are parsed by the same code as when running the proxy,
+    // which warns about and ignores invalid values
+    let mut mirror = Ok(None);
+    let mut canary = Ok(None);
+    let mut handoff = Ok(());
+    let mut services = Ok(());
+
+    problems.extend(collect_warnings(|| {
+        mirror = Mirror::from_env();
+        canary = Canary::from_env();
+        handoff = Handoff::from_env().map(drop);
+        services = Services::from_env().map(drop);
+        ApiVersions::from_env();
+        Budgets::from_env();
+        Chaos::from_env();
+        ClientCredentials::from_env();
+        FairQueue::from_env();
+        HttpVersions::from_env();
+        PayloadLimits::from_env();
+        Probe::from_env();
+        QueueLimits::from_env();
+        QueueTimeout::from_env();
+        ResponseValidation::from_env();
+        Sublimits::from_env();
+        ConcurrencyLimits::from_env();
+        RateCeiling::from_env();
+        TrafficClasses::from_env();
+        TrustedProxies::from_env();
+        Cors::from_env();
+        GatewayUrl::from_env();
+        GlobalLimits::from_env();
+        SessionGuard::from_env();
+        Slos::from_env();
+        MemoryPressure::from_env();
+        MentionPolicy::from_env();
+        Lockdown::from_env();
+
+        if let Some(upstream) = &upstream {
+            Edges::from_env(upstream);
+        }
+
+        parse_env::<u64>("CLIENT_DECAY_TIMEOUT");
+        parse_env::<usize>("CLIENT_CACHE_MAX_SIZE");
+        parse_env::<usize>("MAX_QUERY_LENGTH");
+        parse_env::<usize>("MAX_HEADER_COUNT");
+        parse_env::<usize>("MAX_HEADER_SIZE");
+        parse_env::<usize>("MAX_TAGS");
+        parse_env::<usize>("WORKER_PROCESSES");
+        parse_env::<usize>("WORKER_THREADS");
+        parse_env::<usize>("MAX_BLOCKING_THREADS");
+        parse_env::<u64>("BLOCKING_THREAD_KEEP_ALIVE");
+        parse_env::<usize>("RATELIMIT_LOG_SIZE");
+        parse_env::<usize>("REQUEST_LOG_SIZE");
+        parse_env::<u64>("REQUEST_LOG_WINDOW");
+        parse_env::<usize>("TOP_MAJOR_PARAMETERS");
+        parse_env::<u64>("MAX_RESPONSE_SIZE");
+        parse_env::<usize>("PAUSE_QUEUE_LIMIT");
+        parse_env::<u64>("STARVATION_THRESHOLD");
+        parse_env::<u64>("DEFAULT_DEADLINE_MS");
+        #[cfg(feature = "expose-metrics")]
+        parse_env::<u64>("METRIC_TIMEOUT");
+        #[cfg(feature = "expose-metrics")]
+        crate::metric_labels::MetricLabels::from_env();
+    }));
+
+    if let Err(e) = mirror {
+        problems.push(format!("MIRROR_URL: {}", e));
+    }
+
+    if let Err(e) = canary {
+        problems.push(format!("CANARY_URL: {}", e));
+    }
+
+    if let Err(e) = handoff {
+        problems.push(format!("HANDOFF_URL: {}", e));
+    }
+
+    if let Err(e) = services {
+        problems.push(format!("SERVICES: {}", e));
+    }
+
+    if let Err(e) = backend::from_env().await {
+        problems.push(e.to_string());
+    }
+
+    if verify_token && token.is_none() {
+        problems.push("no default token is set, so it can't be verified".to_string());
+    }
+
+    if let (true, Some(upstream)) = (probe || verify_token, upstream) {
+        let token = token.as_deref().filter(|_| verify_token);
+
+        if let Err(e) = probe_upstream(&upstream, token).await {
+            problems.push(e.to_string());
+        }
+    }
+
+    if problems.is_empty() {
+        println!("Configuration is valid");
+
+        return Ok(());
+    }
+
+    for problem in &problems {
+        println!("error: {}", problem);
+    }
+
+    Err(format!("found {} configuration problems", problems.len()).into())
+}
+
+/// Check that a prefixed token looks like one Discord could have issued.
+///
+/// Bot tokens consist of three base64url segments separated by dots, bearer
+/// tokens of a single one.
+fn check_token(token: &str) -> Result<(), &'static str> {
+    if HeaderValue::from_str(token).is_err() {
+        return Err("contains characters not allowed in a header");
+    }
+
+    let is_base64url = |segment: &str| {
+        !segment.is_empty()
+            && segment
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+    };
+
+    if let Some(token) = token.strip_prefix("Bot ") {
+        if token.split('.').count() != 3 || !token.split('.').all(is_base64url) {
+            return Err("is not a bot token, expected three segments separated by dots");
+        }
+    } else if let Some(token) = token.strip_prefix("Bearer ") {
+        if !is_base64url(token) {
+            return Err("is not a bearer token");
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that the proxy will be able to append to a capture file, without
+/// creating it.
+fn check_capture_file(path: &Path) -> Result<(), &'static str> {
+    match path.metadata() {
+        Ok(metadata) if metadata.is_dir() => Err("is a directory"),
+        Ok(metadata) if metadata.permissions().readonly() => Err("is read-only"),
+        Ok(_) => Ok(()),
+        Err(_) => {
+            let parent = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new("."));
+
+            if parent.is_dir() {
+                Ok(())
+            } else {
+                Err("is in a directory that does not exist")
+            }
+        }
+    }
+}
+
+/// Resolve the upstream's host, complete a TLS handshake with it and request
+/// the gateway endpoint, which needs no authorization, the same way the proxy
+/// connects to it. With a token, the current user is requested as well.
+///
+/// Any response of the gateway endpoint counts as reachable.
+async fn probe_upstream(upstream: &Upstream, token: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let mut resolver = EdgeResolver::new(Edges::from_env(upstream));
+    let name = Name::from_str(upstream.host_name())?;
+    let addrs = timeout(PROBE_TIMEOUT, resolver.call(name))
+        .await
+        .map_err(|_| format!("resolving {} timed out", upstream.host_name()))?
+        .map_err(|e| format!("failed to resolve {}: {}", upstream.host_name(), e))?
+        .map(|addr| addr.ip())
+        .collect::<Vec<_>>();
+
+    println!("Resolved {} to {:?}", upstream.host_name(), addrs);
+
+    let mut http_connector = HttpConnector::new_with_resolver(resolver);
+    http_connector.enforce_http(false);
+    http_connector.set_connect_timeout(Some(edges::CONNECT_TIMEOUT));
+
+    let mut connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(http_connector);
+
+    let uri = upstream.uri("/api/v10", "/gateway", None)?;
+    let stream = timeout(PROBE_TIMEOUT, connector.call(uri.clone()))
+        .await
+        .map_err(|_| "connecting to the upstream timed out")?
+        .map_err(|e| format!("failed to connect to the upstream: {}", e))?;
+
+    match stream {
+        MaybeHttpsStream::Https(_) => {
+            println!("Completed TLS handshake with {}", upstream.host_name())
+        }
+        MaybeHttpsStream::Http(_) => println!("Connected to {} without TLS", upstream.host_name()),
+    }
+
+    // The client opens its own connection
+    drop(stream);
+
+    let client: Client<_, Body> = Client::builder().build(connector);
+
+    let response = timeout(PROBE_TIMEOUT, client.get(uri))
+        .await
+        .map_err(|_| "requesting the upstream timed out")?
+        .map_err(|e| format!("upstream is unreachable: {}", e))?;
+
+    println!("Upstream responded with {}", response.status());
+
+    let token = match token {
+        Some(token) => token,
+        None => return Ok(()),
+    };
+
+    match token_check::verify(&client, upstream, token).await? {
+        Verification::Valid(user) => {
+            println!("Default token belongs to {}", user);
+
+            Ok(())
+        }
+        Verification::Rejected => Err("default token was rejected by Discord".into()),
+    }
+}
+
+/// Run `f`, returning the messages of all warnings and errors it logged.
+fn collect_warnings(f: impl FnOnce()) -> Vec<String> {
+    let warnings = Arc::new(Mutex::new(Vec::new()));
+    let dispatch = Dispatch::new(registry().with(Collector(Arc::clone(&warnings))));
+
+    tracing::dispatcher::with_default(&dispatch, f);
+
+    let warnings = warnings.lock().expect("warnings poisoned");
+
+    warnings.clone()
+}
+
+/// Layer recording the messages of warnings and errors.
+struct Collector(Arc<Mutex<Vec<String>>>);
+
+impl<S: Subscriber> Layer<S> for Collector {
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        if *event.metadata().level() > Level::WARN {
+            return;
+        }
+
+        let mut message = Message(String::new());
+        event.record(&mut message);
+
+        self.0.lock().expect("warnings poisoned").push(message.0);
+    }
+}
+
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            _ = write!(self.0, "{:?}", value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_capture_file, check_token, collect_warnings};
+    use crate::{ratelimiter_map::with_prefix, tenant::parse_tenant_values};
+    use std::path::Path;
+    use tracing::info;
+
+    #[test]
+    fn test_check_token() {
+        assert!(check_token(&with_prefix("MTE.Gx1b2c.a-b_c".to_string())).is_ok());
+        assert!(check_token("Bearer abc123").is_ok());
+        assert!(check_token(&with_prefix("abc".to_string())).is_err());
+        assert!(check_token("Bot MTE.Gx1b2c.").is_err());
+        assert!(check_token("Bot MTE.Gx1b2c.abc\n").is_err());
+        assert!(check_token("Bearer a.b").is_err());
+    }
+
+    #[test]
+    fn test_check_capture_file() {
+        assert!(check_capture_file(Path::new("capture.jsonl")).is_ok());
+        assert!(check_capture_file(Path::new("/")).is_err());
+        assert!(check_capture_file(Path::new("/does/not/exist.jsonl")).is_err());
+    }
+
+    #[test]
+    fn test_collect_warnings() {
+        let warnings = collect_warnings(|| {
+            info!("Not a problem");
+            parse_tenant_values("abc=1,invalid", "test");
+        });
+
+        assert_eq!(warnings, ["Ignoring invalid test entry \"invalid\""]);
+    }
+}
@@ -0,0 +1,207 @@
+//! Command line options, as an alternative to environment variables for the
+//! most common settings.
+
+use crate::{selftest::DEFAULT_REQUESTS, version_diff::parse_version};
+use clap::{Parser, Subcommand};
+use std::{env, net::IpAddr};
+
+/// Options, which precede the command and its arguments.
+#[derive(Debug, Parser, PartialEq)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    after_help = "Without a command, the proxy is started. Every setting may also be set as an \
+                  environment variable, which options override."
+)]
+pub struct Cli {
+    /// Load settings from a TOML file
+    #[arg(long, value_name = "path")]
+    pub config: Option<String>,
+    /// Override them with a profile of the file
+    #[arg(long, value_name = "name", requires = "config")]
+    pub profile: Option<String>,
+    /// Address to listen on (HOST)
+    #[arg(long, value_name = "address")]
+    host: Option<IpAddr>,
+    /// Port to listen on (PORT)
+    #[arg(long, value_name = "port")]
+    port: Option<u16>,
+    /// Read the default token from a file (DISCORD_TOKEN_FILE)
+    #[arg(long, value_name = "path")]
+    token_file: Option<String>,
+    /// Only use HTTP/1.1 to Discord (DISABLE_HTTP2)
+    #[arg(long)]
+    disable_http2: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, PartialEq, Subcommand)]
+pub enum Command {
+    /// Validate and print the configuration
+    CheckConfig {
+        /// Also connect to the upstream and request its gateway
+        #[arg(long)]
+        probe: bool,
+        /// Also check the default token with Discord
+        #[arg(long)]
+        verify_token: bool,
+    },
+    /// Compare captured GET requests between two API versions
+    DiffVersions {
+        /// File written with CAPTURE_FILE
+        #[arg(value_name = "capture file")]
+        file: String,
+        /// URL of the proxy to send the requests to
+        #[arg(value_name = "target url")]
+        target: String,
+        /// API version to compare, e.g. 9 or v9
+        #[arg(value_name = "version", value_parser = parse_version)]
+        first: u8,
+        /// API version to compare it with
+        #[arg(value_name = "version", value_parser = parse_version)]
+        second: u8,
+    },
+    /// Exit with 1 unless the proxy is alive
+    Healthcheck {
+        /// Check whether the proxy is ready instead
+        #[arg(long)]
+        ready: bool,
+    },
+    /// Replay captured requests
+    Replay {
+        /// File written with CAPTURE_FILE
+        #[arg(value_name = "capture file")]
+        file: String,
+        /// URL of the mock server or proxy to send the requests to
+        #[arg(value_name = "target url")]
+        target: String,
+    },
+    /// Send a request through the proxy
+    Request {
+        /// HTTP method, e.g. GET
+        method: String,
+        /// Path of the request, e.g. /api/v10/users/@me
+        path: String,
+        /// JSON body of the request
+        #[arg(value_name = "json body")]
+        body: Option<String>,
+    },
+    /// Test the ratelimiter at high concurrency
+    Selftest {
+        /// Amount of requests to send
+        #[arg(default_value_t = DEFAULT_REQUESTS)]
+        requests: usize,
+    },
+}
+
+impl Cli {
+    /// Export the options as environment variables, overriding set ones.
+    ///
+    /// Must be called before any other threads are started.
+    pub fn apply(&self) {
+        if let Some(host) = &self.host {
+            env::set_var("HOST", host.to_string());
+        }
+
+        if let Some(port) = &self.port {
+            env::set_var("PORT", port.to_string());
+        }
+
+        if let Some(token_file) = &self.token_file {
+            env::set_var("DISCORD_TOKEN_FILE", token_file);
+        }
+
+        if self.disable_http2 {
+            env::set_var("DISABLE_HTTP2", "1");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cli, Command};
+    use clap::{error::ErrorKind, CommandFactory, Parser};
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(["twilight-http-proxy"].iter().chain(args))
+    }
+
+    #[test]
+    fn test_command() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_parse() {
+        let cli = parse(&[
+            "--port",
+            "3000",
+            "--token-file=/run/secrets/token",
+            "--disable-http2",
+            "--config",
+            "proxy.toml",
+            "--profile",
+            "canary",
+        ])
+        .unwrap();
+
+        assert_eq!(cli.command, None);
+        assert_eq!(cli.config.as_deref(), Some("proxy.toml"));
+        assert_eq!(cli.profile.as_deref(), Some("canary"));
+        assert_eq!(cli.port, Some(3000));
+        assert_eq!(cli.token_file.as_deref(), Some("/run/secrets/token"));
+        assert!(cli.disable_http2);
+
+        let cli = parse(&["--port", "3000", "check-config", "--probe"]).unwrap();
+        assert_eq!(
+            cli.command,
+            Some(Command::CheckConfig {
+                probe: true,
+                verify_token: false
+            })
+        );
+
+        let cli = parse(&["diff-versions", "capture.jsonl", "http://proxy", "v9", "10"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::DiffVersions {
+                first: 9,
+                second: 10,
+                ..
+            })
+        ));
+
+        let cli = parse(&["selftest"]).unwrap();
+        assert_eq!(cli.command, Some(Command::Selftest { requests: 10_000 }));
+
+        assert_eq!(
+            parse(&["--help", "--bogus"]).unwrap_err().kind(),
+            ErrorKind::DisplayHelp
+        );
+        assert_eq!(
+            parse(&["-V"]).unwrap_err().kind(),
+            ErrorKind::DisplayVersion
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(parse(&["--port"]).is_err());
+        assert!(parse(&["--port", "http"]).is_err());
+        assert_eq!(
+            parse(&["--prot", "3000"]).unwrap_err().kind(),
+            ErrorKind::UnknownArgument
+        );
+        assert_eq!(
+            parse(&["--profile", "canary"]).unwrap_err().kind(),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            parse(&["serve"]).unwrap_err().kind(),
+            ErrorKind::InvalidSubcommand
+        );
+        assert!(parse(&["diff-versions", "capture.jsonl", "http://proxy", "9", "ten"]).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+//! Limits on the amount of requests to a path that are in flight at once,
+//! e.g. to serialize operations whose ordering matters.
+
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, warn};
+
+/// Maximum amount of paths tracked before pruning idle ones.
+const PRUNE_THRESHOLD: usize = 64;
+
+/// A limit of `max` concurrent requests to paths matching `pattern`.
+#[derive(Debug, PartialEq)]
+struct Rule {
+    /// Path segments, of which ones starting with `:` match any segment.
+    pattern: Vec<String>,
+    max: usize,
+}
+
+impl Rule {
+    fn matches(&self, path: &str) -> bool {
+        let mut segments = path.split('/').filter(|segment| !segment.is_empty());
+
+        self.pattern.iter().all(|expected| {
+            segments
+                .next()
+                .is_some_and(|segment| expected.starts_with(':') || segment == expected)
+        }) && segments.next().is_none()
+    }
+}
+
+/// Configured concurrency limits and the semaphores of paths in use.
+pub struct ConcurrencyLimits {
+    rules: Vec<Rule>,
+    semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl ConcurrencyLimits {
+    /// Load the rules from the `CONCURRENCY_LIMITS` environment variable.
+    pub fn from_env() -> Self {
+        Self::new(
+            env::var("CONCURRENCY_LIMITS")
+                .map(|value| parse_rules(&value))
+                .unwrap_or_default(),
+        )
+    }
+
+    fn new(rules: Vec<Rule>) -> Self {
+        Self {
+            rules,
+            semaphores: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Wait until a request to a path, without API prefix, may be sent.
+    ///
+    /// Returns `None` if no limit applies to the path. Otherwise, the slot is
+    /// freed when the returned permit is dropped.
+    pub async fn acquire(&self, path: &str) -> Option<OwnedSemaphorePermit> {
+        let rule = self.rules.iter().find(|rule| rule.matches(path))?;
+
+        let semaphore = {
+            let mut semaphores = self.semaphores.lock().expect("concurrency poisoned");
+
+            if semaphores.len() > PRUNE_THRESHOLD {
+                // Permits and waiters hold a reference to the semaphore
+                semaphores.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+            }
+
+            Arc::clone(
+                semaphores
+                    .entry(path.to_string())
+                    .or_insert_with(|| Arc::new(Semaphore::new(rule.max))),
+            )
+        };
+
+        if semaphore.available_permits() == 0 {
+            debug!("Waiting for a concurrency slot of {}", path);
+        }
+
+        semaphore.acquire_owned().await.ok()
+    }
+}
+
+/// Parse rules in the format `path/:param=max,...`.
+fn parse_rules(value: &str) -> Vec<Rule> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let rule = parse_rule(entry);
+
+            if rule.is_none() {
+                warn!("Ignoring invalid concurrency limit {:?}", entry);
+            }
+
+            rule
+        })
+        .collect()
+}
+
+fn parse_rule(entry: &str) -> Option<Rule> {
+    let (pattern, max) = entry.split_once('=')?;
+    let max = max.trim().parse().ok().filter(|max| *max > 0)?;
+    let pattern = pattern
+        .trim()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+
+    if pattern.is_empty() {
+        return None;
+    }
+
+    Some(Rule { pattern, max })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_rule, ConcurrencyLimits};
+    use tokio::time::{timeout, Duration};
+
+    #[test]
+    fn test_parse_rule() {
+        let rule = parse_rule("channels/:id/messages = 2").unwrap();
+
+        assert_eq!(rule.max, 2);
+        assert!(rule.matches("/channels/1/messages"));
+        assert!(!rule.matches("/channels/1/messages/2"));
+        assert!(!rule.matches("/channels/1"));
+        assert!(!rule.matches("/guilds/1/messages"));
+
+        assert!(parse_rule("channels/:id/messages").is_none());
+        assert!(parse_rule("channels/:id/messages=0").is_none());
+        assert!(parse_rule("/=1").is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_acquire() {
+        let limits = ConcurrencyLimits::new(vec![
+            parse_rule("guilds/:id/members/:id/roles/:id=1").unwrap()
+        ]);
+        let path = "/guilds/1/members/2/roles/3";
+
+        let permit = limits.acquire(path).await;
+        assert!(permit.is_some());
+        assert!(timeout(Duration::from_secs(1), limits.acquire(path))
+            .await
+            .is_err());
+
+        // Other members are not affected
+        assert!(limits
+            .acquire("/guilds/1/members/3/roles/3")
+            .await
+            .is_some());
+        // Unlimited paths don't need a permit
+        assert!(limits.acquire("/guilds/1/members/2").await.is_none());
+
+        drop(permit);
+        assert!(limits.acquire(path).await.is_some());
+    }
+}
@@ -0,0 +1,395 @@
+//! Configuration file given with `--config`, as an alternative to setting
+//! every environment variable.
+//!
+//! Values of the file are exported as the environment variables they stand
+//! for, unless those are set already, so environment variables override the
+//! file and the rest of the proxy only reads the environment.
+//!
+//! Named profiles in `[profile.<name>]` tables override the top-level tables
+//! when selected with `--profile`, so one file can drive several deployments.
+
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::BTreeMap,
+    env,
+    error::Error,
+    fmt::{Display, Formatter, Result as FmtResult},
+    fs, io,
+    net::IpAddr,
+    str::FromStr,
+};
+use toml::{de::Error as TomlError, Table, Value};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read { path: String, source: io::Error },
+    Parse { source: TomlError },
+    InvalidProfile { profile: String, source: TomlError },
+    UnknownProfile { profile: String },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Read { path, source } => {
+                f.write_str("failed to read config file ")?;
+                f.write_str(path)?;
+                f.write_str(": ")?;
+
+                source.fmt(f)
+            }
+            Self::Parse { source } => {
+                f.write_str("invalid config file: ")?;
+                source.fmt(f)
+            }
+            Self::InvalidProfile { profile, source } => {
+                f.write_str("invalid config profile ")?;
+                f.write_str(profile)?;
+                f.write_str(": ")?;
+
+                source.fmt(f)
+            }
+            Self::UnknownProfile { profile } => {
+                f.write_str("config file has no profile ")?;
+                f.write_str(profile)
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// Deserialize a string setting, which may be given as an array that is
+/// joined into the comma-separated list the environment variable expects.
+fn list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum List {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(Some(match List::deserialize(deserializer)? {
+        List::One(value) => value,
+        List::Many(values) => values.join(","),
+    }))
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Listener {
+    pub host: Option<IpAddr>,
+    pub port: Option<u16>,
+    #[serde(deserialize_with = "list")]
+    pub trusted_proxies: Option<String>,
+    #[serde(deserialize_with = "list")]
+    pub cors_origins: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Upstream {
+    pub url: Option<String>,
+    #[serde(deserialize_with = "list")]
+    pub http_versions: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Ratelimiter {
+    pub default_token: Option<String>,
+    pub default_token_file: Option<String>,
+    pub max_requests_per_second: Option<f64>,
+    #[serde(deserialize_with = "list")]
+    pub concurrency_limits: Option<String>,
+    #[serde(deserialize_with = "list")]
+    pub sublimits: Option<String>,
+    pub pause_queue_limit: Option<usize>,
+    pub backend: Option<String>,
+    pub redis_url: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cache {
+    pub client_decay_timeout: Option<u64>,
+    pub client_cache_max_size: Option<usize>,
+    pub bucket_limits_file: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Metrics {
+    pub key: Option<String>,
+    pub timeout: Option<u64>,
+    #[serde(deserialize_with = "list")]
+    pub labels: Option<String>,
+    #[serde(deserialize_with = "list")]
+    pub dimensions: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Log {
+    pub filter: Option<String>,
+}
+
+/// Settings of the configuration file, grouped into tables, e.g.
+///
+/// ```toml
+/// [listener]
+/// port = 3000
+/// trusted_proxies = ["10.0.0.0/8"]
+///
+/// [cache]
+/// client_decay_timeout = 600
+///
+/// [profile.canary.upstream]
+/// url = "https://canary.discord.com"
+/// ```
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listener: Listener,
+    pub upstream: Upstream,
+    pub ratelimiter: Ratelimiter,
+    pub cache: Cache,
+    pub metrics: Metrics,
+    pub log: Log,
+}
+
+impl Config {
+    /// Load a file, with the tables of a profile overriding the top-level
+    /// ones.
+    pub fn load(path: &str, profile: Option<&str>) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_string(),
+            source,
+        })?;
+
+        Self::parse_profile(&contents, profile)
+    }
+
+    fn parse_profile(contents: &str, profile: Option<&str>) -> Result<Self, ConfigError> {
+        let mut table = contents
+            .parse::<Table>()
+            .map_err(|source| ConfigError::Parse { source })?;
+        let profiles = match table.remove("profile") {
+            Some(profiles) => profiles
+                .try_into::<BTreeMap<String, Table>>()
+                .map_err(|source| ConfigError::Parse { source })?,
+            None => BTreeMap::new(),
+        };
+
+        // Profiles that aren't selected are still validated
+        for (name, tables) in &profiles {
+            Self::deserialize(Value::Table(tables.clone())).map_err(|source| {
+                ConfigError::InvalidProfile {
+                    profile: name.clone(),
+                    source,
+                }
+            })?;
+        }
+
+        if let Some(profile) = profile {
+            let overrides = profiles
+                .get(profile)
+                .ok_or_else(|| ConfigError::UnknownProfile {
+                    profile: profile.to_string(),
+                })?;
+
+            merge(&mut table, overrides);
+        }
+
+        Self::deserialize(Value::Table(table)).map_err(|source| ConfigError::Parse { source })
+    }
+
+    /// Environment variables and the values the file sets for them.
+    pub fn vars(&self) -> Vec<(&'static str, String)> {
+        fn var<T: ToString>(
+            name: &'static str,
+            value: Option<&T>,
+        ) -> Option<(&'static str, String)> {
+            value.map(|value| (name, value.to_string()))
+        }
+
+        let Self {
+            listener,
+            upstream,
+            ratelimiter,
+            cache,
+            metrics,
+            log,
+        } = self;
+
+        vec![
+            var("HOST", listener.host.as_ref()),
+            var("PORT", listener.port.as_ref()),
+            var("TRUSTED_PROXIES", listener.trusted_proxies.as_ref()),
+            var("CORS_ORIGINS", listener.cors_origins.as_ref()),
+            var("UPSTREAM_URL", upstream.url.as_ref()),
+            var("UPSTREAM_HTTP_VERSIONS", upstream.http_versions.as_ref()),
+            var("DISCORD_TOKEN", ratelimiter.default_token.as_ref()),
+            var(
+                "DISCORD_TOKEN_FILE",
+                ratelimiter.default_token_file.as_ref(),
+            ),
+            var(
+                "MAX_REQUESTS_PER_SECOND",
+                ratelimiter.max_requests_per_second.as_ref(),
+            ),
+            var(
+                "CONCURRENCY_LIMITS",
+                ratelimiter.concurrency_limits.as_ref(),
+            ),
+            var("SUBLIMITS", ratelimiter.sublimits.as_ref()),
+            var("PAUSE_QUEUE_LIMIT", ratelimiter.pause_queue_limit.as_ref()),
+            var("RATELIMITER_BACKEND", ratelimiter.backend.as_ref()),
+            var("REDIS_URL", ratelimiter.redis_url.as_ref()),
+            var("CLIENT_DECAY_TIMEOUT", cache.client_decay_timeout.as_ref()),
+            var(
+                "CLIENT_CACHE_MAX_SIZE",
+                cache.client_cache_max_size.as_ref(),
+            ),
+            var("BUCKET_LIMITS_FILE", cache.bucket_limits_file.as_ref()),
+            var("METRIC_KEY", metrics.key.as_ref()),
+            var("METRIC_TIMEOUT", metrics.timeout.as_ref()),
+            var("METRIC_LABELS", metrics.labels.as_ref()),
+            var("METRIC_DIMENSIONS", metrics.dimensions.as_ref()),
+            var("RUST_LOG", log.filter.as_ref()),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Export the values as environment variables that aren't set yet.
+    ///
+    /// Must be called before any other threads are started.
+    pub fn apply(&self) {
+        for (name, value) in self.vars() {
+            if env::var_os(name).is_none() {
+                env::set_var(name, value);
+            }
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(contents: &str) -> Result<Self, Self::Err> {
+        Self::parse_profile(contents, None)
+    }
+}
+
+/// Override the keys of a table with those of another, merging nested tables.
+fn merge(table: &mut Table, overrides: &Table) {
+    for (key, value) in overrides {
+        match (table.get_mut(key), value) {
+            (Some(Value::Table(table)), Value::Table(overrides)) => merge(table, overrides),
+            _ => {
+                table.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, ConfigError};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_parse() {
+        let config = r#"
+            [listener]
+            host = "127.0.0.1"
+            port = 3000
+            trusted_proxies = ["10.0.0.0/8", "fd00::/8"]
+
+            [ratelimiter]
+            max_requests_per_second = 40
+
+            [cache]
+            client_decay_timeout = 600
+        "#
+        .parse::<Config>()
+        .unwrap();
+
+        assert_eq!(
+            config.listener.host,
+            Some(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)))
+        );
+        assert_eq!(config.ratelimiter.max_requests_per_second, Some(40.0));
+        assert_eq!(
+            config.vars(),
+            [
+                ("HOST", "127.0.0.1".to_string()),
+                ("PORT", "3000".to_string()),
+                ("TRUSTED_PROXIES", "10.0.0.0/8,fd00::/8".to_string()),
+                ("MAX_REQUESTS_PER_SECOND", "40".to_string()),
+                ("CLIENT_DECAY_TIMEOUT", "600".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_profile() {
+        let file = r#"
+            [upstream]
+            url = "https://discord.com"
+
+            [metrics]
+            key = "proxy"
+
+            [profile.canary.upstream]
+            url = "https://canary.discord.com"
+
+            [profile.canary.cache]
+            client_cache_max_size = 100
+
+            [profile.prod.metrics]
+            key = "proxy_prod"
+        "#;
+
+        let config = Config::parse_profile(file, Some("canary")).unwrap();
+        assert_eq!(
+            config.vars(),
+            [
+                ("UPSTREAM_URL", "https://canary.discord.com".to_string()),
+                ("CLIENT_CACHE_MAX_SIZE", "100".to_string()),
+                ("METRIC_KEY", "proxy".to_string()),
+            ]
+        );
+
+        let config = Config::parse_profile(file, None).unwrap();
+        assert_eq!(config.upstream.url.as_deref(), Some("https://discord.com"));
+        assert_eq!(config.cache.client_cache_max_size, None);
+
+        assert!(matches!(
+            Config::parse_profile(file, Some("staging")),
+            Err(ConfigError::UnknownProfile { profile }) if profile == "staging"
+        ));
+        assert!(matches!(
+            Config::parse_profile("[profile.prod.listener]\nprot = 3000", None),
+            Err(ConfigError::InvalidProfile { profile, source })
+                if profile == "prod" && source.to_string().contains("unknown field `prot`")
+        ));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let error = |file: &str| match file.parse::<Config>() {
+            Err(ConfigError::Parse { source }) => source.to_string(),
+            result => panic!("{:?} is not a parse error", result),
+        };
+
+        assert!(error("[listener]\nport = 70000").contains("u16"));
+        assert!(error("[listener]\nprot = 3000").contains("unknown field `prot`"));
+        assert!(error("port = 3000").contains("unknown field `port`"));
+        assert!(error("[listener]\nhost = 1").contains("invalid type"));
+        assert!(error("profile = 1").contains("invalid type"));
+        error("[listener");
+    }
+}
@@ -0,0 +1,186 @@
+//! Cross-origin resource sharing, allowing browsers to send requests to the
+//! proxy from web pages.
+
+use http::{
+    header::{
+        ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+        ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+        ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+    },
+    HeaderMap, HeaderValue, Method, Request, Response, StatusCode,
+};
+use hyper::Body;
+use std::env;
+use tracing::debug;
+
+/// Methods the proxy forwards to Discord.
+const ALLOWED_METHODS: &str = "DELETE, GET, HEAD, PATCH, POST, PUT";
+
+/// Response headers browsers may read, besides the CORS-safelisted ones.
+const EXPOSED_HEADERS: &str = "retry-after, x-ratelimit-bucket, x-ratelimit-global, \
+                               x-ratelimit-limit, x-ratelimit-remaining, x-ratelimit-reset, \
+                               x-ratelimit-reset-after, x-ratelimit-scope";
+
+/// Seconds browsers may cache the result of a preflight request.
+const MAX_AGE: &str = "86400";
+
+/// Origins allowed to send requests to the proxy.
+pub struct Cors {
+    /// Allowed origins, or `None` if any origin is allowed.
+    origins: Option<Vec<String>>,
+}
+
+impl Cors {
+    /// Load the allowed origins from `CORS_ORIGINS`, which is a
+    /// comma-separated list of origins or `*`.
+    ///
+    /// Returns `None` if CORS is not enabled.
+    pub fn from_env() -> Option<Self> {
+        let value = env::var("CORS_ORIGINS").ok()?;
+
+        let origins = (value.trim() != "*").then(|| {
+            value
+                .split(',')
+                .map(|origin| origin.trim().trim_end_matches('/').to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect()
+        });
+
+        Some(Self { origins })
+    }
+
+    fn is_allowed(&self, origin: &HeaderValue) -> bool {
+        match &self.origins {
+            Some(origins) => origin
+                .to_str()
+                .is_ok_and(|origin| origins.iter().any(|allowed| allowed == origin)),
+            None => true,
+        }
+    }
+
+    /// Answer a preflight request, which never needs a ratelimit ticket.
+    ///
+    /// Returns `None` if the request is not a preflight request.
+    pub fn preflight(&self, request: &Request<Body>) -> Option<Response<Body>> {
+        let headers = request.headers();
+
+        if request.method() != Method::OPTIONS
+            || !headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            return None;
+        }
+
+        let origin = headers.get(ORIGIN)?;
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NO_CONTENT;
+
+        // Without the allow headers, the browser rejects the actual request
+        if self.is_allowed(origin) {
+            let response_headers = response.headers_mut();
+            self.allow(origin, response_headers);
+            response_headers.insert(
+                ACCESS_CONTROL_ALLOW_METHODS,
+                HeaderValue::from_static(ALLOWED_METHODS),
+            );
+            response_headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static(MAX_AGE));
+
+            if let Some(requested) = headers.get(ACCESS_CONTROL_REQUEST_HEADERS) {
+                response_headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+            }
+        } else {
+            debug!("Rejecting preflight request from origin {:?}", origin);
+        }
+
+        Some(response)
+    }
+
+    /// Allow the origin of a request to read its response.
+    pub fn apply(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        if let Some(origin) = origin.filter(|origin| self.is_allowed(origin)) {
+            self.allow(origin, headers);
+            headers.insert(
+                ACCESS_CONTROL_EXPOSE_HEADERS,
+                HeaderValue::from_static(EXPOSED_HEADERS),
+            );
+        }
+    }
+
+    fn allow(&self, origin: &HeaderValue, headers: &mut HeaderMap) {
+        if self.origins.is_some() {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+            headers.append(VARY, HeaderValue::from_static("origin"));
+        } else {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cors;
+    use http::{
+        header::{
+            ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_ORIGIN,
+            ACCESS_CONTROL_EXPOSE_HEADERS,
+        },
+        HeaderMap, HeaderValue, Method, Request, StatusCode,
+    };
+    use hyper::Body;
+
+    fn preflight(origin: &'static str) -> Request<Body> {
+        Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/api/v10/channels/1/messages")
+            .header("origin", origin)
+            .header("access-control-request-method", "POST")
+            .header(
+                "access-control-request-headers",
+                "authorization, content-type",
+            )
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_preflight() {
+        let cors = Cors {
+            origins: Some(vec!["https://example.com".to_string()]),
+        };
+
+        let response = cors.preflight(&preflight("https://example.com")).unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+        assert_eq!(
+            response.headers()[ACCESS_CONTROL_ALLOW_HEADERS],
+            "authorization, content-type"
+        );
+
+        let response = cors.preflight(&preflight("https://evil.com")).unwrap();
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        // Plain OPTIONS requests are not preflight requests
+        let request = Request::builder()
+            .method(Method::OPTIONS)
+            .body(Body::empty())
+            .unwrap();
+        assert!(cors.preflight(&request).is_none());
+    }
+
+    #[test]
+    fn test_apply() {
+        let cors = Cors { origins: None };
+        let origin = HeaderValue::from_static("https://example.com");
+
+        let mut headers = HeaderMap::new();
+        cors.apply(Some(&origin), &mut headers);
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(headers.contains_key(ACCESS_CONTROL_EXPOSE_HEADERS));
+
+        let mut headers = HeaderMap::new();
+        cors.apply(None, &mut headers);
+        assert!(headers.is_empty());
+    }
+}
@@ -0,0 +1,206 @@
+//! Deadlines set by clients, after which queued requests are dropped.
+//!
+//! A request's deadline is its time budget, consumed by each stage it passes
+//! through until Discord responded.
+
+use http::HeaderMap;
+use std::future::Future;
+use tokio::time::{Duration, Instant};
+use tracing::debug;
+
+/// Header with the amount of milliseconds the client waits for a response.
+pub const DEADLINE_HEADER: &str = "x-proxy-deadline-ms";
+
+/// Header with the amount of seconds the client waits for a response.
+const REQUEST_TIMEOUT: &str = "request-timeout";
+
+/// Remove the deadline headers of a request and return the deadline they set,
+/// relative to now.
+///
+/// If both headers are set, the earlier deadline applies and if neither is,
+/// the `default` timeout. Invalid values are ignored.
+pub fn take_deadline(headers: &mut HeaderMap, default: Option<Duration>) -> Option<Instant> {
+    let now = Instant::now();
+
+    let millis = headers
+        .remove(DEADLINE_HEADER)
+        .and_then(|value| parse(value.to_str().ok()?, 1000.0));
+    let seconds = headers
+        .remove(REQUEST_TIMEOUT)
+        .and_then(|value| parse(value.to_str().ok()?, 1.0));
+
+    match (millis, seconds) {
+        (Some(millis), Some(seconds)) => Some(now + millis.min(seconds)),
+        (Some(timeout), None) | (None, Some(timeout)) => Some(now + timeout),
+        (None, None) => default.map(|timeout| now + timeout),
+    }
+}
+
+/// Parse a non-negative amount of units, of which there are `per_second`.
+fn parse(value: &str, per_second: f64) -> Option<Duration> {
+    match value.trim().parse::<f64>() {
+        Ok(amount) if amount.is_finite() && amount >= 0.0 => {
+            Some(Duration::from_secs_f64(amount / per_second))
+        }
+        _ => {
+            debug!("Ignoring invalid deadline {:?}", value);
+
+            None
+        }
+    }
+}
+
+/// Stage of a request that spends time of its budget.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Stage {
+    /// Reading the body from the client.
+    Body,
+    /// Waiting for the ratelimit and concurrency limits.
+    Queue,
+    /// Connecting to Discord and waiting for its response.
+    Upstream,
+}
+
+impl Stage {
+    const ALL: [Self; 3] = [Self::Body, Self::Queue, Self::Upstream];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Body => "body",
+            Self::Queue => "queue",
+            Self::Upstream => "upstream",
+        }
+    }
+}
+
+/// Time a request may take until its deadline and how much of it each stage
+/// spent.
+pub struct Budget {
+    deadline: Option<Instant>,
+    spent: [Duration; Stage::ALL.len()],
+}
+
+impl Budget {
+    /// Without a deadline, every stage may take as long as it needs.
+    pub const fn new(deadline: Option<Instant>) -> Self {
+        Self {
+            deadline,
+            spent: [Duration::ZERO; Stage::ALL.len()],
+        }
+    }
+
+    /// Run a stage until it completes or the deadline expires, returning the
+    /// stage in the latter case.
+    pub async fn run<F: Future>(&mut self, stage: Stage, future: F) -> Result<F::Output, Stage> {
+        let start = Instant::now();
+
+        let output = match self.deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, future).await.ok(),
+            None => Some(future.await),
+        };
+
+        self.spent[stage as usize] += start.elapsed();
+
+        output.ok_or(stage)
+    }
+
+    /// Time spent in a stage so far.
+    pub fn spent(&self, stage: Stage) -> Duration {
+        self.spent[stage as usize]
+    }
+
+    /// Record the time spent in each stage.
+    #[cfg(feature = "expose-metrics")]
+    pub fn record(&self) {
+        let key = format!("{}_stage_seconds", crate::METRIC_KEY.as_str());
+
+        for stage in Stage::ALL {
+            metrics::histogram!(
+                key.clone(),
+                self.spent(stage).as_secs_f64(),
+                "stage" => stage.name()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{take_deadline, Budget, Stage, DEADLINE_HEADER};
+    use http::{HeaderMap, HeaderValue};
+    use tokio::time::{Duration, Instant};
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_take_deadline() {
+        let now = Instant::now();
+
+        let mut map = headers(&[(DEADLINE_HEADER, "1500"), ("x-other", "1")]);
+        assert_eq!(
+            take_deadline(&mut map, None),
+            Some(now + Duration::from_millis(1500))
+        );
+        assert!(map.get(DEADLINE_HEADER).is_none());
+        assert!(map.get("x-other").is_some());
+
+        let mut map = headers(&[("request-timeout", "2.5")]);
+        assert_eq!(
+            take_deadline(&mut map, None),
+            Some(now + Duration::from_millis(2500))
+        );
+
+        let mut map = headers(&[(DEADLINE_HEADER, "3000"), ("request-timeout", "1")]);
+        assert_eq!(
+            take_deadline(&mut map, None),
+            Some(now + Duration::from_secs(1))
+        );
+
+        let mut map = headers(&[(DEADLINE_HEADER, "-1"), ("request-timeout", "soon")]);
+        assert_eq!(take_deadline(&mut map, None), None);
+        assert!(map.is_empty());
+
+        let mut map = headers(&[(DEADLINE_HEADER, "500")]);
+        assert_eq!(
+            take_deadline(&mut map, Some(Duration::from_secs(10))),
+            Some(now + Duration::from_millis(500))
+        );
+        assert_eq!(
+            take_deadline(&mut map, Some(Duration::from_secs(10))),
+            Some(now + Duration::from_secs(10))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_budget() {
+        let mut budget = Budget::new(Some(Instant::now() + Duration::from_secs(3)));
+
+        let output = budget
+            .run(Stage::Queue, async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+
+                1
+            })
+            .await;
+        assert_eq!(output, Ok(1));
+        assert_eq!(budget.spent(Stage::Queue), Duration::from_secs(1));
+
+        let output = budget
+            .run(Stage::Upstream, tokio::time::sleep(Duration::from_secs(5)))
+            .await;
+        assert_eq!(output, Err(Stage::Upstream));
+        assert_eq!(budget.spent(Stage::Upstream), Duration::from_secs(2));
+        assert_eq!(budget.spent(Stage::Body), Duration::ZERO);
+
+        let mut unbounded = Budget::new(None);
+        let output = unbounded
+            .run(Stage::Queue, tokio::time::sleep(Duration::from_secs(60)))
+            .await;
+        assert_eq!(output, Ok(()));
+    }
+}
@@ -0,0 +1,114 @@
+//! Diagnostic snapshot logged on `SIGUSR1`, showing what a stuck proxy is
+//! waiting for without restarting it.
+
+use crate::State;
+use std::fmt::Write;
+
+/// Describe the state of the proxy, one line per entry.
+pub fn snapshot(state: &State) -> String {
+    let stats = &state.stats;
+    let tenants = state.ratelimiter_map.tenants();
+    let mut out = String::new();
+
+    _ = writeln!(
+        out,
+        "uptime {:?}, {} connections open, {} requests served, {} dropped in queue",
+        stats.uptime(),
+        stats.open_connections(),
+        stats.requests_served(),
+        stats.requests_dropped()
+    );
+    _ = writeln!(
+        out,
+        "traffic {}, {} routes under maintenance",
+        if state.pause.is_paused() {
+            "paused"
+        } else {
+            "flowing"
+        },
+        state.maintenance.routes().len()
+    );
+
+    if let Some(remaining) = state.lockdown.as_ref().and_then(|l| l.remaining()) {
+        _ = writeln!(out, "locked down for another {:?}", remaining);
+    }
+
+    _ = writeln!(
+        out,
+        "cache: {} tenants with {} buckets, {} major parameters, {} recent 429s",
+        tenants.len(),
+        tenants
+            .iter()
+            .map(|tenant| tenant.usage.paths().len())
+            .sum::<usize>(),
+        state.major_parameters.top().len(),
+        state.ratelimit_log.entries().len()
+    );
+
+    for tenant in &tenants {
+        let queue_ages = tenant.usage.queue_ages();
+
+        if queue_ages.is_empty() {
+            continue;
+        }
+
+        _ = writeln!(
+            out,
+            "tenant {}: {} requests queued",
+            tenant.usage.hash(),
+            tenant.usage.queue_depth()
+        );
+
+        for (path, age) in queue_ages {
+            _ = writeln!(
+                out,
+                "  {:?}: {} queued, oldest for {:?}",
+                path,
+                tenant.usage.queued(&path),
+                age
+            );
+        }
+    }
+
+    let in_flight = stats.in_flight_requests();
+    _ = writeln!(out, "{} requests in flight", in_flight.len());
+
+    for request in in_flight {
+        _ = writeln!(
+            out,
+            "  {} {} of tenant {} for {:?}",
+            request.method,
+            request.route,
+            request.tenant,
+            request.since.elapsed()
+        );
+    }
+
+    #[cfg(tokio_unstable)]
+    {
+        let metrics = tokio::runtime::Handle::current().metrics();
+        _ = writeln!(
+            out,
+            "runtime: {} workers, {} tasks, {} blocking threads",
+            metrics.num_workers(),
+            metrics.num_alive_tasks(),
+            metrics.num_blocking_threads()
+        );
+    }
+
+    out
+}
+
+/// Log a snapshot on every `SIGUSR1` until the process exits.
+#[cfg(unix)]
+pub async fn run(state: &State) {
+    use tokio::signal::unix::{signal, SignalKind};
+    use tracing::info;
+
+    let mut signals =
+        signal(SignalKind::user_defined1()).expect("failed to install SIGUSR1 handler");
+
+    while signals.recv().await.is_some() {
+        info!("Diagnostic snapshot:\n{}", snapshot(state));
+    }
+}
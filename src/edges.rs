@@ -0,0 +1,280 @@
+//! Selection between several addresses of the upstream, e.g. the IPs of
+//! different Cloudflare edges, preferring the one with the lowest latency.
+//!
+//! The addresses replace the DNS resolution of the upstream's host, so the
+//! `Host` header and TLS still use the host name. Connections are attempted
+//! in order of latency, falling back to the next address if one fails.
+
+use crate::upstream::Upstream;
+use hyper::{client::connect::dns::Name, service::Service};
+use hyper_trust_dns::TrustDnsResolver;
+use std::{
+    env,
+    error::Error,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+    vec::IntoIter,
+};
+use tokio::{
+    net::TcpStream,
+    time::{self, Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+/// Time to connect to the upstream, split between the addresses tried.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval in which the latency of the addresses is measured.
+const MEASURE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time after which an address is considered unreachable.
+const MEASURE_TIMEOUT: Duration = Duration::from_secs(2);
+
+struct Edge {
+    ip: IpAddr,
+    /// Smoothed connect latency, or `None` if unreachable or not measured
+    /// yet.
+    latency: Option<Duration>,
+}
+
+/// Addresses of the upstream, ordered by latency.
+pub struct Edges {
+    host: String,
+    port: u16,
+    edges: RwLock<Vec<Edge>>,
+}
+
+impl Edges {
+    /// Load the addresses from `UPSTREAM_ADDRS`.
+    ///
+    /// Returns `None` if none are configured.
+    pub fn from_env(upstream: &Upstream) -> Option<Arc<Self>> {
+        let ips = env::var("UPSTREAM_ADDRS")
+            .ok()?
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let ip = entry.parse::<IpAddr>().ok();
+
+                if ip.is_none() {
+                    warn!("Ignoring invalid upstream address {:?}", entry);
+                }
+
+                ip
+            })
+            .collect::<Vec<_>>();
+
+        if ips.is_empty() {
+            return None;
+        }
+
+        Some(Arc::new(Self::new(
+            upstream.host_name(),
+            upstream.port(),
+            ips,
+        )))
+    }
+
+    fn new(host: &str, port: u16, ips: Vec<IpAddr>) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            edges: RwLock::new(
+                ips.into_iter()
+                    .map(|ip| Edge { ip, latency: None })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Addresses in the order they should be tried.
+    fn ordered(&self) -> Vec<SocketAddr> {
+        let edges = self.edges.read().expect("edges poisoned");
+
+        edges
+            .iter()
+            .map(|edge| SocketAddr::new(edge.ip, self.port))
+            .collect()
+    }
+
+    /// Record a latency measurement and reorder the addresses.
+    fn record(&self, ip: IpAddr, latency: Option<Duration>) {
+        let mut edges = self.edges.write().expect("edges poisoned");
+        let fastest = edges.first().map(|edge| edge.ip);
+
+        if let Some(edge) = edges.iter_mut().find(|edge| edge.ip == ip) {
+            // Smooth measurements so a single slow connect doesn't reorder
+            edge.latency = match (edge.latency, latency) {
+                (Some(previous), Some(latency)) => Some((previous * 3 + latency) / 4),
+                (_, latency) => latency,
+            };
+        }
+
+        // Unreachable addresses are tried last, ties keep the configured
+        // order
+        edges.sort_by_key(|edge| edge.latency.unwrap_or(Duration::MAX));
+
+        if edges.first().map(|edge| edge.ip) != fastest {
+            info!(
+                "Preferring upstream address {} ({:?})",
+                edges[0].ip, edges[0].latency
+            );
+        }
+    }
+
+    /// Measure the connect latency of every address until the process exits.
+    pub async fn run(&self) {
+        let mut interval = time::interval(MEASURE_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let ips = self
+                .edges
+                .read()
+                .expect("edges poisoned")
+                .iter()
+                .map(|edge| edge.ip)
+                .collect::<Vec<_>>();
+
+            for ip in ips {
+                let latency = measure(SocketAddr::new(ip, self.port)).await;
+                debug!("Latency of upstream address {}: {:?}", ip, latency);
+
+                self.record(ip, latency);
+            }
+        }
+    }
+}
+
+/// Time to open a TCP connection to an address.
+async fn measure(addr: SocketAddr) -> Option<Duration> {
+    let start = Instant::now();
+
+    match time::timeout(MEASURE_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(Ok(_)) => Some(start.elapsed()),
+        _ => None,
+    }
+}
+
+/// Resolver returning the configured addresses for the upstream's host and
+/// resolving all other hosts via DNS.
+#[derive(Clone)]
+pub struct EdgeResolver {
+    dns: TrustDnsResolver,
+    edges: Option<Arc<Edges>>,
+}
+
+impl EdgeResolver {
+    pub fn new(edges: Option<Arc<Edges>>) -> Self {
+        Self {
+            dns: TrustDnsResolver::default(),
+            edges,
+        }
+    }
+}
+
+impl Service<Name> for EdgeResolver {
+    type Response = IntoIter<SocketAddr>;
+    type Error = Box<dyn Error + Send + Sync>;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.dns.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        if let Some(edges) = self
+            .edges
+            .as_ref()
+            .filter(|edges| edges.host.eq_ignore_ascii_case(name.as_str()))
+        {
+            let addrs = edges.ordered();
+
+            return Box::pin(async move { Ok(addrs.into_iter()) });
+        }
+
+        let lookup = self.dns.call(name);
+
+        Box::pin(async move { Ok(lookup.await?.collect::<Vec<_>>().into_iter()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{measure, EdgeResolver, Edges};
+    use hyper::{client::connect::dns::Name, service::Service};
+    use std::{
+        net::{IpAddr, SocketAddr, TcpListener},
+        str::FromStr,
+        sync::Arc,
+        time::Duration,
+    };
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_order() {
+        let edges = Edges::new(
+            "discord.com",
+            443,
+            vec![ip("10.0.0.1"), ip("10.0.0.2"), ip("10.0.0.3")],
+        );
+
+        edges.record(ip("10.0.0.1"), None);
+        edges.record(ip("10.0.0.2"), Some(Duration::from_millis(80)));
+        edges.record(ip("10.0.0.3"), Some(Duration::from_millis(20)));
+
+        let ordered = edges.ordered();
+        assert_eq!(
+            ordered,
+            [
+                SocketAddr::new(ip("10.0.0.3"), 443),
+                SocketAddr::new(ip("10.0.0.2"), 443),
+                SocketAddr::new(ip("10.0.0.1"), 443),
+            ]
+        );
+
+        // A single slow measurement is smoothed out
+        edges.record(ip("10.0.0.3"), Some(Duration::from_millis(100)));
+        assert_eq!(edges.ordered()[0].ip(), ip("10.0.0.3"));
+    }
+
+    #[tokio::test]
+    async fn test_measure_and_resolve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Nothing listens on the other loopback address
+        assert!(measure(SocketAddr::new(ip("127.0.0.2"), port))
+            .await
+            .is_none());
+        assert!(measure(SocketAddr::new(ip("127.0.0.1"), port))
+            .await
+            .is_some());
+
+        let edges = Arc::new(Edges::new(
+            "discord.com",
+            port,
+            vec![ip("127.0.0.2"), ip("127.0.0.1")],
+        ));
+        edges.record(ip("127.0.0.2"), None);
+        edges.record(ip("127.0.0.1"), Some(Duration::from_millis(1)));
+
+        let mut resolver = EdgeResolver::new(Some(edges));
+        let addrs = resolver
+            .call(Name::from_str("Discord.com").unwrap())
+            .await
+            .unwrap()
+            .map(|addr| addr.ip())
+            .collect::<Vec<_>>();
+        assert_eq!(addrs, [ip("127.0.0.1"), ip("127.0.0.2")]);
+    }
+}
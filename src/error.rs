@@ -1,5 +1,9 @@
-use http::{uri::InvalidUri, Method, Response};
+use crate::{
+    deadline::Stage, limits::LimitExceeded, multipart::InvalidMultipart, query::InvalidQuery,
+};
+use http::{header::RETRY_AFTER, Error as HttpError, Method, Response};
 use hyper::{Body, Error as HyperError};
+use serde_json::Error as JsonError;
 use std::{
     error::Error,
     fmt::{Display, Formatter, Result as FmtResult},
@@ -8,10 +12,50 @@ use twilight_http_ratelimiting::request::PathParseError;
 
 static ACQUIRING_TICKET_FAILED_MSG: &str =
     "http-proxy: Acquiring ticket from the ratelimiter failed";
+static BUDGET_EXCEEDED_MSG: &str =
+    "http-proxy: Daily request budget exhausted, retry after midnight UTC";
+static CONNECT_MSG: &str = "http-proxy: CONNECT is not supported, the proxy is not a forward \
+                            proxy. Set it as the API base URL of your library over plain HTTP \
+                            (e.g. http://proxy:3000/api/v10) instead of as an HTTP proxy";
+static DEADLINE_EXCEEDED_MSG: &str =
+    "http-proxy: Deadline expired before the request could be sent to Discord";
+static DEADLINE_EXCEEDED_UPSTREAM_MSG: &str =
+    "http-proxy: Deadline expired while waiting for Discord's response, the request may have \
+     been executed";
+static HEADERS_TOO_LARGE_MSG: &str = "http-proxy: Request headers exceed the maximum count or size";
+static INVALID_BODY_MSG: &str = "http-proxy: Failed to read request body";
+static INVALID_BULK_BAN_MSG: &str = "http-proxy: Bulk bans are a POST to \
+                                     /__proxy/bulk-ban/guilds/{id} with a list of user_ids";
+static INVALID_JSON_MSG: &str = "http-proxy: Request body is not valid JSON";
+static INVALID_MULTIPART_MSG: &str = "http-proxy: Malformed multipart request body";
+static INVALID_QUERY_MSG: &str = "http-proxy: Query string is too long or malformed";
 static INVALID_URI_MSG: &str = "http-proxy: Failed to create URI for requesting Discord API";
 static INVALID_METHOD_MSG: &str = "http-proxy: Unsupported HTTP method in request";
+static INVALID_PAGE_LIMIT_MSG: &str = "http-proxy: Pagination limit must be a positive integer";
 static INVALID_PATH_MSG: &str = "http-proxy: Failed to parse API path from client request";
+static LIMIT_EXCEEDED_MSG: &str = "http-proxy: Request payload exceeds Discord's limits";
+static NOT_PAGINATED_MSG: &str =
+    "http-proxy: Only GET requests to Discord's list endpoints can be paginated";
+static PAUSED_MSG: &str = "http-proxy: Traffic is paused and too many requests are waiting";
+static PAYLOAD_TOO_LARGE_MSG: &str = "http-proxy: Request body exceeds the upload limit";
+static LOCKDOWN_MSG: &str =
+    "http-proxy: Discord banned the proxy's IP, traffic is halted until the ban expires";
+static MEMORY_PRESSURE_MSG: &str =
+    "http-proxy: Memory usage is high, bulk requests are shed until it recovers";
+static MISSING_TOKEN_MSG: &str =
+    "http-proxy: Request has no Authorization header and no default token is configured";
+static QUEUE_FULL_MSG: &str =
+    "http-proxy: Too many requests are waiting for their ratelimit, try again later";
+static QUEUE_TIMEOUT_MSG: &str = "http-proxy: Request waited too long for its ratelimit";
 static REQUEST_ISSUE_MSG: &str = "http-proxy: Error requesting the Discord API";
+static RESPONSE_TOO_LARGE_MSG: &str = "http-proxy: Discord's response exceeds the size limit";
+static SESSION_STARTS_EXHAUSTED_MSG: &str =
+    "http-proxy: Session starts are nearly exhausted, not fetching the gateway";
+static SUBLIMITED_MSG: &str =
+    "http-proxy: The route's sublimit is exhausted for longer than requests are paced";
+static UPGRADE_MSG: &str = "http-proxy: WebSocket upgrades are not supported, the proxy only \
+                           handles REST calls. Connect to the gateway directly, using the URL \
+                           returned by GET /gateway/bot";
 
 #[allow(clippy::module_name_repetitions)]
 #[derive(Debug)]
@@ -19,34 +63,128 @@ pub enum RequestError {
     AcquiringTicket {
         source: Box<dyn Error + Send + Sync>,
     },
+    BudgetExceeded {
+        retry_after: u64,
+    },
+    Connect,
+    DeadlineExceeded {
+        stage: Stage,
+    },
+    HeadersTooLarge,
+    InvalidBody {
+        source: HyperError,
+    },
+    InvalidBulkBan,
     InvalidMethod {
         method: Method,
     },
+    InvalidJson {
+        source: JsonError,
+        /// Byte offset of the error within the JSON payload.
+        offset: usize,
+    },
+    InvalidMultipart {
+        source: InvalidMultipart,
+    },
+    InvalidPageLimit,
     InvalidPath {
         source: PathParseError,
     },
+    InvalidQuery {
+        source: InvalidQuery,
+    },
     InvalidURI {
-        source: InvalidUri,
+        source: HttpError,
+    },
+    LimitExceeded {
+        source: LimitExceeded,
+    },
+    Lockdown {
+        retry_after: u64,
+    },
+    MemoryPressure,
+    MissingToken,
+    NotPaginated,
+    Paused,
+    QueueFull,
+    QueueTimeout {
+        retry_after: u64,
     },
     RequestIssue {
         source: HyperError,
     },
+    ResponseTooLarge {
+        size: u64,
+    },
+    SessionStartsExhausted {
+        retry_after: u64,
+    },
+    Sublimited {
+        retry_after: u64,
+    },
+    Upgrade,
 }
 
 impl RequestError {
     pub fn as_response(&self) -> Response<Body> {
         let (status_code, body) = match self {
             RequestError::AcquiringTicket { .. } => (500, ACQUIRING_TICKET_FAILED_MSG),
+            RequestError::BudgetExceeded { .. } => (429, BUDGET_EXCEEDED_MSG),
+            RequestError::Connect => (405, CONNECT_MSG),
+            RequestError::DeadlineExceeded {
+                stage: Stage::Upstream,
+            } => (504, DEADLINE_EXCEEDED_UPSTREAM_MSG),
+            RequestError::DeadlineExceeded { .. } => (504, DEADLINE_EXCEEDED_MSG),
+            RequestError::HeadersTooLarge => (431, HEADERS_TOO_LARGE_MSG),
+            RequestError::InvalidBody { .. } => (400, INVALID_BODY_MSG),
+            RequestError::InvalidBulkBan => (400, INVALID_BULK_BAN_MSG),
+            RequestError::InvalidJson { .. } => (400, INVALID_JSON_MSG),
+            RequestError::InvalidMultipart { .. } => (400, INVALID_MULTIPART_MSG),
+            RequestError::InvalidQuery { .. } => (400, INVALID_QUERY_MSG),
             RequestError::InvalidURI { .. } => (500, INVALID_URI_MSG),
             RequestError::InvalidMethod { .. } => (501, INVALID_METHOD_MSG),
+            RequestError::InvalidPageLimit => (400, INVALID_PAGE_LIMIT_MSG),
             RequestError::InvalidPath { .. } => (501, INVALID_PATH_MSG),
+            RequestError::LimitExceeded {
+                source: LimitExceeded::Upload { .. },
+            } => (413, PAYLOAD_TOO_LARGE_MSG),
+            RequestError::LimitExceeded { .. } => (400, LIMIT_EXCEEDED_MSG),
+            RequestError::Lockdown { .. } => (503, LOCKDOWN_MSG),
+            RequestError::MemoryPressure => (503, MEMORY_PRESSURE_MSG),
+            RequestError::MissingToken => (401, MISSING_TOKEN_MSG),
+            RequestError::NotPaginated => (404, NOT_PAGINATED_MSG),
+            RequestError::Paused => (503, PAUSED_MSG),
+            RequestError::QueueFull => (503, QUEUE_FULL_MSG),
+            RequestError::QueueTimeout { .. } => (429, QUEUE_TIMEOUT_MSG),
             RequestError::RequestIssue { .. } => (502, REQUEST_ISSUE_MSG),
+            RequestError::ResponseTooLarge { .. } => (502, RESPONSE_TOO_LARGE_MSG),
+            RequestError::SessionStartsExhausted { .. } => (429, SESSION_STARTS_EXHAUSTED_MSG),
+            RequestError::Sublimited { .. } => (429, SUBLIMITED_MSG),
+            RequestError::Upgrade => (400, UPGRADE_MSG),
         };
 
-        Response::builder()
-            .status(status_code)
-            .body(Body::from(body))
-            .unwrap()
+        let mut builder = Response::builder().status(status_code);
+
+        if let RequestError::BudgetExceeded { retry_after }
+        | RequestError::Lockdown { retry_after }
+        | RequestError::QueueTimeout { retry_after }
+        | RequestError::SessionStartsExhausted { retry_after }
+        | RequestError::Sublimited { retry_after } = self
+        {
+            builder = builder.header(RETRY_AFTER, *retry_after);
+        }
+
+        // Point clients to the location of the syntax error
+        if let RequestError::InvalidJson { source, offset } = self {
+            return builder
+                .body(Body::from(format!(
+                    "{} (at byte {}: {})",
+                    body, offset, source
+                )))
+                .unwrap();
+        }
+
+        builder.body(Body::from(body)).unwrap()
     }
 }
 
@@ -57,22 +195,96 @@ impl Display for RequestError {
                 f.write_str("error when acquiring ratelimiting ticket: ")?;
                 source.fmt(f)
             }
+            Self::BudgetExceeded { retry_after } => {
+                f.write_str("daily budget exceeded, resets in ")?;
+                retry_after.fmt(f)?;
+
+                f.write_str(" seconds")
+            }
+            Self::Connect => f.write_str("client tried to tunnel with CONNECT"),
+            Self::DeadlineExceeded { stage } => {
+                f.write_str("deadline expired in stage ")?;
+
+                f.write_str(stage.name())
+            }
+            Self::HeadersTooLarge => f.write_str("request headers exceed the limits"),
+            Self::InvalidBody { source } => {
+                f.write_str("failed to read request body: ")?;
+                source.fmt(f)
+            }
+            Self::InvalidBulkBan => f.write_str("invalid bulk ban request"),
             Self::InvalidMethod { method } => {
                 f.write_str("invalid method: ")?;
                 method.fmt(f)
             }
+            Self::InvalidJson { source, offset } => {
+                f.write_str("invalid json at byte ")?;
+                offset.fmt(f)?;
+                f.write_str(": ")?;
+
+                source.fmt(f)
+            }
+            Self::InvalidMultipart { source } => {
+                f.write_str("invalid multipart body: ")?;
+                source.fmt(f)
+            }
+            Self::InvalidPageLimit => f.write_str("pagination limit is not a positive integer"),
             Self::InvalidPath { source } => {
                 f.write_str("invalid path: ")?;
                 source.fmt(f)
             }
+            Self::InvalidQuery { source } => {
+                f.write_str("invalid query: ")?;
+                source.fmt(f)
+            }
             Self::InvalidURI { source } => {
                 f.write_str("generated uri for discord api is invalid: ")?;
                 source.fmt(f)
             }
+            Self::LimitExceeded { source } => {
+                f.write_str("payload limit exceeded: ")?;
+                source.fmt(f)
+            }
+            Self::Lockdown { retry_after } => {
+                f.write_str("locked down after a ban, ends in ")?;
+                retry_after.fmt(f)?;
+
+                f.write_str(" seconds")
+            }
+            Self::MemoryPressure => f.write_str("bulk request shed under memory pressure"),
+            Self::MissingToken => f.write_str("request has no token and no default is configured"),
+            Self::NotPaginated => f.write_str("endpoint can't be paginated"),
+            Self::Paused => f.write_str("traffic is paused and the queue is full"),
+            Self::QueueFull => f.write_str("too many requests are queued"),
+            Self::QueueTimeout { retry_after } => {
+                f.write_str("waited too long for the ratelimit, retry in ")?;
+                retry_after.fmt(f)?;
+
+                f.write_str(" seconds")
+            }
             Self::RequestIssue { source } => {
                 f.write_str("error executing request: ")?;
                 source.fmt(f)
             }
+            Self::ResponseTooLarge { size } => {
+                f.write_str("response of ")?;
+                size.fmt(f)?;
+
+                f.write_str(" bytes exceeds the size limit")
+            }
+            Self::SessionStartsExhausted { retry_after } => {
+                f.write_str("session starts nearly exhausted, resets in ")?;
+                retry_after.fmt(f)?;
+
+                f.write_str(" seconds")
+            }
+            Self::Sublimited { retry_after } => {
+                f.write_str("sublimit exhausted, retry in ")?;
+                retry_after.fmt(f)?;
+
+                f.write_str(" seconds")
+            }
+            Self::Upgrade => f.write_str("client tried to upgrade to a websocket"),
         }
     }
 }
@@ -1,6 +1,16 @@
 use dashmap::{mapref::one::Ref, DashMap};
 use futures_util::StreamExt;
-use std::{borrow::Borrow, hash::Hash, marker::PhantomData, ops::Deref, sync::Arc, time::Duration};
+use std::{
+    borrow::Borrow,
+    hash::Hash,
+    marker::PhantomData,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 use tokio_util::time::{delay_queue::Key, DelayQueue};
 use tracing::debug;
@@ -43,7 +53,7 @@ where
 
 async fn decay_task<K, V>(
     map: Arc<DashMap<K, Entry<V>>>,
-    expiration: Duration,
+    mut expiration: Duration,
     mut rx: UnboundedReceiver<TimerUpdate<K, V>>,
 ) where
     K: Eq + Hash + Clone + Send + Sync + 'static,
@@ -81,6 +91,10 @@ async fn decay_task<K, V>(
                             map.remove(expired.get_ref());
                         }
                     }
+                    TimerUpdate::Expiration(new) => {
+                        debug!("Changing expiration of ratelimiter decay queue to {:?}", new);
+                        expiration = new;
+                    }
                 }
             },
             else => {
@@ -93,15 +107,25 @@ async fn decay_task<K, V>(
 }
 
 enum TimerUpdate<K, V> {
-    Add { key: K, value: V },
-    Refresh { key: Key },
+    Add {
+        key: K,
+        value: V,
+    },
+    Refresh {
+        key: Key,
+    },
     RemoveLru,
+    /// Expiration of entries that are added or refreshed from now on.
+    Expiration(Duration),
 }
 
+/// Stored maximum size of an unbounded cache.
+const UNBOUNDED: usize = usize::MAX;
+
 pub struct ExpiringLru<K, V> {
     inner: Arc<DashMap<K, Entry<V>>>,
     decay_tx: UnboundedSender<TimerUpdate<K, V>>,
-    max_size: Option<usize>,
+    max_size: AtomicUsize,
 }
 
 impl<K, V> ExpiringLru<K, V>
@@ -116,7 +140,7 @@ where
         let this = Self {
             inner: inner.clone(),
             decay_tx,
-            max_size,
+            max_size: AtomicUsize::new(max_size.unwrap_or(UNBOUNDED)),
         };
 
         tokio::spawn(decay_task(inner, expiration, decay_rx));
@@ -125,9 +149,9 @@ where
     }
 
     pub fn insert(&self, key: K, value: V) {
-        match self.max_size {
-            Some(max_size) if max_size == 0 => return,
-            Some(max_size) if self.len() >= max_size => {
+        match self.max_size.load(Ordering::Relaxed) {
+            0 => return,
+            max_size if self.len() >= max_size => {
                 self.remove_lru();
             }
             _ => {}
@@ -149,6 +173,45 @@ where
         Some(EntryRef(entry))
     }
 
+    /// Find the first value matching the predicate without refreshing its
+    /// expiration.
+    pub fn find<F>(&self, mut predicate: F) -> Option<V>
+    where
+        V: Clone,
+        F: FnMut(&K, &V) -> bool,
+    {
+        self.inner
+            .iter()
+            .find(|entry| predicate(entry.key(), &entry.value().inner))
+            .map(|entry| entry.value().inner.clone())
+    }
+
+    /// Clone all values without refreshing their expiration.
+    pub fn values(&self) -> Vec<V>
+    where
+        V: Clone,
+    {
+        self.inner
+            .iter()
+            .map(|entry| entry.value().inner.clone())
+            .collect()
+    }
+
+    /// Change the expiration and maximum size, evicting the least recently
+    /// used entries if there are too many.
+    ///
+    /// Entries keep their current expiration until they are used again.
+    pub fn resize(&self, expiration: Duration, max_size: Option<usize>) {
+        let max_size = max_size.unwrap_or(UNBOUNDED);
+
+        self.max_size.store(max_size, Ordering::Relaxed);
+        _ = self.decay_tx.send(TimerUpdate::Expiration(expiration));
+
+        for _ in max_size..self.len() {
+            self.remove_lru();
+        }
+    }
+
     fn remove_lru(&self) {
         _ = self.decay_tx.send(TimerUpdate::RemoveLru);
     }
@@ -243,4 +306,28 @@ mod tests {
         assert!(lru.get(&2).is_none());
         assert!(lru.get(&4).is_some());
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_resize() {
+        let lru = Builder::new().expiration(Duration::from_secs(1)).build();
+
+        for i in 0..3 {
+            lru.insert(i, 0);
+            sleep(Duration::from_millis(50)).await;
+        }
+
+        lru.resize(Duration::from_secs(10), Some(1));
+        tokio::task::yield_now().await;
+
+        assert_eq!(lru.len(), 1);
+        assert!(lru.get(&2).is_some());
+
+        lru.insert(3, 0);
+        tokio::task::yield_now().await;
+        assert_eq!(lru.len(), 1);
+
+        // Added after the resize, so it expires after 10 seconds
+        sleep(Duration::from_secs(5)).await;
+        assert!(lru.get(&3).is_some());
+    }
 }
@@ -0,0 +1,168 @@
+//! Synthetic 429s for requests that would wait for their ratelimit, for
+//! clients that handle 429s themselves and prefer backing off over having the
+//! proxy hold their connection open.
+//!
+//! Enabled for all requests with `FAIL_FAST_RATELIMITS`, or per request with
+//! `X-RateLimit-Precision: fail-fast`.
+
+use crate::tenant::Tenant;
+use http::{header::RETRY_AFTER, HeaderMap, Response, StatusCode};
+use hyper::Body;
+use std::time::Duration;
+use twilight_http_ratelimiting::Path;
+
+/// Header requesting a synthetic 429 instead of waiting for the ratelimit.
+pub const PRECISION_HEADER: &str = "x-ratelimit-precision";
+
+/// Header added to synthetic 429s.
+pub const FAIL_FAST_HEADER: &str = "x-proxy-fail-fast";
+
+/// Value of [`PRECISION_HEADER`] enabling failing fast.
+const FAIL_FAST: &str = "fail-fast";
+
+/// Assumed wait if the tenant is globally locked, which Discord usually lifts
+/// within a second.
+const GLOBAL_WAIT: Duration = Duration::from_secs(1);
+
+/// Whether a request fails fast, removing the header requesting it.
+///
+/// Other values of the header, like the `millisecond` older clients send, are
+/// left as they are.
+pub fn take(headers: &mut HeaderMap, default: bool) -> bool {
+    let requested = headers
+        .get(PRECISION_HEADER)
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(FAIL_FAST.as_bytes()));
+
+    if requested {
+        headers.remove(PRECISION_HEADER);
+    }
+
+    default || requested
+}
+
+/// Time until a request to a path behind `queued` others would be forwarded,
+/// ignoring the latency of the requests before it.
+pub async fn estimated_wait(tenant: &Tenant, path: &Path, queued: usize) -> Duration {
+    match tenant.ratelimiter.bucket(path).await {
+        // Buckets which have not received headers yet don't limit requests
+        Ok(Some(bucket)) if bucket.limit() != u64::MAX => estimate_wait(
+            queued as u64,
+            bucket.remaining(),
+            bucket.limit(),
+            bucket.time_remaining().unwrap_or_default(),
+            bucket.reset_after(),
+        ),
+        _ => Duration::ZERO,
+    }
+}
+
+/// Time until a request behind `queued` others is forwarded.
+fn estimate_wait(
+    queued: u64,
+    remaining: u64,
+    limit: u64,
+    time_remaining: Duration,
+    reset_after: Duration,
+) -> Duration {
+    if queued < remaining || limit == 0 {
+        return Duration::ZERO;
+    }
+
+    // Requests that don't fit into the current window wait for the reset and
+    // as many further windows as they fill up
+    let windows = (queued - remaining) / limit;
+
+    time_remaining + reset_after * windows as u32
+}
+
+/// A synthetic 429 if a request to a path would have to wait for its
+/// ratelimit.
+pub async fn check(tenant: &Tenant, path: &Path) -> Option<Response<Body>> {
+    let wait = estimated_wait(tenant, path, tenant.usage.queued(path)).await;
+
+    let global = wait.is_zero()
+        && tenant
+            .ratelimiter
+            .is_globally_locked()
+            .await
+            .unwrap_or(false);
+
+    let retry_after = match (wait, global) {
+        (_, true) => GLOBAL_WAIT,
+        (wait, false) if !wait.is_zero() => wait,
+        _ => return None,
+    };
+
+    Some(response(retry_after, global))
+}
+
+/// A 429 shaped like Discord's, so clients handle it like one.
+fn response(retry_after: Duration, global: bool) -> Response<Body> {
+    let seconds = retry_after.as_secs_f64();
+
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header("content-type", "application/json")
+        // Rounded up so clients don't retry before the reset
+        .header(RETRY_AFTER, seconds.ceil() as u64)
+        .header("x-ratelimit-global", global.to_string())
+        .header("x-ratelimit-remaining", "0")
+        .header("x-ratelimit-reset-after", format!("{:.3}", seconds))
+        .header("x-ratelimit-scope", if global { "global" } else { "user" })
+        .header(FAIL_FAST_HEADER, "true")
+        .body(Body::from(format!(
+            r#"{{"message":"You are being rate limited.","retry_after":{:.3},"global":{}}}"#,
+            seconds, global
+        )))
+        .expect("response is valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{estimate_wait, response, take, PRECISION_HEADER};
+    use http::{header::RETRY_AFTER, HeaderMap, HeaderValue, StatusCode};
+    use std::time::Duration;
+
+    #[test]
+    fn test_estimate_wait() {
+        let reset_after = Duration::from_secs(5);
+        let time_remaining = Duration::from_secs(2);
+
+        assert_eq!(
+            estimate_wait(2, 3, 5, time_remaining, reset_after),
+            Duration::ZERO
+        );
+        assert_eq!(
+            estimate_wait(3, 3, 5, time_remaining, reset_after),
+            time_remaining
+        );
+        assert_eq!(
+            estimate_wait(13, 3, 5, time_remaining, reset_after),
+            Duration::from_secs(12)
+        );
+    }
+
+    #[test]
+    fn test_take() {
+        let mut headers = HeaderMap::new();
+        assert!(!take(&mut headers, false));
+        assert!(take(&mut headers, true));
+
+        headers.insert(PRECISION_HEADER, HeaderValue::from_static("millisecond"));
+        assert!(!take(&mut headers, false));
+        assert!(headers.contains_key(PRECISION_HEADER));
+
+        headers.insert(PRECISION_HEADER, HeaderValue::from_static("Fail-Fast"));
+        assert!(take(&mut headers, false));
+        assert!(!headers.contains_key(PRECISION_HEADER));
+    }
+
+    #[test]
+    fn test_response() {
+        let response = response(Duration::from_millis(1500), false);
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "2");
+        assert_eq!(response.headers()["x-ratelimit-reset-after"], "1.500");
+    }
+}
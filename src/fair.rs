@@ -0,0 +1,242 @@
+//! Weighted fair queueing across the tokens sharing the proxy, so a single
+//! chatty bot can't take all upstream connections from the others.
+//!
+//! A limited amount of requests of all tokens may be in flight to Discord at
+//! once. Once they are, further requests wait in a queue per token and free
+//! slots go to the token with the earliest virtual finish time, so backlogged
+//! tokens get shares in proportion to their weights. Slots are taken once the
+//! ratelimit ticket is granted, so requests waiting for an exhausted bucket
+//! don't hold any. Slots are freed as soon as Discord responds, so requests
+//! holding a ticket only wait briefly for one.
+
+use crate::{parse_env, tenant::parse_tenant_values};
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+    sync::Mutex,
+};
+use tokio::sync::oneshot::{self, Receiver, Sender};
+
+/// Virtual time a request of a token with weight 1 takes.
+const COST: u64 = 1_000_000;
+
+/// Configured via `FAIR_SHARE_SLOTS` and `TOKEN_WEIGHTS`.
+pub struct FairQueue {
+    /// Requests of all tokens that may be in flight at once.
+    slots: usize,
+    /// Weights of token hashes, which default to 1.
+    weights: HashMap<String, u64>,
+    queues: Mutex<Queues>,
+}
+
+#[derive(Default)]
+struct Queues {
+    in_flight: usize,
+    /// Virtual finish time of the last dispatched request.
+    virtual_time: u64,
+    /// Waiting requests of tokens by hash, with their virtual finish times.
+    waiting: HashMap<String, VecDeque<(u64, Sender<()>)>>,
+}
+
+impl Queues {
+    /// Hand a slot to the waiting request with the earliest finish time.
+    ///
+    /// Returns whether a request received it.
+    fn hand_over(&mut self) -> bool {
+        loop {
+            let hash = match self
+                .waiting
+                .iter()
+                .filter_map(|(hash, queue)| Some((queue.front()?.0, hash)))
+                .min()
+            {
+                Some((_, hash)) => hash.clone(),
+                None => return false,
+            };
+
+            let queue = self.waiting.get_mut(&hash).expect("token is waiting");
+            let (finish, sender) = queue.pop_front().expect("queue is not empty");
+
+            if queue.is_empty() {
+                self.waiting.remove(&hash);
+            }
+
+            self.virtual_time = finish;
+
+            // Requests that gave up while waiting are skipped
+            if sender.send(()).is_ok() {
+                return true;
+            }
+        }
+    }
+}
+
+impl FairQueue {
+    /// Returns `None` if fair queueing is not enabled.
+    pub fn from_env() -> Option<Self> {
+        let slots = parse_env("FAIR_SHARE_SLOTS").filter(|slots| *slots > 0)?;
+        let weights = env::var("TOKEN_WEIGHTS")
+            .map(|value| parse_tenant_values(&value, "token weight"))
+            .unwrap_or_default();
+
+        Some(Self::new(slots, weights))
+    }
+
+    fn new(slots: usize, weights: HashMap<String, u64>) -> Self {
+        Self {
+            slots,
+            weights,
+            queues: Mutex::new(Queues::default()),
+        }
+    }
+
+    fn weight(&self, hash: &str) -> u64 {
+        self.weights.get(hash).copied().unwrap_or(1).max(1)
+    }
+
+    /// Wait for a slot for a request of a token, which is freed when the
+    /// returned guard is dropped.
+    pub async fn acquire(&self, hash: &str) -> Slot<'_> {
+        let receiver = {
+            let mut queues = self.queues.lock().expect("fair queue poisoned");
+
+            if queues.in_flight < self.slots && queues.waiting.is_empty() {
+                queues.in_flight += 1;
+
+                return Slot(self);
+            }
+
+            let virtual_time = queues.virtual_time;
+            let queue = queues.waiting.entry(hash.to_string()).or_default();
+
+            // Tokens that were idle start at the current virtual time, so
+            // they can't save up a share
+            let start = queue
+                .back()
+                .map_or(virtual_time, |(finish, _)| (*finish).max(virtual_time));
+
+            let (sender, receiver) = oneshot::channel();
+            queue.push_back((start + COST / self.weight(hash), sender));
+
+            receiver
+        };
+
+        let mut waiting = Waiting {
+            queue: self,
+            receiver: Some(receiver),
+        };
+
+        // The sender is only dropped together with the queue
+        _ = waiting.receiver.as_mut().expect("not received yet").await;
+        waiting.receiver = None;
+
+        Slot(self)
+    }
+
+    fn release(&self) {
+        let mut queues = self.queues.lock().expect("fair queue poisoned");
+
+        if !queues.hand_over() {
+            queues.in_flight -= 1;
+        }
+    }
+}
+
+/// A request's slot, held until Discord responded.
+pub struct Slot<'a>(&'a FairQueue);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// Hands the slot on if a request is dropped right after receiving it.
+struct Waiting<'a> {
+    queue: &'a FairQueue,
+    receiver: Option<Receiver<()>>,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if let Some(mut receiver) = self.receiver.take() {
+            receiver.close();
+
+            if receiver.try_recv().is_ok() {
+                self.queue.release();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FairQueue;
+    use std::{
+        collections::HashMap,
+        iter,
+        sync::{Arc, Mutex},
+    };
+    use tokio::{
+        task,
+        time::{timeout, Duration},
+    };
+
+    #[tokio::test]
+    async fn test_weighted_order() {
+        let queue = Arc::new(FairQueue::new(
+            1,
+            HashMap::from([("light".to_string(), 1), ("heavy".to_string(), 3)]),
+        ));
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let first = queue.acquire("light").await;
+
+        // The chatty token queues all its requests before the other one
+        let requests = iter::repeat_n("heavy", 6).chain(iter::repeat_n("light", 2));
+        let handles = requests
+            .map(|hash| {
+                let (queue, order) = (Arc::clone(&queue), Arc::clone(&order));
+
+                tokio::spawn(async move {
+                    let _slot = queue.acquire(hash).await;
+                    order.lock().unwrap().push(hash);
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for _ in 0..10 {
+            task::yield_now().await;
+        }
+
+        drop(first);
+
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        assert_eq!(
+            *order.lock().unwrap(),
+            ["heavy", "heavy", "heavy", "light", "heavy", "heavy", "heavy", "light"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_abandoned() {
+        let queue = FairQueue::new(1, HashMap::new());
+
+        let first = queue.acquire("a").await;
+
+        // A request that gives up while waiting doesn't keep its slot
+        assert!(timeout(Duration::from_millis(10), queue.acquire("b"))
+            .await
+            .is_err());
+
+        drop(first);
+        drop(queue.acquire("c").await);
+
+        let queues = queue.queues.lock().unwrap();
+        assert_eq!(queues.in_flight, 0);
+        assert!(queues.waiting.is_empty());
+    }
+}
@@ -0,0 +1,246 @@
+//! The address of the client that sent a request, taking reverse proxies in
+//! front of this proxy into account.
+
+use http::{header::FORWARDED, HeaderMap};
+use std::{env, net::IpAddr};
+use tracing::warn;
+
+/// Header set by most reverse proxies, listing the client and all proxies
+/// but the last one.
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Address of the client that sent a request, stored in its extensions.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientAddr(pub IpAddr);
+
+/// A range of addresses, such as `10.0.0.0/8`.
+#[derive(Debug, PartialEq)]
+struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    fn parse(value: &str) -> Option<Self> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse().ok()?)),
+            None => (value.parse::<IpAddr>().ok()?, None),
+        };
+
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+
+        (prefix <= max).then_some(Self { addr, prefix })
+    }
+
+    fn contains(&self, addr: IpAddr) -> bool {
+        let (network, addr, bits) = match (self.addr, canonical(addr)) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => (
+                u128::from(u32::from(network)),
+                u128::from(u32::from(addr)),
+                32,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(addr)) => (u128::from(network), u128::from(addr), 128),
+            _ => return false,
+        };
+
+        let shift = bits - u32::from(self.prefix);
+
+        shift >= 128 || network >> shift == addr >> shift
+    }
+}
+
+/// Reverse proxies whose forwarding headers are trusted.
+pub struct TrustedProxies {
+    networks: Vec<Network>,
+}
+
+impl TrustedProxies {
+    /// Load the networks from `TRUSTED_PROXIES`.
+    pub fn from_env() -> Self {
+        let networks = env::var("TRUSTED_PROXIES")
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|entry| !entry.is_empty())
+                    .filter_map(|entry| {
+                        let network = Network::parse(entry);
+
+                        if network.is_none() {
+                            warn!("Ignoring invalid trusted proxy {:?}", entry);
+                        }
+
+                        network
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self { networks }
+    }
+
+    fn is_trusted(&self, addr: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(addr))
+    }
+
+    /// Determine the client's address from the address of the connection and
+    /// the forwarding headers.
+    ///
+    /// Hops are followed from the right for as long as they are trusted, so
+    /// clients can't spoof their address by sending the headers themselves.
+    /// `Forwarded` takes precedence over `X-Forwarded-For`.
+    pub fn client_addr(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let peer = canonical(peer);
+
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+
+        let hops = if headers.contains_key(FORWARDED) {
+            forwarded_hops(headers)
+        } else {
+            header_values(headers, X_FORWARDED_FOR)
+                .map(ToString::to_string)
+                .collect()
+        };
+
+        let mut client = peer;
+
+        for hop in hops.iter().rev() {
+            match parse_hop(hop) {
+                Some(addr) => {
+                    client = addr;
+
+                    if !self.is_trusted(addr) {
+                        break;
+                    }
+                }
+                // Obfuscated or unknown hops end the trusted chain
+                None => break,
+            }
+        }
+
+        client
+    }
+}
+
+/// Comma-separated values of all headers with a name.
+fn header_values<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+}
+
+/// The `for` parameters of the `Forwarded` header, as specified in RFC 7239.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<String> {
+    header_values(headers, FORWARDED.as_str())
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                .map(|(_, value)| value.trim().trim_matches('"').to_string())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// Parse a hop, which may contain a port and brackets around IPv6 addresses.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    if let Ok(addr) = hop.parse::<IpAddr>() {
+        return Some(canonical(addr));
+    }
+
+    let host = match hop.strip_prefix('[') {
+        Some(rest) => rest.split_once(']')?.0,
+        None => hop.rsplit_once(':')?.0,
+    };
+
+    host.parse().ok().map(canonical)
+}
+
+/// Treat IPv4-mapped IPv6 addresses, as reported by dual-stack sockets, as
+/// IPv4 addresses.
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        IpAddr::V4(_) => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Network, TrustedProxies};
+    use http::{HeaderMap, HeaderValue};
+    use std::net::IpAddr;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn proxies(networks: &[&str]) -> TrustedProxies {
+        TrustedProxies {
+            networks: networks
+                .iter()
+                .map(|network| Network::parse(network).unwrap())
+                .collect(),
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    #[test]
+    fn test_network() {
+        let network = Network::parse("10.0.0.0/8").unwrap();
+        assert!(network.contains(ip("10.1.2.3")));
+        assert!(network.contains(ip("::ffff:10.1.2.3")));
+        assert!(!network.contains(ip("11.0.0.1")));
+
+        assert!(Network::parse("0.0.0.0/0").unwrap().contains(ip("1.2.3.4")));
+        assert!(Network::parse("fd00::/8").unwrap().contains(ip("fd12::1")));
+        assert!(Network::parse("127.0.0.1")
+            .unwrap()
+            .contains(ip("127.0.0.1")));
+        assert!(Network::parse("10.0.0.0/33").is_none());
+        assert!(Network::parse("localhost").is_none());
+    }
+
+    #[test]
+    fn test_client_addr() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let balancer = ip("10.0.0.1");
+
+        // Headers of untrusted peers are ignored
+        let spoofed = headers(&[("x-forwarded-for", "1.1.1.1")]);
+        assert_eq!(proxies.client_addr(ip("2.2.2.2"), &spoofed), ip("2.2.2.2"));
+
+        let chain = headers(&[("x-forwarded-for", "1.1.1.1, 3.3.3.3, 10.0.0.2")]);
+        assert_eq!(proxies.client_addr(balancer, &chain), ip("3.3.3.3"));
+
+        let forwarded = headers(&[
+            (
+                "forwarded",
+                r#"for="[2001:db8::17]:4711";proto=https, for=10.0.0.2"#,
+            ),
+            ("x-forwarded-for", "1.1.1.1"),
+        ]);
+        assert_eq!(
+            proxies.client_addr(balancer, &forwarded),
+            ip("2001:db8::17")
+        );
+
+        let obfuscated = headers(&[("forwarded", "for=_hidden, for=10.0.0.2")]);
+        assert_eq!(proxies.client_addr(balancer, &obfuscated), ip("10.0.0.2"));
+
+        assert_eq!(proxies.client_addr(balancer, &HeaderMap::new()), balancer);
+    }
+}
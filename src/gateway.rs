@@ -0,0 +1,134 @@
+//! Rewriting of the gateway URL Discord returns, so clients connect to a
+//! gateway proxy instead.
+
+use http::{
+    header::{CONTENT_ENCODING, CONTENT_LENGTH},
+    Uri,
+};
+use hyper::{Body, Response};
+use serde_json::Value;
+use std::env;
+use tracing::{debug, warn};
+use twilight_http_ratelimiting::Path;
+
+/// URL of the gateway returned to clients instead of Discord's.
+pub struct GatewayUrl {
+    url: String,
+}
+
+impl GatewayUrl {
+    /// Load the URL from `GATEWAY_URL`.
+    ///
+    /// Returns `None` if rewriting is not enabled or the URL is invalid.
+    pub fn from_env() -> Option<Self> {
+        let url = env::var("GATEWAY_URL").ok()?;
+
+        match url.parse::<Uri>() {
+            Ok(uri) if matches!(uri.scheme_str(), Some("ws" | "wss")) => Some(Self { url }),
+            _ => {
+                warn!(
+                    "GATEWAY_URL {:?} is not a ws:// or wss:// URL, ignoring",
+                    url
+                );
+
+                None
+            }
+        }
+    }
+
+    /// Replace the `url` field of successful responses to `/gateway` and
+    /// `/gateway/bot`.
+    ///
+    /// Compressed bodies and bodies that aren't a JSON object with a `url`
+    /// are forwarded unchanged.
+    pub async fn rewrite(
+        &self,
+        path: &Path,
+        response: Response<Body>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        if !matches!(path, Path::Gateway | Path::GatewayBot)
+            || !response.status().is_success()
+            || response.headers().contains_key(CONTENT_ENCODING)
+        {
+            return Ok(response);
+        }
+
+        let (mut parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+
+        let rewritten = match serde_json::from_slice::<Value>(&body) {
+            Ok(Value::Object(mut object)) if object.get("url").is_some_and(Value::is_string) => {
+                object.insert("url".to_string(), Value::String(self.url.clone()));
+
+                serde_json::to_vec(&object).ok()
+            }
+            _ => None,
+        };
+
+        let body = match rewritten {
+            Some(rewritten) => {
+                debug!("Rewrote gateway URL to {}", self.url);
+                // hyper sets the length of the new body
+                parts.headers.remove(CONTENT_LENGTH);
+
+                Body::from(rewritten)
+            }
+            None => Body::from(body),
+        };
+
+        Ok(Response::from_parts(parts, body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GatewayUrl;
+    use hyper::{Body, Response};
+    use serde_json::{json, Value};
+    use twilight_http_ratelimiting::Path;
+
+    async fn rewrite(path: Path, response: Response<Body>) -> Value {
+        let gateway = GatewayUrl {
+            url: "ws://gateway-proxy:7878".to_string(),
+        };
+        let response = gateway.rewrite(&path, response).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_rewrite() {
+        let body = json!({
+            "url": "wss://gateway.discord.gg",
+            "shards": 1,
+            "session_start_limit": {"total": 1000, "remaining": 999}
+        });
+
+        let rewritten = rewrite(
+            Path::GatewayBot,
+            Response::new(Body::from(body.to_string())),
+        )
+        .await;
+        assert_eq!(rewritten["url"], "ws://gateway-proxy:7878");
+        assert_eq!(
+            rewritten["session_start_limit"],
+            body["session_start_limit"]
+        );
+
+        // Other paths and errors are left alone
+        let unchanged = rewrite(
+            Path::ChannelsIdMessages(1),
+            Response::new(Body::from(body.to_string())),
+        )
+        .await;
+        assert_eq!(unchanged, body);
+
+        let error = Response::builder()
+            .status(401)
+            .body(Body::from(r#"{"message":"401: Unauthorized","code":0}"#))
+            .unwrap();
+        let unchanged = rewrite(Path::Gateway, error).await;
+        assert_eq!(unchanged["code"], 0);
+    }
+}
@@ -0,0 +1,222 @@
+//! Per-token cap on the requests sent to Discord per second, to stay below the
+//! global ratelimit instead of running into it.
+//!
+//! Discord allows 50 requests per second by default and raises the limit for
+//! large bots on request, so the limit is configured per token. The
+//! ratelimiters only track per-route buckets and react to global 429s, so this
+//! is a separate limiter in front of them.
+
+use crate::{parse_env, tenant::parse_tenant_values};
+use std::{collections::HashMap, env, mem, sync::Mutex};
+use tokio::time::{sleep_until, Duration, Instant};
+use tracing::debug;
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Configured via `GLOBAL_RATELIMIT_PER_SECOND` for all tokens and
+/// `GLOBAL_RATELIMITS` per token hash.
+pub struct GlobalLimits {
+    default: Option<u64>,
+    tenants: HashMap<String, u64>,
+}
+
+impl GlobalLimits {
+    pub fn from_env() -> Self {
+        let tenants = env::var("GLOBAL_RATELIMITS")
+            .map(|value| parse_tenant_values(&value, "global ratelimit"))
+            .unwrap_or_default();
+
+        Self {
+            default: parse_env("GLOBAL_RATELIMIT_PER_SECOND"),
+            tenants,
+        }
+    }
+
+    /// Requests per second a tenant may send, if capped.
+    pub fn limit(&self, hash: &str) -> Option<u64> {
+        self.tenants
+            .get(hash)
+            .copied()
+            .or(self.default)
+            .filter(|limit| *limit > 0)
+    }
+}
+
+/// Requests of a token reserved in consecutive one-second windows.
+pub struct GlobalWindow {
+    /// Pair of the start of the first window and the requests reserved since.
+    reserved: Mutex<(Instant, u64)>,
+}
+
+impl Default for GlobalWindow {
+    fn default() -> Self {
+        Self {
+            reserved: Mutex::new((Instant::now(), 0)),
+        }
+    }
+}
+
+/// A request's slot in a window, given back if the request is dropped while
+/// waiting for the window to start.
+struct Reservation<'a> {
+    window: &'a GlobalWindow,
+    /// Start of the first window when the slot was reserved.
+    start: Instant,
+    /// Index of the window the slot is in.
+    index: u64,
+    limit: u64,
+    /// When the slot's window starts.
+    at: Instant,
+}
+
+impl Reservation<'_> {
+    /// Keep the slot, as the request is sent.
+    fn keep(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.window.release(self);
+    }
+}
+
+impl GlobalWindow {
+    /// Reserve a slot for a request and wait until its window starts.
+    ///
+    /// Must be called right before the request is sent.
+    pub async fn wait(&self, limit: u64) {
+        let reservation = self.reserve(limit, Instant::now());
+
+        if reservation.at > Instant::now() {
+            debug!("Delaying request to stay below the global ratelimit");
+            sleep_until(reservation.at).await;
+        }
+
+        reservation.keep();
+    }
+
+    /// Reserve the start of the first window with a free slot.
+    ///
+    /// Unlike a ceiling spreading requests evenly, a full window is available
+    /// for bursts, like Discord's global ratelimit.
+    fn reserve(&self, limit: u64, now: Instant) -> Reservation<'_> {
+        let mut reserved = self.reserved.lock().expect("global window poisoned");
+        let (start, count) = &mut *reserved;
+
+        // Nothing reserved yet, or all reserved windows have passed
+        if *count == 0 || now >= *start + WINDOW * (*count / limit + 1) as u32 {
+            *start = now;
+            *count = 0;
+        }
+
+        let index = *count / limit;
+        *count += 1;
+
+        Reservation {
+            window: self,
+            start: *start,
+            index,
+            limit,
+            at: (*start + WINDOW * index as u32).max(now),
+        }
+    }
+
+    /// Give back the slot of a request that wasn't sent.
+    ///
+    /// Only slots of the last reserved window can be reused, as earlier
+    /// windows are full.
+    fn release(&self, reservation: &Reservation<'_>) {
+        let mut reserved = self.reserved.lock().expect("global window poisoned");
+        let (start, count) = &mut *reserved;
+
+        if *start == reservation.start
+            && *count > 0
+            && (*count - 1) / reservation.limit == reservation.index
+        {
+            *count -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GlobalWindow;
+    use tokio::time::{timeout, Duration, Instant};
+
+    #[test]
+    fn test_reserve() {
+        let window = GlobalWindow::default();
+        let reserve = |now| {
+            let reservation = window.reserve(2, now);
+            let at = reservation.at;
+            reservation.keep();
+
+            at
+        };
+        let start = Instant::now();
+
+        // A full window is available at once
+        assert_eq!(reserve(start), start);
+        assert_eq!(reserve(start), start);
+        assert_eq!(reserve(start), start + Duration::from_secs(1));
+        assert_eq!(reserve(start), start + Duration::from_secs(1));
+        assert_eq!(reserve(start), start + Duration::from_secs(2));
+
+        // A window that started already is used until it is full
+        let during = start + Duration::from_millis(2500);
+        assert_eq!(reserve(during), during);
+        assert_eq!(reserve(during), start + Duration::from_secs(3));
+
+        // Idle windows are not saved up for bursts
+        let later = start + Duration::from_secs(10);
+        assert_eq!(reserve(later), later);
+        assert_eq!(reserve(later), later);
+        assert_eq!(reserve(later), later + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_release() {
+        let window = GlobalWindow::default();
+        let start = Instant::now();
+
+        let first = window.reserve(2, start);
+        let second = window.reserve(2, start);
+        let third = window.reserve(2, start);
+        assert_eq!(third.at, start + Duration::from_secs(1));
+
+        // Slots of a full window aren't reused
+        drop(first);
+        let fourth = window.reserve(2, start);
+        assert_eq!(fourth.at, start + Duration::from_secs(1));
+
+        // Slots of the last window are
+        drop(fourth);
+        let fifth = window.reserve(2, start);
+        assert_eq!(fifth.at, start + Duration::from_secs(1));
+
+        second.keep();
+        third.keep();
+        fifth.keep();
+        assert_eq!(window.reserved.lock().unwrap().1, 4);
+        assert_eq!(window.reserve(2, start).at, start + Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait() {
+        let window = GlobalWindow::default();
+
+        window.wait(1).await;
+
+        // A request dropped while waiting gives its slot back
+        assert!(timeout(Duration::from_millis(100), window.wait(1))
+            .await
+            .is_err());
+        assert_eq!(window.reserved.lock().unwrap().1, 1);
+
+        let start = Instant::now();
+        window.wait(1).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(900));
+    }
+}
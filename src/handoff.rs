@@ -0,0 +1,246 @@
+//! Handoff of bucket states to a peer replica on shutdown, so rolling
+//! restarts don't start without knowledge of the ratelimits.
+//!
+//! Buckets are identified by the token hash and a hash of their path, so the
+//! handoff contains no tokens. The receiving replica seeds a bucket with its
+//! handed off state when the tenant first requests it.
+
+use crate::{
+    admin,
+    prewarm::seed_bucket,
+    tenant::{hash_token, Tenant},
+    upstream::{Upstream, UpstreamError},
+};
+use http::{header::CONTENT_TYPE, Method, Request};
+use hyper::{client::HttpConnector, Body, Client};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, env, error::Error, sync::Mutex};
+use tokio::time::{timeout, Duration, Instant};
+use tracing::debug;
+use twilight_http_ratelimiting::Path;
+
+/// How long to wait for the peer to accept the handoff.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// State of a bucket as sent to the peer.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct HandedOff {
+    tenant: String,
+    path: String,
+    limit: u64,
+    remaining: u64,
+    reset_after_ms: u64,
+    /// Milliseconds until the current window resets.
+    time_remaining_ms: u64,
+}
+
+/// A handed off bucket waiting for its tenant's first request.
+struct Pending {
+    limit: u64,
+    remaining: u64,
+    reset_after_ms: u64,
+    resets_at: Instant,
+}
+
+/// Bucket states received from a peer and the peer to hand off to.
+pub struct Handoff {
+    pending: Mutex<HashMap<(String, String), Pending>>,
+    peer: Option<Peer>,
+}
+
+struct Peer {
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    upstream: Upstream,
+    /// The `ADMIN_TOKEN`, which the peer shares.
+    admin_token: Option<String>,
+}
+
+impl Handoff {
+    /// Load the URL of the peer from `HANDOFF_URL`.
+    pub fn from_env() -> Result<Self, UpstreamError> {
+        let peer = match env::var("HANDOFF_URL") {
+            Ok(url) => {
+                let connector = HttpsConnectorBuilder::new()
+                    .with_webpki_roots()
+                    .https_or_http()
+                    .enable_http1()
+                    .build();
+
+                Some(Peer {
+                    client: Client::builder().build(connector),
+                    upstream: Upstream::new(&url)?,
+                    admin_token: env::var("ADMIN_TOKEN").ok(),
+                })
+            }
+            Err(_) => None,
+        };
+
+        Ok(Self {
+            pending: Mutex::new(HashMap::new()),
+            peer,
+        })
+    }
+
+    /// Store bucket states received from a peer.
+    pub fn receive(&self, buckets: Vec<HandedOff>) {
+        let now = Instant::now();
+        let mut pending = self.pending.lock().expect("handoff poisoned");
+
+        // Buckets that reset in the meantime would only be seeded with their
+        // limit, which is not worth keeping them around for
+        pending.retain(|_, bucket| bucket.resets_at > now);
+
+        for bucket in buckets {
+            pending.insert(
+                (bucket.tenant, bucket.path),
+                Pending {
+                    limit: bucket.limit,
+                    remaining: bucket.remaining,
+                    reset_after_ms: bucket.reset_after_ms,
+                    resets_at: now + Duration::from_millis(bucket.time_remaining_ms),
+                },
+            );
+        }
+    }
+
+    /// Seed the bucket of a path with its handed off state, if there is one.
+    pub async fn seed(&self, tenant: &Tenant, path: &Path) {
+        let bucket = {
+            let mut pending = self.pending.lock().expect("handoff poisoned");
+
+            if pending.is_empty() {
+                return;
+            }
+
+            match pending.remove(&(tenant.usage.hash().to_string(), path_key(path))) {
+                Some(bucket) => bucket,
+                None => return,
+            }
+        };
+
+        // The ratelimiter uses the reset interval of a bucket's first window
+        // for all further ones, so it can't be shortened to the time
+        // remaining. Waiting for a full window instead is slower, but never
+        // exceeds the limit.
+        let remaining = if bucket.resets_at > Instant::now() {
+            bucket.remaining
+        } else {
+            bucket.limit
+        };
+
+        seed_bucket(tenant, path, bucket.limit, remaining, bucket.reset_after_ms).await;
+    }
+
+    /// Send the state of all known buckets to the peer, if one is configured.
+    pub async fn send(&self, tenants: &[Tenant]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let peer = match &self.peer {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+
+        let buckets = collect(tenants).await;
+        let body = serde_json::to_vec(&buckets)?;
+
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(peer.upstream.uri("", "/__proxy/handoff", None)?)
+            .header(CONTENT_TYPE, "application/json");
+
+        if let Some(admin_token) = &peer.admin_token {
+            request = request.header(admin::TOKEN_HEADER, admin_token);
+        }
+
+        let request = request.body(Body::from(body))?;
+
+        let response = timeout(TIMEOUT, peer.client.request(request)).await??;
+
+        if !response.status().is_success() {
+            return Err(format!("peer responded with {}", response.status()).into());
+        }
+
+        debug!("Handed off {} buckets", buckets.len());
+
+        Ok(())
+    }
+}
+
+/// States of the buckets of the tenants whose limit is known.
+async fn collect(tenants: &[Tenant]) -> Vec<HandedOff> {
+    let mut buckets = Vec::new();
+
+    for tenant in tenants {
+        for path in tenant.usage.paths() {
+            if let Ok(Some(bucket)) = tenant.ratelimiter.bucket(&path).await {
+                if bucket.limit() == u64::MAX {
+                    continue;
+                }
+
+                buckets.push(HandedOff {
+                    tenant: tenant.usage.hash().to_string(),
+                    path: path_key(&path),
+                    limit: bucket.limit(),
+                    remaining: bucket.remaining(),
+                    reset_after_ms: bucket.reset_after().as_millis() as u64,
+                    time_remaining_ms: bucket
+                        .time_remaining()
+                        .map_or(0, |remaining| remaining.as_millis() as u64),
+                });
+            }
+        }
+    }
+
+    buckets
+}
+
+/// Hash of a path, which may contain webhook tokens.
+fn path_key(path: &Path) -> String {
+    hash_token(&format!("{:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{collect, HandedOff, Handoff};
+    use crate::{prewarm::seed_bucket, tenant::Tenant};
+    use std::{collections::HashMap, sync::Mutex, time::Duration};
+    use twilight_http_ratelimiting::Path;
+
+    #[tokio::test]
+    async fn test_handoff() {
+        let path = Path::ChannelsIdMessages(1);
+
+        let old = Tenant::new("Bot abc");
+        seed_bucket(&old, &path, 5, 2, 5000).await;
+        old.usage.record(&path, 200, false);
+        // The bucket is updated in the background
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let buckets = collect(&[old]).await;
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(buckets[0].remaining, 2);
+        assert!(!buckets[0].path.contains("ChannelsIdMessages"));
+
+        let handoff = Handoff {
+            pending: Mutex::new(HashMap::new()),
+            peer: None,
+        };
+        handoff.receive(
+            serde_json::from_slice::<Vec<HandedOff>>(&serde_json::to_vec(&buckets).unwrap())
+                .unwrap(),
+        );
+
+        let new = Tenant::new("Bot abc");
+        handoff.seed(&new, &path).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let bucket = new.ratelimiter.bucket(&path).await.unwrap().unwrap();
+        assert_eq!(bucket.limit(), 5);
+        assert_eq!(bucket.remaining(), 2);
+        assert!(handoff.pending.lock().unwrap().is_empty());
+
+        // Other tenants are not seeded
+        let other = Tenant::new("Bot def");
+        handoff.seed(&other, &path).await;
+        assert!(other.ratelimiter.bucket(&path).await.unwrap().is_none());
+    }
+}
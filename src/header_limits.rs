@@ -0,0 +1,92 @@
+//! Limits of the headers of incoming requests, which are rejected with a
+//! `431` before anything else is done with them.
+//!
+//! The size is also enforced while reading, so clients can't force large
+//! allocations before the request is parsed.
+
+use crate::parse_env;
+use http::HeaderMap;
+use hyper::server::Builder;
+use std::convert::TryFrom;
+
+/// Maximum amount of headers if `MAX_HEADER_COUNT` is not set.
+const DEFAULT_MAX_COUNT: usize = 100;
+
+/// Maximum total size of the headers in bytes if `MAX_HEADER_SIZE` is not
+/// set.
+const DEFAULT_MAX_SIZE: usize = 32 * 1024;
+
+/// Smallest read buffer hyper supports for HTTP/1.
+const MIN_BUFFER_SIZE: usize = 8192;
+
+/// Configured via `MAX_HEADER_COUNT` and `MAX_HEADER_SIZE` in bytes.
+pub struct HeaderLimits {
+    max_count: usize,
+    max_size: usize,
+}
+
+impl HeaderLimits {
+    pub fn from_env() -> Self {
+        Self {
+            max_count: parse_env("MAX_HEADER_COUNT").unwrap_or(DEFAULT_MAX_COUNT),
+            max_size: parse_env("MAX_HEADER_SIZE").unwrap_or(DEFAULT_MAX_SIZE),
+        }
+    }
+
+    /// Limit how much of a request's head the server buffers.
+    ///
+    /// HTTP/1 buffers the request line as well and can't be limited to less
+    /// than 8 KiB, so smaller limits are only enforced by [`Self::admits`].
+    pub fn apply<I, E>(&self, builder: Builder<I, E>) -> Builder<I, E> {
+        builder
+            .http1_max_buf_size(self.max_size.max(MIN_BUFFER_SIZE))
+            .http2_max_header_list_size(u32::try_from(self.max_size).unwrap_or(u32::MAX))
+    }
+
+    /// Whether the headers of a request are within the limits.
+    pub fn admits(&self, headers: &HeaderMap) -> bool {
+        if headers.len() > self.max_count {
+            return false;
+        }
+
+        let size = headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum::<usize>();
+
+        size <= self.max_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HeaderLimits;
+    use http::{HeaderMap, HeaderName, HeaderValue};
+
+    #[test]
+    fn test_admits() {
+        let limits = HeaderLimits {
+            max_count: 3,
+            max_size: 32,
+        };
+        let mut headers = HeaderMap::new();
+
+        for i in 0..3 {
+            headers.insert(
+                HeaderName::from_bytes(format!("x-{}", i).as_bytes()).unwrap(),
+                HeaderValue::from_static("value"),
+            );
+        }
+        assert!(limits.admits(&headers));
+
+        headers.insert("x-3", HeaderValue::from_static("value"));
+        assert!(!limits.admits(&headers));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-large",
+            HeaderValue::from_static("a value of 27 bytes or more"),
+        );
+        assert!(!limits.admits(&headers));
+    }
+}
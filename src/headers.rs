@@ -0,0 +1,400 @@
+use http::{
+    header::{CONNECTION, CONTENT_LENGTH, TE, TRAILER, TRANSFER_ENCODING, UPGRADE},
+    HeaderMap, Method, Response, StatusCode,
+};
+use hyper::Body;
+use std::{env, fmt::Write};
+
+/// Header used by Discord to attach a reason to audit log entries.
+pub const AUDIT_LOG_REASON: &str = "x-audit-log-reason";
+
+/// Response headers always forwarded in allow-list mode, which clients need to
+/// decode responses and respect ratelimits. Names ending with `*` match all
+/// headers starting with the rest.
+const DEFAULT_ALLOWED: &[&str] = &[
+    "content-encoding",
+    "content-length",
+    "content-type",
+    "retry-after",
+    "x-proxy-*",
+    "x-ratelimit-*",
+];
+
+/// Whether a request asks to upgrade the connection to a WebSocket, which
+/// some libraries try when opening the gateway through an HTTP proxy.
+pub fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(UPGRADE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| {
+            protocol
+                .split('/')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .eq_ignore_ascii_case("websocket")
+        })
+}
+
+/// Remove hop-by-hop headers, which only apply to a single connection and must
+/// not be forwarded.
+///
+/// These are also forbidden in HTTP/2.
+/// https://datatracker.ietf.org/doc/html/rfc7540#section-8.1.2.2
+pub fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    // Headers listed in `Connection` are hop-by-hop as well
+    let listed = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<_>>();
+
+    for name in listed {
+        // Never let a client drop end-to-end headers Discord relies on
+        if name != AUDIT_LOG_REASON {
+            headers.remove(name.as_str());
+        }
+    }
+
+    headers.remove(CONNECTION);
+    headers.remove("keep-alive");
+    headers.remove("proxy-connection");
+    headers.remove(TE);
+    headers.remove(TRAILER);
+    headers.remove(TRANSFER_ENCODING);
+    headers.remove(UPGRADE);
+}
+
+/// Percent-encode an `X-Audit-Log-Reason` header containing raw UTF-8.
+///
+/// Discord expects the reason to be URL-encoded and rejects non-ASCII values.
+/// Values that are entirely ASCII are left untouched, as they may already be
+/// encoded.
+pub fn encode_audit_log_reason(headers: &mut HeaderMap) {
+    let value = match headers.get(AUDIT_LOG_REASON) {
+        Some(value) if !value.as_bytes().is_ascii() => value,
+        _ => return,
+    };
+
+    let encoded = value
+        .as_bytes()
+        .iter()
+        .fold(String::new(), |mut encoded, byte| {
+            if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+                encoded.push(char::from(*byte));
+            } else {
+                _ = write!(encoded, "%{:02X}", byte);
+            }
+
+            encoded
+        });
+
+    headers.insert(
+        AUDIT_LOG_REASON,
+        encoded.parse().expect("percent-encoded values are valid"),
+    );
+}
+
+/// Prepare a response received from Discord to be forwarded to the client.
+///
+/// The body is re-framed by hyper on the client connection, so framing
+/// headers of the upstream connection must not be forwarded. Responses to
+/// `HEAD` keep their `Content-Length`, as it describes the body a `GET` would
+/// have returned, while responses that can never have a body drop it so
+/// clients don't wait for bytes that will never arrive.
+pub fn prepare_response(method: &Method, response: &mut Response<Body>) {
+    remove_hop_by_hop(response.headers_mut());
+
+    let status = response.status();
+    let bodiless = status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED;
+
+    if bodiless {
+        response.headers_mut().remove(CONTENT_LENGTH);
+    }
+
+    if bodiless || method == Method::HEAD {
+        *response.body_mut() = Body::empty();
+    }
+}
+
+/// Configured via `RESPONSE_HEADER_ALLOWLIST`, additional headers forwarded
+/// on top of the defaults.
+pub struct ResponseHeaderFilter {
+    allowed: Vec<String>,
+}
+
+impl ResponseHeaderFilter {
+    /// Returns `None` if allow-list mode is disabled, which is the default.
+    pub fn from_env() -> Option<Self> {
+        let value = env::var("RESPONSE_HEADER_ALLOWLIST").ok()?;
+
+        Some(Self::new(&value))
+    }
+
+    fn new(value: &str) -> Self {
+        let allowed = DEFAULT_ALLOWED
+            .iter()
+            .map(|name| (*name).to_string())
+            .chain(
+                value
+                    .split(',')
+                    .map(|name| name.trim().to_ascii_lowercase())
+                    .filter(|name| !name.is_empty()),
+            )
+            .collect();
+
+        Self { allowed }
+    }
+
+    fn allows(&self, name: &str) -> bool {
+        self.allowed
+            .iter()
+            .any(|allowed| match allowed.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == allowed,
+            })
+    }
+
+    /// Remove all headers that are not allowed, such as `cf-ray` or
+    /// `set-cookie`.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        let denied = headers
+            .keys()
+            .filter(|name| !self.allows(name.as_str()))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for name in denied {
+            headers.remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        encode_audit_log_reason, is_websocket_upgrade, prepare_response, remove_hop_by_hop,
+        ResponseHeaderFilter, AUDIT_LOG_REASON,
+    };
+    use http::{
+        header::{CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING, UPGRADE},
+        HeaderMap, HeaderValue, Method, Request, StatusCode,
+    };
+    use hyper::{
+        body,
+        server::Server,
+        service::{make_service_fn, service_fn},
+        Body, Client,
+    };
+    use std::{convert::Infallible, net::SocketAddr, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        time::timeout,
+    };
+
+    #[test]
+    fn test_audit_log_reason_pass_through() {
+        let mut headers = HeaderMap::new();
+        let raw = HeaderValue::from_bytes("Spam über alles".as_bytes()).unwrap();
+        headers.insert(AUDIT_LOG_REASON, raw.clone());
+        headers.insert(CONNECTION, HeaderValue::from_static("x-audit-log-reason"));
+
+        remove_hop_by_hop(&mut headers);
+
+        assert_eq!(headers[AUDIT_LOG_REASON], raw);
+    }
+
+    #[test]
+    fn test_is_websocket_upgrade() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_websocket_upgrade(&headers));
+
+        headers.insert(UPGRADE, HeaderValue::from_static("h2c"));
+        assert!(!is_websocket_upgrade(&headers));
+
+        headers.insert(UPGRADE, HeaderValue::from_static("h2c, WebSocket/13"));
+        assert!(is_websocket_upgrade(&headers));
+    }
+
+    #[test]
+    fn test_encode_audit_log_reason() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUDIT_LOG_REASON,
+            HeaderValue::from_bytes("Spam über 100%".as_bytes()).unwrap(),
+        );
+
+        encode_audit_log_reason(&mut headers);
+
+        assert_eq!(headers[AUDIT_LOG_REASON], "Spam%20%C3%BCber%20100%25");
+
+        // ASCII values may already be encoded, so they are left untouched
+        let mut headers = HeaderMap::new();
+        headers.insert(AUDIT_LOG_REASON, HeaderValue::from_static("Spam%20100%25"));
+
+        encode_audit_log_reason(&mut headers);
+
+        assert_eq!(headers[AUDIT_LOG_REASON], "Spam%20100%25");
+    }
+
+    #[test]
+    fn test_response_header_filter() {
+        let mut headers = HeaderMap::new();
+
+        for name in [
+            "content-type",
+            "x-ratelimit-bucket",
+            "x-proxy-dry-run",
+            "cf-ray",
+            "set-cookie",
+            "via",
+            "x-envoy-upstream-service-time",
+        ] {
+            headers.insert(name, HeaderValue::from_static("1"));
+        }
+
+        ResponseHeaderFilter::new(" Via ,").apply(&mut headers);
+
+        let mut names = headers.keys().map(|name| name.as_str()).collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(
+            names,
+            [
+                "content-type",
+                "via",
+                "x-proxy-dry-run",
+                "x-ratelimit-bucket"
+            ]
+        );
+    }
+
+    /// Start an upstream that answers every request with a raw response, so
+    /// that it can send headers hyper would not send itself.
+    async fn mock_upstream(response: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    // Requests in these tests have no body and fit in a
+                    // single read
+                    while let Ok(read) = stream.read(&mut buf).await {
+                        if read == 0 || stream.write_all(response.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        address
+    }
+
+    /// Start a proxy forwarding all requests to the upstream.
+    fn proxy(upstream: SocketAddr) -> SocketAddr {
+        let client = Client::new();
+
+        let service = make_service_fn(move |_| {
+            let client = client.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |mut request: Request<Body>| {
+                    let client = client.clone();
+
+                    async move {
+                        let method = request.method().clone();
+                        *request.uri_mut() = format!("http://{}{}", upstream, request.uri())
+                            .parse()
+                            .unwrap();
+
+                        let mut response = client.request(request).await?;
+                        prepare_response(&method, &mut response);
+
+                        Ok::<_, hyper::Error>(response)
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        address
+    }
+
+    async fn send(response: &'static str, method: Method) -> http::Response<Vec<u8>> {
+        let proxy = proxy(mock_upstream(response).await);
+
+        let request = Request::builder()
+            .method(method)
+            .uri(format!("http://{}/api/v10/gateway", proxy))
+            .body(Body::empty())
+            .unwrap();
+
+        timeout(Duration::from_secs(5), async {
+            let response = Client::new().request(request).await.unwrap();
+            let (parts, body) = response.into_parts();
+
+            http::Response::from_parts(parts, body::to_bytes(body).await.unwrap().to_vec())
+        })
+        .await
+        .expect("client stalled waiting for the response")
+    }
+
+    #[tokio::test]
+    async fn test_no_content() {
+        let response = send(
+            "HTTP/1.1 204 No Content\r\nContent-Length: 10\r\nConnection: keep-alive\r\n\r\n",
+            Method::DELETE,
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(response.headers().get(CONTENT_LENGTH).is_none());
+        assert!(response.body().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_head() {
+        let response = send(
+            "HTTP/1.1 200 OK\r\nContent-Length: 42\r\n\r\n",
+            Method::HEAD,
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_LENGTH], "42");
+        assert!(response.body().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_chunked() {
+        let response = send(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: x-custom\r\nX-Custom: 1\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+            Method::GET,
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("x-custom").is_none());
+        assert_ne!(
+            response
+                .headers()
+                .get(TRANSFER_ENCODING)
+                .map(|value| value.as_bytes()),
+            Some(&b"chunked, chunked"[..])
+        );
+        assert_eq!(response.body(), b"hello");
+    }
+}
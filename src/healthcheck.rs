@@ -0,0 +1,74 @@
+//! The `healthcheck` subcommand, for container healthchecks in images without
+//! an HTTP client.
+
+use crate::admin::PREFIX;
+use http::{StatusCode, Uri};
+use hyper::Client;
+use std::{env, error::Error};
+use tokio::time::{timeout, Duration};
+
+/// Time the proxy has to respond before it is considered unhealthy.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// URL of an admin endpoint of the proxy at `target`.
+fn endpoint_url(target: &str, endpoint: &str) -> Result<Uri, Box<dyn Error>> {
+    Ok(format!("{}{}{}", target.trim_end_matches('/'), PREFIX, endpoint).parse()?)
+}
+
+/// Request the liveness of the proxy at `PROXY_URL`, defaulting to the local
+/// proxy listening on `PORT`, and fail unless it is alive, so the process
+/// exits with 1 if it is unhealthy.
+///
+/// Liveness only checks that the proxy responds, so a proxy that can't reach
+/// Discord or is locked down isn't restarted. With `--ready`, its readiness is
+/// checked instead.
+pub async fn run(ready: bool) -> Result<(), Box<dyn Error>> {
+    let endpoint = if ready { "ready" } else { "live" };
+
+    let target = env::var("PROXY_URL").unwrap_or_else(|_| {
+        format!(
+            "http://127.0.0.1:{}",
+            env::var("PORT").unwrap_or_else(|_| "80".into())
+        )
+    });
+
+    let response = timeout(TIMEOUT, Client::new().get(endpoint_url(&target, endpoint)?))
+        .await
+        .map_err(|_| format!("proxy did not respond within {:?}", TIMEOUT))??;
+
+    match response.status() {
+        StatusCode::OK if endpoint == "ready" => {
+            println!("ready");
+
+            Ok(())
+        }
+        StatusCode::OK => {
+            println!("healthy");
+
+            Ok(())
+        }
+        status if endpoint == "ready" => Err(format!("proxy is not ready: {}", status).into()),
+        status => Err(format!("proxy is not healthy: {}", status).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::endpoint_url;
+
+    #[test]
+    fn test_endpoint_url() {
+        assert_eq!(
+            endpoint_url("http://proxy:3000/", "live")
+                .unwrap()
+                .to_string(),
+            "http://proxy:3000/__proxy/live"
+        );
+        assert_eq!(
+            endpoint_url("http://127.0.0.1:80", "ready")
+                .unwrap()
+                .to_string(),
+            "http://127.0.0.1:80/__proxy/ready"
+        );
+    }
+}
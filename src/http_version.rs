@@ -0,0 +1,179 @@
+//! HTTP version used for each upstream, as the best one differs between e.g.
+//! Discord's API, its CDN and local mock servers.
+//!
+//! Every version has its own client, so the choice is made once on startup
+//! and requests to different upstreams never share connections.
+
+use crate::{
+    edges::{self, EdgeResolver, Edges},
+    upstream::Upstream,
+};
+use hyper::{client::HttpConnector, Body, Client};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use std::{collections::HashMap, env, sync::Arc};
+use tracing::warn;
+
+pub type UpstreamClient = Client<HttpsConnector<HttpConnector<EdgeResolver>>, Body>;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HttpVersion {
+    /// Only HTTP/1.1.
+    Http1,
+    /// Only HTTP/2, without TLS via prior knowledge.
+    Http2,
+    /// HTTP/2 if the upstream offers it via TLS, HTTP/1.1 otherwise.
+    Auto,
+}
+
+impl HttpVersion {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "http1" => Some(Self::Http1),
+            "http2" => Some(Self::Http2),
+            "auto" => Some(Self::Auto),
+            _ => None,
+        }
+    }
+}
+
+/// Configured via `UPSTREAM_HTTP_VERSIONS`, with `DISABLE_HTTP2` setting the
+/// version of upstreams that aren't listed to HTTP/1.1.
+#[derive(Debug)]
+pub struct HttpVersions {
+    default: HttpVersion,
+    /// Versions by lowercase host, optionally with a port.
+    hosts: HashMap<String, HttpVersion>,
+}
+
+impl HttpVersions {
+    pub fn from_env() -> Self {
+        let default = if env::var_os("DISABLE_HTTP2").is_some() {
+            HttpVersion::Http1
+        } else {
+            HttpVersion::Auto
+        };
+
+        Self::parse(
+            &env::var("UPSTREAM_HTTP_VERSIONS").unwrap_or_default(),
+            default,
+        )
+    }
+
+    /// Parse a comma-separated list of `host=version` entries.
+    fn parse(value: &str, default: HttpVersion) -> Self {
+        let mut hosts = HashMap::new();
+
+        for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let parsed = entry.split_once('=').and_then(|(host, version)| {
+                Some((host.trim(), HttpVersion::parse(version.trim())?))
+            });
+
+            match parsed {
+                Some((host, version)) if !host.is_empty() => {
+                    hosts.insert(host.to_ascii_lowercase(), version);
+                }
+                _ => warn!("Ignoring invalid UPSTREAM_HTTP_VERSIONS entry {:?}", entry),
+            }
+        }
+
+        Self { default, hosts }
+    }
+
+    /// The version of an upstream, matched by its host with the port first.
+    pub fn get(&self, upstream: &Upstream) -> HttpVersion {
+        let authority = upstream
+            .host()
+            .to_str()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        self.hosts
+            .get(&authority)
+            .or_else(|| self.hosts.get(&upstream.host_name().to_ascii_lowercase()))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Clients for every HTTP version, sharing the resolver of the API's edges.
+pub struct UpstreamClients {
+    http1: UpstreamClient,
+    http2: UpstreamClient,
+    auto: UpstreamClient,
+    versions: HttpVersions,
+}
+
+impl UpstreamClients {
+    pub fn new(upstream: &Upstream, edges: Option<Arc<Edges>>, versions: HttpVersions) -> Self {
+        let mut http_connector = HttpConnector::new_with_resolver(EdgeResolver::new(edges.clone()));
+        http_connector.enforce_http(false);
+
+        // Without a timeout, an unreachable address would block failing over
+        // to the next one
+        if edges.is_some() {
+            http_connector.set_connect_timeout(Some(edges::CONNECT_TIMEOUT));
+        }
+
+        let builder = || {
+            let builder = HttpsConnectorBuilder::new().with_webpki_roots();
+
+            // Plain HTTP is only allowed if explicitly configured, e.g. for
+            // local mock servers
+            if upstream.is_https() {
+                builder.https_only()
+            } else {
+                builder.https_or_http()
+            }
+        };
+
+        Self {
+            http1: Client::builder().build(
+                builder()
+                    .enable_http1()
+                    .wrap_connector(http_connector.clone()),
+            ),
+            http2: Client::builder().http2_only(true).build(
+                builder()
+                    .enable_http2()
+                    .wrap_connector(http_connector.clone()),
+            ),
+            auto: Client::builder().build(
+                builder()
+                    .enable_http1()
+                    .enable_http2()
+                    .wrap_connector(http_connector),
+            ),
+            versions,
+        }
+    }
+
+    /// The client to request an upstream with.
+    pub fn get(&self, upstream: &Upstream) -> &UpstreamClient {
+        match self.versions.get(upstream) {
+            HttpVersion::Http1 => &self.http1,
+            HttpVersion::Http2 => &self.http2,
+            HttpVersion::Auto => &self.auto,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HttpVersion, HttpVersions};
+    use crate::upstream::Upstream;
+
+    #[test]
+    fn test_parse() {
+        let versions = HttpVersions::parse(
+            "cdn.discordapp.com=http1, LOCALHOST:8080=http2,localhost=auto,invalid,a=http3",
+            HttpVersion::Http1,
+        );
+        assert_eq!(versions.hosts.len(), 3);
+
+        let version = |url: &str| versions.get(&Upstream::new(url).unwrap());
+        assert_eq!(version("https://cdn.discordapp.com"), HttpVersion::Http1);
+        assert_eq!(version("http://localhost:8080/api"), HttpVersion::Http2);
+        assert_eq!(version("http://localhost:3000"), HttpVersion::Auto);
+        assert_eq!(version("https://discord.com"), HttpVersion::Http1);
+    }
+}
@@ -0,0 +1,344 @@
+//! Local enforcement of Discord's payload limits.
+//!
+//! Requests Discord would reject for their size are rejected before they use
+//! up a ratelimit ticket.
+
+use crate::{parse_env, tenant::parse_tenant_values};
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    fmt::{Display, Formatter, Result as FmtResult},
+};
+use twilight_http_ratelimiting::{Method, Path};
+
+/// Default maximum size of a request body, matching Discord's upload limit
+/// for unboosted guilds.
+pub const DEFAULT_MAX_UPLOAD_SIZE: u64 = 25 * 1024 * 1024;
+
+/// Maximum length of a message's content.
+const MAX_CONTENT_LENGTH: usize = 2000;
+
+/// Maximum amount of embeds in a message.
+const MAX_EMBEDS: usize = 10;
+
+/// Maximum amount of fields in an embed.
+const MAX_EMBED_FIELDS: usize = 25;
+
+/// Maximum combined length of all text in a message's embeds.
+const MAX_EMBED_TOTAL_LENGTH: usize = 6000;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum LimitExceeded {
+    /// The message content is too long.
+    Content { length: usize },
+    /// An embed text field is too long.
+    EmbedField {
+        field: &'static str,
+        length: usize,
+        max: usize,
+    },
+    /// An embed has too many fields.
+    EmbedFields { count: usize },
+    /// The message has too many embeds.
+    Embeds { count: usize },
+    /// The combined text of all embeds is too long.
+    EmbedTotal { length: usize },
+    /// The request body is larger than the upload limit.
+    Upload { size: u64, max: u64 },
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Content { length } => {
+                f.write_str("content is ")?;
+                length.fmt(f)?;
+                f.write_str(" characters long, the maximum is ")?;
+
+                MAX_CONTENT_LENGTH.fmt(f)
+            }
+            Self::EmbedField { field, length, max } => {
+                f.write_str("embed ")?;
+                f.write_str(field)?;
+                f.write_str(" is ")?;
+                length.fmt(f)?;
+                f.write_str(" characters long, the maximum is ")?;
+
+                max.fmt(f)
+            }
+            Self::EmbedFields { count } => {
+                f.write_str("embed has ")?;
+                count.fmt(f)?;
+                f.write_str(" fields, the maximum is ")?;
+
+                MAX_EMBED_FIELDS.fmt(f)
+            }
+            Self::Embeds { count } => {
+                f.write_str("message has ")?;
+                count.fmt(f)?;
+                f.write_str(" embeds, the maximum is ")?;
+
+                MAX_EMBEDS.fmt(f)
+            }
+            Self::EmbedTotal { length } => {
+                f.write_str("embeds are ")?;
+                length.fmt(f)?;
+                f.write_str(" characters long in total, the maximum is ")?;
+
+                MAX_EMBED_TOTAL_LENGTH.fmt(f)
+            }
+            Self::Upload { size, max } => {
+                f.write_str("body is ")?;
+                size.fmt(f)?;
+                f.write_str(" bytes large, the maximum is ")?;
+
+                max.fmt(f)
+            }
+        }
+    }
+}
+
+impl Error for LimitExceeded {}
+
+/// Configured payload limits.
+pub struct PayloadLimits {
+    default_upload: u64,
+    /// Upload limits of tenants, e.g. bots only used in boosted guilds.
+    uploads: HashMap<String, u64>,
+}
+
+impl PayloadLimits {
+    pub fn from_env() -> Self {
+        let uploads = env::var("UPLOAD_LIMITS")
+            .map(|value| parse_tenant_values(&value, "upload limit"))
+            .unwrap_or_default();
+
+        Self {
+            default_upload: parse_env("MAX_UPLOAD_SIZE").unwrap_or(DEFAULT_MAX_UPLOAD_SIZE),
+            uploads,
+        }
+    }
+
+    /// The maximum body size of a tenant.
+    pub fn upload_limit(&self, hash: &str) -> u64 {
+        self.uploads
+            .get(hash)
+            .copied()
+            .unwrap_or(self.default_upload)
+    }
+
+    /// Check the size of a request body against the tenant's upload limit.
+    pub fn check_size(&self, hash: &str, size: u64) -> Result<(), LimitExceeded> {
+        let max = self.upload_limit(hash);
+
+        if size > max {
+            return Err(LimitExceeded::Upload { size, max });
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether a request to a path creates or edits a message.
+pub fn is_message_route(method: Method, path: &Path) -> bool {
+    matches!(
+        (method, path),
+        (Method::Post, Path::ChannelsIdMessages(_))
+            | (Method::Patch, Path::ChannelsIdMessagesId(..))
+            | (Method::Post, Path::WebhooksIdToken(..))
+            | (Method::Patch, Path::WebhooksIdTokenMessagesId(..))
+            | (Method::Post, Path::InteractionCallback(_))
+    )
+}
+
+/// Check a JSON message payload against Discord's message limits.
+///
+/// Interaction responses nest the message in `data`, which is checked as
+/// well. Bodies that aren't JSON objects are left for Discord to reject.
+pub fn check_message(body: &[u8]) -> Result<(), LimitExceeded> {
+    let object = match serde_json::from_slice::<Map<String, Value>>(body) {
+        Ok(object) => object,
+        Err(_) => return Ok(()),
+    };
+
+    check_object(&object)?;
+
+    if let Some(Value::Object(data)) = object.get("data") {
+        check_object(data)?;
+    }
+
+    Ok(())
+}
+
+fn check_object(object: &Map<String, Value>) -> Result<(), LimitExceeded> {
+    if let Some(Value::String(content)) = object.get("content") {
+        let length = content.chars().count();
+
+        if length > MAX_CONTENT_LENGTH {
+            return Err(LimitExceeded::Content { length });
+        }
+    }
+
+    let embeds = match object.get("embeds") {
+        Some(Value::Array(embeds)) => embeds,
+        _ => return Ok(()),
+    };
+
+    if embeds.len() > MAX_EMBEDS {
+        return Err(LimitExceeded::Embeds {
+            count: embeds.len(),
+        });
+    }
+
+    let mut total = 0;
+
+    for embed in embeds {
+        total += check_embed(embed)?;
+    }
+
+    if total > MAX_EMBED_TOTAL_LENGTH {
+        return Err(LimitExceeded::EmbedTotal { length: total });
+    }
+
+    Ok(())
+}
+
+/// Check an embed's limits, returning the length of its text.
+fn check_embed(embed: &Value) -> Result<usize, LimitExceeded> {
+    let mut total = 0;
+    let mut text = |field: &'static str, value: Option<&Value>, max: usize| {
+        let length = value
+            .and_then(Value::as_str)
+            .map_or(0, |value| value.chars().count());
+
+        if length > max {
+            return Err(LimitExceeded::EmbedField { field, length, max });
+        }
+
+        total += length;
+
+        Ok(())
+    };
+
+    text("title", embed.get("title"), 256)?;
+    text("description", embed.get("description"), 4096)?;
+    text(
+        "footer text",
+        embed.get("footer").and_then(|footer| footer.get("text")),
+        2048,
+    )?;
+    text(
+        "author name",
+        embed.get("author").and_then(|author| author.get("name")),
+        256,
+    )?;
+
+    if let Some(Value::Array(fields)) = embed.get("fields") {
+        if fields.len() > MAX_EMBED_FIELDS {
+            return Err(LimitExceeded::EmbedFields {
+                count: fields.len(),
+            });
+        }
+
+        for field in fields {
+            text("field name", field.get("name"), 256)?;
+            text("field value", field.get("value"), 1024)?;
+        }
+    }
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_message, is_message_route, LimitExceeded, PayloadLimits};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use twilight_http_ratelimiting::{Method, Path};
+
+    fn check(value: serde_json::Value) -> Result<(), LimitExceeded> {
+        check_message(&serde_json::to_vec(&value).unwrap())
+    }
+
+    #[test]
+    fn test_is_message_route() {
+        assert!(is_message_route(Method::Post, &Path::ChannelsIdMessages(1)));
+        assert!(is_message_route(
+            Method::Patch,
+            &Path::ChannelsIdMessagesId(Method::Patch, 1)
+        ));
+        assert!(!is_message_route(Method::Patch, &Path::ChannelsId(1)));
+        assert!(!is_message_route(Method::Get, &Path::ChannelsIdMessages(1)));
+    }
+
+    #[test]
+    fn test_upload_limit() {
+        let limits = PayloadLimits {
+            default_upload: 100,
+            uploads: HashMap::from([("abc".to_string(), 500)]),
+        };
+
+        assert_eq!(limits.check_size("def", 100), Ok(()));
+        assert_eq!(
+            limits.check_size("def", 101),
+            Err(LimitExceeded::Upload {
+                size: 101,
+                max: 100
+            })
+        );
+        assert_eq!(limits.check_size("abc", 500), Ok(()));
+    }
+
+    #[test]
+    fn test_content() {
+        assert_eq!(check(json!({ "content": "ü".repeat(2000) })), Ok(()));
+        assert_eq!(
+            check(json!({ "content": "a".repeat(2001) })),
+            Err(LimitExceeded::Content { length: 2001 })
+        );
+        assert_eq!(
+            check(json!({ "type": 4, "data": { "content": "a".repeat(2001) } })),
+            Err(LimitExceeded::Content { length: 2001 })
+        );
+    }
+
+    #[test]
+    fn test_embeds() {
+        let embed = json!({
+            "title": "a".repeat(256),
+            "description": "a".repeat(1000),
+            "fields": [{ "name": "a", "value": "a".repeat(243) }],
+        });
+
+        // Each embed is 1500 characters long
+        assert_eq!(check(json!({ "embeds": vec![embed.clone(); 4] })), Ok(()));
+        assert_eq!(
+            check(json!({ "embeds": vec![embed.clone(); 5] })),
+            Err(LimitExceeded::EmbedTotal { length: 7500 })
+        );
+        assert_eq!(
+            check(json!({ "embeds": vec![json!({}); 11] })),
+            Err(LimitExceeded::Embeds { count: 11 })
+        );
+        assert_eq!(
+            check(json!({ "embeds": [{ "title": "a".repeat(257) }] })),
+            Err(LimitExceeded::EmbedField {
+                field: "title",
+                length: 257,
+                max: 256
+            })
+        );
+        assert_eq!(
+            check(json!({ "embeds": [{ "fields": vec![json!({}); 26] }] })),
+            Err(LimitExceeded::EmbedFields { count: 26 })
+        );
+    }
+
+    #[test]
+    fn test_not_json() {
+        assert_eq!(check_message(b"not json"), Ok(()));
+        assert_eq!(check(json!(["content"])), Ok(()));
+    }
+}
@@ -0,0 +1,294 @@
+//! Lockdown after Cloudflare banned the proxy's IP, e.g. with error 1015.
+//!
+//! Further requests during a ban extend it, so all traffic is halted for a
+//! cool-down period once a ban page is detected.
+//!
+//! All of Discord's responses pass Cloudflare, so only responses served by
+//! Cloudflare that are an error 1015 page or a `429` without Discord's
+//! ratelimit headers count as bans.
+
+use crate::parse_env;
+use http::{
+    header::{CONTENT_TYPE, RETRY_AFTER, SERVER},
+    HeaderMap, StatusCode,
+};
+use hyper::{Body, Response};
+use serde::Serialize;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+use tracing::{error, info};
+
+/// Whether a response may be a ban page of Cloudflare, whose body needs to be
+/// checked.
+///
+/// Ban pages are `403`s or `429`s served by Cloudflare that aren't JSON, which
+/// the API always responds with.
+fn is_candidate(status: StatusCode, headers: &HeaderMap) -> bool {
+    if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+        return false;
+    }
+
+    let cloudflare = headers.contains_key("cf-ray")
+        || headers
+            .get(SERVER)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|server| server.eq_ignore_ascii_case("cloudflare"));
+    let json = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+
+    cloudflare && !json
+}
+
+/// Whether a candidate response is a ban page, either error 1015 or a `429`
+/// without Discord's ratelimit headers.
+fn is_ban(status: StatusCode, headers: &HeaderMap, body: &[u8]) -> bool {
+    let error_1015 = body.windows(4).any(|window| window == b"1015");
+    let ratelimit_headers = headers
+        .keys()
+        .any(|name| name.as_str().starts_with("x-ratelimit-"));
+
+    error_1015 || (status == StatusCode::TOO_MANY_REQUESTS && !ratelimit_headers)
+}
+
+struct Active {
+    until: Instant,
+    status: StatusCode,
+}
+
+/// State of the lockdown, served by the admin API.
+#[derive(Serialize)]
+pub struct Status {
+    locked_down: bool,
+    /// Status of the ban page that caused the lockdown.
+    status: Option<u16>,
+    remaining_ms: Option<u128>,
+}
+
+/// Configured via `LOCKDOWN_COOLDOWN` in seconds.
+pub struct Lockdown {
+    cooldown: Duration,
+    active: Mutex<Option<Active>>,
+}
+
+impl Lockdown {
+    /// Returns `None` if the lockdown is not enabled.
+    pub fn from_env() -> Option<Self> {
+        let seconds = parse_env::<u64>("LOCKDOWN_COOLDOWN").filter(|seconds| *seconds > 0)?;
+
+        Some(Self::new(Duration::from_secs(seconds)))
+    }
+
+    const fn new(cooldown: Duration) -> Self {
+        Self {
+            cooldown,
+            active: Mutex::new(None),
+        }
+    }
+
+    /// Start a lockdown if a response is a ban page.
+    ///
+    /// Only the bodies of possible ban pages are read. The response is
+    /// returned unchanged.
+    pub async fn inspect(&self, response: Response<Body>) -> Result<Response<Body>, hyper::Error> {
+        if !is_candidate(response.status(), response.headers()) {
+            return Ok(response);
+        }
+
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+
+        if is_ban(parts.status, &parts.headers, &body) {
+            self.start(parts.status, &parts.headers);
+        }
+
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+
+    /// Start a lockdown caused by a ban page.
+    ///
+    /// The cool-down lasts at least as long as the ban page's `Retry-After`.
+    fn start(&self, status: StatusCode, headers: &HeaderMap) {
+        let retry_after = headers
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .map_or(Duration::ZERO, Duration::from_secs);
+        let until = Instant::now() + self.cooldown.max(retry_after);
+
+        let mut active = self.active.lock().expect("lockdown poisoned");
+
+        if active.as_ref().is_some_and(|active| active.until >= until) {
+            return;
+        }
+
+        error!(
+            "Discord responded with a {} ban page, halting all traffic for {:?}",
+            status,
+            until - Instant::now()
+        );
+
+        *active = Some(Active { until, status });
+        record(true);
+    }
+
+    /// Time remaining until traffic may be sent again, if locked down.
+    pub fn remaining(&self) -> Option<Duration> {
+        let mut active = self.active.lock().expect("lockdown poisoned");
+        let remaining = active
+            .as_ref()?
+            .until
+            .checked_duration_since(Instant::now());
+
+        if remaining.is_none() {
+            info!("Lockdown ended, sending traffic again");
+
+            *active = None;
+            record(false);
+        }
+
+        remaining
+    }
+
+    /// End the lockdown early, returning whether there was one.
+    pub fn lift(&self) -> bool {
+        let lifted = self.active.lock().expect("lockdown poisoned").take();
+        record(false);
+
+        lifted.is_some()
+    }
+
+    pub fn status(&self) -> Status {
+        let remaining = self.remaining();
+        let active = self.active.lock().expect("lockdown poisoned");
+
+        Status {
+            locked_down: remaining.is_some(),
+            status: active.as_ref().map(|active| active.status.as_u16()),
+            remaining_ms: remaining.map(|remaining| remaining.as_millis()),
+        }
+    }
+}
+
+#[cfg(feature = "expose-metrics")]
+fn record(locked_down: bool) {
+    metrics::gauge!(
+        format!("{}_lockdown", crate::METRIC_KEY.as_str()),
+        if locked_down { 1.0 } else { 0.0 }
+    );
+}
+
+#[cfg(not(feature = "expose-metrics"))]
+const fn record(_: bool) {}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_ban, is_candidate, Lockdown};
+    use http::{HeaderMap, StatusCode};
+    use hyper::{Body, Response};
+    use tokio::time::Duration;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+
+        headers
+    }
+
+    fn response(status: StatusCode, headers: HeaderMap, body: &'static str) -> Response<Body> {
+        let mut response = Response::new(Body::from(body));
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+
+        response
+    }
+
+    #[test]
+    fn test_is_candidate() {
+        let html = headers(&[("content-type", "text/html"), ("cf-ray", "1-AMS")]);
+        let json = headers(&[("content-type", "application/json"), ("cf-ray", "1-AMS")]);
+
+        assert!(is_candidate(StatusCode::TOO_MANY_REQUESTS, &html));
+        assert!(is_candidate(
+            StatusCode::FORBIDDEN,
+            &headers(&[("server", "cloudflare")])
+        ));
+        assert!(!is_candidate(StatusCode::TOO_MANY_REQUESTS, &json));
+        assert!(!is_candidate(StatusCode::OK, &html));
+
+        // Not served by Cloudflare, e.g. by a proxy in between
+        assert!(!is_candidate(
+            StatusCode::FORBIDDEN,
+            &headers(&[("content-type", "text/html")])
+        ));
+    }
+
+    #[test]
+    fn test_is_ban() {
+        let ratelimited = headers(&[("x-ratelimit-scope", "user")]);
+
+        assert!(is_ban(
+            StatusCode::FORBIDDEN,
+            &HeaderMap::new(),
+            b"<title>Access denied | discord.com used Cloudflare | Error 1015</title>"
+        ));
+        assert!(is_ban(
+            StatusCode::TOO_MANY_REQUESTS,
+            &HeaderMap::new(),
+            b""
+        ));
+        assert!(!is_ban(StatusCode::TOO_MANY_REQUESTS, &ratelimited, b""));
+        assert!(!is_ban(
+            StatusCode::FORBIDDEN,
+            &HeaderMap::new(),
+            b"error code: 1020"
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lockdown() {
+        let lockdown = Lockdown::new(Duration::from_secs(60));
+
+        let json = headers(&[("content-type", "application/json"), ("cf-ray", "1-AMS")]);
+        lockdown
+            .inspect(response(StatusCode::TOO_MANY_REQUESTS, json, "{}"))
+            .await
+            .unwrap();
+        assert!(lockdown.remaining().is_none());
+
+        let ban = || {
+            headers(&[
+                ("content-type", "text/html"),
+                ("cf-ray", "1-AMS"),
+                ("retry-after", "120"),
+            ])
+        };
+        let inspected = lockdown
+            .inspect(response(
+                StatusCode::TOO_MANY_REQUESTS,
+                ban(),
+                "error code: 1015",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(lockdown.remaining(), Some(Duration::from_secs(120)));
+
+        // The body is forwarded unchanged
+        let body = hyper::body::to_bytes(inspected.into_body()).await.unwrap();
+        assert_eq!(body, "error code: 1015");
+
+        tokio::time::advance(Duration::from_secs(121)).await;
+        assert!(lockdown.remaining().is_none());
+        assert!(!lockdown.status().locked_down);
+
+        lockdown
+            .inspect(response(StatusCode::FORBIDDEN, ban(), "error code: 1015"))
+            .await
+            .unwrap();
+        assert!(lockdown.lift());
+        assert!(lockdown.remaining().is_none());
+    }
+}
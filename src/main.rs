@@ -1,50 +1,174 @@
+mod admin;
+mod api_versions;
+mod backend;
+mod backoff;
+mod body;
+mod budget;
+mod bulk_ban;
+mod canary;
+mod capture;
+mod ceiling;
+mod chaos;
+mod check_config;
+mod cli;
+mod concurrency;
+mod config;
+mod cors;
+mod deadline;
+mod diagnostics;
+mod edges;
 mod error;
 mod expiring_lru;
+mod fail_fast;
+mod fair;
+mod forwarded;
+mod gateway;
+mod global_limit;
+mod handoff;
+mod header_limits;
+mod headers;
+mod healthcheck;
+mod http_version;
+mod limits;
+mod lockdown;
+mod maintenance;
+mod major;
+mod memory;
+mod mentions;
+#[cfg(feature = "expose-metrics")]
+mod metric_labels;
+mod mirror;
+mod multipart;
+mod oauth;
+mod paginate;
+mod path;
+mod pause;
+mod prewarm;
+mod probe;
+mod protocol;
+mod proxy_protocol;
+mod query;
+mod queue_limits;
+mod queue_timeout;
+mod ratelimit_log;
 mod ratelimiter_map;
+mod reactions;
+mod redis_ratelimiter;
+mod reload;
+mod replay;
+mod report;
+mod request;
+mod request_log;
+mod response_size;
+mod runtime;
+mod schema;
+mod selftest;
+mod services;
+mod session;
+mod signing;
+mod simulation;
+mod slo;
+mod starvation;
+mod status;
+mod sublimit;
+mod tags;
+mod tenant;
+mod token_check;
+mod token_file;
+mod traffic;
+mod upstream;
+mod version_diff;
+mod workers;
 
+use api_versions::ApiVersions;
+use budget::Budgets;
+use canary::{Canary, CANARY_HEADER};
+use capture::{Capture, Exchange, Payload, RecordedResponse};
+use ceiling::RateCeiling;
+use chaos::{Chaos, Injection};
+use clap::Parser;
+use cli::{Cli, Command};
+use concurrency::ConcurrencyLimits;
+use config::Config;
+use cors::Cors;
+use deadline::{Budget, Stage};
+use edges::Edges;
 use error::RequestError;
+use fair::FairQueue;
+use forwarded::{ClientAddr, TrustedProxies};
+use gateway::GatewayUrl;
+use global_limit::GlobalLimits;
+use handoff::Handoff;
+use header_limits::HeaderLimits;
+use headers::ResponseHeaderFilter;
 use http::{
-    header::{AUTHORIZATION, CONNECTION, HOST, TRANSFER_ENCODING, UPGRADE},
-    HeaderValue, Method as HttpMethod, Uri,
+    header::{AUTHORIZATION, CONTENT_TYPE, HOST, ORIGIN},
+    HeaderValue, Method as HttpMethod, StatusCode,
 };
-use hyper::{
-    body::Body,
-    server::{conn::AddrStream, Server},
-    service, Client, Request, Response,
+use http_version::{HttpVersions, UpstreamClients};
+use hyper::{body::Body, server::Server, service, Request, Response};
+use limits::PayloadLimits;
+use lockdown::Lockdown;
+use maintenance::Maintenance;
+use major::MajorParameters;
+use memory::MemoryPressure;
+use mentions::MentionPolicy;
+use mirror::Mirror;
+use oauth::ClientCredentials;
+use path::{normalize_path, ratelimit_path};
+use pause::Pause;
+use prewarm::KnownLimits;
+use probe::Probe;
+use queue_limits::QueueLimits;
+use queue_timeout::QueueTimeout;
+use ratelimit_log::RatelimitLog;
+use ratelimiter_map::{
+    is_shared_ratelimit, ratelimit_headers, webhook_credentials, RatelimiterMap,
 };
-use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
-use hyper_trust_dns::{TrustDnsHttpConnector, TrustDnsResolver};
-use ratelimiter_map::RatelimiterMap;
+use reactions::{Join, ReactionBatching};
+use reload::Reloader;
+use report::{Connection, Stats};
+use request_log::RequestLog;
+use response_size::ResponseSize;
+use schema::ResponseValidation;
+use services::Services;
+use session::SessionGuard;
+use signing::Signer;
+use slo::Slos;
 use std::{
-    convert::{Infallible, TryFrom},
+    convert::Infallible,
     env,
     error::Error,
     net::{IpAddr, SocketAddr},
     str::FromStr,
     sync::Arc,
+    time::{Duration, Instant},
 };
-use tracing::{debug, error, info, trace, warn};
-use tracing_subscriber::EnvFilter;
-use twilight_http_ratelimiting::{
-    InMemoryRatelimiter, Method, Path, RatelimitHeaders, Ratelimiter,
-};
+use sublimit::Sublimits;
+use tags::Tags;
+use tenant::Tenant;
+use token_check::OnRejected;
+use tracing::{debug, debug_span, error, field, info, info_span, trace, warn, Instrument, Span};
+use tracing_subscriber::{fmt, prelude::*, reload::Layer};
+use traffic::{Priority, TrafficClasses};
+use twilight_http_ratelimiting::{Method, Path};
+use upstream::{Upstream, DEFAULT_UPSTREAM};
 
 #[cfg(unix)]
 use tokio::signal::unix::{signal, SignalKind};
 
 #[cfg(feature = "expose-metrics")]
-use std::time::Instant;
-
+use canary::Build;
 #[cfg(feature = "expose-metrics")]
 use lazy_static::lazy_static;
 #[cfg(feature = "expose-metrics")]
+use metric_labels::MetricLabels;
+#[cfg(feature = "expose-metrics")]
 use metrics::histogram;
 #[cfg(feature = "expose-metrics")]
 use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
 #[cfg(feature = "expose-metrics")]
 use metrics_util::MetricKindMask;
-#[cfg(feature = "expose-metrics")]
-use std::time::Duration;
 
 #[cfg(feature = "expose-metrics")]
 lazy_static! {
@@ -52,120 +176,528 @@ lazy_static! {
         env::var("METRIC_KEY").unwrap_or_else(|_| "twilight_http_proxy".into());
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
-        )
-        .init();
+/// Header added to responses synthesized in dry run mode.
+const DRY_RUN_HEADER: &str = "x-proxy-dry-run";
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let (filter, log_filter) = Layer::new(reload::log_filter(env::var("RUST_LOG").ok().as_deref()));
+
+    // The filter only applies to the logs, so tokio-console receives all
+    // events of the runtime
+    let subscriber = tracing_subscriber::registry().with(fmt::layer().with_filter(filter));
+
+    #[cfg(feature = "tokio-console")]
+    let (subscriber, console_server) = {
+        let (layer, server) = console_subscriber::ConsoleLayer::builder()
+            .with_default_env()
+            .build();
+
+        (subscriber.with(layer), server)
+    };
+
+    subscriber.init();
+
+    let cli = Cli::parse();
+
+    // Applied before the runtime starts any threads, as they set environment
+    // variables. Options override the environment, which overrides the file
+    cli.apply();
+
+    let reloader = Reloader::new(cli.config.clone(), cli.profile.clone(), log_filter);
+
+    if let Some(path) = &cli.config {
+        Config::load(path, cli.profile.as_deref())?.apply();
+        reloader.reload_log_filter()?;
+
+        match &cli.profile {
+            Some(profile) => info!(
+                "Loaded configuration from {} with profile {}",
+                path, profile
+            ),
+            None => info!("Loaded configuration from {}", path),
+        }
+    }
+
+    let runtime = runtime::build()?;
+
+    #[cfg(feature = "tokio-console")]
+    runtime.spawn(async move {
+        if let Err(e) = console_server.serve().await {
+            error!("Failed to serve tokio-console: {}", e);
+        }
+    });
+
+    runtime.block_on(run(cli.command, reloader))
+}
+
+async fn run(command: Option<Command>, reloader: Reloader) -> Result<(), Box<dyn Error>> {
+    match command {
+        Some(Command::CheckConfig {
+            probe,
+            verify_token,
+        }) => return check_config::run(probe, verify_token).await,
+        Some(Command::DiffVersions {
+            file,
+            target,
+            first,
+            second,
+        }) => return version_diff::run(&file, &target, [first, second]).await,
+        Some(Command::Healthcheck { ready }) => return healthcheck::run(ready).await,
+        Some(Command::Replay { file, target }) => return replay::run(&file, &target).await,
+        Some(Command::Request { method, path, body }) => {
+            return request::run(&method, &path, body.as_deref()).await
+        }
+        Some(Command::Selftest { requests }) => return selftest::run(requests).await,
+        None => {}
+    }
+
+    if let Some(count) = workers::count() {
+        return workers::supervise(count).await;
+    }
 
     let host_raw = env::var("HOST").unwrap_or_else(|_| "0.0.0.0".into());
     let host = IpAddr::from_str(&host_raw)?;
     let port = env::var("PORT").unwrap_or_else(|_| "80".into()).parse()?;
 
-    let https_connector = {
-        let mut http_connector = TrustDnsResolver::default().into_http_connector();
-        http_connector.enforce_http(false);
+    let upstream =
+        Upstream::new(&env::var("UPSTREAM_URL").unwrap_or_else(|_| DEFAULT_UPSTREAM.into()))?;
 
-        let builder = HttpsConnectorBuilder::new()
-            .with_webpki_roots()
-            .https_only()
-            .enable_http1();
+    let edges = Edges::from_env(&upstream);
 
-        if env::var("DISABLE_HTTP2").is_ok() {
-            builder.wrap_connector(http_connector)
-        } else {
-            builder.enable_http2().wrap_connector(http_connector)
-        }
-    };
+    let clients = UpstreamClients::new(&upstream, edges.clone(), HttpVersions::from_env());
+    let default_token = token_file::default_token()?;
 
-    let client: Client<_, Body> = Client::builder().build(https_connector);
-    let ratelimiter_map = Arc::new(RatelimiterMap::new(env::var("DISCORD_TOKEN")?));
+    if default_token.is_none() {
+        info!("No DISCORD_TOKEN set, requests without an Authorization header will be rejected");
+    }
+
+    let ratelimiter_map = RatelimiterMap::new(default_token, backend::from_env().await?);
 
     let address = SocketAddr::from((host, port));
 
     #[cfg(feature = "expose-metrics")]
-    let handle: Arc<PrometheusHandle>;
+    let metric_labels = MetricLabels::from_env();
 
     #[cfg(feature = "expose-metrics")]
-    {
+    let metrics_handle = {
         let timeout = parse_env("METRIC_TIMEOUT").unwrap_or(300);
-        let recorder = PrometheusBuilder::new()
+        let recorder = metric_labels
+            .apply(PrometheusBuilder::new())
             .idle_timeout(
                 MetricKindMask::COUNTER | MetricKindMask::HISTOGRAM,
                 Some(Duration::from_secs(timeout)),
             )
             .build_recorder();
-        handle = Arc::new(recorder.handle());
+        let handle = recorder.handle();
         metrics::set_boxed_recorder(Box::new(recorder))
             .expect("Failed to create metrics receiver!");
+
+        handle
+    };
+
+    let chaos = Chaos::from_env();
+    let dry_run = env::var("DRY_RUN").is_ok();
+
+    if dry_run {
+        warn!("Dry run mode is enabled, requests will not be forwarded to Discord");
     }
 
-    // The closure inside `make_service_fn` is run for each connection,
-    // creating a 'service' to handle requests for that specific connection.
-    let service = service::make_service_fn(move |addr: &AddrStream| {
-        trace!("Connection from: {:?}", addr);
-        let ratelimiter_map = ratelimiter_map.clone();
-        // Cloning a hyper client is fairly cheap by design
-        let client = client.clone();
+    if chaos.is_some() {
+        warn!("Chaos mode is enabled, requests will fail randomly");
+    }
 
+    let state = Arc::new(State {
+        admin_token: env::var("ADMIN_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty()),
+        api_versions: ApiVersions::from_env(),
+        budgets: Budgets::from_env(),
+        canary: Canary::from_env()?,
+        capture: Capture::from_env().await?,
+        chaos,
+        client_credentials: ClientCredentials::from_env(),
+        clients,
+        concurrency_limits: ConcurrencyLimits::from_env(),
+        cors: Cors::from_env(),
+        default_deadline: parse_env("DEFAULT_DEADLINE_MS")
+            .filter(|millis| *millis > 0)
+            .map(Duration::from_millis),
+        dry_run,
+        encode_audit_log_reason: env::var("ENCODE_AUDIT_LOG_REASON").is_ok(),
+        enforce_payload_limits: env::var("ENFORCE_PAYLOAD_LIMITS").is_ok(),
+        fail_fast: env::var("FAIL_FAST_RATELIMITS").is_ok(),
+        fair_queue: FairQueue::from_env(),
+        gateway_url: GatewayUrl::from_env(),
+        global_limits: GlobalLimits::from_env(),
+        handoff: Handoff::from_env()?,
+        header_limits: HeaderLimits::from_env(),
+        known_limits: KnownLimits::from_env().await,
+        lockdown: Lockdown::from_env(),
+        maintenance: Maintenance::default(),
+        major_parameters: MajorParameters::from_env(),
+        max_query_length: parse_env("MAX_QUERY_LENGTH").unwrap_or(query::DEFAULT_MAX_LENGTH),
+        memory_pressure: MemoryPressure::from_env(),
+        mention_policy: MentionPolicy::from_env(),
+        mirror: Mirror::from_env()?,
+        pause: Pause::from_env(),
+        payload_limits: PayloadLimits::from_env(),
+        probe: Probe::from_env(),
+        queue_limits: QueueLimits::from_env(),
+        queue_timeout: QueueTimeout::from_env(),
+        rate_ceiling: RateCeiling::from_env(),
+        ratelimit_log: RatelimitLog::from_env(),
+        reaction_batching: ReactionBatching::from_env(),
+        validate_json: env::var("VALIDATE_JSON").is_ok(),
+        validate_multipart: env::var("VALIDATE_MULTIPART").is_ok(),
+        ratelimiter_map,
+        request_log: RequestLog::from_env(),
+        response_headers: ResponseHeaderFilter::from_env(),
+        response_size: ResponseSize::from_env(),
+        response_validation: ResponseValidation::from_env(),
+        services: Services::from_env()?,
+        session_guard: SessionGuard::from_env(),
+        signer: Signer::from_env(),
+        slos: Slos::from_env(),
+        stats: Stats::default(),
+        sublimits: Sublimits::from_env(),
+        tags: Tags::from_env(),
+        traffic_classes: TrafficClasses::from_env(),
+        trusted_proxies: TrustedProxies::from_env(),
+        upstream,
+        #[cfg(feature = "expose-metrics")]
+        metric_labels,
         #[cfg(feature = "expose-metrics")]
-        let handle = handle.clone();
+        metrics_handle,
+    });
+
+    let user = match OnRejected::from_env() {
+        Some(on_rejected) => token_check::on_startup(&state, on_rejected).await?,
+        None => None,
+    };
+
+    if state.probe.is_some() {
+        let state = state.clone();
+
+        tokio::spawn(async move { probe::run(&state).await });
+    }
+
+    #[cfg(unix)]
+    {
+        let state = state.clone();
+
+        tokio::spawn(async move { diagnostics::run(&state).await });
+    }
+
+    {
+        let state = state.clone();
+
+        tokio::spawn(async move { reload::run(reloader, &state).await });
+    }
+
+    if let Ok(path) = env::var("DISCORD_TOKEN_FILE") {
+        let state = state.clone();
+
+        tokio::spawn(async move { token_file::watch(path, &state).await });
+    }
+
+    if state.memory_pressure.is_some() {
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            if let Some(memory_pressure) = &state.memory_pressure {
+                memory_pressure.run().await;
+            }
+        });
+    }
+
+    if let Some(edges) = edges {
+        tokio::spawn(async move { edges.run().await });
+    }
+
+    if let Some(threshold) = starvation::threshold_from_env() {
+        let state = state.clone();
+
+        tokio::spawn(async move { starvation::run(&state, threshold).await });
+    }
+
+    let shutdown_state = state.clone();
+
+    // The closure inside `make_service_fn` is run for each connection,
+    // creating a 'service' to handle requests for that specific connection.
+    let service = service::make_service_fn(move |connection: &protocol::Connection| {
+        let peer = connection.remote_addr();
+        trace!("Connection from: {:?}", peer);
+        let state = state.clone();
+        let source = connection.source_addr();
+        let connection = Connection::open(state.clone());
 
         async move {
             Ok::<_, Infallible>(service::service_fn(move |incoming: Request<Body>| {
-                let token = incoming
-                    .headers()
-                    .get("authorization")
-                    .and_then(|value| value.to_str().ok());
-                let (ratelimiter, token) = ratelimiter_map.get_or_insert(token);
-                let client = client.clone();
-
-                #[cfg(feature = "expose-metrics")]
-                {
-                    let handle = handle.clone();
-
-                    async move {
-                        Ok::<_, Infallible>({
-                            if incoming.uri().path() == "/metrics" {
-                                handle_metrics(handle)
-                            } else {
-                                handle_request(client, ratelimiter, token, incoming)
-                                    .await
-                                    .unwrap_or_else(|err| err.as_response())
-                            }
-                        })
-                    }
-                }
+                let state = connection.state.clone();
+                let peer = source.get().ip();
 
-                #[cfg(not(feature = "expose-metrics"))]
-                {
-                    async move {
-                        Ok::<_, Infallible>(
-                            handle_request(client, ratelimiter, token, incoming)
-                                .await
-                                .unwrap_or_else(|err| err.as_response()),
-                        )
-                    }
+                async move {
+                    let response = route(&state, incoming, peer).await;
+                    state.stats.served();
+
+                    Ok::<_, Infallible>(response)
                 }
             }))
         }
     });
 
-    let server = Server::bind(&address).serve(service);
+    let incoming = protocol::Incoming::from_env(workers::bind(address)?);
+    let server = shutdown_state
+        .header_limits
+        .apply(Server::builder(incoming))
+        .serve(service);
+
+    let graceful = server.with_graceful_shutdown({
+        let state = shutdown_state.clone();
 
-    let graceful = server.with_graceful_shutdown(shutdown_signal());
+        async move {
+            shutdown_signal().await;
+            state.stats.stopping();
+        }
+    });
 
-    info!("Listening on http://{}", address);
+    match user {
+        Some(user) => info!("Listening on http://{} as {}", address, user),
+        None => info!("Listening on http://{}", address),
+    }
 
     if let Err(why) = graceful.await {
         error!("Fatal server error: {}", why);
     }
 
+    let tenants = shutdown_state.ratelimiter_map.tenants();
+
+    if let Some(known_limits) = &shutdown_state.known_limits {
+        if let Err(e) = known_limits.save(&tenants).await {
+            error!("Failed to save bucket limits: {}", e);
+        }
+    }
+
+    if let Err(e) = shutdown_state.handoff.send(&tenants).await {
+        error!("Failed to hand off bucket states: {}", e);
+    }
+
+    report::write(&shutdown_state).await;
+
     Ok(())
 }
 
+/// Shared state of all connections.
+pub struct State {
+    admin_token: Option<String>,
+    api_versions: ApiVersions,
+    budgets: Budgets,
+    canary: Option<Canary>,
+    capture: Option<Capture>,
+    chaos: Option<Chaos>,
+    client_credentials: Option<ClientCredentials>,
+    clients: UpstreamClients,
+    concurrency_limits: ConcurrencyLimits,
+    cors: Option<Cors>,
+    default_deadline: Option<Duration>,
+    dry_run: bool,
+    encode_audit_log_reason: bool,
+    enforce_payload_limits: bool,
+    fail_fast: bool,
+    fair_queue: Option<FairQueue>,
+    gateway_url: Option<GatewayUrl>,
+    global_limits: GlobalLimits,
+    handoff: Handoff,
+    header_limits: HeaderLimits,
+    known_limits: Option<KnownLimits>,
+    lockdown: Option<Lockdown>,
+    maintenance: Maintenance,
+    major_parameters: MajorParameters,
+    max_query_length: usize,
+    memory_pressure: Option<MemoryPressure>,
+    mention_policy: Option<MentionPolicy>,
+    mirror: Option<Mirror>,
+    pause: Pause,
+    payload_limits: PayloadLimits,
+    probe: Option<Probe>,
+    queue_limits: Option<QueueLimits>,
+    queue_timeout: Option<QueueTimeout>,
+    rate_ceiling: Option<RateCeiling>,
+    ratelimit_log: RatelimitLog,
+    reaction_batching: Option<ReactionBatching>,
+    ratelimiter_map: RatelimiterMap,
+    request_log: RequestLog,
+    response_headers: Option<ResponseHeaderFilter>,
+    response_size: ResponseSize,
+    response_validation: Option<ResponseValidation>,
+    services: Services,
+    session_guard: Option<SessionGuard>,
+    signer: Option<Signer>,
+    slos: Slos,
+    stats: Stats,
+    sublimits: Sublimits,
+    tags: Tags,
+    traffic_classes: TrafficClasses,
+    trusted_proxies: TrustedProxies,
+    upstream: Upstream,
+    validate_json: bool,
+    validate_multipart: bool,
+    #[cfg(feature = "expose-metrics")]
+    metric_labels: MetricLabels,
+    #[cfg(feature = "expose-metrics")]
+    metrics_handle: PrometheusHandle,
+}
+
+/// Dispatch an incoming request to the endpoints served by the proxy itself
+/// or forward it to Discord.
+async fn route(state: &Arc<State>, mut incoming: Request<Body>, peer: IpAddr) -> Response<Body> {
+    if !state.header_limits.admits(incoming.headers()) {
+        debug!("Rejecting request from {} with oversized headers", peer);
+
+        return RequestError::HeadersTooLarge.as_response();
+    }
+
+    let client = state.trusted_proxies.client_addr(peer, incoming.headers());
+    incoming.extensions_mut().insert(ClientAddr(client));
+
+    #[cfg(feature = "expose-metrics")]
+    if incoming.uri().path() == "/metrics" {
+        return handle_metrics(&state.metrics_handle);
+    }
+
+    if incoming.uri().path().starts_with(bulk_ban::PREFIX) {
+        return bulk_ban::handle(state, incoming).await;
+    }
+
+    if incoming.uri().path().starts_with(paginate::PREFIX) {
+        return paginate::handle(Arc::clone(state), incoming).await;
+    }
+
+    if incoming.uri().path().starts_with(admin::PREFIX) {
+        return admin::handle(state, incoming).await;
+    }
+
+    if headers::is_websocket_upgrade(incoming.headers()) {
+        warn!(
+            "Client {} tried to open a WebSocket to {} through the proxy",
+            client,
+            incoming.uri()
+        );
+        return RequestError::Upgrade.as_response();
+    }
+
+    if status::is_status_page(&incoming) {
+        return status::page(state);
+    }
+
+    if incoming.method() == HttpMethod::CONNECT {
+        warn!(
+            "Client {} tried to tunnel to {} with CONNECT",
+            client,
+            incoming.uri()
+        );
+        return RequestError::Connect.as_response();
+    }
+
+    // Requests of another proxy were routed to this instance already
+    let from_primary = incoming.headers_mut().remove(CANARY_HEADER).is_some();
+
+    let canary = match state.canary.as_ref().filter(|_| !from_primary) {
+        Some(canary) => canary,
+        None => return with_cors(state, incoming).await,
+    };
+
+    let incoming = match canary.forward(incoming).await {
+        Ok(response) => return response,
+        Err(incoming) => incoming,
+    };
+
+    #[cfg(feature = "expose-metrics")]
+    let start = Instant::now();
+
+    let response = with_cors(state, incoming).await;
+
+    #[cfg(feature = "expose-metrics")]
+    canary::record(Build::Primary, response.status(), start.elapsed());
+
+    response
+}
+
+/// Forward a request, answering CORS preflights and adding CORS headers to
+/// the response if configured.
+async fn with_cors(state: &State, incoming: Request<Body>) -> Response<Body> {
+    let cors = match &state.cors {
+        Some(cors) => cors,
+        None => return forward(state, incoming).await,
+    };
+
+    if let Some(response) = cors.preflight(&incoming) {
+        return response;
+    }
+
+    let origin = incoming.headers().get(ORIGIN).cloned();
+    let mut response = forward(state, incoming).await;
+    cors.apply(origin.as_ref(), response.headers_mut());
+
+    response
+}
+
+/// Forward a request to Discord with the ratelimiter of its tenant.
+async fn forward(state: &State, incoming: Request<Body>) -> Response<Body> {
+    if let Some((upstream, path)) = state.services.find(&incoming) {
+        return services::relay(state, upstream, &path, incoming)
+            .await
+            .unwrap_or_else(|err| err.as_response());
+    }
+
+    let token = incoming
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok());
+    let normalized = normalize_path(incoming.uri().path());
+    let webhook = token
+        .is_none()
+        .then(|| webhook_credentials(&normalized.path))
+        .flatten();
+
+    let (tenant, token) = if let Some((id, webhook_token)) = webhook {
+        // Executing a webhook with its token needs no authorization
+        (
+            state
+                .ratelimiter_map
+                .get_or_insert_webhook(id, webhook_token),
+            None,
+        )
+    } else {
+        match state.ratelimiter_map.get_or_insert(token) {
+            Some((tenant, token)) => (tenant, Some(token)),
+            None => {
+                debug!("Rejecting request without Authorization header");
+                return RequestError::MissingToken.as_response();
+            }
+        }
+    };
+
+    let span = info_span!(
+        "request",
+        method = %incoming.method(),
+        tenant = tenant.usage.hash(),
+        client = field::Empty,
+        route = field::Empty,
+        tag = field::Empty,
+        status = field::Empty,
+    );
+
+    let response = handle_request(state, tenant, token, incoming)
+        .instrument(span.clone())
+        .await
+        .unwrap_or_else(|err| err.as_response());
+
+    span.record("status", response.status().as_u16());
+
+    response
+}
+
 #[cfg(windows)]
 async fn shutdown_signal() {
     tokio::signal::ctrl_c()
@@ -268,34 +800,32 @@ fn path_name(path: &Path) -> &'static str {
     }
 }
 
-fn normalize_path(request_path: &str) -> (&str, &str) {
-    if let Some(trimmed_path) = request_path.strip_prefix("/api") {
-        if let Some(maybe_api_version) = trimmed_path.split('/').nth(1) {
-            if let Some(version_number) = maybe_api_version.strip_prefix('v') {
-                if version_number.parse::<u8>().is_ok() {
-                    let len = "/api/v".len() + version_number.len();
-                    return (&request_path[..len], &request_path[len..]);
-                };
-            };
-        }
-
-        ("/api", trimmed_path)
-    } else {
-        ("/api", request_path)
-    }
-}
-
 async fn handle_request(
-    client: Client<HttpsConnector<TrustDnsHttpConnector>, Body>,
-    ratelimiter: InMemoryRatelimiter,
-    token: String,
+    state: &State,
+    tenant: Tenant,
+    token: Option<String>,
     mut request: Request<Body>,
 ) -> Result<Response<Body>, RequestError> {
     trace!("Incoming request: {:?}", request);
 
+    let received = Instant::now();
+
+    let mut budget = Budget::new(deadline::take_deadline(
+        request.headers_mut(),
+        state.default_deadline,
+    ));
+    let client = request
+        .extensions()
+        .get::<ClientAddr>()
+        .map_or_else(|| "unknown".to_string(), |addr| addr.0.to_string());
+    let span = Span::current();
+    span.record("client", client.as_str());
+
     let (method, m) = match *request.method() {
         HttpMethod::DELETE => (Method::Delete, "DELETE"),
         HttpMethod::GET => (Method::Get, "GET"),
+        // HEAD requests are ratelimited like the equivalent GET request
+        HttpMethod::HEAD => (Method::Get, "HEAD"),
         HttpMethod::PATCH => (Method::Patch, "PATCH"),
         HttpMethod::POST => (Method::Post, "POST"),
         HttpMethod::PUT => (Method::Put, "PUT"),
@@ -307,11 +837,13 @@ async fn handle_request(
         }
     };
 
+    let http_method = request.method().clone();
     let request_path = request.uri().path().to_owned();
 
-    let (api_path, trimmed_path) = normalize_path(&request_path);
+    let normalized = normalize_path(&request_path);
+    let (api_path, trimmed_path) = (normalized.api.as_str(), normalized.path.as_str());
 
-    let path = match Path::try_from((method, trimmed_path)) {
+    let path = match ratelimit_path(method, trimmed_path) {
         Ok(path) => path,
         Err(e) => {
             error!(
@@ -323,40 +855,276 @@ async fn handle_request(
     };
 
     let p = path_name(&path);
+    span.record("route", p);
+    state
+        .api_versions
+        .record(normalized.version(), tenant.usage.hash());
 
-    let header_sender = match ratelimiter.wait_for_ticket(path).await {
-        Ok(sender) => sender,
-        Err(e) => {
-            error!("Failed to receive ticket for ratelimiting: {:?}", e);
-            return Err(RequestError::AcquiringTicket { source: e });
+    let _in_flight = state.stats.in_flight(m, p, tenant.usage.hash());
+    let class = state.traffic_classes.classify(request.headers_mut(), &path);
+    let priority = Priority::take(request.headers_mut());
+    let tag = state.tags.take(request.headers_mut());
+    let fail_fast = fail_fast::take(request.headers_mut(), state.fail_fast);
+
+    if let Some(tag) = &tag {
+        span.record("tag", tag.as_str());
+    }
+
+    if let Some(query) = request.uri().query() {
+        if let Err(e) = query::validate_query(query, state.max_query_length) {
+            debug!("Rejecting query: {}", e);
+            return Err(RequestError::InvalidQuery { source: e });
+        }
+    }
+
+    if let Some(response) = state.maintenance.respond(&path) {
+        debug!("Route is under maintenance");
+        return Ok(response);
+    }
+
+    if let Some(memory_pressure) = &state.memory_pressure {
+        if !memory_pressure.admit(class) {
+            debug!("Shedding bulk request under memory pressure");
+            return Err(RequestError::MemoryPressure);
+        }
+    }
+
+    if let Some(remaining) = state.lockdown.as_ref().and_then(Lockdown::remaining) {
+        debug!("Rejecting request during lockdown");
+        return Err(RequestError::Lockdown {
+            retry_after: remaining.as_secs() + 1,
+        });
+    }
+
+    let daily_reservation = match state.budgets.admit(&tenant.usage, method) {
+        Ok(reservation) => reservation,
+        Err(retry_after) => {
+            debug!("Daily budget exhausted");
+            return Err(RequestError::BudgetExceeded { retry_after });
         }
     };
 
-    request.headers_mut().insert(
-        AUTHORIZATION,
-        HeaderValue::from_bytes(token.as_bytes())
-            .expect("strings are guaranteed to be valid utf-8"),
-    );
-    request
-        .headers_mut()
-        .insert(HOST, HeaderValue::from_static("discord.com"));
+    if let Some(session_guard) = &state.session_guard {
+        if let Err(reset_after) = session_guard.check(&tenant, &path) {
+            debug!("Session starts nearly exhausted, refusing request");
+            return Err(RequestError::SessionStartsExhausted {
+                // Round up so clients don't retry before the reset
+                retry_after: reset_after.as_secs() + 1,
+            });
+        }
+    }
 
-    // Remove forbidden HTTP/2 headers
-    // https://datatracker.ietf.org/doc/html/rfc7540#section-8.1.2.2
-    request.headers_mut().remove(CONNECTION);
-    request.headers_mut().remove("keep-alive");
-    request.headers_mut().remove("proxy-connection");
-    request.headers_mut().remove(TRANSFER_ENCODING);
-    request.headers_mut().remove(UPGRADE);
+    let reaction = state
+        .reaction_batching
+        .as_ref()
+        .and_then(|batching| batching.join(tenant.usage.hash(), method, &path, trimmed_path));
 
-    let mut uri_string = format!("https://discord.com{}{}", api_path, trimmed_path);
+    let reaction = match reaction {
+        Some(Join::Follow(outcome)) => {
+            let shared = budget
+                .run(Stage::Queue, reactions::follow(outcome))
+                .await
+                .map_err(|stage| deadline_exceeded(&budget, stage))?;
 
-    if let Some(query) = request.uri().query() {
-        uri_string.push('?');
-        uri_string.push_str(query);
+            if let Some(response) = shared {
+                debug!("Coalesced with a pending request");
+                return Ok(response);
+            }
+
+            None
+        }
+        Some(Join::Lead(lead)) => Some(lead),
+        None => None,
+    };
+
+    let sublimit_rule = state.sublimits.rule(method, &path).or_else(|| {
+        state
+            .reaction_batching
+            .as_ref()
+            .and_then(|batching| batching.rule(&state.sublimits, method, &path))
+    });
+
+    let sublimit = budget
+        .run(
+            Stage::Body,
+            body::inspect(
+                state,
+                tenant.usage.hash(),
+                method,
+                &path,
+                &mut request,
+                sublimit_rule,
+            ),
+        )
+        .await
+        .map_err(|stage| deadline_exceeded(&budget, stage))??;
+
+    match state.chaos.as_ref().and_then(|chaos| chaos.inject(&path)) {
+        Some(Injection::Latency(latency)) => {
+            debug!("Injecting {:?} of latency", latency);
+            tokio::time::sleep(latency).await;
+        }
+        Some(Injection::Respond(response)) => {
+            debug!("Injecting {}", response.status());
+            return Ok(response);
+        }
+        None => {}
+    }
+
+    let mirror = state
+        .mirror
+        .as_ref()
+        .filter(|mirror| mirror.sample(&http_method));
+
+    let buffered_body = if state.capture.is_some() || mirror.is_some() || state.signer.is_some() {
+        let body = budget
+            .run(
+                Stage::Body,
+                body::read(state, tenant.usage.hash(), request.body_mut()),
+            )
+            .await
+            .map_err(|stage| deadline_exceeded(&budget, stage))??;
+        *request.body_mut() = Body::from(body.clone());
+
+        Some(body)
+    } else {
+        None
+    };
+
+    // Webhooks executed with their token aren't subject to the global
+    // ratelimit
+    let global_limit = token
+        .as_ref()
+        .and_then(|_| state.global_limits.limit(tenant.usage.hash()));
+
+    if fail_fast {
+        if let Some(response) = fail_fast::check(&tenant, &path).await {
+            debug!("Failing fast instead of waiting for the ratelimit");
+            return Ok(response);
+        }
+    }
+
+    let (concurrency_permit, fair_slot, header_sender) = {
+        let _queued = tenant.usage.enqueue(&path);
+
+        let _slot = match &state.queue_limits {
+            Some(queue_limits) => Some(
+                queue_limits
+                    .admit(tenant.usage.queued(&path))
+                    .ok_or(RequestError::QueueFull)?,
+            ),
+            None => None,
+        };
+
+        let dropped = state.stats.queued();
+
+        let ticket = async {
+            if state.pause.wait().await.is_err() {
+                debug!("Rejecting request while paused, the queue is full");
+                return Err(RequestError::Paused);
+            }
+
+            let permit = state.concurrency_limits.acquire(trimmed_path).await;
+
+            if let Some(rule) = sublimit {
+                let max_wait = state.sublimits.max_wait();
+
+                if let Err(wait) = tenant.pacer.wait(&path, rule, max_wait).await {
+                    debug!("Rejecting request, its sublimit is exhausted");
+                    return Err(RequestError::Sublimited {
+                        // Round up so clients don't retry before the slot
+                        retry_after: wait.as_secs() + 1,
+                    });
+                }
+            }
+
+            tenant.backoff.wait(&path).await;
+
+            let _turn = tenant
+                .dispatcher
+                .acquire(&path, class, priority, &state.traffic_classes)
+                .await;
+
+            state.handoff.seed(&tenant, &path).await;
+
+            if let Some(known_limits) = &state.known_limits {
+                known_limits.seed(&tenant, &path).await;
+            }
+
+            let sender = tenant
+                .ratelimiter
+                .wait_for_ticket(path.clone())
+                .await
+                .map_err(|source| {
+                    error!("Failed to receive ticket for ratelimiting: {:?}", source);
+                    RequestError::AcquiringTicket { source }
+                })?;
+
+            if let Some(ceiling) = &state.rate_ceiling {
+                ceiling.wait().await;
+            }
+
+            // Taken once the ticket is granted, so requests waiting for an
+            // exhausted bucket don't hold slots other tokens could use
+            let slot = match &state.fair_queue {
+                Some(fair_queue) => Some(fair_queue.acquire(tenant.usage.hash()).await),
+                None => None,
+            };
+
+            // Waited for last, so the cap holds when requests are sent
+            if let Some(limit) = global_limit {
+                tenant.global_window.wait(limit).await;
+            }
+
+            Ok((permit, slot, sender))
+        };
+
+        let ticket = ticket.instrument(debug_span!("ticket"));
+
+        // Dropping the ticket's receiver removes the request from the queue
+        let ticket = budget
+            .run(Stage::Queue, async {
+                match &state.queue_timeout {
+                    Some(queue_timeout) => {
+                        match tokio::time::timeout(queue_timeout.max_wait(), ticket).await {
+                            Ok(ticket) => ticket,
+                            Err(_) => Err(queue_timeout.exceeded(&tenant, &path).await),
+                        }
+                    }
+                    None => ticket.await,
+                }
+            })
+            .await
+            .map_err(|stage| deadline_exceeded(&budget, stage))??;
+
+        dropped.dispatched();
+
+        ticket
+    };
+
+    // Removed first, so clients can't list the headers set below in
+    // `Connection` to have them dropped
+    headers::remove_hop_by_hop(request.headers_mut());
+
+    if let Some(token) = token {
+        request.headers_mut().insert(
+            AUTHORIZATION,
+            HeaderValue::from_bytes(token.as_bytes())
+                .expect("strings are guaranteed to be valid utf-8"),
+        );
     }
+    request
+        .headers_mut()
+        .insert(HOST, state.upstream.host().clone());
 
-    let uri = match Uri::from_str(&uri_string) {
+    if state.encode_audit_log_reason {
+        headers::encode_audit_log_reason(request.headers_mut());
+    }
+
+    let uri = match state
+        .upstream
+        .uri(api_path, trimmed_path, request.uri().query())
+    {
         Ok(uri) => uri,
         Err(e) => {
             error!("Failed to create URI for requesting Discord API: {:?}", e);
@@ -365,23 +1133,115 @@ async fn handle_request(
     };
     *request.uri_mut() = uri;
 
+    if let (Some(signer), Some(body)) = (&state.signer, &buffered_body) {
+        let path = request.uri().query().map_or_else(
+            || format!("{}{}", api_path, trimmed_path),
+            |query| format!("{}{}?{}", api_path, trimmed_path, query),
+        );
+
+        signer.sign(&http_method, &path, body, request.headers_mut());
+    }
+
+    if let (Some(mirror), Some(body)) = (mirror, &buffered_body) {
+        mirror.send(
+            http_method.clone(),
+            api_path,
+            trimmed_path,
+            request.uri().query(),
+            request.headers(),
+            body.clone(),
+        );
+    }
+
+    let exchange = state
+        .capture
+        .as_ref()
+        .and(buffered_body)
+        .map(|body| Exchange {
+            tenant: tenant.usage.hash().to_string(),
+            method: m.to_string(),
+            path: request.uri().query().map_or_else(
+                || format!("{}{}", api_path, trimmed_path),
+                |query| format!("{}{}?{}", api_path, trimmed_path, query),
+            ),
+            headers: capture::capture_headers(request.headers()),
+            body: Payload::new(&body),
+            response: None,
+        });
+
     #[cfg(feature = "expose-metrics")]
     let start = Instant::now();
 
-    let resp = match client.request(request).await {
-        Ok(response) => response,
-        Err(e) => {
-            error!("Error when requesting the Discord API: {:?}", e);
-            return Err(RequestError::RequestIssue { source: e });
+    let mut resp = if state.dry_run {
+        debug!("Dry run, not forwarding request");
+
+        dry_run_response(&http_method)
+    } else {
+        // Only requests sent to Discord use up the budget
+        if let Some(reservation) = daily_reservation {
+            reservation.keep();
+        }
+
+        let response = budget
+            .run(
+                Stage::Upstream,
+                state
+                    .clients
+                    .get(&state.upstream)
+                    .request(request)
+                    .instrument(debug_span!("upstream")),
+            )
+            .await
+            .map_err(|stage| deadline_exceeded(&budget, stage));
+
+        match response? {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Error when requesting the Discord API: {:?}", e);
+
+                if let (Some(capture), Some(exchange)) = (&state.capture, exchange) {
+                    capture.record(exchange);
+                }
+
+                return Err(RequestError::RequestIssue { source: e });
+            }
         }
     };
 
-    let ratelimit_headers = RatelimitHeaders::from_pairs(
-        resp.headers()
-            .into_iter()
-            .map(|(k, v)| (k.as_str(), v.as_bytes())),
-    )
-    .ok();
+    // Discord processed the request, so the next one to the path may be sent
+    drop(concurrency_permit);
+    drop(fair_slot);
+
+    headers::prepare_response(&http_method, &mut resp);
+
+    let status = resp.status();
+
+    if let Some(lockdown) = &state.lockdown {
+        resp = match lockdown.inspect(resp).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                error!("Error when reading the Discord API response: {:?}", e);
+
+                return Err(RequestError::RequestIssue { source: e });
+            }
+        };
+    }
+
+    let shared_ratelimit = is_shared_ratelimit(status, resp.headers());
+
+    let ratelimit_headers = if shared_ratelimit {
+        debug!("Ignoring ratelimit headers of shared 429");
+
+        None
+    } else {
+        ratelimit_headers(resp.headers())
+    };
+
+    if !shared_ratelimit {
+        tenant
+            .backoff
+            .record(&path, status, ratelimit_headers.as_ref());
+    }
 
     if header_sender.headers(ratelimit_headers).is_err() {
         error!("Error when sending ratelimit headers to ratelimiter");
@@ -392,7 +1252,105 @@ async fn handle_request(
 
     trace!("Response: {:?}", resp);
 
-    let status = resp.status();
+    tenant
+        .usage
+        .record(&path, status.as_u16(), shared_ratelimit);
+    state.slos.record(&path, received.elapsed());
+    state
+        .request_log
+        .record(m, p, status.as_u16(), received.elapsed());
+    state
+        .major_parameters
+        .record(&path, status == StatusCode::TOO_MANY_REQUESTS);
+
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        state
+            .ratelimit_log
+            .record(m, p, tenant.usage.hash(), resp.headers());
+    }
+
+    if let Some(tag) = &tag {
+        state.tags.record(tag, status.as_u16());
+    }
+
+    // Responses to HEAD announce the length of a body they don't have
+    if http_method != HttpMethod::HEAD {
+        resp = match state.response_size.relay(p, resp) {
+            Ok(resp) => resp,
+            Err(size) => {
+                warn!("Response of {} bytes to {} {} is too large", size, m, p);
+
+                return Err(RequestError::ResponseTooLarge { size });
+            }
+        };
+    }
+
+    if let (Some(capture), Some(mut exchange)) = (&state.capture, exchange) {
+        if capture.responses() {
+            let (parts, body) = resp.into_parts();
+            let body = match hyper::body::to_bytes(body).await {
+                Ok(body) => body,
+                Err(e) => {
+                    error!("Error when reading the Discord API response: {:?}", e);
+                    capture.record(exchange);
+
+                    return Err(RequestError::RequestIssue { source: e });
+                }
+            };
+
+            exchange.response = Some(RecordedResponse {
+                status: status.as_u16(),
+                headers: capture::capture_headers(&parts.headers),
+                body: Payload::new(&body),
+            });
+            resp = Response::from_parts(parts, Body::from(body));
+        }
+
+        capture.record(exchange);
+    }
+
+    if let Some(response_validation) = &state.response_validation {
+        resp = match response_validation.validate(method, &path, resp).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                error!("Error when reading the Discord API response: {:?}", e);
+
+                return Err(RequestError::RequestIssue { source: e });
+            }
+        };
+    }
+
+    if let Some(session_guard) = &state.session_guard {
+        resp = match session_guard.record(&tenant, &path, resp).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                error!("Error when reading the Discord API response: {:?}", e);
+
+                return Err(RequestError::RequestIssue { source: e });
+            }
+        };
+    }
+
+    if let Some(gateway_url) = &state.gateway_url {
+        resp = match gateway_url.rewrite(&path, resp).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                error!("Error when reading the Discord API response: {:?}", e);
+
+                return Err(RequestError::RequestIssue { source: e });
+            }
+        };
+    }
+
+    // Only now, so captures keep all headers Discord sent
+    if let Some(response_headers) = &state.response_headers {
+        response_headers.apply(resp.headers_mut());
+    }
+
+    if let Some(reaction) = reaction {
+        reaction.finish(&resp);
+    }
+
     #[cfg(feature = "expose-metrics")]
     {
         let scope = resp
@@ -401,16 +1359,57 @@ async fn handle_request(
             .and_then(|header| header.to_str().ok())
             .unwrap_or("")
             .to_string();
-        histogram!(METRIC_KEY.as_str(), end - start, "method"=>m.to_string(), "route"=>p, "status"=>status.to_string(), "scope" => scope);
+        histogram!(
+            METRIC_KEY.as_str(),
+            end - start,
+            state.metric_labels.request(m, p, status.to_string(), scope)
+        );
+
+        budget.record();
     }
 
-    debug!("{} {} ({}): {}", m, p, request_path, status);
+    debug!(path = %request_path, status = status.as_u16(), "Request completed");
 
     Ok(resp)
 }
 
+/// Log how a request spent its budget once its deadline expired in a stage.
+fn deadline_exceeded(budget: &Budget, stage: Stage) -> RequestError {
+    debug!(
+        stage = stage.name(),
+        body = ?budget.spent(Stage::Body),
+        queue = ?budget.spent(Stage::Queue),
+        upstream = ?budget.spent(Stage::Upstream),
+        "Deadline expired"
+    );
+
+    #[cfg(feature = "expose-metrics")]
+    budget.record();
+
+    RequestError::DeadlineExceeded { stage }
+}
+
+/// Response returned instead of forwarding a request in dry run mode.
+///
+/// Deletions get an empty `204` like from Discord, everything else an empty
+/// JSON object.
+fn dry_run_response(method: &HttpMethod) -> Response<Body> {
+    let builder = Response::builder().header(DRY_RUN_HEADER, "true");
+
+    if method == HttpMethod::DELETE {
+        builder.status(StatusCode::NO_CONTENT).body(Body::empty())
+    } else {
+        builder
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from("{}"))
+    }
+    .expect("response is valid")
+}
+
 #[cfg(feature = "expose-metrics")]
-fn handle_metrics(handle: Arc<PrometheusHandle>) -> Response<Body> {
+fn handle_metrics(handle: &PrometheusHandle) -> Response<Body> {
+    runtime::record_metrics();
+
     Response::builder()
         .body(Body::from(handle.render()))
         .unwrap()
@@ -0,0 +1,144 @@
+//! Maintenance windows of routes, during which requests to them are answered
+//! by the proxy with a notice instead of being forwarded.
+
+use crate::sublimit::route_name;
+use http::{
+    header::{CONTENT_TYPE, RETRY_AFTER},
+    Response, StatusCode,
+};
+use hyper::Body;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::RwLock,
+};
+use twilight_http_ratelimiting::Path;
+
+/// Message of notices that don't set their own.
+const DEFAULT_MESSAGE: &str = "This feature is under maintenance, try again later";
+
+/// Notice returned for requests to a route under maintenance.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Notice {
+    #[serde(default = "default_message")]
+    message: String,
+    /// Seconds after which clients should retry.
+    #[serde(default)]
+    retry_after: Option<u64>,
+}
+
+fn default_message() -> String {
+    DEFAULT_MESSAGE.to_string()
+}
+
+impl Default for Notice {
+    fn default() -> Self {
+        Self {
+            message: default_message(),
+            retry_after: None,
+        }
+    }
+}
+
+/// Routes under maintenance, by route name such as `ChannelsIdMessages`.
+#[derive(Default)]
+pub struct Maintenance {
+    routes: RwLock<HashMap<String, Notice>>,
+}
+
+impl Maintenance {
+    /// Put a route under maintenance, replacing its previous notice.
+    pub fn start(&self, route: &str, notice: Notice) {
+        self.routes
+            .write()
+            .expect("maintenance poisoned")
+            .insert(route.to_string(), notice);
+    }
+
+    /// End the maintenance of a route, returning whether it was under
+    /// maintenance.
+    pub fn end(&self, route: &str) -> bool {
+        self.routes
+            .write()
+            .expect("maintenance poisoned")
+            .remove(route)
+            .is_some()
+    }
+
+    /// Notices of all routes under maintenance.
+    pub fn routes(&self) -> BTreeMap<String, Notice> {
+        let routes = self.routes.read().expect("maintenance poisoned");
+
+        routes
+            .iter()
+            .map(|(route, notice)| (route.clone(), notice.clone()))
+            .collect()
+    }
+
+    /// Response to a request to a path if its route is under maintenance.
+    pub fn respond(&self, path: &Path) -> Option<Response<Body>> {
+        let routes = self.routes.read().expect("maintenance poisoned");
+
+        if routes.is_empty() {
+            return None;
+        }
+
+        let route = route_name(path);
+        let notice = routes.get(&route)?;
+
+        let mut builder = Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(CONTENT_TYPE, "application/json");
+
+        if let Some(retry_after) = notice.retry_after {
+            builder = builder.header(RETRY_AFTER, retry_after);
+        }
+
+        let body = json!({
+            "message": notice.message,
+            "route": route,
+            "maintenance": true,
+        });
+
+        Some(
+            builder
+                .body(Body::from(body.to_string()))
+                .expect("response is valid"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Maintenance, Notice};
+    use http::StatusCode;
+    use twilight_http_ratelimiting::{Method, Path};
+
+    #[tokio::test]
+    async fn test_maintenance() {
+        let maintenance = Maintenance::default();
+        let path = Path::ChannelsIdMessagesId(Method::Patch, 1);
+
+        assert!(maintenance.respond(&path).is_none());
+
+        let notice = serde_json::from_str::<Notice>(r#"{"retry_after":60}"#).unwrap();
+        maintenance.start("ChannelsIdMessagesId", notice);
+
+        let response = maintenance.respond(&path).unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["retry-after"], "60");
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(body["route"], "ChannelsIdMessagesId");
+        assert_eq!(body["message"], Notice::default().message);
+
+        // Other routes are not affected
+        assert!(maintenance.respond(&Path::ChannelsIdMessages(1)).is_none());
+
+        assert!(maintenance.end("ChannelsIdMessagesId"));
+        assert!(!maintenance.end("ChannelsIdMessagesId"));
+        assert!(maintenance.respond(&path).is_none());
+    }
+}
@@ -0,0 +1,180 @@
+//! Requests and 429s by major parameter, finding the guild or channel that
+//! exhausts its buckets.
+//!
+//! Only the most requested parameters are tracked, using the space-saving
+//! algorithm: once full, a new parameter replaces the least requested one and
+//! inherits its count as the possible overestimation.
+
+use crate::{parse_env, sublimit::route_name};
+use serde::Serialize;
+use std::{collections::HashMap, sync::Mutex};
+use twilight_http_ratelimiting::Path;
+
+/// Amount of parameters tracked if `TOP_MAJOR_PARAMETERS` is not set.
+const DEFAULT_CAPACITY: usize = 100;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Channel,
+    Guild,
+    Webhook,
+}
+
+/// Major parameter of a path, if it has one.
+fn major_parameter(path: &Path) -> Option<(Kind, u64)> {
+    let route = route_name(path);
+
+    let kind = if route.starts_with("Channels") {
+        Kind::Channel
+    } else if route.starts_with("Guilds") {
+        Kind::Guild
+    } else if route.starts_with("Webhooks") {
+        Kind::Webhook
+    } else {
+        return None;
+    };
+
+    // The major parameter is the first argument of the variant, which is
+    // followed by others such as webhook tokens that are not exposed
+    let debug = format!("{:?}", path);
+    let arguments = debug.get(route.len() + 1..)?;
+    let id = arguments.split([',', ')']).next()?;
+
+    Some((kind, id.trim().parse().ok()?))
+}
+
+#[derive(Clone, Copy, Default)]
+struct Counts {
+    requests: u64,
+    ratelimited: u64,
+    /// Requests possibly counted for another parameter this one replaced.
+    error: u64,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Entry {
+    kind: Kind,
+    id: u64,
+    requests: u64,
+    ratelimited: u64,
+    /// Upper bound of how much `requests` may be overestimated.
+    error: u64,
+}
+
+/// Configured via `TOP_MAJOR_PARAMETERS`.
+pub struct MajorParameters {
+    capacity: usize,
+    counts: Mutex<HashMap<(Kind, u64), Counts>>,
+}
+
+impl MajorParameters {
+    pub fn from_env() -> Self {
+        Self::new(parse_env("TOP_MAJOR_PARAMETERS").unwrap_or(DEFAULT_CAPACITY))
+    }
+
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a request to a path Discord responded to.
+    pub fn record(&self, path: &Path, ratelimited: bool) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let key = match major_parameter(path) {
+            Some(key) => key,
+            None => return,
+        };
+
+        let mut counts = self.counts.lock().expect("major parameters poisoned");
+
+        if !counts.contains_key(&key) && counts.len() >= self.capacity {
+            let (least, replaced) = counts
+                .iter()
+                .min_by_key(|(_, counts)| counts.requests)
+                .map(|(key, counts)| (*key, *counts))
+                .expect("capacity is not 0");
+
+            counts.remove(&least);
+            counts.insert(
+                key,
+                Counts {
+                    requests: replaced.requests,
+                    ratelimited: 0,
+                    error: replaced.requests,
+                },
+            );
+        }
+
+        let entry = counts.entry(key).or_default();
+        entry.requests += 1;
+        entry.ratelimited += u64::from(ratelimited);
+    }
+
+    /// The tracked parameters, most requested first.
+    pub fn top(&self) -> Vec<Entry> {
+        let counts = self.counts.lock().expect("major parameters poisoned");
+
+        let mut entries = counts
+            .iter()
+            .map(|((kind, id), counts)| Entry {
+                kind: *kind,
+                id: *id,
+                requests: counts.requests,
+                ratelimited: counts.ratelimited,
+                error: counts.error,
+            })
+            .collect::<Vec<_>>();
+
+        entries.sort_by(|a, b| b.requests.cmp(&a.requests).then(a.id.cmp(&b.id)));
+
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{major_parameter, Kind, MajorParameters};
+    use twilight_http_ratelimiting::Path;
+
+    #[test]
+    fn test_major_parameter() {
+        assert_eq!(
+            major_parameter(&Path::ChannelsIdMessages(1)),
+            Some((Kind::Channel, 1))
+        );
+        assert_eq!(
+            major_parameter(&Path::GuildsIdMembers(2)),
+            Some((Kind::Guild, 2))
+        );
+        assert_eq!(
+            major_parameter(&Path::WebhooksIdToken(3, "token".to_string())),
+            Some((Kind::Webhook, 3))
+        );
+        assert_eq!(major_parameter(&Path::Gateway), None);
+    }
+
+    #[test]
+    fn test_top() {
+        let parameters = MajorParameters::new(2);
+
+        for _ in 0..3 {
+            parameters.record(&Path::ChannelsIdMessages(1), true);
+        }
+
+        parameters.record(&Path::ChannelsIdMessages(2), false);
+        parameters.record(&Path::GuildsId(3), false);
+
+        let top = parameters.top();
+        assert_eq!(top.len(), 2);
+        assert_eq!((top[0].id, top[0].requests, top[0].ratelimited), (1, 3, 3));
+        // The guild replaced the least requested channel
+        assert_eq!(top[1].kind, Kind::Guild);
+        assert_eq!((top[1].requests, top[1].error), (2, 1));
+    }
+}
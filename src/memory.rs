@@ -0,0 +1,124 @@
+//! Admission control based on the memory used by the process, shedding bulk
+//! traffic while the resident set size is above a watermark so floods of
+//! requests with large bodies don't get the process killed.
+
+use crate::{parse_env, traffic::Class};
+use std::{
+    fs,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::time::{self, Duration};
+use tracing::{info, warn};
+
+/// Interval in which the resident set size is sampled.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Configured via `MEMORY_WATERMARK` in bytes.
+pub struct MemoryPressure {
+    watermark: u64,
+    /// Resident set size at the last sample.
+    rss: AtomicU64,
+}
+
+impl MemoryPressure {
+    pub fn from_env() -> Option<Self> {
+        let watermark = parse_env("MEMORY_WATERMARK")?;
+
+        if resident_set_size().is_none() {
+            warn!("MEMORY_WATERMARK is set, but the memory usage can't be read on this system");
+
+            return None;
+        }
+
+        Some(Self {
+            watermark,
+            rss: AtomicU64::new(0),
+        })
+    }
+
+    /// Whether a request of a class is admitted at the current memory usage.
+    pub fn admit(&self, class: Class) -> bool {
+        class == Class::Interactive || !self.is_high()
+    }
+
+    fn is_high(&self) -> bool {
+        self.rss.load(Ordering::Relaxed) > self.watermark
+    }
+
+    /// Sample the resident set size until the process exits.
+    pub async fn run(&self) {
+        let mut interval = time::interval(SAMPLE_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let rss = match resident_set_size() {
+                Some(rss) => rss,
+                None => continue,
+            };
+
+            let was_high = self.is_high();
+            self.rss.store(rss, Ordering::Relaxed);
+
+            match (was_high, self.is_high()) {
+                (false, true) => warn!(
+                    "Memory usage of {} bytes exceeds MEMORY_WATERMARK, shedding bulk requests",
+                    rss
+                ),
+                (true, false) => info!("Memory usage is below MEMORY_WATERMARK again"),
+                _ => {}
+            }
+
+            #[cfg(feature = "expose-metrics")]
+            metrics::gauge!(
+                format!("{}_resident_memory_bytes", crate::METRIC_KEY.as_str()),
+                rss as f64
+            );
+        }
+    }
+}
+
+/// Resident set size of the process in bytes, read from procfs.
+fn resident_set_size() -> Option<u64> {
+    parse_status(&fs::read_to_string("/proc/self/status").ok()?)
+}
+
+fn parse_status(status: &str) -> Option<u64> {
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes = line
+        .trim_start_matches("VmRSS:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+
+    Some(kilobytes * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_status, MemoryPressure};
+    use crate::traffic::Class;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn test_parse_status() {
+        let status = "Name:\thttp-proxy\nVmPeak:\t  20000 kB\nVmRSS:\t    1234 kB\nThreads:\t4\n";
+        assert_eq!(parse_status(status), Some(1234 * 1024));
+        assert_eq!(parse_status("Name:\thttp-proxy\n"), None);
+    }
+
+    #[test]
+    fn test_admit() {
+        let pressure = MemoryPressure {
+            watermark: 100,
+            rss: AtomicU64::new(50),
+        };
+        assert!(pressure.admit(Class::Bulk));
+
+        pressure.rss.store(200, Ordering::Relaxed);
+        assert!(!pressure.admit(Class::Bulk));
+        assert!(pressure.admit(Class::Interactive));
+    }
+}
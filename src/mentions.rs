@@ -0,0 +1,173 @@
+//! Default `allowed_mentions` for messages sent through the proxy, so a client
+//! that doesn't set any can't accidentally ping `@everyone` or whole roles.
+//!
+//! Payloads that set `allowed_mentions` themselves are forwarded unchanged.
+
+use crate::limits;
+use serde_json::{Map, Value};
+use std::{collections::HashSet, env};
+use tracing::warn;
+use twilight_http_ratelimiting::{Method, Path};
+
+/// Interaction response types whose `data` is a message,
+/// `CHANNEL_MESSAGE_WITH_SOURCE` and `UPDATE_MESSAGE`.
+const MESSAGE_RESPONSE_TYPES: [u64; 2] = [4, 7];
+
+/// Configured via `DEFAULT_ALLOWED_MENTIONS` and `ALLOWED_MENTIONS_OPT_OUT`.
+pub struct MentionPolicy {
+    allowed_mentions: Value,
+    /// Hashes of tokens whose payloads are forwarded unchanged.
+    opt_out: HashSet<String>,
+}
+
+impl MentionPolicy {
+    /// Returns `None` if no policy is configured or it isn't a JSON object.
+    pub fn from_env() -> Option<Self> {
+        let value = env::var("DEFAULT_ALLOWED_MENTIONS").ok()?;
+
+        let allowed_mentions = match serde_json::from_str::<Value>(&value) {
+            Ok(value @ Value::Object(_)) => value,
+            Ok(_) => {
+                warn!("DEFAULT_ALLOWED_MENTIONS is not a JSON object, not enforcing it");
+
+                return None;
+            }
+            Err(e) => {
+                warn!(
+                    "DEFAULT_ALLOWED_MENTIONS is invalid JSON, not enforcing it: {}",
+                    e
+                );
+
+                return None;
+            }
+        };
+
+        let opt_out = env::var("ALLOWED_MENTIONS_OPT_OUT")
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|hash| !hash.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Some(Self {
+            allowed_mentions,
+            opt_out,
+        })
+    }
+
+    /// Whether requests of a token to a path create or edit messages the
+    /// policy applies to.
+    pub fn applies(&self, hash: &str, method: Method, path: &Path) -> bool {
+        limits::is_message_route(method, path) && !self.opt_out.contains(hash)
+    }
+
+    /// The payload with the default `allowed_mentions` added, or `None` if it
+    /// already sets them or isn't a message.
+    pub fn apply(&self, path: &Path, payload: &[u8]) -> Option<Vec<u8>> {
+        let mut value = serde_json::from_slice::<Value>(payload).ok()?;
+
+        let message = match (path, value.as_object_mut()?) {
+            (Path::InteractionCallback(_), response) => {
+                let kind = response.get("type").and_then(Value::as_u64)?;
+
+                if !MESSAGE_RESPONSE_TYPES.contains(&kind) {
+                    return None;
+                }
+
+                response
+                    .entry("data")
+                    .or_insert_with(|| Value::Object(Map::new()))
+                    .as_object_mut()?
+            }
+            (_, message) => message,
+        };
+
+        // An explicit `null` uses Discord's default of parsing all mentions
+        if message
+            .get("allowed_mentions")
+            .is_some_and(|allowed_mentions| !allowed_mentions.is_null())
+        {
+            return None;
+        }
+
+        message.insert(
+            "allowed_mentions".to_string(),
+            self.allowed_mentions.clone(),
+        );
+
+        serde_json::to_vec(&value).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MentionPolicy;
+    use serde_json::{json, Value};
+    use std::collections::HashSet;
+    use twilight_http_ratelimiting::{Method, Path};
+
+    fn apply(path: &Path, payload: Value) -> Option<Value> {
+        let policy = MentionPolicy {
+            allowed_mentions: json!({"parse": ["users"]}),
+            opt_out: HashSet::from(["abc".to_string()]),
+        };
+
+        policy
+            .apply(path, payload.to_string().as_bytes())
+            .map(|payload| serde_json::from_slice(&payload).unwrap())
+    }
+
+    #[test]
+    fn test_applies() {
+        let policy = MentionPolicy {
+            allowed_mentions: json!({"parse": []}),
+            opt_out: HashSet::from(["abc".to_string()]),
+        };
+
+        assert!(policy.applies("def", Method::Post, &Path::ChannelsIdMessages(1)));
+        assert!(policy.applies(
+            "def",
+            Method::Patch,
+            &Path::ChannelsIdMessagesId(Method::Patch, 1)
+        ));
+        assert!(!policy.applies("abc", Method::Post, &Path::ChannelsIdMessages(1)));
+        assert!(!policy.applies("def", Method::Get, &Path::ChannelsIdMessages(1)));
+        assert!(!policy.applies("def", Method::Post, &Path::ChannelsIdPins(1)));
+    }
+
+    #[test]
+    fn test_apply() {
+        let messages = Path::ChannelsIdMessages(1);
+
+        assert_eq!(
+            apply(&messages, json!({"content": "@everyone"})),
+            Some(json!({"content": "@everyone", "allowed_mentions": {"parse": ["users"]}}))
+        );
+        assert_eq!(
+            apply(&messages, json!({"allowed_mentions": null})),
+            Some(json!({"allowed_mentions": {"parse": ["users"]}}))
+        );
+        assert_eq!(
+            apply(
+                &messages,
+                json!({"allowed_mentions": {"parse": ["everyone"]}})
+            ),
+            None
+        );
+        assert_eq!(apply(&messages, json!([])), None);
+
+        let callback = Path::InteractionCallback(1);
+
+        assert_eq!(
+            apply(&callback, json!({"type": 4, "data": {"content": "hi"}})),
+            Some(
+                json!({"type": 4, "data": {"content": "hi", "allowed_mentions": {"parse": ["users"]}}})
+            )
+        );
+        assert_eq!(apply(&callback, json!({"type": 5})), None);
+    }
+}
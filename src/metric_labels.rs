@@ -0,0 +1,166 @@
+//! Labels of the exported metrics, to fit the conventions of an existing
+//! monitoring setup.
+
+use metrics::Label;
+use metrics_exporter_prometheus::PrometheusBuilder;
+use std::env;
+use tracing::warn;
+
+/// Whether a name is a valid Prometheus label name.
+fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Configured via `METRIC_LABELS`, labels added to every metric, and
+/// `METRIC_DIMENSIONS`, the labels of the request histogram.
+pub struct MetricLabels {
+    constant: Vec<(String, String)>,
+    method: bool,
+    route: bool,
+    status: bool,
+    scope: bool,
+}
+
+impl MetricLabels {
+    pub fn from_env() -> Self {
+        let mut labels = Self::new(&env::var("METRIC_LABELS").unwrap_or_default());
+
+        if let Ok(value) = env::var("METRIC_DIMENSIONS") {
+            labels.set_dimensions(&value);
+        }
+
+        labels
+    }
+
+    fn new(constant: &str) -> Self {
+        let constant = constant
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let label = entry
+                    .split_once('=')
+                    .map(|(name, value)| (name.trim(), value.trim()))
+                    .filter(|(name, _)| is_label_name(name));
+
+                if label.is_none() {
+                    warn!("Ignoring invalid metric label {:?}", entry);
+                }
+
+                label.map(|(name, value)| (name.to_string(), value.to_string()))
+            })
+            .collect();
+
+        Self {
+            constant,
+            method: true,
+            route: true,
+            status: true,
+            scope: true,
+        }
+    }
+
+    /// Enable only the listed dimensions of the request histogram.
+    fn set_dimensions(&mut self, value: &str) {
+        self.method = false;
+        self.route = false;
+        self.status = false;
+        self.scope = false;
+
+        for dimension in value.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match dimension {
+                "method" => self.method = true,
+                "route" => self.route = true,
+                "status" => self.status = true,
+                "scope" => self.scope = true,
+                _ => warn!("Ignoring unknown metric dimension {:?}", dimension),
+            }
+        }
+    }
+
+    /// Add the constant labels to every metric of the exporter.
+    pub fn apply(&self, builder: PrometheusBuilder) -> PrometheusBuilder {
+        self.constant
+            .iter()
+            .fold(builder, |builder, (name, value)| {
+                builder.add_global_label(name, value)
+            })
+    }
+
+    /// Labels of a request in the request histogram, for the enabled
+    /// dimensions.
+    pub fn request(
+        &self,
+        method: &'static str,
+        route: &'static str,
+        status: String,
+        scope: String,
+    ) -> Vec<Label> {
+        let mut labels = Vec::with_capacity(4);
+
+        if self.method {
+            labels.push(Label::new("method", method));
+        }
+
+        if self.route {
+            labels.push(Label::new("route", route));
+        }
+
+        if self.status {
+            labels.push(Label::new("status", status));
+        }
+
+        if self.scope {
+            labels.push(Label::new("scope", scope));
+        }
+
+        labels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_label_name, MetricLabels};
+
+    #[test]
+    fn test_is_label_name() {
+        assert!(is_label_name("instance"));
+        assert!(is_label_name("_env_2"));
+        assert!(!is_label_name("2env"));
+        assert!(!is_label_name("env-name"));
+        assert!(!is_label_name(""));
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut labels = MetricLabels::new("instance=proxy-1, environment = prod,bad-name=x,x");
+        assert_eq!(
+            labels.constant,
+            [
+                ("instance".to_string(), "proxy-1".to_string()),
+                ("environment".to_string(), "prod".to_string())
+            ]
+        );
+        assert_eq!(
+            labels
+                .request("GET", "Gateway", "200".into(), "".into())
+                .len(),
+            4
+        );
+
+        labels.set_dimensions("route, status,bucket");
+        let request = labels.request("GET", "Gateway", "200".into(), "".into());
+        assert_eq!(
+            request
+                .iter()
+                .map(|label| (label.key(), label.value()))
+                .collect::<Vec<_>>(),
+            [("route", "Gateway"), ("status", "200")]
+        );
+    }
+}
@@ -0,0 +1,217 @@
+//! Mirroring of requests to a shadow upstream.
+//!
+//! Mirrored requests are sent in the background and their responses are
+//! discarded, so they never affect the response to the client or the
+//! ratelimiter.
+//!
+//! Only `GET` and `HEAD` requests are mirrored by default, as mirrored
+//! requests carry the client's `Authorization` and a shadow upstream that
+//! forwards them would execute mutations twice.
+
+use crate::{
+    parse_env,
+    upstream::{Upstream, UpstreamError},
+};
+use http::{
+    header::{CONTENT_LENGTH, HOST},
+    HeaderMap, Method, Request,
+};
+use hyper::{body::Bytes, client::HttpConnector, Body, Client};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use std::{env, sync::Arc};
+use tokio::{
+    sync::Semaphore,
+    time::{timeout, Duration},
+};
+use tracing::{debug, warn};
+
+/// How long to wait for the shadow upstream before giving up on a request.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Mirrored requests in flight if `MIRROR_MAX_IN_FLIGHT` is not set.
+const DEFAULT_MAX_IN_FLIGHT: usize = 100;
+
+pub struct Mirror {
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    /// Percentage of requests to mirror.
+    percent: f64,
+    /// Methods of requests to mirror.
+    methods: Vec<Method>,
+    /// Permits of mirrored requests in flight, further requests aren't
+    /// mirrored.
+    in_flight: Arc<Semaphore>,
+    upstream: Upstream,
+}
+
+/// Parse a comma-separated list of methods, skipping invalid ones.
+fn parse_methods(value: &str) -> Vec<Method> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|method| !method.is_empty())
+        .filter_map(
+            |method| match Method::from_bytes(method.to_ascii_uppercase().as_bytes()) {
+                Ok(method) => Some(method),
+                Err(_) => {
+                    warn!("MIRROR_METHODS contains invalid method {:?}", method);
+
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+impl Mirror {
+    /// Load the configuration from `MIRROR_URL`, `MIRROR_PERCENT`,
+    /// `MIRROR_METHODS` and `MIRROR_MAX_IN_FLIGHT`.
+    ///
+    /// Returns `None` if mirroring is not enabled.
+    pub fn from_env() -> Result<Option<Self>, UpstreamError> {
+        let upstream = match env::var("MIRROR_URL") {
+            Ok(url) => Upstream::new(&url)?,
+            Err(_) => return Ok(None),
+        };
+
+        let mut percent = parse_env("MIRROR_PERCENT").unwrap_or(100.0);
+
+        if !(0.0..=100.0).contains(&percent) {
+            warn!("MIRROR_PERCENT must be between 0 and 100, using 100");
+            percent = 100.0;
+        }
+
+        let methods = env::var("MIRROR_METHODS")
+            .map(|value| parse_methods(&value))
+            .unwrap_or_else(|_| vec![Method::GET, Method::HEAD]);
+        let max_in_flight = parse_env("MIRROR_MAX_IN_FLIGHT").unwrap_or(DEFAULT_MAX_IN_FLIGHT);
+
+        Ok(Some(Self::new(upstream, percent, methods, max_in_flight)))
+    }
+
+    fn new(upstream: Upstream, percent: f64, methods: Vec<Method>, max_in_flight: usize) -> Self {
+        let connector = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        Self {
+            client: Client::builder().build(connector),
+            percent,
+            methods,
+            in_flight: Arc::new(Semaphore::new(max_in_flight)),
+            upstream,
+        }
+    }
+
+    /// Decide whether to mirror a request.
+    pub fn sample(&self, method: &Method) -> bool {
+        self.methods.contains(method) && fastrand::f64() * 100.0 < self.percent
+    }
+
+    /// Send a copy of a request to the shadow upstream in the background.
+    ///
+    /// `headers` are the headers forwarded to Discord, the `Host` is replaced
+    /// with the shadow upstream's. The request is dropped if too many are in
+    /// flight already.
+    pub fn send(
+        &self,
+        method: Method,
+        api: &str,
+        path: &str,
+        query: Option<&str>,
+        headers: &HeaderMap,
+        body: Bytes,
+    ) {
+        let permit = match Arc::clone(&self.in_flight).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                debug!("Not mirroring request, too many are in flight");
+
+                return;
+            }
+        };
+
+        let uri = match self.upstream.uri(api, path, query) {
+            Ok(uri) => uri,
+            Err(e) => {
+                debug!("Failed to create URI for mirroring: {:?}", e);
+
+                return;
+            }
+        };
+
+        let mut request = Request::new(Body::from(body));
+        *request.method_mut() = method;
+        *request.uri_mut() = uri;
+        *request.headers_mut() = headers.clone();
+        request.headers_mut().remove(CONTENT_LENGTH);
+        request
+            .headers_mut()
+            .insert(HOST, self.upstream.host().clone());
+
+        let response = self.client.request(request);
+
+        tokio::spawn(async move {
+            let _permit = permit;
+
+            match timeout(TIMEOUT, response).await {
+                Ok(Ok(response)) => debug!("Mirror responded with {}", response.status()),
+                Ok(Err(e)) => debug!("Error when mirroring request: {:?}", e),
+                Err(_) => debug!("Mirrored request timed out"),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_methods, Mirror};
+    use crate::upstream::Upstream;
+    use http::{HeaderMap, Method};
+    use hyper::body::Bytes;
+
+    fn mirror(percent: f64) -> Mirror {
+        Mirror::new(
+            Upstream::new("http://127.0.0.1:1").unwrap(),
+            percent,
+            vec![Method::GET, Method::HEAD],
+            1,
+        )
+    }
+
+    #[test]
+    fn test_sample() {
+        assert!((0..100).all(|_| mirror(100.0).sample(&Method::GET)));
+        assert!((0..100).all(|_| !mirror(0.0).sample(&Method::GET)));
+        assert!(!mirror(100.0).sample(&Method::POST));
+    }
+
+    #[test]
+    fn test_parse_methods() {
+        assert_eq!(parse_methods("get, POST,,b@d"), [Method::GET, Method::POST]);
+    }
+
+    #[tokio::test]
+    async fn test_max_in_flight() {
+        let mirror = mirror(100.0);
+
+        let send = || {
+            mirror.send(
+                Method::GET,
+                "/api/v10",
+                "/gateway",
+                None,
+                &HeaderMap::new(),
+                Bytes::new(),
+            );
+        };
+
+        send();
+        assert_eq!(mirror.in_flight.available_permits(), 0);
+
+        // Requests beyond the limit are dropped
+        send();
+        assert_eq!(mirror.in_flight.available_permits(), 0);
+    }
+}
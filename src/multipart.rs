@@ -0,0 +1,392 @@
+//! Validation of `multipart/form-data` bodies, used for attachment uploads.
+//!
+//! Only the framing is validated: the delimiters, part headers and the
+//! `Content-Disposition` every part needs. The part contents are forwarded
+//! as-is.
+
+use http::{header::CONTENT_TYPE, HeaderMap};
+use std::{
+    error::Error,
+    fmt::{Display, Formatter, Result as FmtResult},
+};
+
+/// Maximum length of a boundary.
+/// https://datatracker.ietf.org/doc/html/rfc2046#section-5.1.1
+const MAX_BOUNDARY_LENGTH: usize = 70;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum InvalidMultipart {
+    /// The `boundary` parameter is not a valid boundary.
+    InvalidBoundary,
+    /// A part has a header line that isn't `name: value`.
+    InvalidHeader { part: usize },
+    /// A delimiter is not followed by a line break or the closing `--`.
+    MalformedDelimiter { part: usize },
+    /// The `Content-Type` has no `boundary` parameter.
+    MissingBoundary,
+    /// A part has no `Content-Disposition: form-data` header with a name.
+    MissingContentDisposition { part: usize },
+    /// The body doesn't contain the opening delimiter.
+    MissingDelimiter,
+    /// The body has no parts.
+    NoParts,
+    /// The body ends before the closing delimiter.
+    NotTerminated { part: usize },
+}
+
+impl Display for InvalidMultipart {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::InvalidBoundary => f.write_str("boundary is invalid"),
+            Self::InvalidHeader { part } => {
+                f.write_str("part ")?;
+                part.fmt(f)?;
+
+                f.write_str(" has an invalid header")
+            }
+            Self::MalformedDelimiter { part } => {
+                f.write_str("delimiter before part ")?;
+                part.fmt(f)?;
+
+                f.write_str(" is malformed")
+            }
+            Self::MissingBoundary => f.write_str("content type has no boundary"),
+            Self::MissingContentDisposition { part } => {
+                f.write_str("part ")?;
+                part.fmt(f)?;
+
+                f.write_str(" has no form-data content disposition with a name")
+            }
+            Self::MissingDelimiter => f.write_str("body doesn't contain the boundary"),
+            Self::NoParts => f.write_str("body has no parts"),
+            Self::NotTerminated { part } => {
+                f.write_str("body ends within part ")?;
+                part.fmt(f)
+            }
+        }
+    }
+}
+
+impl Error for InvalidMultipart {}
+
+/// Boundary of a `multipart/form-data` request.
+///
+/// Returns `None` if the request has a different content type.
+pub fn boundary(headers: &HeaderMap) -> Option<Result<String, InvalidMultipart>> {
+    let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    let (mime, rest) = content_type.split_once(';').unwrap_or((content_type, ""));
+
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+
+    let boundary = match parameters(rest)
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+    {
+        Some((_, boundary)) => boundary,
+        None => return Some(Err(InvalidMultipart::MissingBoundary)),
+    };
+
+    Some(if is_valid_boundary(&boundary) {
+        Ok(boundary)
+    } else {
+        Err(InvalidMultipart::InvalidBoundary)
+    })
+}
+
+/// A part of a `multipart/form-data` body.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Part<'a> {
+    /// Name from the part's `Content-Disposition`.
+    pub name: String,
+    pub content: &'a [u8],
+}
+
+/// Split a `multipart/form-data` body into its parts, validating its
+/// framing.
+pub fn parse<'a>(body: &'a [u8], boundary: &str) -> Result<Vec<Part<'a>>, InvalidMultipart> {
+    let delimiter = format!("--{}", boundary);
+    let delimiter = delimiter.as_bytes();
+
+    // The first delimiter may be preceded by a preamble, which is ignored
+    let mut position = if body.starts_with(delimiter) {
+        delimiter.len()
+    } else {
+        find(body, &[b"\r\n", delimiter].concat()).ok_or(InvalidMultipart::MissingDelimiter)?
+            + 2
+            + delimiter.len()
+    };
+
+    let mut parts = Vec::new();
+
+    loop {
+        let rest = &body[position..];
+
+        if rest.starts_with(b"--") {
+            return if parts.is_empty() {
+                Err(InvalidMultipart::NoParts)
+            } else {
+                Ok(parts)
+            };
+        }
+
+        let part = parts.len() + 1;
+
+        // Delimiters may be followed by whitespace before the line break
+        let padding = rest
+            .iter()
+            .take_while(|byte| matches!(byte, b' ' | b'\t'))
+            .count();
+
+        if !rest[padding..].starts_with(b"\r\n") {
+            return Err(InvalidMultipart::MalformedDelimiter { part });
+        }
+
+        let headers_start = position + padding + 2;
+        let rest = &body[headers_start..];
+
+        let (headers, content_start) = if rest.starts_with(b"\r\n") {
+            (&rest[..0], headers_start + 2)
+        } else {
+            let end = find(rest, b"\r\n\r\n").ok_or(InvalidMultipart::NotTerminated { part })?;
+
+            (&rest[..end], headers_start + end + 4)
+        };
+
+        let name = validate_headers(headers, part)?;
+
+        let end = find(&body[content_start..], &[b"\r\n", delimiter].concat())
+            .ok_or(InvalidMultipart::NotTerminated { part })?;
+
+        parts.push(Part {
+            name,
+            content: &body[content_start..content_start + end],
+        });
+        position = content_start + end + 2 + delimiter.len();
+    }
+}
+
+/// Validate the headers of a part, returning the name from its
+/// `Content-Disposition`.
+fn validate_headers(headers: &[u8], part: usize) -> Result<String, InvalidMultipart> {
+    let headers =
+        std::str::from_utf8(headers).map_err(|_| InvalidMultipart::InvalidHeader { part })?;
+    let mut disposition_name = None;
+
+    for line in headers.split("\r\n").filter(|line| !line.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or(InvalidMultipart::InvalidHeader { part })?;
+
+        // Rejecting leading whitespace also rejects obsolete line folding
+        if name.is_empty()
+            || !name.bytes().all(is_token)
+            || value
+                .bytes()
+                .any(|byte| byte.is_ascii_control() && byte != b'\t')
+        {
+            return Err(InvalidMultipart::InvalidHeader { part });
+        }
+
+        if name.eq_ignore_ascii_case("content-disposition") {
+            let (kind, rest) = value.split_once(';').unwrap_or((value, ""));
+
+            disposition_name = kind
+                .trim()
+                .eq_ignore_ascii_case("form-data")
+                .then(|| {
+                    parameters(rest)
+                        .into_iter()
+                        .find(|(name, _)| name.eq_ignore_ascii_case("name"))
+                        .map(|(_, value)| value)
+                })
+                .flatten();
+        }
+    }
+
+    disposition_name.ok_or(InvalidMultipart::MissingContentDisposition { part })
+}
+
+/// Parse the `name=value` parameters of a header value, unquoting quoted
+/// values.
+fn parameters(value: &str) -> Vec<(String, String)> {
+    let mut parameters = Vec::new();
+    let mut chars = value.chars().peekable();
+
+    loop {
+        let name = chars
+            .by_ref()
+            .skip_while(|c| *c == ';' || c.is_whitespace())
+            .take_while(|c| *c != '=')
+            .collect::<String>();
+
+        if name.is_empty() {
+            break;
+        }
+
+        let mut value = String::new();
+
+        if chars.peek() == Some(&'"') {
+            chars.next();
+
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    _ => value.push(c),
+                }
+            }
+
+            // Skip anything up to the next parameter
+            chars.by_ref().take_while(|c| *c != ';').for_each(drop);
+        } else {
+            value = chars.by_ref().take_while(|c| *c != ';').collect();
+        }
+
+        parameters.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    parameters
+}
+
+fn is_valid_boundary(boundary: &str) -> bool {
+    (1..=MAX_BOUNDARY_LENGTH).contains(&boundary.len())
+        && !boundary.ends_with(' ')
+        && boundary
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"'()+_,-./:=? ".contains(&byte))
+}
+
+/// Whether a byte is allowed in a header name.
+const fn is_token(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric()
+        || matches!(
+            byte,
+            b'!' | b'#'
+                | b'$'
+                | b'%'
+                | b'&'
+                | b'\''
+                | b'*'
+                | b'+'
+                | b'-'
+                | b'.'
+                | b'^'
+                | b'_'
+                | b'`'
+                | b'|'
+                | b'~'
+        )
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{boundary, parse, InvalidMultipart};
+    use http::{header::CONTENT_TYPE, HeaderMap, HeaderValue};
+
+    fn validate(body: &[u8], boundary: &str) -> Result<(), InvalidMultipart> {
+        parse(body, boundary).map(drop)
+    }
+
+    fn content_type(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(value));
+
+        headers
+    }
+
+    #[test]
+    fn test_boundary() {
+        assert_eq!(
+            boundary(&content_type("multipart/form-data; boundary=abc123")),
+            Some(Ok("abc123".to_string()))
+        );
+        assert_eq!(
+            boundary(&content_type(
+                "Multipart/Form-Data; charset=utf-8; Boundary=\"a b:c\""
+            )),
+            Some(Ok("a b:c".to_string()))
+        );
+        assert_eq!(
+            boundary(&content_type("multipart/form-data")),
+            Some(Err(InvalidMultipart::MissingBoundary))
+        );
+        assert_eq!(
+            boundary(&content_type("multipart/form-data; boundary=\"\"")),
+            Some(Err(InvalidMultipart::InvalidBoundary))
+        );
+        assert_eq!(boundary(&content_type("application/json")), None);
+        assert_eq!(boundary(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_valid() {
+        let body = b"preamble\r\n\
+            --abc\r\n\
+            Content-Disposition: form-data; name=\"payload_json\"\r\n\
+            Content-Type: application/json\r\n\
+            \r\n\
+            {\"content\":\"hi\"}\r\n\
+            --abc  \r\n\
+            Content-Disposition: form-data; name=\"files[0]\"; filename=\"a;b.png\"\r\n\
+            \r\n\
+            \x89PNG\r\n--ab\r\n\
+            --abc--\r\n";
+
+        let parts = parse(body, "abc").unwrap();
+
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name, "payload_json");
+        assert_eq!(parts[0].content, b"{\"content\":\"hi\"}");
+        assert_eq!(parts[1].name, "files[0]");
+        assert_eq!(parts[1].content, b"\x89PNG\r\n--ab");
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(
+            validate(b"--abc--\r\n", "abc"),
+            Err(InvalidMultipart::NoParts)
+        );
+        assert_eq!(
+            validate(b"--xyz\r\n\r\n--xyz--", "abc"),
+            Err(InvalidMultipart::MissingDelimiter)
+        );
+        assert_eq!(
+            validate(b"--abcd\r\n", "abc"),
+            Err(InvalidMultipart::MalformedDelimiter { part: 1 })
+        );
+        assert_eq!(
+            validate(
+                b"--abc\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nhello",
+                "abc"
+            ),
+            Err(InvalidMultipart::NotTerminated { part: 1 })
+        );
+        assert_eq!(
+            validate(b"--abc\r\n\r\nhello\r\n--abc--", "abc"),
+            Err(InvalidMultipart::MissingContentDisposition { part: 1 })
+        );
+        assert_eq!(
+            validate(
+                b"--abc\r\nContent-Disposition: attachment; name=\"a\"\r\n\r\nhello\r\n--abc--",
+                "abc"
+            ),
+            Err(InvalidMultipart::MissingContentDisposition { part: 1 })
+        );
+        assert_eq!(
+            validate(
+                b"--abc\r\nContent-Disposition: form-data; name=\"a\"\r\n Folded: yes\r\n\r\nhello\r\n--abc--",
+                "abc"
+            ),
+            Err(InvalidMultipart::InvalidHeader { part: 1 })
+        );
+    }
+}
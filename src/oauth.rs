@@ -0,0 +1,242 @@
+//! Bearer tokens of the application obtained with the OAuth2 client
+//! credentials grant, so internal tools can get short-lived tokens from the
+//! proxy without handling the application's secret themselves.
+
+use crate::upstream::Upstream;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Request, StatusCode,
+};
+use hyper::{client::connect::Connect, Body, Client};
+use serde::{Deserialize, Serialize};
+use std::{env, error::Error};
+use tokio::{
+    sync::Mutex,
+    time::{timeout, Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// Scopes requested if `OAUTH2_SCOPES` is not set.
+const DEFAULT_SCOPES: &str = "identify";
+
+/// How long before a token expires a new one is requested, so tools never
+/// receive a token that expires while they use it.
+const REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+/// How long to wait for Discord to respond.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A token as Discord returns it and the admin API serves it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Token {
+    access_token: String,
+    token_type: String,
+    /// Seconds until the token expires.
+    expires_in: u64,
+    scope: String,
+}
+
+struct Cached {
+    token: Token,
+    expires_at: Instant,
+}
+
+/// Configured via `OAUTH2_CLIENT_ID`, `OAUTH2_CLIENT_SECRET` and
+/// `OAUTH2_SCOPES`.
+pub struct ClientCredentials {
+    /// Basic authorization of the application.
+    authorization: String,
+    /// Form-encoded scopes.
+    scopes: String,
+    /// Held while a token is requested, so concurrent callers share it.
+    cached: Mutex<Option<Cached>>,
+}
+
+impl ClientCredentials {
+    /// Returns `None` unless both the client ID and secret are set.
+    pub fn from_env() -> Option<Self> {
+        match (
+            env::var("OAUTH2_CLIENT_ID"),
+            env::var("OAUTH2_CLIENT_SECRET"),
+        ) {
+            (Ok(id), Ok(secret)) => Some(Self::new(
+                &id,
+                &secret,
+                &env::var("OAUTH2_SCOPES").unwrap_or_else(|_| DEFAULT_SCOPES.to_string()),
+            )),
+            (Ok(_), Err(_)) | (Err(_), Ok(_)) => {
+                warn!("Only one of OAUTH2_CLIENT_ID and OAUTH2_CLIENT_SECRET is set, not issuing OAuth2 tokens");
+
+                None
+            }
+            (Err(_), Err(_)) => None,
+        }
+    }
+
+    /// Scopes are separated by spaces or commas.
+    fn new(id: &str, secret: &str, scopes: &str) -> Self {
+        let scopes = scopes
+            .split([' ', ','])
+            .filter(|scope| !scope.is_empty())
+            .collect::<Vec<_>>()
+            .join("+");
+
+        Self {
+            authorization: format!("Basic {}", STANDARD.encode(format!("{}:{}", id, secret))),
+            scopes,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// The cached token, or a new one if it expires soon.
+    ///
+    /// The token's `expires_in` is the time remaining until it expires.
+    pub async fn token<C>(
+        &self,
+        client: &Client<C, Body>,
+        upstream: &Upstream,
+    ) -> Result<Token, Box<dyn Error>>
+    where
+        C: Connect + Clone + Send + Sync + 'static,
+    {
+        let mut cached = self.cached.lock().await;
+        let now = Instant::now();
+
+        if let Some(cached) = cached
+            .as_ref()
+            .filter(|cached| cached.expires_at > now + REFRESH_MARGIN)
+        {
+            return Ok(Token {
+                expires_in: (cached.expires_at - now).as_secs(),
+                ..cached.token.clone()
+            });
+        }
+
+        let token = self.request(client, upstream).await?;
+        info!("Obtained a new OAuth2 token with scopes {}", token.scope);
+
+        *cached = Some(Cached {
+            token: token.clone(),
+            expires_at: now + Duration::from_secs(token.expires_in),
+        });
+
+        Ok(token)
+    }
+
+    async fn request<C>(
+        &self,
+        client: &Client<C, Body>,
+        upstream: &Upstream,
+    ) -> Result<Token, Box<dyn Error>>
+    where
+        C: Connect + Clone + Send + Sync + 'static,
+    {
+        let request = Request::post(upstream.uri("/api/v10", "/oauth2/token", None)?)
+            .header(AUTHORIZATION, &self.authorization)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(format!(
+                "grant_type=client_credentials&scope={}",
+                self.scopes
+            )))?;
+        let response = timeout(TIMEOUT, client.request(request))
+            .await
+            .map_err(|_| "requesting an OAuth2 token timed out")??;
+
+        match response.status() {
+            StatusCode::OK => {
+                let body = hyper::body::to_bytes(response.into_body()).await?;
+
+                Ok(serde_json::from_slice(&body)?)
+            }
+            status => Err(format!("requesting an OAuth2 token failed with {}", status).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClientCredentials;
+    use crate::upstream::Upstream;
+    use http::{header::AUTHORIZATION, Request, Response, StatusCode};
+    use hyper::{
+        server::Server,
+        service::{make_service_fn, service_fn},
+        Body, Client,
+    };
+    use std::{
+        convert::Infallible,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    #[tokio::test]
+    async fn test_token() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+
+        let service = make_service_fn(move |_| {
+            let counter = Arc::clone(&counter);
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let counter = Arc::clone(&counter);
+
+                    async move {
+                        // "1:secret"
+                        let authorized = request.headers()[AUTHORIZATION] == "Basic MTpzZWNyZXQ=";
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+
+                        let response = if authorized
+                            && body.as_ref()
+                                == b"grant_type=client_credentials&scope=identify+guilds"
+                        {
+                            let expires_in = if counter.fetch_add(1, Ordering::Relaxed) == 0 {
+                                604_800
+                            } else {
+                                // Expires within the refresh margin
+                                60
+                            };
+
+                            Response::new(Body::from(format!(
+                                r#"{{"access_token":"abc","token_type":"Bearer","expires_in":{},"scope":"identify guilds"}}"#,
+                                expires_in
+                            )))
+                        } else {
+                            let mut response = Response::new(Body::empty());
+                            *response.status_mut() = StatusCode::UNAUTHORIZED;
+
+                            response
+                        };
+
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(service);
+        let upstream = Upstream::new(&format!("http://{}", server.local_addr())).unwrap();
+        tokio::spawn(server);
+
+        let client = Client::new();
+        let credentials = ClientCredentials::new("1", "secret", "identify, guilds");
+
+        let token = credentials.token(&client, &upstream).await.unwrap();
+        assert_eq!(token.access_token, "abc");
+        assert_eq!(token.expires_in, 604_800);
+
+        // Cached until it expires soon
+        credentials.token(&client, &upstream).await.unwrap();
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+        credentials.cached.lock().await.as_mut().unwrap().expires_at = tokio::time::Instant::now();
+        credentials.token(&client, &upstream).await.unwrap();
+        credentials.token(&client, &upstream).await.unwrap();
+        assert_eq!(requests.load(Ordering::Relaxed), 3);
+
+        let wrong = ClientCredentials::new("1", "wrong", "identify guilds");
+        assert!(wrong.token(&client, &upstream).await.is_err());
+    }
+}
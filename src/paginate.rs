@@ -0,0 +1,315 @@
+//! Endpoints fetching all pages of Discord's paginated list endpoints, e.g.
+//! `/__proxy/paginate/channels/{id}/messages?limit=1000`, so clients don't
+//! have to implement the pagination loop themselves.
+//!
+//! Every page is requested through the proxy's own pipeline with the client's
+//! token, so it is ratelimited like any other request. The items are streamed
+//! back as a single JSON array while the following pages are requested.
+
+use crate::{error::RequestError, forwarded::ClientAddr, handle_request, tenant::Tenant, State};
+use http::{header::CONTENT_TYPE, HeaderMap, Method, Request, Response};
+use hyper::{body::Bytes, Body};
+use serde_json::Value;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/// Path prefix of the pagination endpoints.
+pub const PREFIX: &str = "/__proxy/paginate/";
+
+/// Amount of items returned if the request has no `limit`.
+const DEFAULT_LIMIT: u64 = 1000;
+
+/// Query parameter moving through the pages.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Cursor {
+    /// Pages are requested from newest to oldest.
+    Before,
+    /// Pages are requested from oldest to newest.
+    After,
+}
+
+impl Cursor {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Before => "before",
+            Self::After => "after",
+        }
+    }
+}
+
+/// How an endpoint is paginated.
+#[derive(Debug, Eq, PartialEq)]
+struct Pagination {
+    /// Maximum amount of items Discord returns per page.
+    page_size: u64,
+    cursor: Cursor,
+    /// Whether items are identified by the ID of their `user`, like members.
+    user_id: bool,
+}
+
+impl Pagination {
+    /// Pagination of a path relative to the API, `None` if it isn't a
+    /// supported list endpoint.
+    fn of(path: &str) -> Option<Self> {
+        let segments = path.trim_end_matches('/').split('/').collect::<Vec<_>>();
+
+        let (page_size, cursor, user_id) = match segments.as_slice() {
+            ["channels", _, "messages"] => (100, Cursor::Before, false),
+            ["channels", _, "messages", _, "reactions", _] => (100, Cursor::After, false),
+            ["guilds", _, "bans"] | ["guilds", _, "members"] => (1000, Cursor::After, true),
+            ["guilds", _, "scheduled-events", _, "users"] => (100, Cursor::After, true),
+            ["users", "@me", "guilds"] => (200, Cursor::After, false),
+            _ => return None,
+        };
+
+        Some(Self {
+            page_size,
+            cursor,
+            user_id,
+        })
+    }
+
+    /// ID of an item, which the next page starts from.
+    fn id<'a>(&self, item: &'a Value) -> Option<&'a str> {
+        let item = if self.user_id {
+            item.get("user")?
+        } else {
+            item
+        };
+
+        item.get("id")?.as_str()
+    }
+}
+
+/// Requests the pages of a paginated endpoint one after another.
+struct Pages {
+    state: Arc<State>,
+    tenant: Tenant,
+    token: Option<String>,
+    headers: HeaderMap,
+    client: Option<ClientAddr>,
+    path: String,
+    /// Query parameters of the client, without `limit` and the cursor.
+    query: Vec<String>,
+    pagination: Pagination,
+    /// Amount of items that are still to be requested.
+    remaining: u64,
+    /// ID of the item the next page starts from.
+    position: Option<String>,
+}
+
+impl Pages {
+    fn is_done(&self) -> bool {
+        self.remaining == 0
+    }
+
+    /// Request the next page, returning the response to relay to the client
+    /// instead if it isn't a successful one.
+    async fn next(&mut self) -> Result<Vec<Value>, Response<Body>> {
+        let page_size = self.remaining.min(self.pagination.page_size);
+
+        let mut query = self.query.clone();
+        query.push(format!("limit={}", page_size));
+
+        if let Some(position) = &self.position {
+            query.push(format!("{}={}", self.pagination.cursor.name(), position));
+        }
+
+        let mut request = Request::get(format!("/api/v10/{}?{}", self.path, query.join("&")))
+            .body(Body::empty())
+            .map_err(|e| RequestError::InvalidURI { source: e }.as_response())?;
+        *request.headers_mut() = self.headers.clone();
+
+        if let Some(client) = self.client {
+            request.extensions_mut().insert(client);
+        }
+
+        let response = handle_request(
+            &self.state,
+            self.tenant.clone(),
+            self.token.clone(),
+            request,
+        )
+        .await
+        .map_err(|e| e.as_response())?;
+
+        if !response.status().is_success() {
+            return Err(response);
+        }
+
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body)
+            .await
+            .map_err(|e| RequestError::RequestIssue { source: e }.as_response())?;
+
+        // Relay anything that isn't a list as-is
+        let items = match serde_json::from_slice::<Vec<Value>>(&body) {
+            Ok(items) => items,
+            Err(_) => return Err(Response::from_parts(parts, Body::from(body))),
+        };
+
+        self.remaining = self.remaining.saturating_sub(items.len() as u64);
+        self.position = items
+            .last()
+            .and_then(|item| self.pagination.id(item))
+            .map(str::to_string);
+
+        // A short page is the last one
+        if (items.len() as u64) < page_size || self.position.is_none() {
+            self.remaining = 0;
+        }
+
+        Ok(items)
+    }
+}
+
+/// Handle a request to a pagination endpoint.
+pub async fn handle(state: Arc<State>, request: Request<Body>) -> Response<Body> {
+    start(state, request)
+        .await
+        .unwrap_or_else(|e| e.as_response())
+}
+
+async fn start(state: Arc<State>, request: Request<Body>) -> Result<Response<Body>, RequestError> {
+    let path = request.uri().path()[PREFIX.len()..].to_string();
+
+    let pagination = match Pagination::of(&path) {
+        Some(pagination) if request.method() == Method::GET => pagination,
+        _ => {
+            debug!("Rejecting pagination of {} {}", request.method(), path);
+            return Err(RequestError::NotPaginated);
+        }
+    };
+
+    let mut limit = DEFAULT_LIMIT;
+    let mut position = None;
+    let mut query = Vec::new();
+
+    for param in request
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| !param.is_empty())
+    {
+        match param.split_once('=') {
+            Some(("limit", value)) => {
+                limit = value
+                    .parse()
+                    .ok()
+                    .filter(|limit| *limit > 0)
+                    .ok_or(RequestError::InvalidPageLimit)?;
+            }
+            Some((name, value)) if name == pagination.cursor.name() => {
+                position = Some(value.to_string());
+            }
+            _ => query.push(param.to_string()),
+        }
+    }
+
+    let token = request
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok());
+
+    let (tenant, token) = match state.ratelimiter_map.get_or_insert(token) {
+        Some(tenant) => tenant,
+        None => {
+            debug!("Rejecting pagination without Authorization header");
+            return Err(RequestError::MissingToken);
+        }
+    };
+
+    let mut pages = Pages {
+        client: request.extensions().get::<ClientAddr>().copied(),
+        headers: request.headers().clone(),
+        state,
+        tenant,
+        token: Some(token),
+        path,
+        query,
+        pagination,
+        remaining: limit,
+        position,
+    };
+
+    // The first page decides the status of the response
+    let first = match pages.next().await {
+        Ok(items) => items,
+        Err(response) => return Ok(response),
+    };
+
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        let mut items = first;
+        let mut separator = "[";
+
+        loop {
+            let mut chunk = Vec::new();
+
+            for item in &items {
+                chunk.extend_from_slice(separator.as_bytes());
+                serde_json::to_writer(&mut chunk, item).expect("values serialize");
+                separator = ",";
+            }
+
+            // The client went away
+            if sender.send_data(Bytes::from(chunk)).await.is_err() {
+                return;
+            }
+
+            if pages.is_done() {
+                break;
+            }
+
+            items = match pages.next().await {
+                Ok(items) => items,
+                Err(response) => {
+                    warn!(
+                        "Pagination of {} stopped early with {}",
+                        pages.path,
+                        response.status()
+                    );
+
+                    // Cut off the array, so clients don't mistake the items
+                    // for all of them
+                    sender.abort();
+
+                    return;
+                }
+            };
+        }
+
+        let end = if separator == "[" { "[]" } else { "]" };
+        let _ = sender.send_data(Bytes::from_static(end.as_bytes())).await;
+    });
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .expect("response is valid"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cursor, Pagination};
+    use serde_json::json;
+
+    #[test]
+    fn test_pagination() {
+        let messages = Pagination::of("channels/1/messages").unwrap();
+        assert_eq!(messages.page_size, 100);
+        assert_eq!(messages.cursor, Cursor::Before);
+        assert_eq!(messages.id(&json!({"id": "2"})), Some("2"));
+
+        let members = Pagination::of("guilds/1/members/").unwrap();
+        assert_eq!(members.page_size, 1000);
+        assert_eq!(members.cursor, Cursor::After);
+        assert_eq!(members.id(&json!({"user": {"id": "3"}})), Some("3"));
+        assert_eq!(members.id(&json!({"id": "3"})), None);
+
+        assert!(Pagination::of("channels/1/messages/2").is_none());
+        assert!(Pagination::of("guilds/1/roles").is_none());
+    }
+}
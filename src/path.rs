@@ -0,0 +1,332 @@
+use std::convert::TryFrom;
+use twilight_http_ratelimiting::{
+    request::{PathParseError, PathParseErrorType},
+    Method, Path,
+};
+
+/// A request path split into the API prefix and the path within the API.
+#[derive(Debug, Eq, PartialEq)]
+pub struct NormalizedPath {
+    /// API prefix including the version, if any, such as `/api/v10`.
+    pub api: String,
+    /// Path within the API, such as `/users/@me`.
+    pub path: String,
+}
+
+impl NormalizedPath {
+    /// The API version, if the path has one.
+    pub fn version(&self) -> Option<u8> {
+        self.api.strip_prefix("/api/v")?.parse().ok()
+    }
+}
+
+/// Normalize a request path received from a client.
+///
+/// Several client libraries emit slightly nonstandard paths, so this:
+///
+/// - removes empty segments caused by duplicate or trailing slashes
+/// - decodes percent-encoded characters that don't need to be encoded and
+///   uppercases the remaining escapes
+/// - resolves `.` and `..` segments, so the path used for ratelimiting is the
+///   one Discord will see
+/// - matches the `/api` prefix and version case-insensitively, also if they
+///   are repeated or the prefix is missing before the version
+pub fn normalize_path(request_path: &str) -> NormalizedPath {
+    let mut segments = Vec::new();
+
+    for segment in request_path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+    {
+        let segment = decode_segment(segment);
+
+        match segment.as_str() {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    let mut rest = segments.as_slice();
+    let mut version = None;
+
+    // Clients configured with a base URL that already contains the prefix
+    // repeat it, e.g. `/api/api/v10` or `/api/v10/api/v10`, and some omit
+    // `/api` before the version. The last version given is the one the
+    // client library appended itself.
+    while let Some(segment) = rest.first() {
+        if segment.eq_ignore_ascii_case("api") {
+            rest = &rest[1..];
+        } else if let Some(parsed) = parse_version(segment) {
+            version = Some(parsed);
+            rest = &rest[1..];
+        } else {
+            break;
+        }
+    }
+
+    let api = match version {
+        Some(version) => format!("/api/v{}", version),
+        None => String::from("/api"),
+    };
+
+    let path = rest.iter().fold(String::new(), |mut path, segment| {
+        path.push('/');
+        path.push_str(segment);
+
+        path
+    });
+
+    NormalizedPath { api, path }
+}
+
+/// Parse the ratelimit path of a normalized path.
+///
+/// Bulk bans are newer than the ratelimiter's routes, so they are ratelimited
+/// like banning a single member of the guild.
+pub fn ratelimit_path(method: Method, path: &str) -> Result<Path, PathParseError> {
+    Path::try_from((method, path)).or_else(|e| {
+        if !matches!(e.kind(), PathParseErrorType::NoMatch) {
+            return Err(e);
+        }
+
+        match path.trim_start_matches('/').split('/').collect::<Vec<_>>()[..] {
+            ["guilds", id, "bulk-ban"] => match id.parse() {
+                Ok(id) => Ok(Path::GuildsIdBansUserId(id)),
+                Err(_) => Err(e),
+            },
+            _ => Err(e),
+        }
+    })
+}
+
+fn parse_version(segment: &str) -> Option<u8> {
+    let number = segment
+        .strip_prefix('v')
+        .or_else(|| segment.strip_prefix('V'))?;
+
+    // Don't accept signs or whitespace that `parse` would otherwise allow
+    if !number.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    number.parse().ok()
+}
+
+/// Whether a byte may appear decoded in a path segment without changing its
+/// meaning, i.e. is unreserved or one of `@` and `:`.
+const fn is_safe(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~' | b'@' | b':')
+}
+
+fn decode_segment(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = String::with_capacity(segment.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let escaped = match bytes.get(index..index + 3) {
+            Some([b'%', high, low]) => hex_value(*high)
+                .zip(hex_value(*low))
+                .map(|(high, low)| high << 4 | low),
+            _ => None,
+        };
+
+        match escaped {
+            Some(byte) if is_safe(byte) => {
+                decoded.push(char::from(byte));
+                index += 3;
+            }
+            Some(byte) => {
+                decoded.push('%');
+                decoded.push_str(&format!("{:02X}", byte));
+                index += 3;
+            }
+            None => {
+                // `segment` is a string slice and the input is only split on
+                // ASCII characters, so this will always be on a char boundary
+                let next = segment[index..]
+                    .chars()
+                    .next()
+                    .expect("index is within bounds");
+                decoded.push(next);
+                index += next.len_utf8();
+            }
+        }
+    }
+
+    decoded
+}
+
+const fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize_path, ratelimit_path, NormalizedPath};
+    use proptest::prelude::*;
+    use twilight_http_ratelimiting::{Method, Path};
+
+    fn normalized(api: &str, path: &str) -> NormalizedPath {
+        NormalizedPath {
+            api: api.to_string(),
+            path: path.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(
+            normalize_path("/api/v10/users/@me"),
+            normalized("/api/v10", "/users/@me")
+        );
+        assert_eq!(
+            normalize_path("/api/users/@me"),
+            normalized("/api", "/users/@me")
+        );
+        assert_eq!(
+            normalize_path("/users/@me"),
+            normalized("/api", "/users/@me")
+        );
+        assert_eq!(
+            normalize_path("//API//V9///channels/1/messages/"),
+            normalized("/api/v9", "/channels/1/messages")
+        );
+        assert_eq!(
+            normalize_path("/api/v10/users/%40me"),
+            normalized("/api/v10", "/users/@me")
+        );
+        assert_eq!(
+            normalize_path("/channels/%31/messages/2/reactions/%f0%9f%91%8d/@me"),
+            normalized("/api", "/channels/1/messages/2/reactions/%F0%9F%91%8D/@me")
+        );
+        assert_eq!(
+            normalize_path("/api/v10/channels/1/../../users/./@me"),
+            normalized("/api/v10", "/users/@me")
+        );
+        assert_eq!(
+            normalize_path("/api/v+1/users/@me"),
+            normalized("/api", "/v+1/users/@me")
+        );
+        assert_eq!(normalize_path("/api/v10/"), normalized("/api/v10", ""));
+
+        assert_eq!(normalize_path("/API/V9/users/@me").version(), Some(9));
+        assert_eq!(normalize_path("/api/users/@me").version(), None);
+    }
+
+    #[test]
+    fn test_ratelimit_path() {
+        assert_eq!(
+            ratelimit_path(Method::Get, "/users/@me").unwrap(),
+            Path::UsersId
+        );
+        assert_eq!(
+            ratelimit_path(Method::Post, "/guilds/1/bulk-ban").unwrap(),
+            Path::GuildsIdBansUserId(1)
+        );
+        assert!(ratelimit_path(Method::Post, "/guilds/x/bulk-ban").is_err());
+        assert!(ratelimit_path(Method::Get, "/unknown").is_err());
+    }
+
+    #[test]
+    fn test_library_paths() {
+        let expected = normalized("/api/v10", "/channels/1/messages");
+
+        // twilight-http and serenity with the proxy's root as their proxy,
+        // discord.js with `rest.api` set to the proxy's `/api`
+        assert_eq!(normalize_path("/api/v10/channels/1/messages"), expected);
+        // serenity with the proxy's `/api` as its proxy
+        assert_eq!(normalize_path("/api/api/v10/channels/1/messages"), expected);
+        // Clients appending the versioned prefix to a base URL which already
+        // contains it
+        assert_eq!(
+            normalize_path("/api/v10/api/v10/channels/1/messages"),
+            expected
+        );
+        // discord.js with `rest.api` set to the proxy's root
+        assert_eq!(normalize_path("/v10/channels/1/messages"), expected);
+        // The version appended by the library wins over the base's
+        assert_eq!(
+            normalize_path("/api/v9/api/v10/channels/1/messages"),
+            expected
+        );
+        // Unversioned paths keep using Discord's default version
+        assert_eq!(
+            normalize_path("/api/api/channels/1/messages"),
+            normalized("/api", "/channels/1/messages")
+        );
+    }
+
+    fn segment() -> impl Strategy<Value = String> {
+        prop_oneof![
+            "[0-9]{1,20}",
+            "[a-z@\\-_.]{1,10}",
+            "%[0-9a-fA-F]{2}",
+            Just("@me".to_string()),
+        ]
+    }
+
+    fn join(segments: &[String], separator: &str) -> String {
+        segments
+            .iter()
+            .fold(String::new(), |path, segment| path + separator + segment)
+    }
+
+    proptest! {
+        #[test]
+        fn normalizing_is_idempotent(segments in prop::collection::vec(segment(), 0..8)) {
+            let first = normalize_path(&join(&segments, "/"));
+            let second = normalize_path(&(first.api.clone() + &first.path));
+
+            prop_assert_eq!(first, second);
+        }
+
+        #[test]
+        fn output_has_no_empty_segments(segments in prop::collection::vec(segment(), 0..8)) {
+            let normalized = normalize_path(&join(&segments, "//"));
+
+            prop_assert!(!normalized.path.contains("//"));
+            prop_assert!(!normalized.path.ends_with('/'));
+        }
+
+        #[test]
+        fn extra_slashes_are_ignored(
+            segments in prop::collection::vec(segment(), 0..8),
+            separator in "/{1,3}",
+        ) {
+            prop_assert_eq!(
+                normalize_path(&join(&segments, "/")),
+                normalize_path(&(join(&segments, &separator) + &separator)),
+            );
+        }
+
+        #[test]
+        fn api_prefix_is_case_insensitive(
+            segments in prop::collection::vec("[0-9]{1,20}", 0..8),
+            prefix in "/(a|A)(p|P)(i|I)/(v|V)10",
+        ) {
+            prop_assert_eq!(
+                normalize_path(&(prefix + &join(&segments, "/"))),
+                normalize_path(&("/api/v10".to_string() + &join(&segments, "/"))),
+            );
+        }
+
+        #[test]
+        fn encoded_digits_are_decoded(id in "[0-9]{1,20}") {
+            let encoded = id.bytes().fold(String::new(), |acc, byte| acc + &format!("%{:02X}", byte));
+
+            prop_assert_eq!(
+                normalize_path(&format!("/channels/{}", encoded)),
+                normalize_path(&format!("/channels/{}", id)),
+            );
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! Pausing of all upstream traffic, e.g. during Discord incidents or token
+//! rotation.
+//!
+//! While paused, requests are held before they take a ratelimit ticket and
+//! continue in order once traffic is resumed.
+
+use crate::parse_env;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use tokio::sync::Notify;
+
+/// Maximum amount of held requests if `PAUSE_QUEUE_LIMIT` is not set.
+const DEFAULT_LIMIT: usize = 10_000;
+
+/// Returned when a request arrives while paused and the queue is full.
+#[derive(Debug)]
+pub struct QueueFull;
+
+/// State reported by the admin endpoints.
+#[derive(Serialize)]
+pub struct Status {
+    paused: bool,
+    held: usize,
+    limit: usize,
+}
+
+pub struct Pause {
+    paused: AtomicBool,
+    held: AtomicUsize,
+    limit: usize,
+    resumed: Notify,
+}
+
+impl Pause {
+    /// Load the queue limit from `PAUSE_QUEUE_LIMIT`.
+    pub fn from_env() -> Self {
+        Self::new(parse_env("PAUSE_QUEUE_LIMIT").unwrap_or(DEFAULT_LIMIT))
+    }
+
+    const fn new(limit: usize) -> Self {
+        Self {
+            paused: AtomicBool::new(false),
+            held: AtomicUsize::new(0),
+            limit,
+            resumed: Notify::const_new(),
+        }
+    }
+
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+        self.resumed.notify_waiters();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            paused: self.is_paused(),
+            held: self.held.load(Ordering::SeqCst),
+            limit: self.limit,
+        }
+    }
+
+    /// Wait until traffic is not paused.
+    ///
+    /// Returns an error right away if traffic is paused and the limit of held
+    /// requests is reached.
+    pub async fn wait(&self) -> Result<(), QueueFull> {
+        if !self.is_paused() {
+            return Ok(());
+        }
+
+        let held = self.held.fetch_add(1, Ordering::SeqCst);
+        let _guard = HeldGuard(&self.held);
+
+        if held >= self.limit {
+            return Err(QueueFull);
+        }
+
+        loop {
+            // Created before checking the flag so that resuming in between
+            // is not missed
+            let resumed = self.resumed.notified();
+
+            if !self.is_paused() {
+                return Ok(());
+            }
+
+            resumed.await;
+        }
+    }
+}
+
+/// Decrements the amount of held requests when dropped.
+struct HeldGuard<'a>(&'a AtomicUsize);
+
+impl Drop for HeldGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pause;
+    use std::sync::Arc;
+    use tokio::task;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pause() {
+        let pause = Arc::new(Pause::new(2));
+
+        assert!(pause.wait().await.is_ok());
+
+        pause.pause();
+
+        let held = (0..2)
+            .map(|_| {
+                let pause = Arc::clone(&pause);
+
+                tokio::spawn(async move { pause.wait().await })
+            })
+            .collect::<Vec<_>>();
+
+        task::yield_now().await;
+        assert_eq!(pause.status().held, 2);
+
+        // The queue is full
+        assert!(pause.wait().await.is_err());
+
+        // Requests that give up free their slot
+        held[0].abort();
+        task::yield_now().await;
+        assert_eq!(pause.status().held, 1);
+
+        pause.resume();
+
+        for handle in held.into_iter().skip(1) {
+            assert!(handle.await.unwrap().is_ok());
+        }
+
+        assert_eq!(pause.status().held, 0);
+        assert!(!pause.status().paused);
+    }
+}
@@ -0,0 +1,301 @@
+//! Pre-warming of buckets with limits known from configuration or a previous
+//! run, so the first burst after a deploy is paced correctly instead of being
+//! sent unthrottled until Discord's first headers arrive.
+
+use crate::tenant::Tenant;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env, io,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::fs;
+use tracing::{debug, warn};
+use twilight_http_ratelimiting::{Path, RatelimitHeaders};
+
+/// Limit of a bucket as advertised by Discord.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct KnownLimit {
+    limit: u64,
+    reset_after_ms: u64,
+}
+
+/// Bucket limits by route, read from and persisted to `BUCKET_LIMITS_FILE`.
+pub struct KnownLimits {
+    file: PathBuf,
+    limits: HashMap<String, KnownLimit>,
+}
+
+impl KnownLimits {
+    /// Load the limits from the file at `BUCKET_LIMITS_FILE`.
+    ///
+    /// Returns `None` if pre-warming is not enabled. A missing file is
+    /// created when the limits are saved.
+    pub async fn from_env() -> Option<Self> {
+        let file = PathBuf::from(env::var("BUCKET_LIMITS_FILE").ok()?);
+
+        let limits = match fs::read(&file).await {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|e| {
+                warn!("BUCKET_LIMITS_FILE {:?} is invalid: {}", file, e);
+
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                warn!("Failed to read BUCKET_LIMITS_FILE {:?}: {}", file, e);
+
+                HashMap::new()
+            }
+        };
+
+        Some(Self { file, limits })
+    }
+
+    /// Seed the bucket of a path with its known limit if the tenant has not
+    /// made requests to it yet.
+    pub async fn seed(&self, tenant: &Tenant, path: &Path) {
+        if let Some(known) = self.limits.get(&route_key(path)) {
+            seed_bucket(tenant, path, known.limit, known.limit, known.reset_after_ms).await;
+        }
+    }
+
+    /// Add the limits of all buckets the tenants know to the file.
+    pub async fn save(&self, tenants: &[Tenant]) -> io::Result<()> {
+        let mut limits = self.limits.clone();
+
+        for tenant in tenants {
+            for path in tenant.usage.paths() {
+                if let Ok(Some(bucket)) = tenant.ratelimiter.bucket(&path).await {
+                    if bucket.limit() != u64::MAX {
+                        limits.insert(
+                            route_key(&path),
+                            KnownLimit {
+                                limit: bucket.limit(),
+                                reset_after_ms: bucket.reset_after().as_millis() as u64,
+                            },
+                        );
+                    }
+                }
+            }
+        }
+
+        let contents = serde_json::to_vec_pretty(&limits).map_err(io::Error::other)?;
+
+        // Write to a temporary file first so a crash can't leave it truncated
+        let temporary = self.file.with_extension("tmp");
+        fs::write(&temporary, contents).await?;
+        fs::rename(&temporary, &self.file).await?;
+
+        debug!("Saved {} bucket limits to {:?}", limits.len(), self.file);
+
+        Ok(())
+    }
+}
+
+/// Seed the bucket of a path with a state if the tenant has not made requests
+/// to it yet.
+pub async fn seed_bucket(
+    tenant: &Tenant,
+    path: &Path,
+    limit: u64,
+    remaining: u64,
+    reset_after_ms: u64,
+) {
+    if !matches!(tenant.ratelimiter.bucket(path).await, Ok(None)) {
+        return;
+    }
+
+    let sender = match tenant.ratelimiter.wait_for_ticket(path.clone()).await {
+        Ok(sender) => sender,
+        Err(_) => return,
+    };
+
+    // Concurrent requests may have seeded the bucket or received Discord's
+    // headers in the meantime, which must not be overwritten
+    let (limit, remaining, reset_after_ms) = match tenant.ratelimiter.bucket(path).await {
+        Ok(Some(bucket)) if bucket.limit() != u64::MAX => (
+            bucket.limit(),
+            bucket.remaining(),
+            bucket.reset_after().as_millis() as u64,
+        ),
+        _ => {
+            debug!(
+                "Seeding bucket of {:?} with {}/{} remaining, resetting in {}ms",
+                path, remaining, limit, reset_after_ms
+            );
+
+            (limit, remaining, reset_after_ms)
+        }
+    };
+
+    _ = sender.headers(headers(limit, remaining, reset_after_ms));
+}
+
+/// Overwrite the state of a path's bucket, e.g. with limits Discord support
+/// communicated, until Discord's next response to it sets the state again.
+///
+/// The ratelimiter keeps the limit and reset interval of a bucket once it
+/// knows them, so only `remaining` is overwritten for those. Waits for a
+/// ticket like a request would, so requests queued before are sent with the
+/// previous state. Returns whether the state was sent to the ratelimiter.
+pub async fn inject_bucket(
+    tenant: &Tenant,
+    path: &Path,
+    limit: u64,
+    remaining: u64,
+    reset_after_ms: u64,
+) -> bool {
+    let sender = match tenant.ratelimiter.wait_for_ticket(path.clone()).await {
+        Ok(sender) => sender,
+        Err(_) => return false,
+    };
+
+    sender
+        .headers(headers(limit, remaining, reset_after_ms))
+        .is_ok()
+}
+
+/// Key of a path's route, such as `ChannelsIdMessagesId(Delete)`.
+///
+/// Major parameters and tokens are removed, so buckets of all channels or
+/// guilds share their limit and the file contains no secrets.
+fn route_key(path: &Path) -> String {
+    let debug = format!("{:?}", path);
+
+    let (name, arguments) = match debug.split_once('(') {
+        Some((name, arguments)) => (name, arguments.trim_end_matches(')')),
+        None => return debug,
+    };
+
+    let methods = arguments
+        .split(", ")
+        .filter(|argument| argument.bytes().all(|byte| byte.is_ascii_alphabetic()))
+        .collect::<Vec<_>>();
+
+    if methods.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}({})", name, methods.join(", "))
+    }
+}
+
+/// Headers making the ratelimiter assume a bucket's state.
+fn headers(limit: u64, remaining: u64, reset_after_ms: u64) -> Option<RatelimitHeaders> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+
+    let limit = limit.to_string();
+    let remaining = remaining.to_string();
+    let reset = format!("{:.3}", (now + reset_after_ms) as f64 / 1000.0);
+    let reset_after = format!("{:.3}", reset_after_ms as f64 / 1000.0);
+
+    RatelimitHeaders::from_pairs(
+        [
+            ("x-ratelimit-limit", limit.as_bytes()),
+            ("x-ratelimit-remaining", remaining.as_bytes()),
+            ("x-ratelimit-reset", reset.as_bytes()),
+            ("x-ratelimit-reset-after", reset_after.as_bytes()),
+        ]
+        .iter()
+        .copied(),
+    )
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inject_bucket, route_key, seed_bucket, KnownLimit, KnownLimits};
+    use crate::tenant::Tenant;
+    use std::{collections::HashMap, env, time::Duration};
+    use twilight_http_ratelimiting::{Method, Path};
+
+    #[test]
+    fn test_route_key() {
+        assert_eq!(
+            route_key(&Path::ChannelsIdMessages(1)),
+            "ChannelsIdMessages"
+        );
+        assert_eq!(
+            route_key(&Path::ChannelsIdMessagesId(Method::Delete, 1)),
+            "ChannelsIdMessagesId(Delete)"
+        );
+        assert_eq!(
+            route_key(&Path::WebhooksIdToken(1, "secret".to_string())),
+            "WebhooksIdToken"
+        );
+        assert_eq!(route_key(&Path::Gateway), "Gateway");
+    }
+
+    #[tokio::test]
+    async fn test_seed_and_save() {
+        let file = env::temp_dir().join(format!("bucket-limits-{}.json", fastrand::u64(..)));
+        let limits = KnownLimits {
+            file: file.clone(),
+            limits: HashMap::from([(
+                "ChannelsIdMessages".to_string(),
+                KnownLimit {
+                    limit: 5,
+                    reset_after_ms: 5000,
+                },
+            )]),
+        };
+        let tenant = Tenant::new("Bot abc");
+        let path = Path::ChannelsIdMessages(1);
+
+        limits.seed(&tenant, &path).await;
+        // The bucket is updated in the background
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let bucket = tenant.ratelimiter.bucket(&path).await.unwrap().unwrap();
+        assert_eq!(bucket.limit(), 5);
+        assert_eq!(bucket.remaining(), 5);
+        assert_eq!(bucket.reset_after(), Duration::from_secs(5));
+
+        tenant.usage.record(&path, 200, false);
+
+        // Unknown routes are left alone
+        limits.seed(&tenant, &Path::Gateway).await;
+        assert!(tenant
+            .ratelimiter
+            .bucket(&Path::Gateway)
+            .await
+            .unwrap()
+            .is_none());
+
+        limits.save(&[tenant]).await.unwrap();
+        let saved = std::fs::read(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+
+        let saved = serde_json::from_slice::<HashMap<String, KnownLimit>>(&saved).unwrap();
+        assert_eq!(saved, limits.limits);
+    }
+
+    #[tokio::test]
+    async fn test_inject_bucket() {
+        let tenant = Tenant::new("Bot abc");
+        let path = Path::ChannelsIdMessages(1);
+
+        assert!(inject_bucket(&tenant, &path, 2, 1, 10_000).await);
+        // The bucket is updated in the background
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let bucket = tenant.ratelimiter.bucket(&path).await.unwrap().unwrap();
+        assert_eq!(bucket.limit(), 2);
+        assert_eq!(bucket.remaining(), 1);
+        assert_eq!(bucket.reset_after(), Duration::from_secs(10));
+
+        // Unlike seeding, injecting overwrites the remaining tickets of a
+        // known bucket, while its limit is kept
+        let path = Path::ChannelsIdMessages(2);
+        seed_bucket(&tenant, &path, 5, 5, 5000).await;
+        assert!(inject_bucket(&tenant, &path, 2, 0, 10_000).await);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let bucket = tenant.ratelimiter.bucket(&path).await.unwrap().unwrap();
+        assert_eq!(bucket.limit(), 5);
+        assert_eq!(bucket.remaining(), 0);
+    }
+}
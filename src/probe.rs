@@ -0,0 +1,212 @@
+//! Periodic requests through the proxy's own pipeline, telling apart a proxy
+//! that can't reach Discord from one that is down entirely.
+
+use crate::{handle_request, parse_env, State};
+use http::{Request, StatusCode, Uri};
+use hyper::Body;
+use serde::Serialize;
+use std::{env, str::FromStr, sync::Mutex};
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, warn};
+
+/// Path probed if `PROBE_PATH` is not set.
+const DEFAULT_PATH: &str = "/api/v10/gateway/bot";
+
+/// Amount of intervals after which a result is considered stale.
+const STALE_INTERVALS: u32 = 3;
+
+/// Result of a single probe.
+struct Outcome {
+    status: Option<StatusCode>,
+    error: Option<String>,
+    latency: Duration,
+    checked_at: Instant,
+}
+
+impl Outcome {
+    fn is_up(&self) -> bool {
+        self.status.is_some_and(|status| status.is_success())
+    }
+}
+
+/// Report of the latest probe, served by the readiness endpoint.
+#[derive(Serialize)]
+pub struct Report {
+    up: bool,
+    status: Option<u16>,
+    error: Option<String>,
+    latency_ms: u128,
+    age_ms: u128,
+}
+
+pub struct Probe {
+    interval: Duration,
+    path: String,
+    latest: Mutex<Option<Outcome>>,
+}
+
+impl Probe {
+    /// Load the configuration from `PROBE_INTERVAL` and `PROBE_PATH`.
+    ///
+    /// Returns `None` if probing is not enabled.
+    pub fn from_env() -> Option<Self> {
+        let interval = Duration::from_secs(parse_env::<u64>("PROBE_INTERVAL")?.max(1));
+        let path = env::var("PROBE_PATH").unwrap_or_else(|_| DEFAULT_PATH.into());
+
+        if Uri::from_str(&path).is_err() || !path.starts_with('/') {
+            warn!(
+                "PROBE_PATH {:?} is not a valid path, probing is disabled",
+                path
+            );
+
+            return None;
+        }
+
+        Some(Self::new(interval, path))
+    }
+
+    const fn new(interval: Duration, path: String) -> Self {
+        Self {
+            interval,
+            path,
+            latest: Mutex::new(None),
+        }
+    }
+
+    /// Whether the latest probe succeeded and is recent.
+    pub fn is_ready(&self) -> bool {
+        self.latest
+            .lock()
+            .expect("probe poisoned")
+            .as_ref()
+            .is_some_and(|outcome| {
+                outcome.is_up() && outcome.checked_at.elapsed() < self.interval * STALE_INTERVALS
+            })
+    }
+
+    /// Report of the latest probe, if any finished yet.
+    pub fn report(&self) -> Option<Report> {
+        self.latest
+            .lock()
+            .expect("probe poisoned")
+            .as_ref()
+            .map(|outcome| Report {
+                up: outcome.is_up(),
+                status: outcome.status.map(|status| status.as_u16()),
+                error: outcome.error.clone(),
+                latency_ms: outcome.latency.as_millis(),
+                age_ms: outcome.checked_at.elapsed().as_millis(),
+            })
+    }
+
+    fn record(&self, outcome: Outcome) {
+        #[cfg(feature = "expose-metrics")]
+        {
+            let key = crate::METRIC_KEY.as_str();
+
+            metrics::gauge!(
+                format!("{}_probe_up", key),
+                if outcome.is_up() { 1.0 } else { 0.0 }
+            );
+            metrics::histogram!(format!("{}_probe_latency", key), outcome.latency);
+        }
+
+        *self.latest.lock().expect("probe poisoned") = Some(outcome);
+    }
+}
+
+/// Probe the upstream with the default token until the process exits.
+///
+/// The requests go through the same pipeline as the ones of clients, so they
+/// are ratelimited and count towards the default token's usage.
+pub async fn run(state: &State) {
+    let probe = match &state.probe {
+        Some(probe) => probe,
+        None => return,
+    };
+
+    let mut interval = time::interval(probe.interval);
+
+    loop {
+        interval.tick().await;
+
+        let (tenant, token) = match state.ratelimiter_map.get_or_insert(None) {
+            Some(default) => default,
+            None => {
+                warn!("Probing requires DISCORD_TOKEN to be set, probing is disabled");
+
+                return;
+            }
+        };
+
+        let request = Request::get(probe.path.as_str())
+            .body(Body::empty())
+            .expect("path was validated");
+
+        let start = Instant::now();
+        let result = handle_request(state, tenant, Some(token), request).await;
+        let latency = start.elapsed();
+
+        let outcome = match result {
+            Ok(response) => Outcome {
+                status: Some(response.status()),
+                error: None,
+                latency,
+                checked_at: Instant::now(),
+            },
+            Err(e) => Outcome {
+                status: None,
+                error: Some(e.to_string()),
+                latency,
+                checked_at: Instant::now(),
+            },
+        };
+
+        debug!(
+            "Probe of {} finished in {:?}: {:?}",
+            probe.path, latency, outcome.status
+        );
+
+        probe.record(outcome);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Outcome, Probe, DEFAULT_PATH};
+    use http::StatusCode;
+    use tokio::time::{sleep, Duration, Instant};
+
+    fn outcome(status: Option<StatusCode>) -> Outcome {
+        Outcome {
+            status,
+            error: status.is_none().then(|| "unreachable".to_string()),
+            latency: Duration::from_millis(50),
+            checked_at: Instant::now(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_readiness() {
+        let probe = Probe::new(Duration::from_secs(10), DEFAULT_PATH.to_string());
+
+        assert!(!probe.is_ready());
+        assert!(probe.report().is_none());
+
+        probe.record(outcome(Some(StatusCode::OK)));
+        assert!(probe.is_ready());
+
+        probe.record(outcome(Some(StatusCode::UNAUTHORIZED)));
+        assert!(!probe.is_ready());
+
+        probe.record(outcome(None));
+        let report = probe.report().unwrap();
+        assert!(!report.up);
+        assert_eq!(report.error.as_deref(), Some("unreachable"));
+
+        // Results of a probe that got stuck are not trusted forever
+        probe.record(outcome(Some(StatusCode::OK)));
+        sleep(Duration::from_secs(30)).await;
+        assert!(!probe.is_ready());
+    }
+}
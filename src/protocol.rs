@@ -0,0 +1,233 @@
+//! Detection of clients speaking the wrong protocol to the proxy, e.g.
+//! because it was configured as an HTTPS or SOCKS proxy instead of as the API
+//! base URL.
+//!
+//! Such clients usually only report a broken connection, so the proxy logs
+//! what it received and which setting to use instead.
+//!
+//! With `PROXY_PROTOCOL`, every connection has to start with a PROXY protocol
+//! header, which is removed before the rest is checked.
+
+use crate::proxy_protocol;
+use hyper::server::{
+    accept::Accept,
+    conn::{AddrIncoming, AddrStream},
+};
+use std::{
+    env, io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, OnceLock},
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::warn;
+
+/// Explain what a connection's first byte says about the client's
+/// configuration, if it isn't the start of an HTTP request.
+fn misconfiguration(first: u8) -> Option<&'static str> {
+    match first {
+        // Content type of a TLS handshake record
+        0x16 => Some(
+            "sent a TLS handshake, but the proxy only speaks plain HTTP; use an http:// URL for \
+             the proxy",
+        ),
+        0x04 | 0x05 => Some(
+            "sent a SOCKS handshake, but the proxy is not a SOCKS proxy; set it as the API base \
+             URL instead of as a proxy",
+        ),
+        _ => None,
+    }
+}
+
+/// Incoming connections, checked for the wrong protocol.
+pub struct Incoming {
+    incoming: AddrIncoming,
+    /// Whether connections start with a PROXY protocol header.
+    proxy_protocol: bool,
+}
+
+impl Incoming {
+    /// Configured via `PROXY_PROTOCOL`.
+    pub fn from_env(incoming: AddrIncoming) -> Self {
+        Self {
+            incoming,
+            proxy_protocol: env::var_os("PROXY_PROTOCOL").is_some(),
+        }
+    }
+}
+
+impl Accept for Incoming {
+    type Conn = Connection;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let proxy_protocol = self.proxy_protocol;
+
+        Pin::new(&mut self.incoming)
+            .poll_accept(cx)
+            .map(|accepted| {
+                accepted.map(|stream| {
+                    stream.map(|stream| Connection {
+                        client: SourceAddr {
+                            peer: stream.remote_addr(),
+                            source: Arc::default(),
+                        },
+                        stream,
+                        checked: false,
+                        proxy_header: Some(Vec::new()).filter(|_| proxy_protocol),
+                    })
+                })
+            })
+    }
+}
+
+/// Address a connection is from, which is only known once its PROXY protocol
+/// header was read.
+#[derive(Clone)]
+pub struct SourceAddr {
+    peer: SocketAddr,
+    source: Arc<OnceLock<SocketAddr>>,
+}
+
+impl SourceAddr {
+    /// The source of the PROXY protocol header, or the peer's address.
+    pub fn get(&self) -> SocketAddr {
+        self.source.get().copied().unwrap_or(self.peer)
+    }
+}
+
+/// A connection whose first byte is checked when it is read.
+pub struct Connection {
+    stream: AddrStream,
+    checked: bool,
+    /// Bytes of the PROXY protocol header read so far, until it is complete.
+    proxy_header: Option<Vec<u8>>,
+    client: SourceAddr,
+}
+
+impl Connection {
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.stream.remote_addr()
+    }
+
+    pub fn source_addr(&self) -> SourceAddr {
+        self.client.clone()
+    }
+
+    /// Read the PROXY protocol header, without reading beyond it.
+    fn poll_proxy_header(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let header = match &mut self.proxy_header {
+            Some(header) => header,
+            None => return Poll::Ready(Ok(())),
+        };
+
+        loop {
+            let needed = match header.get(..proxy_protocol::PREFIX_LEN) {
+                Some(prefix) => proxy_protocol::header_len(prefix)?,
+                None => proxy_protocol::PREFIX_LEN,
+            };
+
+            if header.len() == needed {
+                break;
+            }
+
+            let start = header.len();
+            header.resize(needed, 0);
+
+            let mut buf = ReadBuf::new(&mut header[start..]);
+            let poll = Pin::new(&mut self.stream).poll_read(cx, &mut buf);
+            let read = buf.filled().len();
+            header.truncate(start + read);
+            ready!(poll)?;
+
+            // Load balancers may close connections without sending anything,
+            // e.g. for health checks
+            if read == 0 && start == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            if read == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+
+        if let Some(source) = proxy_protocol::source(header)? {
+            _ = self.client.source.set(source);
+        }
+
+        self.proxy_header = None;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Err(e) = ready!(self.poll_proxy_header(cx)) {
+            warn!(
+                "Client {} sent no valid PROXY protocol header: {}",
+                self.remote_addr(),
+                e
+            );
+
+            return Poll::Ready(Err(e));
+        }
+
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.stream).poll_read(cx, buf);
+
+        if self.checked || buf.filled().len() == filled {
+            return poll;
+        }
+
+        self.checked = true;
+
+        match misconfiguration(buf.filled()[filled]) {
+            Some(problem) => {
+                warn!("Client {} {}", self.remote_addr(), problem);
+
+                Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, problem)))
+            }
+            None => poll,
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::misconfiguration;
+
+    #[test]
+    fn test_misconfiguration() {
+        assert!(misconfiguration(0x16).unwrap().contains("TLS"));
+        assert!(misconfiguration(0x05).unwrap().contains("SOCKS"));
+        assert!(misconfiguration(b'G').is_none());
+        assert!(misconfiguration(b'P').is_none());
+    }
+}
@@ -0,0 +1,131 @@
+//! Parsing of PROXY protocol v2 headers, which TCP load balancers such as
+//! HAProxy or AWS NLB prepend to connections to pass on the client address.
+//!
+//! https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use std::{
+    convert::TryFrom,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+/// Length of the fixed part of a header, up to and including its length.
+pub const PREFIX_LEN: usize = 16;
+
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Command of connections the load balancer opened itself, e.g. for health
+/// checks.
+const LOCAL: u8 = 0x20;
+const PROXY: u8 = 0x21;
+
+/// Address families over TCP or UDP.
+const INET: u8 = 0x10;
+const INET6: u8 = 0x20;
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Total length of the header starting with a prefix.
+pub fn header_len(prefix: &[u8]) -> io::Result<usize> {
+    if prefix.len() < PREFIX_LEN || prefix[..12] != SIGNATURE {
+        return Err(invalid(
+            "connection did not start with a PROXY protocol header",
+        ));
+    }
+
+    if prefix[12] & 0xF0 != 0x20 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+
+    Ok(PREFIX_LEN + usize::from(u16::from_be_bytes([prefix[14], prefix[15]])))
+}
+
+/// The source address of a complete header.
+///
+/// Returns `None` for connections of the load balancer itself and for
+/// addresses that aren't IPv4 or IPv6, whose peer address should be used.
+pub fn source(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let addresses = &header[PREFIX_LEN..];
+
+    match header[12] {
+        LOCAL => return Ok(None),
+        PROXY => {}
+        _ => return Err(invalid("unsupported PROXY protocol command")),
+    }
+
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+
+    match header[13] & 0xF0 {
+        INET if addresses.len() >= 12 => {
+            let ip = <[u8; 4]>::try_from(&addresses[..4]).expect("length is checked");
+
+            Ok(Some(SocketAddr::from((Ipv4Addr::from(ip), port(8)))))
+        }
+        INET6 if addresses.len() >= 36 => {
+            let ip = <[u8; 16]>::try_from(&addresses[..16]).expect("length is checked");
+
+            Ok(Some(SocketAddr::from((Ipv6Addr::from(ip), port(32)))))
+        }
+        INET | INET6 => Err(invalid("PROXY protocol header is too short")),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{header_len, source, PREFIX_LEN, SIGNATURE};
+    use std::net::SocketAddr;
+
+    fn header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = SIGNATURE.to_vec();
+        header.extend([command, family]);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+
+        header
+    }
+
+    #[test]
+    fn test_ipv4() {
+        let header = header(
+            0x21,
+            0x11,
+            &[203, 0, 113, 7, 10, 0, 0, 1, 0xC3, 0x50, 0, 80],
+        );
+
+        assert_eq!(header_len(&header[..PREFIX_LEN]).unwrap(), header.len());
+        assert_eq!(
+            source(&header).unwrap(),
+            Some("203.0.113.7:50000".parse::<SocketAddr>().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_ipv6() {
+        let mut addresses = vec![0; 36];
+        addresses[..2].copy_from_slice(&[0x20, 0x01]);
+        addresses[15] = 1;
+        addresses[32..34].copy_from_slice(&443_u16.to_be_bytes());
+
+        assert_eq!(
+            source(&header(0x21, 0x21, &addresses)).unwrap(),
+            Some("[2001::1]:443".parse::<SocketAddr>().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(header_len(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(header_len(&header(0x11, 0x11, &[])).is_err());
+
+        // Health checks of the load balancer and unknown families use the
+        // peer address
+        assert_eq!(source(&header(0x20, 0x00, &[])).unwrap(), None);
+        assert_eq!(source(&header(0x21, 0x31, &[0; 216])).unwrap(), None);
+
+        assert!(source(&header(0x21, 0x11, &[0; 4])).is_err());
+        assert!(source(&header(0x22, 0x11, &[0; 12])).is_err());
+    }
+}
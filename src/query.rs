@@ -0,0 +1,173 @@
+use std::{
+    error::Error,
+    fmt::{Display, Formatter, Result as FmtResult},
+};
+
+/// Maximum query string length if `MAX_QUERY_LENGTH` is not set.
+pub const DEFAULT_MAX_LENGTH: usize = 2048;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum InvalidQuery {
+    /// The query contains a control character, either raw or percent-encoded.
+    ControlCharacter {
+        position: usize,
+    },
+    /// The query contains a `#`, which would start a fragment.
+    Fragment {
+        position: usize,
+    },
+    /// A `%` is not followed by two hex digits.
+    MalformedEscape {
+        position: usize,
+    },
+    TooLong {
+        length: usize,
+        max: usize,
+    },
+}
+
+impl Display for InvalidQuery {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::ControlCharacter { position } => {
+                f.write_str("control character at position ")?;
+                position.fmt(f)
+            }
+            Self::Fragment { position } => {
+                f.write_str("fragment delimiter at position ")?;
+                position.fmt(f)
+            }
+            Self::MalformedEscape { position } => {
+                f.write_str("malformed percent-encoding at position ")?;
+                position.fmt(f)
+            }
+            Self::TooLong { length, max } => {
+                f.write_str("query is ")?;
+                length.fmt(f)?;
+                f.write_str(" bytes long, the maximum is ")?;
+                max.fmt(f)
+            }
+        }
+    }
+}
+
+impl Error for InvalidQuery {}
+
+/// Validate a query string before it is forwarded.
+///
+/// The query is forwarded byte-for-byte, so this only rejects queries that
+/// Discord or intermediaries could interpret differently than the proxy.
+pub fn validate_query(query: &str, max_length: usize) -> Result<(), InvalidQuery> {
+    if query.len() > max_length {
+        return Err(InvalidQuery::TooLong {
+            length: query.len(),
+            max: max_length,
+        });
+    }
+
+    let bytes = query.as_bytes();
+
+    for (position, byte) in bytes.iter().enumerate() {
+        if byte.is_ascii_control() {
+            return Err(InvalidQuery::ControlCharacter { position });
+        }
+
+        if *byte == b'#' {
+            return Err(InvalidQuery::Fragment { position });
+        }
+
+        if *byte != b'%' {
+            continue;
+        }
+
+        let decoded = match bytes.get(position + 1..position + 3) {
+            Some([high, low]) if high.is_ascii_hexdigit() && low.is_ascii_hexdigit() => {
+                let digits = [*high, *low];
+                let digits = std::str::from_utf8(&digits).expect("hex digits are ascii");
+
+                u8::from_str_radix(digits, 16).expect("digits are valid hex")
+            }
+            _ => return Err(InvalidQuery::MalformedEscape { position }),
+        };
+
+        if decoded.is_ascii_control() {
+            return Err(InvalidQuery::ControlCharacter { position });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate_query, InvalidQuery};
+    use crate::upstream::Upstream;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_validate_query() {
+        assert!(validate_query("limit=100&after=123", 2048).is_ok());
+        assert!(validate_query("name=%C3%BC%20", 2048).is_ok());
+        assert_eq!(
+            validate_query("a=b", 2),
+            Err(InvalidQuery::TooLong { length: 3, max: 2 })
+        );
+        assert_eq!(
+            validate_query("a=%0d%0aHost:%20evil", 2048),
+            Err(InvalidQuery::ControlCharacter { position: 2 })
+        );
+        assert_eq!(
+            validate_query("a=%7F", 2048),
+            Err(InvalidQuery::ControlCharacter { position: 2 })
+        );
+        assert_eq!(
+            validate_query("a=b#c", 2048),
+            Err(InvalidQuery::Fragment { position: 3 })
+        );
+        assert_eq!(
+            validate_query("a=%2", 2048),
+            Err(InvalidQuery::MalformedEscape { position: 2 })
+        );
+        assert_eq!(
+            validate_query("a=%zz", 2048),
+            Err(InvalidQuery::MalformedEscape { position: 2 })
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn never_panics(query in ".*") {
+            _ = validate_query(&query, 2048);
+        }
+
+        #[test]
+        fn rejects_control_characters(
+            prefix in "[a-z=&]{0,20}",
+            control in 0u8..0x20,
+            encoded in any::<bool>(),
+        ) {
+            let control = if encoded {
+                format!("%{:02x}", control)
+            } else {
+                char::from(control).to_string()
+            };
+
+            prop_assert!(validate_query(&(prefix + &control), 2048).is_err());
+        }
+
+        #[test]
+        fn accepted_queries_are_forwarded_exactly(query in "[ -~]{0,200}") {
+            let upstream = Upstream::new("https://discord.com").unwrap();
+
+            if validate_query(&query, 2048).is_ok() {
+                // Queries that don't form a valid URI are rejected while
+                // building it, which is fine as long as nothing is altered
+                if let Ok(uri) = upstream.uri("/api/v10", "/gateway", Some(&query)) {
+                    prop_assert_eq!(uri.query(), Some(query.as_str()));
+                    prop_assert_eq!(uri.path(), "/api/v10/gateway");
+                    prop_assert_eq!(uri.host(), Some("discord.com"));
+                }
+            }
+        }
+    }
+}
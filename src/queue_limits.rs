@@ -0,0 +1,106 @@
+//! Caps on the amount of requests waiting for their ratelimit, so a client
+//! flooding one bucket can't make the proxy hold an unbounded amount of
+//! requests in memory.
+//!
+//! Requests beyond a cap are answered with a `503` right away instead of
+//! being queued.
+
+use crate::parse_env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::debug;
+
+/// Configured via `MAX_QUEUED_PER_BUCKET` and `MAX_QUEUED`.
+pub struct QueueLimits {
+    /// Maximum amount of queued requests of a token to a path.
+    per_bucket: Option<usize>,
+    /// Maximum amount of queued requests of all tokens.
+    total: Option<usize>,
+    queued: AtomicUsize,
+}
+
+/// Holds a place in the queue, given back when dropped.
+pub struct Slot<'a>(&'a AtomicUsize);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl QueueLimits {
+    /// Returns `None` if neither cap is configured.
+    pub fn from_env() -> Option<Self> {
+        let per_bucket = parse_env("MAX_QUEUED_PER_BUCKET");
+        let total = parse_env("MAX_QUEUED");
+
+        if per_bucket.is_none() && total.is_none() {
+            return None;
+        }
+
+        Some(Self::new(per_bucket, total))
+    }
+
+    const fn new(per_bucket: Option<usize>, total: Option<usize>) -> Self {
+        Self {
+            per_bucket,
+            total,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Take a place in the queue for a request, given the amount of requests
+    /// queued in its bucket including itself.
+    ///
+    /// Returns `None` if either cap is reached.
+    pub fn admit(&self, bucket_queued: usize) -> Option<Slot<'_>> {
+        if self.per_bucket.is_some_and(|limit| bucket_queued > limit) {
+            debug!(
+                "Shedding request, {} are queued in its bucket",
+                bucket_queued
+            );
+
+            return None;
+        }
+
+        let queued = self.queued.fetch_add(1, Ordering::SeqCst);
+        let slot = Slot(&self.queued);
+
+        if self.total.is_some_and(|limit| queued >= limit) {
+            debug!("Shedding request, {} are queued in total", queued);
+
+            return None;
+        }
+
+        Some(slot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QueueLimits;
+
+    #[test]
+    fn test_admit() {
+        let limits = QueueLimits::new(Some(2), Some(3));
+
+        let first = limits.admit(1);
+        assert!(first.is_some());
+        assert!(limits.admit(3).is_none());
+
+        let second = limits.admit(2);
+        let third = limits.admit(1);
+        assert!(second.is_some() && third.is_some());
+        assert!(limits.admit(1).is_none());
+
+        drop(first);
+        assert!(limits.admit(1).is_some());
+    }
+
+    #[test]
+    fn test_admit_per_bucket() {
+        let limits = QueueLimits::new(Some(1), None);
+
+        assert!(limits.admit(1).is_some());
+        assert!(limits.admit(2).is_none());
+    }
+}
@@ -0,0 +1,71 @@
+//! Maximum time requests wait for their ratelimit, after which they are
+//! answered right away, so client libraries time out cleanly instead of
+//! keeping their connections open until an exhausted bucket resets.
+
+use crate::{deadline::Stage, error::RequestError, fail_fast, parse_env, tenant::Tenant};
+use std::env;
+use tokio::time::Duration;
+use tracing::{debug, warn};
+use twilight_http_ratelimiting::Path;
+
+/// Configured via `MAX_QUEUE_WAIT_MS` and `QUEUE_TIMEOUT_STATUS`.
+pub struct QueueTimeout {
+    max_wait: Duration,
+    /// Whether requests that waited too long get a `504` instead of a `429`.
+    gateway_timeout: bool,
+}
+
+impl QueueTimeout {
+    /// Returns `None` if no maximum wait is configured.
+    pub fn from_env() -> Option<Self> {
+        let max_wait = Duration::from_millis(parse_env("MAX_QUEUE_WAIT_MS")?);
+
+        let gateway_timeout = match env::var("QUEUE_TIMEOUT_STATUS").as_deref() {
+            Err(_) | Ok("429") => false,
+            Ok("504") => true,
+            Ok(other) => {
+                warn!(
+                    "QUEUE_TIMEOUT_STATUS {:?} is neither 429 nor 504, using 429",
+                    other
+                );
+
+                false
+            }
+        };
+
+        Some(Self {
+            max_wait,
+            gateway_timeout,
+        })
+    }
+
+    pub const fn max_wait(&self) -> Duration {
+        self.max_wait
+    }
+
+    /// Error for a request to a path that waited too long for its ticket.
+    ///
+    /// `429`s tell clients to retry once the bucket is expected to have room
+    /// for them.
+    pub async fn exceeded(&self, tenant: &Tenant, path: &Path) -> RequestError {
+        debug!(
+            "Request waited longer than {:?} for its ticket",
+            self.max_wait
+        );
+
+        if self.gateway_timeout {
+            return RequestError::DeadlineExceeded {
+                stage: Stage::Queue,
+            };
+        }
+
+        // The request is still queued itself
+        let ahead = tenant.usage.queued(path).saturating_sub(1);
+        let wait = fail_fast::estimated_wait(tenant, path, ahead).await;
+
+        RequestError::QueueTimeout {
+            // Round up so clients don't retry before the reset
+            retry_after: wait.as_secs() + 1,
+        }
+    }
+}
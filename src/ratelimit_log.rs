@@ -0,0 +1,121 @@
+//! Record of the most recent 429s Discord responded with, answering what
+//! exactly is getting ratelimited without searching the logs.
+
+use crate::parse_env;
+use http::HeaderMap;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Amount of 429s kept if `RATELIMIT_LOG_SIZE` is not set.
+const DEFAULT_SIZE: usize = 100;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Ratelimited {
+    /// Milliseconds since the Unix epoch.
+    at: u64,
+    method: String,
+    route: String,
+    tenant: String,
+    /// `X-RateLimit-Scope` of the response, e.g. `user` or `shared`.
+    scope: Option<String>,
+    global: bool,
+    retry_after_ms: Option<u64>,
+}
+
+/// Configured via `RATELIMIT_LOG_SIZE`.
+pub struct RatelimitLog {
+    size: usize,
+    entries: Mutex<VecDeque<Ratelimited>>,
+}
+
+impl RatelimitLog {
+    pub fn from_env() -> Self {
+        Self::new(parse_env("RATELIMIT_LOG_SIZE").unwrap_or(DEFAULT_SIZE))
+    }
+
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            entries: Mutex::new(VecDeque::with_capacity(size)),
+        }
+    }
+
+    /// Record a 429 with its response headers.
+    pub fn record(&self, method: &str, route: &str, tenant: &str, headers: &HeaderMap) {
+        if self.size == 0 {
+            return;
+        }
+
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+        // Discord sends the reset in seconds with millisecond precision
+        let retry_after_ms = header("x-ratelimit-reset-after")
+            .or_else(|| header("retry-after"))
+            .and_then(|value| value.parse::<f64>().ok())
+            .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+            .map(|seconds| (seconds * 1000.0) as u64);
+
+        let entry = Ratelimited {
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_millis() as u64),
+            method: method.to_string(),
+            route: route.to_string(),
+            tenant: tenant.to_string(),
+            scope: header("x-ratelimit-scope").map(ToString::to_string),
+            global: header("x-ratelimit-global").is_some(),
+            retry_after_ms,
+        };
+
+        let mut entries = self.entries.lock().expect("ratelimit log poisoned");
+
+        if entries.len() == self.size {
+            entries.pop_front();
+        }
+
+        entries.push_back(entry);
+    }
+
+    /// The recorded 429s, most recent first.
+    pub fn entries(&self) -> Vec<Ratelimited> {
+        let entries = self.entries.lock().expect("ratelimit log poisoned");
+
+        entries.iter().rev().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RatelimitLog;
+    use http::HeaderMap;
+
+    #[test]
+    fn test_record() {
+        let log = RatelimitLog::new(2);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-scope", "shared".parse().unwrap());
+        headers.insert("x-ratelimit-reset-after", "1.250".parse().unwrap());
+        log.record("POST", "Channel messages", "abc", &headers);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", "3".parse().unwrap());
+        headers.insert("x-ratelimit-global", "true".parse().unwrap());
+        log.record("GET", "Gateway", "def", &headers);
+
+        log.record("GET", "Guild", "abc", &HeaderMap::new());
+
+        let entries = log.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].route, "Guild");
+        assert_eq!(entries[0].retry_after_ms, None);
+        assert_eq!(entries[1].route, "Gateway");
+        assert_eq!(entries[1].retry_after_ms, Some(3000));
+        assert!(entries[1].global);
+        assert_eq!(entries[1].scope, None);
+    }
+}
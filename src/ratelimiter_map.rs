@@ -1,27 +1,53 @@
-use crate::expiring_lru::{Builder, ExpiringLru};
+use crate::{
+    backend::Backend,
+    expiring_lru::{Builder, ExpiringLru},
+    tenant::{hash_token, Tenant},
+};
+use http::{HeaderMap, StatusCode};
+use std::{mem, sync::RwLock};
 use tokio::time::Duration;
-use twilight_http_ratelimiting::InMemoryRatelimiter;
+use twilight_http_ratelimiting::RatelimitHeaders;
 
 use crate::parse_env;
 
+/// Seconds after which unused tenants are dropped if `CLIENT_DECAY_TIMEOUT`
+/// is not set.
+const DEFAULT_DECAY_TIMEOUT: u64 = 3600;
+
+/// Make sure a token is either a bot or bearer token, and assume it's a bot
+/// token if no prefix is given.
+pub fn with_prefix(mut token: String) -> String {
+    if !token.starts_with("Bot ") && !token.starts_with("Bearer ") {
+        token.insert_str(0, "Bot ");
+    }
+
+    token
+}
+
 pub struct RatelimiterMap {
-    default: InMemoryRatelimiter,
-    default_token: String,
-    inner: ExpiringLru<String, InMemoryRatelimiter>,
+    /// Creates the ratelimiters of new tenants.
+    backend: Box<dyn Backend>,
+    /// Tenant and token used for requests without an `Authorization` header.
+    default: RwLock<Option<(Tenant, String)>>,
+    inner: ExpiringLru<String, Tenant>,
 }
 
 impl RatelimiterMap {
-    pub fn new(mut default_token: String) -> Self {
-        let is_bot = default_token.starts_with("Bot ");
-        let is_bearer = default_token.starts_with("Bearer ");
+    pub fn new(default_token: Option<String>, backend: Box<dyn Backend>) -> Self {
+        let default = default_token.map(|default_token| {
+            let default_token = with_prefix(default_token);
 
-        // Make sure it is either a bot or bearer token, and assume it's a bot
-        // token if no prefix is given
-        if !is_bot && !is_bearer {
-            default_token.insert_str(0, "Bot ");
-        }
+            (
+                Tenant::with_ratelimiter(
+                    &default_token,
+                    backend.ratelimiter(&hash_token(&default_token)),
+                ),
+                default_token,
+            )
+        });
 
-        let expiration = Duration::from_secs(parse_env("CLIENT_DECAY_TIMEOUT").unwrap_or(3600));
+        let expiration =
+            Duration::from_secs(parse_env("CLIENT_DECAY_TIMEOUT").unwrap_or(DEFAULT_DECAY_TIMEOUT));
 
         let mut builder = Builder::new().expiration(expiration);
 
@@ -31,30 +57,265 @@ impl RatelimiterMap {
 
         let inner = builder.build();
 
-        let default = InMemoryRatelimiter::new();
-
         Self {
-            default,
-            default_token,
+            backend,
+            default: RwLock::new(default),
             inner,
         }
     }
 
-    pub fn get_or_insert(&self, token: Option<&str>) -> (InMemoryRatelimiter, String) {
-        if let Some(token) = token {
-            if token == self.default_token {
-                (self.default.clone(), self.default_token.clone())
-            } else if let Some(entry) = self.inner.get(token) {
-                (entry.value().clone(), token.to_string())
-            } else {
-                let ratelimiter = InMemoryRatelimiter::new();
+    fn tenant(&self, token: &str) -> Tenant {
+        Tenant::with_ratelimiter(token, self.backend.ratelimiter(&hash_token(token)))
+    }
+
+    fn default(&self) -> Option<(Tenant, String)> {
+        self.default
+            .read()
+            .expect("default tenant poisoned")
+            .clone()
+    }
 
-                self.inner.insert(token.to_string(), ratelimiter.clone());
+    /// Replace the default token.
+    ///
+    /// The previous default tenant is kept in the LRU, so its buckets are
+    /// still used by requests sending its token explicitly, and a new default
+    /// token that was already sent explicitly keeps its buckets as well.
+    pub fn set_default_token(&self, default_token: Option<String>) {
+        let default_token = default_token.map(with_prefix);
+        let mut default = self.default.write().expect("default tenant poisoned");
+
+        if default.as_ref().map(|(_, token)| token) == default_token.as_ref() {
+            return;
+        }
+
+        let new = default_token.map(|token| {
+            let tenant = match self.inner.get(&token) {
+                Some(entry) => entry.value().clone(),
+                None => self.tenant(&token),
+            };
+
+            (tenant, token)
+        });
+
+        if let Some((tenant, token)) = mem::replace(&mut *default, new) {
+            self.inner.insert(token, tenant);
+        }
+    }
+
+    /// Change how long unused tenants are kept and how many, evicting the
+    /// least recently used ones if there are too many.
+    pub fn set_cache_limits(&self, decay_timeout: Option<u64>, max_size: Option<usize>) {
+        self.inner.resize(
+            Duration::from_secs(decay_timeout.unwrap_or(DEFAULT_DECAY_TIMEOUT)),
+            max_size,
+        );
+    }
 
-                (ratelimiter, token.to_string())
+    /// Get the tenant for a token, falling back to the default token if none
+    /// is given.
+    ///
+    /// Returns `None` if no token is given and no default token is configured.
+    pub fn get_or_insert(&self, token: Option<&str>) -> Option<(Tenant, String)> {
+        let default = self.default();
+
+        let token = match token {
+            Some(token) => token,
+            None => return default,
+        };
+
+        match default {
+            Some((tenant, default_token)) if token == default_token => {
+                return Some((tenant, default_token));
             }
+            _ => {}
+        }
+
+        if let Some(entry) = self.inner.get(token) {
+            Some((entry.value().clone(), token.to_string()))
+        } else {
+            let tenant = self.tenant(token);
+
+            self.inner.insert(token.to_string(), tenant.clone());
+
+            Some((tenant, token.to_string()))
+        }
+    }
+
+    /// Get the tenant for a webhook executed with its token instead of an
+    /// `Authorization` header.
+    ///
+    /// Webhooks share the LRU with regular tokens, but are keyed by their ID
+    /// and token so they don't use the default token's ratelimits.
+    pub fn get_or_insert_webhook(&self, id: &str, token: &str) -> Tenant {
+        let key = format!("Webhook {}/{}", id, token);
+
+        if let Some(entry) = self.inner.get(&key) {
+            entry.value().clone()
         } else {
-            (self.default.clone(), self.default_token.clone())
+            let tenant = self.tenant(&key);
+
+            self.inner.insert(key, tenant.clone());
+
+            tenant
+        }
+    }
+
+    /// Look up a tenant by its token hash without refreshing its expiration.
+    pub fn get_by_hash(&self, hash: &str) -> Option<Tenant> {
+        match self.default() {
+            Some((tenant, _)) if tenant.usage.hash() == hash => return Some(tenant),
+            _ => {}
+        }
+
+        self.inner.find(|_, tenant| tenant.usage.hash() == hash)
+    }
+
+    /// All tenants, including the default one, without refreshing their
+    /// expiration.
+    pub fn tenants(&self) -> Vec<Tenant> {
+        let mut tenants = self.inner.values();
+
+        if let Some((tenant, _)) = self.default() {
+            tenants.push(tenant);
         }
+
+        tenants
+    }
+}
+
+/// Parse the ratelimit headers of a response from Discord.
+///
+/// Resets are kept in millisecond precision, so fractional
+/// `X-RateLimit-Reset-After` values are never truncated to whole seconds.
+pub fn ratelimit_headers(headers: &HeaderMap) -> Option<RatelimitHeaders> {
+    RatelimitHeaders::from_pairs(
+        headers
+            .into_iter()
+            .map(|(name, value)| (name.as_str(), value.as_bytes())),
+    )
+    .ok()
+}
+
+/// Whether a response is a 429 with shared scope.
+///
+/// These are caused by a resource-wide limit rather than the token's own
+/// usage, so they must not affect the token's bucket.
+pub fn is_shared_ratelimit(status: StatusCode, headers: &HeaderMap) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        && headers
+            .get("x-ratelimit-scope")
+            .is_some_and(|scope| scope == "shared")
+}
+
+/// Extract the webhook ID and token from an API path without version prefix,
+/// such as `/webhooks/1/abc/messages/2`.
+pub fn webhook_credentials(path: &str) -> Option<(&str, &str)> {
+    let mut segments = path.strip_prefix("/webhooks/")?.split('/');
+    let id = segments.next()?;
+    let token = segments.next()?;
+
+    if id.is_empty() || !id.bytes().all(|byte| byte.is_ascii_digit()) || token.is_empty() {
+        return None;
+    }
+
+    Some((id, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ratelimit_headers, webhook_credentials, with_prefix, RatelimiterMap};
+    use crate::backend::InMemory;
+    use http::{HeaderMap, HeaderValue};
+    use std::sync::Arc;
+    use tokio::time::{Duration, Instant};
+    use twilight_http_ratelimiting::{InMemoryRatelimiter, Path, RatelimitHeaders, Ratelimiter};
+
+    #[test]
+    fn test_with_prefix() {
+        assert_eq!(with_prefix("abc".to_string()), "Bot abc");
+        assert_eq!(with_prefix("Bot abc".to_string()), "Bot abc");
+        assert_eq!(with_prefix("Bearer abc".to_string()), "Bearer abc");
+    }
+
+    fn headers(remaining: &'static str, reset_after: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-limit", HeaderValue::from_static("1"));
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_static(remaining));
+        headers.insert(
+            "x-ratelimit-reset",
+            HeaderValue::from_static("1700000000.250"),
+        );
+        headers.insert(
+            "x-ratelimit-reset-after",
+            HeaderValue::from_static(reset_after),
+        );
+
+        headers
+    }
+
+    #[test]
+    fn test_ratelimit_headers_precision() {
+        let reset_after = |value| match ratelimit_headers(&headers("0", value)) {
+            Some(RatelimitHeaders::Present(present)) => present.reset_after(),
+            _ => panic!("headers are present"),
+        };
+
+        assert_eq!(reset_after("0.250"), 250);
+        assert_eq!(reset_after("1.5"), 1500);
+        // Sub-millisecond values are rounded up rather than released early
+        assert_eq!(reset_after("0.0004"), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sub_second_bucket() {
+        let ratelimiter = InMemoryRatelimiter::new();
+        let start = Instant::now();
+
+        let sender = ratelimiter.wait_for_ticket(Path::Gateway).await.unwrap();
+        sender
+            .headers(ratelimit_headers(&headers("0", "0.250")))
+            .unwrap();
+
+        // Let the bucket task process the headers
+        tokio::task::yield_now().await;
+
+        let sender = ratelimiter.wait_for_ticket(Path::Gateway).await.unwrap();
+        let waited = start.elapsed();
+
+        assert!(waited >= Duration::from_millis(249), "waited {:?}", waited);
+        assert!(waited < Duration::from_secs(1), "waited {:?}", waited);
+
+        sender.headers(None).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_set_default_token() {
+        let map = RatelimiterMap::new(Some("a".to_string()), Box::new(InMemory));
+        let (previous, token) = map.get_or_insert(None).unwrap();
+        assert_eq!(token, "Bot a");
+
+        map.set_default_token(Some("Bearer b".to_string()));
+        tokio::task::yield_now().await;
+        assert_eq!(map.get_or_insert(None).unwrap().1, "Bearer b");
+
+        // The previous default tenant keeps its buckets
+        let (tenant, _) = map.get_or_insert(Some("Bot a")).unwrap();
+        assert!(Arc::ptr_eq(&tenant.usage, &previous.usage));
+
+        map.set_default_token(None);
+        assert!(map.get_or_insert(None).is_none());
+    }
+
+    #[test]
+    fn test_webhook_credentials() {
+        assert_eq!(webhook_credentials("/webhooks/1/abc"), Some(("1", "abc")));
+        assert_eq!(
+            webhook_credentials("/webhooks/1/abc/messages/2"),
+            Some(("1", "abc"))
+        );
+        assert_eq!(webhook_credentials("/webhooks/1"), None);
+        assert_eq!(webhook_credentials("/webhooks/1/"), None);
+        assert_eq!(webhook_credentials("/webhooks/abc/def"), None);
+        assert_eq!(webhook_credentials("/channels/1/webhooks"), None);
     }
 }
@@ -0,0 +1,244 @@
+//! Batching of reaction requests, which bots often send in quick succession
+//! and which are limited by a sublimit Discord doesn't advertise.
+//!
+//! Adding and removing reactions in a channel are paced together, and a
+//! request for a reaction that is already pending with the same method waits
+//! for the pending one instead of being sent again. Only successful outcomes
+//! are shared, so requests of a failed one are sent on their own.
+
+use crate::sublimit::{Rule, Sublimits};
+use http::{HeaderMap, HeaderValue, StatusCode};
+use hyper::{Body, Response};
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::watch::{self, Receiver, Sender};
+use twilight_http_ratelimiting::{Method, Path};
+
+/// Header added to responses shared with a pending request.
+pub const COALESCED_HEADER: &str = "x-proxy-coalesced";
+
+/// Headers of the successful response of a pending request, once it is
+/// received.
+type Outcome = Option<HeaderMap>;
+
+/// Token hash and path of a reaction.
+type Key = (String, String);
+
+struct Pending {
+    method: Method,
+    outcome: Arc<Sender<Outcome>>,
+}
+
+/// Enabled by setting `REACTION_BATCHING`.
+#[derive(Default)]
+pub struct ReactionBatching {
+    pending: Mutex<HashMap<Key, Pending>>,
+}
+
+impl ReactionBatching {
+    pub fn from_env() -> Option<Self> {
+        env::var_os("REACTION_BATCHING").map(|_| Self::default())
+    }
+
+    /// Sublimit to pace removing a reaction with, which is the one of adding
+    /// reactions.
+    pub fn rule<'a>(
+        &self,
+        sublimits: &'a Sublimits,
+        method: Method,
+        path: &Path,
+    ) -> Option<&'a Rule> {
+        match (method, path) {
+            (Method::Delete, Path::ChannelsIdMessagesIdReactionsUserIdType(_)) => {
+                sublimits.rule(Method::Put, path)
+            }
+            _ => None,
+        }
+    }
+
+    /// Join the pending request for the same reaction with the same method,
+    /// or become the pending one.
+    ///
+    /// Returns `None` for requests that aren't adding or removing a reaction.
+    pub fn join(
+        &self,
+        hash: &str,
+        method: Method,
+        path: &Path,
+        raw_path: &str,
+    ) -> Option<Join<'_>> {
+        if !matches!(method, Method::Delete | Method::Put)
+            || !matches!(path, Path::ChannelsIdMessagesIdReactionsUserIdType(_))
+        {
+            return None;
+        }
+
+        let key = (hash.to_string(), raw_path.to_string());
+        let mut pending = self.pending.lock().expect("pending reactions poisoned");
+
+        if let Some(existing) = pending.get(&key) {
+            if existing.method == method {
+                return Some(Join::Follow(existing.outcome.subscribe()));
+            }
+        }
+
+        // The opposite request has to be sent after the pending one, so later
+        // requests must not skip it by joining the earlier one
+        let (outcome, _) = watch::channel(None);
+        let outcome = Arc::new(outcome);
+
+        pending.insert(
+            key.clone(),
+            Pending {
+                method,
+                outcome: outcome.clone(),
+            },
+        );
+
+        Some(Join::Lead(Lead {
+            batching: self,
+            key,
+            outcome,
+        }))
+    }
+}
+
+pub enum Join<'a> {
+    /// The request is sent and its outcome shared.
+    Lead(Lead<'a>),
+    /// The request waits for the outcome of the pending one.
+    Follow(Receiver<Outcome>),
+}
+
+pub struct Lead<'a> {
+    batching: &'a ReactionBatching,
+    key: Key,
+    outcome: Arc<Sender<Outcome>>,
+}
+
+impl Lead<'_> {
+    /// Share the response with requests waiting for it if it succeeded.
+    pub fn finish(self, response: &Response<Body>) {
+        if response.status() == StatusCode::NO_CONTENT {
+            self.outcome.send_replace(Some(response.headers().clone()));
+        }
+    }
+}
+
+impl Drop for Lead<'_> {
+    fn drop(&mut self) {
+        let mut pending = self
+            .batching
+            .pending
+            .lock()
+            .expect("pending reactions poisoned");
+
+        if pending
+            .get(&self.key)
+            .is_some_and(|pending| Arc::ptr_eq(&pending.outcome, &self.outcome))
+        {
+            pending.remove(&self.key);
+        }
+    }
+}
+
+/// Wait for the outcome of the pending request.
+///
+/// Returns `None` if it failed, so the request has to be sent on its own.
+pub async fn follow(mut outcome: Receiver<Outcome>) -> Option<Response<Body>> {
+    loop {
+        if let Some(headers) = outcome.borrow_and_update().clone() {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NO_CONTENT;
+            *response.headers_mut() = headers;
+            response
+                .headers_mut()
+                .insert(COALESCED_HEADER, HeaderValue::from_static("true"));
+
+            return Some(response);
+        }
+
+        outcome.changed().await.ok()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{follow, Join, ReactionBatching, COALESCED_HEADER};
+    use http::StatusCode;
+    use hyper::{Body, Response};
+    use twilight_http_ratelimiting::{Method, Path};
+
+    const REACTION: &str = "/channels/1/messages/2/reactions/%F0%9F%91%8D/@me";
+
+    fn join(batching: &ReactionBatching, method: Method) -> Join<'_> {
+        batching
+            .join(
+                "hash",
+                method,
+                &Path::ChannelsIdMessagesIdReactionsUserIdType(1),
+                REACTION,
+            )
+            .unwrap()
+    }
+
+    fn response(status: StatusCode) -> Response<Body> {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = status;
+
+        response
+    }
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let batching = ReactionBatching::default();
+
+        let lead = match join(&batching, Method::Put) {
+            Join::Lead(lead) => lead,
+            Join::Follow(_) => panic!("no request is pending"),
+        };
+        let follower = match join(&batching, Method::Put) {
+            Join::Follow(outcome) => tokio::spawn(follow(outcome)),
+            Join::Lead(_) => panic!("the request is pending"),
+        };
+
+        // Removing the reaction again must not be skipped
+        assert!(matches!(join(&batching, Method::Delete), Join::Lead(_)));
+
+        lead.finish(&response(StatusCode::NO_CONTENT));
+
+        let shared = follower.await.unwrap().unwrap();
+        assert_eq!(shared.status(), StatusCode::NO_CONTENT);
+        assert_eq!(shared.headers()[COALESCED_HEADER], "true");
+        assert!(batching.pending.lock().unwrap().is_empty());
+
+        assert!(batching
+            .join(
+                "hash",
+                Method::Get,
+                &Path::ChannelsIdMessagesIdReactions(1),
+                REACTION
+            )
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_failed() {
+        let batching = ReactionBatching::default();
+        let lead = join(&batching, Method::Delete);
+        let follower = match join(&batching, Method::Delete) {
+            Join::Follow(outcome) => tokio::spawn(follow(outcome)),
+            Join::Lead(_) => panic!("the request is pending"),
+        };
+
+        match lead {
+            Join::Lead(lead) => lead.finish(&response(StatusCode::TOO_MANY_REQUESTS)),
+            Join::Follow(_) => panic!("no request is pending"),
+        }
+
+        assert!(follower.await.unwrap().is_none());
+    }
+}
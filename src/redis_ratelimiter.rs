@@ -0,0 +1,554 @@
+//! Ratelimiter keeping bucket state and the global lock in Redis, so several
+//! replicas of the proxy share the ratelimits of the tokens they forward.
+//!
+//! Buckets are updated by Lua scripts using the clock of Redis, so replicas
+//! with skewed clocks agree on when a bucket resets. Until Discord's headers
+//! of the first request are known, a bucket lets one request through at a
+//! time, like the in-memory ratelimiter.
+//!
+//! The tests of the scripts run against the Redis server at `REDIS_TEST_URL`
+//! and are skipped if it's not set.
+
+use redis::{aio::ConnectionManager, Client, Script};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter, Result as FmtResult},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tokio::{
+    sync::Mutex as AsyncMutex,
+    time::{sleep, timeout, Duration},
+};
+use tracing::{debug, warn};
+use twilight_http_ratelimiting::{
+    ticket, Bucket, GenericError, GetBucketFuture, GetTicketFuture, HasBucketFuture,
+    IsGloballyLockedFuture, Path, RatelimitHeaders, Ratelimiter,
+};
+
+/// Prefix of all keys if `REDIS_KEY_PREFIX` is not set.
+const DEFAULT_PREFIX: &str = "twilight-http-proxy";
+
+/// How long to wait for the response headers of a request, after which its
+/// bucket lets the next request through.
+const WAIT: Duration = Duration::from_secs(10);
+
+/// Interval in which requests waiting for the headers of a bucket's first
+/// request check whether they arrived.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long buckets are kept after they reset without being used.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Take a ticket of a bucket, returning `0` if it was granted and the
+/// milliseconds to wait before trying again otherwise.
+///
+/// Unknown buckets are created as pending with a single ticket.
+const ACQUIRE: &str = r"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local wait = tonumber(ARGV[1])
+local bucket = redis.call('HMGET', KEYS[1], 'limit', 'remaining', 'reset_after', 'reset_at', 'pending')
+
+if not bucket[1] then
+    redis.call('HSET', KEYS[1], 'limit', 1, 'remaining', 0, 'reset_after', wait, 'reset_at', now + wait, 'pending', 1)
+    redis.call('PEXPIRE', KEYS[1], wait)
+
+    return 0
+end
+
+local remaining = tonumber(bucket[2])
+local reset_at = tonumber(bucket[4])
+
+if reset_at <= now then
+    remaining = tonumber(bucket[1])
+    reset_at = now + tonumber(bucket[3])
+end
+
+if remaining > 0 then
+    redis.call('HSET', KEYS[1], 'remaining', remaining - 1, 'reset_at', reset_at)
+    redis.call('PEXPIRE', KEYS[1], reset_at - now + tonumber(ARGV[3]))
+
+    return 0
+end
+
+if bucket[5] then
+    return math.min(reset_at - now, tonumber(ARGV[2]))
+end
+
+return reset_at - now
+";
+
+/// Store the ratelimit headers of a response.
+///
+/// Several requests of a bucket may be in flight, so their responses can
+/// arrive out of order. Within the stored window, the remaining tickets are
+/// only ever lowered, so a late response can't give back tickets that were
+/// already handed out.
+const UPDATE: &str = r"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local bucket = redis.call('HMGET', KEYS[1], 'remaining', 'reset_at', 'pending')
+local remaining = tonumber(ARGV[2])
+local reset_at = now + tonumber(ARGV[3])
+
+if bucket[1] and not bucket[3] and tonumber(bucket[2]) > now and tonumber(bucket[1]) < remaining then
+    remaining = tonumber(bucket[1])
+    reset_at = tonumber(bucket[2])
+end
+
+redis.call('HSET', KEYS[1], 'limit', ARGV[1], 'remaining', remaining, 'reset_after', ARGV[3], 'reset_at', reset_at)
+redis.call('HDEL', KEYS[1], 'pending')
+redis.call('PEXPIRE', KEYS[1], reset_at - now + tonumber(ARGV[4]))
+";
+
+/// Read a bucket as its limit, remaining tickets, reset interval and the
+/// milliseconds until it resets.
+const BUCKET: &str = r"
+local bucket = redis.call('HMGET', KEYS[1], 'limit', 'remaining', 'reset_after', 'reset_at')
+
+if not bucket[1] then
+    return false
+end
+
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local until_reset = tonumber(bucket[4]) - now
+
+if until_reset <= 0 then
+    return {tonumber(bucket[1]), tonumber(bucket[1]), tonumber(bucket[3]), 0}
+end
+
+return {tonumber(bucket[1]), tonumber(bucket[2]), tonumber(bucket[3]), until_reset}
+";
+
+struct Scripts {
+    acquire: Script,
+    update: Script,
+    bucket: Script,
+}
+
+/// Ratelimiter of a single token, or the connection tenants' ratelimiters
+/// are created from.
+#[derive(Clone)]
+pub struct RedisRatelimiter {
+    connection: ConnectionManager,
+    /// Prefix of the keys of this token.
+    prefix: String,
+    scripts: Arc<Scripts>,
+    /// Locks per path, so requests of this replica get their tickets in order.
+    queues: Arc<Mutex<HashMap<Path, Arc<AsyncMutex<()>>>>>,
+}
+
+impl Debug for RedisRatelimiter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("RedisRatelimiter")
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RedisRatelimiter {
+    /// Connect to Redis, prefixing keys with `prefix` or the default.
+    pub async fn connect(url: &str, prefix: Option<String>) -> Result<Self, GenericError> {
+        let connection = ConnectionManager::new(Client::open(url)?).await?;
+
+        Ok(Self {
+            connection,
+            prefix: prefix.unwrap_or_else(|| DEFAULT_PREFIX.to_string()),
+            scripts: Arc::new(Scripts {
+                acquire: Script::new(ACQUIRE),
+                update: Script::new(UPDATE),
+                bucket: Script::new(BUCKET),
+            }),
+            queues: Arc::default(),
+        })
+    }
+
+    /// The ratelimiter of a token, identified by its hash.
+    pub fn for_token(&self, hash: &str) -> Self {
+        Self {
+            connection: self.connection.clone(),
+            prefix: format!("{}:{}", self.prefix, hash),
+            scripts: Arc::clone(&self.scripts),
+            queues: Arc::default(),
+        }
+    }
+
+    fn bucket_key(&self, path: &Path) -> String {
+        format!("{}:bucket:{:?}", self.prefix, path)
+    }
+
+    fn global_key(&self) -> String {
+        format!("{}:global", self.prefix)
+    }
+
+    fn queue(&self, path: &Path) -> Arc<AsyncMutex<()>> {
+        Arc::clone(
+            self.queues
+                .lock()
+                .expect("redis queues poisoned")
+                .entry(path.clone())
+                .or_default(),
+        )
+    }
+
+    /// Stop tracking the queue of a path once no request is waiting in it.
+    fn release_queue(&self, path: &Path, queue: Arc<AsyncMutex<()>>) {
+        let mut queues = self.queues.lock().expect("redis queues poisoned");
+
+        // The map and this function hold the only references
+        if Arc::strong_count(&queue) == 2 {
+            queues.remove(path);
+        }
+    }
+
+    /// Wait until the global lock is released and a ticket of the path's
+    /// bucket is granted.
+    async fn acquire(&self, path: &Path) -> Result<(), GenericError> {
+        let mut connection = self.connection.clone();
+        let bucket_key = self.bucket_key(path);
+        let global_key = self.global_key();
+
+        loop {
+            let locked: i64 = redis::cmd("PTTL")
+                .arg(&global_key)
+                .query_async(&mut connection)
+                .await?;
+
+            if locked > 0 {
+                debug!(path = ?path, "waiting for global ratelimit to pass");
+                sleep(Duration::from_millis(locked as u64)).await;
+
+                continue;
+            }
+
+            let wait: u64 = self
+                .scripts
+                .acquire
+                .key(&bucket_key)
+                .arg(WAIT.as_millis() as u64)
+                .arg(POLL_INTERVAL.as_millis() as u64)
+                .arg(IDLE_TIMEOUT.as_millis() as u64)
+                .invoke_async(&mut connection)
+                .await?;
+
+            if wait == 0 {
+                return Ok(());
+            }
+
+            sleep(Duration::from_millis(wait)).await;
+        }
+    }
+
+    /// Update the bucket of a path with the headers of its response.
+    async fn handle_headers(
+        &self,
+        path: &Path,
+        headers: &RatelimitHeaders,
+    ) -> Result<(), GenericError> {
+        let mut connection = self.connection.clone();
+
+        match headers {
+            RatelimitHeaders::Global(global) => {
+                debug!(path = ?path, "request got global ratelimited");
+
+                redis::cmd("SET")
+                    .arg(self.global_key())
+                    .arg(1)
+                    .arg("PX")
+                    .arg(global.retry_after().max(1) * 1000)
+                    .query_async::<()>(&mut connection)
+                    .await?;
+
+                self.release_pending(path).await?;
+            }
+            RatelimitHeaders::Present(present) => {
+                self.scripts
+                    .update
+                    .key(self.bucket_key(path))
+                    .arg(present.limit())
+                    .arg(present.remaining())
+                    .arg(present.reset_after())
+                    .arg(IDLE_TIMEOUT.as_millis() as u64)
+                    .invoke_async::<()>(&mut connection)
+                    .await?;
+            }
+            _ => self.release_pending(path).await?,
+        }
+
+        Ok(())
+    }
+
+    /// Forget the pending bucket of a request that didn't receive headers,
+    /// so the next request doesn't have to wait for it to time out.
+    async fn release_pending(&self, path: &Path) -> Result<(), GenericError> {
+        let mut connection = self.connection.clone();
+        let bucket_key = self.bucket_key(path);
+
+        let pending: bool = redis::cmd("HEXISTS")
+            .arg(&bucket_key)
+            .arg("pending")
+            .query_async(&mut connection)
+            .await?;
+
+        if pending {
+            redis::cmd("DEL")
+                .arg(&bucket_key)
+                .query_async::<()>(&mut connection)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn wait_for_headers(self, path: Path, headers: ticket::TicketHeaders) {
+        let result = match timeout(WAIT, headers).await {
+            Ok(Ok(Some(headers))) => self.handle_headers(&path, &headers).await,
+            Ok(Ok(None)) | Ok(Err(_)) => {
+                debug!(path = ?path, "request aborted");
+
+                self.release_pending(&path).await
+            }
+            Err(_) => {
+                debug!(path = ?path, "receiver timed out");
+
+                Ok(())
+            }
+        };
+
+        if let Err(e) = result {
+            warn!("Failed to update bucket of {:?} in Redis: {}", path, e);
+        }
+    }
+
+    fn exists(&self, key: String) -> impl Future<Output = Result<bool, GenericError>> {
+        let mut connection = self.connection.clone();
+
+        async move {
+            Ok(redis::cmd("EXISTS")
+                .arg(key)
+                .query_async(&mut connection)
+                .await?)
+        }
+    }
+}
+
+/// Convert a bucket read by the [`BUCKET`] script.
+fn to_bucket((limit, remaining, reset_after, until_reset): (u64, u64, u64, u64)) -> Bucket {
+    let started_at = if until_reset == 0 {
+        None
+    } else {
+        Instant::now().checked_sub(Duration::from_millis(
+            reset_after.saturating_sub(until_reset),
+        ))
+    };
+
+    Bucket::new(
+        limit,
+        remaining,
+        Duration::from_millis(reset_after),
+        started_at,
+    )
+}
+
+impl Ratelimiter for RedisRatelimiter {
+    fn bucket(&self, path: &Path) -> GetBucketFuture {
+        let mut connection = self.connection.clone();
+        let scripts = Arc::clone(&self.scripts);
+        let key = self.bucket_key(path);
+
+        Box::pin(async move {
+            let bucket: Option<(u64, u64, u64, u64)> = scripts
+                .bucket
+                .key(key)
+                .invoke_async(&mut connection)
+                .await?;
+
+            Ok(bucket.map(to_bucket))
+        })
+    }
+
+    fn is_globally_locked(&self) -> IsGloballyLockedFuture {
+        Box::pin(self.exists(self.global_key()))
+    }
+
+    fn has(&self, path: &Path) -> HasBucketFuture {
+        Box::pin(self.exists(self.bucket_key(path)))
+    }
+
+    fn ticket(&self, path: Path) -> GetTicketFuture {
+        let ratelimiter = self.clone();
+
+        Box::pin(async move {
+            let queue = ratelimiter.queue(&path);
+            let acquired = {
+                let _turn = queue.lock().await;
+
+                ratelimiter.acquire(&path).await
+            };
+            ratelimiter.release_queue(&path, queue);
+            acquired?;
+
+            let (notifier, receiver) = ticket::channel();
+
+            if let Some(headers) = notifier.available() {
+                tokio::spawn(ratelimiter.wait_for_headers(path, headers));
+            }
+
+            Ok(receiver)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_bucket, Scripts, ACQUIRE, BUCKET, IDLE_TIMEOUT, POLL_INTERVAL, UPDATE, WAIT};
+    use redis::{aio::ConnectionManager, Client, Script};
+    use std::env;
+    use tokio::time::Duration;
+
+    #[test]
+    fn test_to_bucket() {
+        let bucket = to_bucket((5, 2, 10_000, 4_000));
+        assert_eq!(bucket.limit(), 5);
+        assert_eq!(bucket.remaining(), 2);
+        assert_eq!(bucket.reset_after(), Duration::from_secs(10));
+
+        let time_remaining = bucket.time_remaining().unwrap();
+        assert!(time_remaining <= Duration::from_secs(4));
+        assert!(time_remaining > Duration::from_secs(3));
+
+        // Buckets that reset already haven't started a new interval
+        let bucket = to_bucket((5, 5, 10_000, 0));
+        assert!(bucket.started_at().is_none());
+        assert!(bucket.time_remaining().is_none());
+    }
+
+    /// Connection to the Redis server at `REDIS_TEST_URL`, with the test's
+    /// key removed.
+    ///
+    /// Returns `None`, skipping the test, if it's not set.
+    async fn connect(key: &str) -> Option<(ConnectionManager, Scripts)> {
+        let url = env::var("REDIS_TEST_URL").ok()?;
+        let client = Client::open(url).expect("invalid REDIS_TEST_URL");
+        let mut connection = ConnectionManager::new(client).await.unwrap();
+
+        redis::cmd("DEL")
+            .arg(key)
+            .query_async::<()>(&mut connection)
+            .await
+            .unwrap();
+
+        let scripts = Scripts {
+            acquire: Script::new(ACQUIRE),
+            update: Script::new(UPDATE),
+            bucket: Script::new(BUCKET),
+        };
+
+        Some((connection, scripts))
+    }
+
+    async fn acquire(connection: &mut ConnectionManager, scripts: &Scripts, key: &str) -> u64 {
+        scripts
+            .acquire
+            .key(key)
+            .arg(WAIT.as_millis() as u64)
+            .arg(POLL_INTERVAL.as_millis() as u64)
+            .arg(IDLE_TIMEOUT.as_millis() as u64)
+            .invoke_async(connection)
+            .await
+            .unwrap()
+    }
+
+    async fn update(
+        connection: &mut ConnectionManager,
+        scripts: &Scripts,
+        key: &str,
+        (limit, remaining, reset_after): (u64, u64, u64),
+    ) {
+        scripts
+            .update
+            .key(key)
+            .arg(limit)
+            .arg(remaining)
+            .arg(reset_after)
+            .arg(IDLE_TIMEOUT.as_millis() as u64)
+            .invoke_async::<()>(connection)
+            .await
+            .unwrap();
+    }
+
+    async fn bucket(
+        connection: &mut ConnectionManager,
+        scripts: &Scripts,
+        key: &str,
+    ) -> Option<(u64, u64, u64, u64)> {
+        scripts
+            .bucket
+            .key(key)
+            .invoke_async(connection)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_acquire_pending() {
+        let key = "twilight-http-proxy-test:bucket:pending";
+        let (mut connection, scripts) = match connect(key).await {
+            Some(connected) => connected,
+            None => return,
+        };
+
+        // The first request of an unknown bucket goes through alone
+        assert_eq!(acquire(&mut connection, &scripts, key).await, 0);
+        let wait = acquire(&mut connection, &scripts, key).await;
+        assert!(wait > 0 && wait <= POLL_INTERVAL.as_millis() as u64);
+
+        update(&mut connection, &scripts, key, (5, 4, 10_000)).await;
+        assert_eq!(acquire(&mut connection, &scripts, key).await, 0);
+
+        let (limit, remaining, reset_after, until_reset) =
+            bucket(&mut connection, &scripts, key).await.unwrap();
+        assert_eq!((limit, remaining, reset_after), (5, 3, 10_000));
+        assert!(until_reset > 9_000 && until_reset <= 10_000);
+
+        // Exhausted buckets wait for their reset
+        for _ in 0..3 {
+            assert_eq!(acquire(&mut connection, &scripts, key).await, 0);
+        }
+        assert!(acquire(&mut connection, &scripts, key).await > 9_000);
+    }
+
+    #[tokio::test]
+    async fn test_update_out_of_order() {
+        let key = "twilight-http-proxy-test:bucket:out-of-order";
+        let (mut connection, scripts) = match connect(key).await {
+            Some(connected) => connected,
+            None => return,
+        };
+
+        assert_eq!(acquire(&mut connection, &scripts, key).await, 0);
+        update(&mut connection, &scripts, key, (5, 4, 100)).await;
+
+        for _ in 0..4 {
+            assert_eq!(acquire(&mut connection, &scripts, key).await, 0);
+        }
+
+        // The response to an earlier request arrives last
+        update(&mut connection, &scripts, key, (5, 1, 100)).await;
+        update(&mut connection, &scripts, key, (5, 3, 100)).await;
+
+        let (_, remaining, ..) = bucket(&mut connection, &scripts, key).await.unwrap();
+        assert_eq!(remaining, 0);
+        assert!(acquire(&mut connection, &scripts, key).await > 0);
+
+        // Headers of the next window replace the stored one
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        update(&mut connection, &scripts, key, (5, 3, 10_000)).await;
+
+        let (_, remaining, ..) = bucket(&mut connection, &scripts, key).await.unwrap();
+        assert_eq!(remaining, 3);
+    }
+}
@@ -0,0 +1,171 @@
+//! Reloading of settings on `SIGHUP`, or `CTRL+BREAK` on Windows, without
+//! dropping requests in flight or the state of the buckets.
+//!
+//! Only the default token, the limits of the tenant cache and the log filter
+//! are reloaded, all other settings require a restart.
+
+use crate::{config::Config, token_file, State};
+use std::{collections::HashMap, env, error::Error, str::FromStr};
+use tracing::{error, info};
+use tracing_subscriber::{reload::Handle, EnvFilter, Registry};
+
+/// Environment variables of the settings that are reloaded.
+const SETTINGS: &[&str] = &[
+    "CLIENT_CACHE_MAX_SIZE",
+    "CLIENT_DECAY_TIMEOUT",
+    "DISCORD_TOKEN",
+    "DISCORD_TOKEN_FILE",
+    "RUST_LOG",
+];
+
+/// Handle to replace the filter of the logs.
+pub type LogFilter = Handle<EnvFilter, Registry>;
+
+/// Parse a log filter like `RUST_LOG`, defaulting to `info`.
+pub fn log_filter(directives: Option<&str>) -> EnvFilter {
+    directives
+        .and_then(|directives| EnvFilter::try_new(directives).ok())
+        .unwrap_or_else(|| EnvFilter::new("info"))
+}
+
+/// Combine the settings of the file with the ones set by the environment or
+/// options, which take precedence.
+fn merge(
+    file: Vec<(&'static str, String)>,
+    fixed: &[(&'static str, String)],
+) -> HashMap<&'static str, String> {
+    let mut settings = file
+        .into_iter()
+        .filter(|(name, _)| SETTINGS.contains(name))
+        .collect::<HashMap<_, _>>();
+
+    settings.extend(fixed.iter().cloned());
+
+    settings
+}
+
+fn parse<T: FromStr>(settings: &HashMap<&str, String>, name: &str) -> Option<T> {
+    settings.get(name).and_then(|value| value.parse().ok())
+}
+
+pub struct Reloader {
+    config: Option<String>,
+    profile: Option<String>,
+    /// Settings set by the environment or options on startup.
+    fixed: Vec<(&'static str, String)>,
+    log_filter: LogFilter,
+}
+
+impl Reloader {
+    /// Must be created after the options are applied, but before the
+    /// configuration file is.
+    pub fn new(config: Option<String>, profile: Option<String>, log_filter: LogFilter) -> Self {
+        let fixed = SETTINGS
+            .iter()
+            .filter_map(|name| env::var(name).ok().map(|value| (*name, value)))
+            .collect();
+
+        Self {
+            config,
+            profile,
+            fixed,
+            log_filter,
+        }
+    }
+
+    /// Apply `RUST_LOG`, which the configuration file may set after logging
+    /// started.
+    pub fn reload_log_filter(&self) -> Result<(), Box<dyn Error>> {
+        self.log_filter
+            .reload(log_filter(env::var("RUST_LOG").ok().as_deref()))?;
+
+        Ok(())
+    }
+
+    /// Re-read the configuration and token files and apply the settings.
+    pub fn reload(&self, state: &State) -> Result<(), Box<dyn Error>> {
+        let file = match &self.config {
+            Some(path) => Config::load(path, self.profile.as_deref())?.vars(),
+            None => Vec::new(),
+        };
+        let settings = merge(file, &self.fixed);
+
+        let default_token = match settings.get("DISCORD_TOKEN_FILE") {
+            Some(path) => token_file::read(path)
+                .map_err(|e| format!("failed to read token file {}: {}", path, e))?,
+            None => settings.get("DISCORD_TOKEN").cloned(),
+        };
+
+        state.ratelimiter_map.set_default_token(default_token);
+        state.ratelimiter_map.set_cache_limits(
+            parse(&settings, "CLIENT_DECAY_TIMEOUT"),
+            parse(&settings, "CLIENT_CACHE_MAX_SIZE"),
+        );
+        self.log_filter
+            .reload(log_filter(settings.get("RUST_LOG").map(String::as_str)))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+pub async fn run(reloader: Reloader, state: &State) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = signal(SignalKind::hangup()).expect("failed to install SIGHUP handler");
+
+    while signals.recv().await.is_some() {
+        reload(&reloader, state);
+    }
+}
+
+#[cfg(windows)]
+pub async fn run(reloader: Reloader, state: &State) {
+    let mut signals =
+        tokio::signal::windows::ctrl_break().expect("failed to install CTRL+BREAK handler");
+
+    while signals.recv().await.is_some() {
+        reload(&reloader, state);
+    }
+}
+
+fn reload(reloader: &Reloader, state: &State) {
+    match reloader.reload(state) {
+        Ok(()) => info!("Reloaded configuration"),
+        Err(e) => error!(
+            "Failed to reload configuration, keeping the current one: {}",
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{log_filter, merge};
+
+    #[test]
+    fn test_merge() {
+        let settings = merge(
+            vec![
+                ("PORT", "3000".to_string()),
+                ("DISCORD_TOKEN", "file".to_string()),
+                ("CLIENT_CACHE_MAX_SIZE", "100".to_string()),
+            ],
+            &[("DISCORD_TOKEN", "env".to_string())],
+        );
+
+        assert_eq!(settings.len(), 2);
+        assert_eq!(settings["DISCORD_TOKEN"], "env");
+        assert_eq!(settings["CLIENT_CACHE_MAX_SIZE"], "100");
+    }
+
+    #[test]
+    fn test_log_filter() {
+        assert_eq!(log_filter(None).to_string(), "info");
+        assert_eq!(log_filter(Some("[")).to_string(), "info");
+        assert_eq!(
+            log_filter(Some("twilight_http_proxy=debug")).to_string(),
+            "twilight_http_proxy=debug"
+        );
+    }
+}
@@ -0,0 +1,145 @@
+//! The `replay` subcommand, re-executing captured exchanges.
+
+use crate::{
+    capture::{Exchange, Payload},
+    ratelimiter_map::with_prefix,
+    token_file,
+};
+use http::{
+    header::{HeaderName, AUTHORIZATION, CONTENT_LENGTH, HOST},
+    HeaderValue, Method, Request, Uri,
+};
+use hyper::{client::HttpConnector, Body, Client};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use std::{error::Error, str::FromStr};
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, BufReader},
+};
+
+/// Re-execute all exchanges of a capture file against a target, such as a
+/// mock server or another proxy instance.
+///
+/// Requests are sent one after another. If a default token is set, it is
+/// used as the `Authorization` of all requests. Fails if any response status
+/// differs from the captured one.
+pub async fn run(file: &str, target: &str) -> Result<(), Box<dyn Error>> {
+    let target = target.trim_end_matches('/');
+
+    let token = token()?;
+    let client = client();
+
+    let mut lines = BufReader::new(File::open(file).await?).lines();
+    let (mut replayed, mut mismatched) = (0, 0);
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let exchange: Exchange = serde_json::from_str(&line)?;
+        let request = build_request(&exchange, target, token.as_deref())?;
+
+        let status = client.request(request).await?.status();
+        replayed += 1;
+
+        match &exchange.response {
+            Some(response) if response.status != status.as_u16() => {
+                mismatched += 1;
+                println!(
+                    "{} {}: {} (captured {})",
+                    exchange.method, exchange.path, status, response.status
+                );
+            }
+            _ => println!("{} {}: {}", exchange.method, exchange.path, status),
+        }
+    }
+
+    println!("Replayed {} requests, {} mismatched", replayed, mismatched);
+
+    if mismatched > 0 {
+        return Err(format!("{} responses differ from the capture", mismatched).into());
+    }
+
+    Ok(())
+}
+
+/// The `Authorization` of requests sent by subcommands, the default token
+/// prefixed the same way the proxy does.
+pub fn token() -> Result<Option<String>, Box<dyn Error>> {
+    Ok(token_file::default_token()?.map(with_prefix))
+}
+
+pub fn client() -> Client<HttpsConnector<HttpConnector>, Body> {
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+
+    Client::builder().build(connector)
+}
+
+pub fn build_request(
+    exchange: &Exchange,
+    target: &str,
+    token: Option<&str>,
+) -> Result<Request<Body>, Box<dyn Error>> {
+    let mut request = Request::builder()
+        .method(Method::from_str(&exchange.method)?)
+        .uri(Uri::from_str(&format!("{}{}", target, exchange.path))?);
+
+    for (name, value) in &exchange.headers {
+        let name = HeaderName::from_str(name)?;
+
+        // These are set for the target and the body being sent
+        if name != HOST && name != CONTENT_LENGTH {
+            request = request.header(name, HeaderValue::from_str(value)?);
+        }
+    }
+
+    if let Some(token) = token {
+        request = request.header(AUTHORIZATION, token);
+    }
+
+    let body = exchange
+        .body
+        .as_ref()
+        .map(Payload::to_bytes)
+        .transpose()?
+        .map_or_else(Body::empty, Body::from);
+
+    Ok(request.body(body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::build_request;
+    use crate::capture::{Exchange, Payload};
+    use http::header::{AUTHORIZATION, CONTENT_TYPE, HOST};
+
+    #[test]
+    fn test_build_request() {
+        let exchange = Exchange {
+            tenant: "0123456789abcdef".to_string(),
+            method: "POST".to_string(),
+            path: "/api/v10/channels/1/messages".to_string(),
+            headers: vec![
+                ("host".to_string(), "discord.com".to_string()),
+                ("content-type".to_string(), "application/json".to_string()),
+            ],
+            body: Some(Payload::Text("{}".to_string())),
+            response: None,
+        };
+
+        let request = build_request(&exchange, "http://127.0.0.1:8080", Some("Bot abc")).unwrap();
+
+        assert_eq!(
+            request.uri(),
+            "http://127.0.0.1:8080/api/v10/channels/1/messages"
+        );
+        assert_eq!(request.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(request.headers()[AUTHORIZATION], "Bot abc");
+        assert!(request.headers().get(HOST).is_none());
+    }
+}
@@ -0,0 +1,281 @@
+//! Report logged on shutdown, summarizing what the process handled and what
+//! was lost, to help diagnosing issues across restarts.
+
+use crate::State;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    env,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::fs;
+use tracing::{error, info};
+
+/// Counters of the process's lifetime.
+pub struct Stats {
+    started: Instant,
+    served: AtomicU64,
+    dropped: AtomicU64,
+    connections: AtomicUsize,
+    /// Requests being handled by their ID.
+    in_flight: Mutex<HashMap<u64, InFlightRequest>>,
+    next_id: AtomicU64,
+    /// When the shutdown began and the connections open at that time.
+    stopping: OnceLock<(Instant, usize)>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            served: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            connections: AtomicUsize::new(0),
+            in_flight: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            stopping: OnceLock::new(),
+        }
+    }
+}
+
+impl Stats {
+    /// Count a request the proxy responded to.
+    pub fn served(&self) {
+        self.served.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Time since the process started.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Amount of requests the proxy responded to so far.
+    pub fn requests_served(&self) -> u64 {
+        self.served.load(Ordering::Relaxed)
+    }
+
+    /// Amount of requests dropped while waiting to be sent so far.
+    pub fn requests_dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Amount of currently open connections.
+    pub fn open_connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Track a request until the returned guard is dropped.
+    pub fn in_flight(
+        &self,
+        method: &'static str,
+        route: &'static str,
+        tenant: &str,
+    ) -> InFlight<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        self.in_flight.lock().expect("stats poisoned").insert(
+            id,
+            InFlightRequest {
+                method,
+                route,
+                tenant: tenant.to_string(),
+                since: Instant::now(),
+            },
+        );
+
+        InFlight { stats: self, id }
+    }
+
+    /// Requests currently being handled, oldest first.
+    pub fn in_flight_requests(&self) -> Vec<InFlightRequest> {
+        let mut requests = self
+            .in_flight
+            .lock()
+            .expect("stats poisoned")
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        requests.sort_by_key(|request| request.since);
+
+        requests
+    }
+
+    /// Track a request waiting to be sent to Discord, which is counted as
+    /// dropped unless [`Queued::dispatched`] is called.
+    pub fn queued(&self) -> Queued<'_> {
+        Queued {
+            stats: self,
+            dispatched: false,
+        }
+    }
+
+    /// Note that the shutdown began, closing the open connections.
+    pub fn stopping(&self) {
+        let connections = self.connections.load(Ordering::Relaxed);
+        let _ = self.stopping.set((Instant::now(), connections));
+    }
+}
+
+/// A connection's handle to the state, tracked until it is closed.
+pub struct Connection {
+    pub state: Arc<State>,
+}
+
+impl Connection {
+    pub fn open(state: Arc<State>) -> Self {
+        state.stats.connections.fetch_add(1, Ordering::Relaxed);
+
+        Self { state }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.state.stats.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone)]
+pub struct InFlightRequest {
+    pub method: &'static str,
+    pub route: &'static str,
+    pub tenant: String,
+    pub since: Instant,
+}
+
+pub struct InFlight<'a> {
+    stats: &'a Stats,
+    id: u64,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.stats
+            .in_flight
+            .lock()
+            .expect("stats poisoned")
+            .remove(&self.id);
+    }
+}
+
+pub struct Queued<'a> {
+    stats: &'a Stats,
+    dispatched: bool,
+}
+
+impl Queued<'_> {
+    pub fn dispatched(mut self) {
+        self.dispatched = true;
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        if !self.dispatched {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Report {
+    /// Seconds since the Unix epoch.
+    stopped_at: u64,
+    uptime_seconds: u64,
+    /// Time the graceful shutdown took.
+    shutdown_ms: u128,
+    requests_served: u64,
+    /// Requests abandoned while waiting to be sent, e.g. because the client
+    /// disconnected or their deadline expired.
+    requests_dropped: u64,
+    /// Connections open when the shutdown began.
+    connections_closed: usize,
+    tenants: usize,
+    buckets: usize,
+}
+
+/// Log the report and write it to `SHUTDOWN_REPORT_FILE`, if set.
+pub async fn write(state: &State) {
+    let tenants = state.ratelimiter_map.tenants();
+    let stats = &state.stats;
+    let (stopping, connections) = stats
+        .stopping
+        .get()
+        .copied()
+        .unwrap_or_else(|| (Instant::now(), 0));
+
+    let report = Report {
+        stopped_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        uptime_seconds: stats.uptime().as_secs(),
+        shutdown_ms: stopping.elapsed().as_millis(),
+        requests_served: stats.requests_served(),
+        requests_dropped: stats.requests_dropped(),
+        connections_closed: connections,
+        tenants: tenants.len(),
+        buckets: tenants
+            .iter()
+            .map(|tenant| tenant.usage.paths().len())
+            .sum(),
+    };
+
+    info!(
+        "Shut down after {}s: {} requests served, {} dropped in queue, {} connections closed, {} tenants with {} buckets",
+        report.uptime_seconds,
+        report.requests_served,
+        report.requests_dropped,
+        report.connections_closed,
+        report.tenants,
+        report.buckets
+    );
+
+    if let Ok(file) = env::var("SHUTDOWN_REPORT_FILE") {
+        let file = PathBuf::from(file);
+        let contents = serde_json::to_vec_pretty(&report).expect("report is serializable");
+
+        if let Err(e) = fs::write(&file, contents).await {
+            error!("Failed to write shutdown report to {:?}: {}", file, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Stats;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_in_flight() {
+        let stats = Stats::default();
+
+        let first = stats.in_flight("GET", "Gateway", "abc");
+        let second = stats.in_flight("POST", "Channel messages", "def");
+        assert_eq!(stats.in_flight_requests()[0].route, "Gateway");
+
+        drop(first);
+        let requests = stats.in_flight_requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].tenant, "def");
+
+        drop(second);
+        assert!(stats.in_flight_requests().is_empty());
+    }
+
+    #[test]
+    fn test_dropped() {
+        let stats = Stats::default();
+
+        stats.queued().dispatched();
+        assert_eq!(stats.dropped.load(Ordering::Relaxed), 0);
+
+        drop(stats.queued());
+        assert_eq!(stats.dropped.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,130 @@
+//! The `request` subcommand, sending a single request through a running
+//! proxy.
+
+use crate::replay;
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Method, Request, Uri,
+};
+use hyper::{Body, Client};
+use hyper_rustls::HttpsConnectorBuilder;
+use std::{env, error::Error, str::FromStr, time::Instant};
+
+/// Send a request to the proxy at `PROXY_URL` and print the response along
+/// with how long it took.
+///
+/// `PROXY_URL` defaults to the local proxy listening on `PORT`. If a default
+/// token is set, it is used as the `Authorization` of the request, otherwise
+/// the proxy's default token applies.
+pub async fn run(method: &str, path: &str, body: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let target = env::var("PROXY_URL").unwrap_or_else(|_| {
+        format!(
+            "http://127.0.0.1:{}",
+            env::var("PORT").unwrap_or_else(|_| "80".into())
+        )
+    });
+
+    let token = replay::token()?;
+
+    let request = build_request(
+        method,
+        target.trim_end_matches('/'),
+        path,
+        body,
+        token.as_deref(),
+    )?;
+
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client: Client<_, Body> = Client::builder().build(connector);
+
+    let start = Instant::now();
+    let (parts, body) = client.request(request).await?.into_parts();
+    let headers_received = start.elapsed();
+    let body = hyper::body::to_bytes(body).await?;
+    let total = start.elapsed();
+
+    println!("{:?} {}", parts.version, parts.status);
+
+    for (name, value) in &parts.headers {
+        println!("{}: {}", name, String::from_utf8_lossy(value.as_bytes()));
+    }
+
+    println!();
+    println!("{}", String::from_utf8_lossy(&body));
+    println!();
+    println!(
+        "Headers after {:?}, body after {:?}",
+        headers_received, total
+    );
+
+    Ok(())
+}
+
+fn build_request(
+    method: &str,
+    target: &str,
+    path: &str,
+    body: Option<&str>,
+    token: Option<&str>,
+) -> Result<Request<Body>, Box<dyn Error>> {
+    let path = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{}", path)
+    };
+
+    let mut request = Request::builder()
+        .method(Method::from_str(&method.to_ascii_uppercase())?)
+        .uri(Uri::from_str(&format!("{}{}", target, path))?);
+
+    if let Some(token) = token {
+        request = request.header(AUTHORIZATION, token);
+    }
+
+    let body = match body {
+        Some(body) => {
+            request = request.header(CONTENT_TYPE, "application/json");
+
+            Body::from(body.to_string())
+        }
+        None => Body::empty(),
+    };
+
+    Ok(request.body(body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::build_request;
+    use http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        Method,
+    };
+
+    #[test]
+    fn test_build_request() {
+        let request = build_request(
+            "post",
+            "http://127.0.0.1:3000",
+            "channels/1/messages",
+            Some(r#"{"content":"hi"}"#),
+            Some("Bot abc"),
+        )
+        .unwrap();
+
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(request.uri(), "http://127.0.0.1:3000/channels/1/messages");
+        assert_eq!(request.headers()[AUTHORIZATION], "Bot abc");
+        assert_eq!(request.headers()[CONTENT_TYPE], "application/json");
+
+        let request =
+            build_request("GET", "http://127.0.0.1:3000", "/users/@me", None, None).unwrap();
+
+        assert!(request.headers().get(AUTHORIZATION).is_none());
+        assert!(request.headers().get(CONTENT_TYPE).is_none());
+    }
+}
@@ -0,0 +1,211 @@
+//! Record of the most recent requests forwarded to Discord, giving an overview
+//! of the last minutes of traffic in deployments without Prometheus.
+
+use crate::parse_env;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::Instant;
+
+/// Amount of requests kept if `REQUEST_LOG_SIZE` is not set.
+const DEFAULT_SIZE: usize = 1000;
+
+/// Seconds requests are kept for if `REQUEST_LOG_WINDOW` is not set.
+const DEFAULT_WINDOW: u64 = 300;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Record {
+    /// Milliseconds since the Unix epoch.
+    at: u64,
+    method: String,
+    route: String,
+    status: u16,
+    duration_ms: u64,
+    #[serde(skip)]
+    received: Instant,
+}
+
+/// Requests to a method and route within the window.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RouteSummary {
+    method: String,
+    route: String,
+    requests: usize,
+    statuses: BTreeMap<u16, usize>,
+    p50_ms: u64,
+    p99_ms: u64,
+    max_ms: u64,
+}
+
+#[derive(Serialize)]
+pub struct Overview {
+    window_secs: u64,
+    /// Most requested routes first.
+    routes: Vec<RouteSummary>,
+    /// Most recent requests first.
+    recent: Vec<Record>,
+}
+
+/// Configured via `REQUEST_LOG_SIZE` and `REQUEST_LOG_WINDOW` in seconds.
+pub struct RequestLog {
+    size: usize,
+    window: Duration,
+    records: Mutex<VecDeque<Record>>,
+}
+
+impl RequestLog {
+    pub fn from_env() -> Self {
+        Self::new(
+            parse_env("REQUEST_LOG_SIZE").unwrap_or(DEFAULT_SIZE),
+            Duration::from_secs(parse_env("REQUEST_LOG_WINDOW").unwrap_or(DEFAULT_WINDOW)),
+        )
+    }
+
+    fn new(size: usize, window: Duration) -> Self {
+        Self {
+            size,
+            window,
+            records: Mutex::new(VecDeque::with_capacity(size)),
+        }
+    }
+
+    /// Record a request Discord responded to.
+    pub fn record(&self, method: &str, route: &str, status: u16, duration: Duration) {
+        if self.size == 0 {
+            return;
+        }
+
+        let record = Record {
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_millis() as u64),
+            method: method.to_string(),
+            route: route.to_string(),
+            status,
+            duration_ms: duration.as_millis() as u64,
+            received: Instant::now(),
+        };
+
+        let mut records = self.records.lock().expect("request log poisoned");
+
+        if records.len() == self.size {
+            records.pop_front();
+        }
+
+        records.push_back(record);
+    }
+
+    /// Summaries of the routes and the requests within the window.
+    pub fn overview(&self) -> Overview {
+        let recent = self.recent(Instant::now());
+
+        Overview {
+            window_secs: self.window.as_secs(),
+            routes: summarize(&recent),
+            recent,
+        }
+    }
+
+    /// The requests within the window, most recent first.
+    fn recent(&self, now: Instant) -> Vec<Record> {
+        let mut records = self.records.lock().expect("request log poisoned");
+
+        while records
+            .front()
+            .is_some_and(|record| now.duration_since(record.received) > self.window)
+        {
+            records.pop_front();
+        }
+
+        records.iter().rev().cloned().collect()
+    }
+}
+
+fn summarize(records: &[Record]) -> Vec<RouteSummary> {
+    let mut routes = HashMap::<(&str, &str), Vec<&Record>>::new();
+
+    for record in records {
+        routes
+            .entry((&record.method, &record.route))
+            .or_default()
+            .push(record);
+    }
+
+    let mut summaries = routes
+        .into_iter()
+        .map(|((method, route), records)| {
+            let mut durations = records
+                .iter()
+                .map(|record| record.duration_ms)
+                .collect::<Vec<_>>();
+            durations.sort_unstable();
+
+            let percentile =
+                |percent: usize| durations[(durations.len() * percent).div_ceil(100) - 1];
+
+            let mut statuses = BTreeMap::new();
+
+            for record in &records {
+                *statuses.entry(record.status).or_default() += 1;
+            }
+
+            RouteSummary {
+                method: method.to_string(),
+                route: route.to_string(),
+                requests: records.len(),
+                statuses,
+                p50_ms: percentile(50),
+                p99_ms: percentile(99),
+                max_ms: durations[durations.len() - 1],
+            }
+        })
+        .collect::<Vec<_>>();
+
+    summaries.sort_by(|a, b| {
+        b.requests
+            .cmp(&a.requests)
+            .then_with(|| a.route.cmp(&b.route))
+            .then_with(|| a.method.cmp(&b.method))
+    });
+
+    summaries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RequestLog;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[test]
+    fn test_record() {
+        let log = RequestLog::new(3, Duration::from_secs(300));
+
+        log.record("GET", "Gateway", 200, Duration::from_millis(10));
+        log.record("POST", "Channel messages", 200, Duration::from_millis(30));
+        log.record("POST", "Channel messages", 429, Duration::from_millis(50));
+        log.record("POST", "Channel messages", 200, Duration::from_millis(40));
+
+        let overview = log.overview();
+        assert_eq!(overview.recent.len(), 3);
+        assert_eq!(overview.recent[0].duration_ms, 40);
+        assert_eq!(overview.routes.len(), 1);
+
+        let summary = &overview.routes[0];
+        assert_eq!(summary.route, "Channel messages");
+        assert_eq!(summary.requests, 3);
+        assert_eq!(summary.statuses[&200], 2);
+        assert_eq!(summary.statuses[&429], 1);
+        assert_eq!(summary.p50_ms, 40);
+        assert_eq!(summary.p99_ms, 50);
+        assert_eq!(summary.max_ms, 50);
+
+        // Requests older than the window are dropped
+        assert!(log
+            .recent(Instant::now() + Duration::from_secs(301))
+            .is_empty());
+    }
+}
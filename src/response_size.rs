@@ -0,0 +1,128 @@
+//! Accounting of the size of Discord's responses and an optional limit on the
+//! size the proxy relays.
+
+use crate::parse_env;
+use futures_util::StreamExt;
+use http::{header::CONTENT_LENGTH, HeaderMap};
+use hyper::{Body, Response};
+use std::io;
+
+/// Configured via `MAX_RESPONSE_SIZE` in bytes.
+pub struct ResponseSize {
+    max: Option<u64>,
+}
+
+impl ResponseSize {
+    pub fn from_env() -> Self {
+        Self {
+            max: parse_env("MAX_RESPONSE_SIZE"),
+        }
+    }
+
+    /// Prepare a response to be relayed.
+    ///
+    /// Responses announcing a length above the limit are rejected right away
+    /// with their length. Bodies of unknown length are counted while they are
+    /// relayed and cut off once they exceed the limit, as the status has
+    /// already been sent by then.
+    pub fn relay(
+        &self,
+        route: &'static str,
+        response: Response<Body>,
+    ) -> Result<Response<Body>, u64> {
+        let length = content_length(response.headers());
+
+        if let Some(length) = length {
+            if self.max.is_some_and(|max| length > max) {
+                return Err(length);
+            }
+
+            record(route, length);
+
+            return Ok(response);
+        }
+
+        if self.max.is_none() && !cfg!(feature = "expose-metrics") {
+            return Ok(response);
+        }
+
+        let max = self.max.unwrap_or(u64::MAX);
+        let (parts, body) = response.into_parts();
+        let mut tally = Tally { route, bytes: 0 };
+
+        let body = body.map(move |chunk| {
+            let chunk = chunk.map_err(io::Error::other)?;
+            tally.bytes += chunk.len() as u64;
+
+            if tally.bytes > max {
+                return Err(io::Error::other("response exceeds MAX_RESPONSE_SIZE"));
+            }
+
+            Ok(chunk)
+        });
+
+        Ok(Response::from_parts(parts, Body::wrap_stream(body)))
+    }
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+/// Bytes of a body relayed so far, recorded once the body is dropped.
+struct Tally {
+    route: &'static str,
+    bytes: u64,
+}
+
+impl Drop for Tally {
+    fn drop(&mut self) {
+        record(self.route, self.bytes);
+    }
+}
+
+#[cfg(feature = "expose-metrics")]
+fn record(route: &'static str, bytes: u64) {
+    metrics::histogram!(
+        format!("{}_response_size_bytes", crate::METRIC_KEY.as_str()),
+        bytes as f64,
+        "route" => route
+    );
+}
+
+#[cfg(not(feature = "expose-metrics"))]
+const fn record(_: &'static str, _: u64) {}
+
+#[cfg(test)]
+mod tests {
+    use super::ResponseSize;
+    use hyper::{Body, Response};
+
+    #[tokio::test]
+    async fn test_relay() {
+        let limit = ResponseSize { max: Some(4) };
+
+        let announced = Response::builder()
+            .header("content-length", "5")
+            .body(Body::from("12345"))
+            .unwrap();
+        assert_eq!(limit.relay("Gateway", announced).unwrap_err(), 5);
+
+        let small = Response::builder()
+            .header("content-length", "4")
+            .body(Body::from("1234"))
+            .unwrap();
+        assert!(limit.relay("Gateway", small).is_ok());
+
+        // Bodies of unknown length are cut off while relaying
+        let (mut sender, body) = Body::channel();
+        let response = limit.relay("Gateway", Response::new(body)).unwrap();
+
+        tokio::spawn(async move {
+            sender.send_data("123".into()).await.unwrap();
+            sender.send_data("45".into()).await.unwrap();
+        });
+
+        assert!(hyper::body::to_bytes(response.into_body()).await.is_err());
+    }
+}
@@ -0,0 +1,84 @@
+//! Configuration and metrics of the Tokio runtime, for diagnosing executor
+//! saturation at high request rates.
+
+use crate::parse_env;
+use std::{io, time::Duration};
+use tokio::runtime::{Builder, Runtime};
+
+/// Build the multi-threaded runtime, configured via `WORKER_THREADS`,
+/// `MAX_BLOCKING_THREADS` and `BLOCKING_THREAD_KEEP_ALIVE` in seconds.
+///
+/// Unset settings keep Tokio's defaults: one worker per CPU core, up to 512
+/// blocking threads that are kept alive for 10 seconds.
+pub fn build() -> io::Result<Runtime> {
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all();
+
+    if let Some(workers) = parse_env::<usize>("WORKER_THREADS").filter(|workers| *workers > 0) {
+        builder.worker_threads(workers);
+    }
+
+    if let Some(max) = parse_env::<usize>("MAX_BLOCKING_THREADS").filter(|max| *max > 0) {
+        builder.max_blocking_threads(max);
+    }
+
+    if let Some(seconds) = parse_env("BLOCKING_THREAD_KEEP_ALIVE") {
+        builder.thread_keep_alive(Duration::from_secs(seconds));
+    }
+
+    builder.build()
+}
+
+/// Publish the runtime's metrics as gauges, which requires building with
+/// `RUSTFLAGS="--cfg tokio_unstable"`.
+#[cfg(all(feature = "expose-metrics", tokio_unstable))]
+pub fn record_metrics() {
+    use metrics::gauge;
+
+    let key = crate::METRIC_KEY.as_str();
+    let metrics = tokio::runtime::Handle::current().metrics();
+
+    gauge!(
+        format!("{}_runtime_workers", key),
+        metrics.num_workers() as f64
+    );
+    gauge!(
+        format!("{}_runtime_tasks", key),
+        metrics.active_tasks_count() as f64
+    );
+    gauge!(
+        format!("{}_runtime_injection_queue_depth", key),
+        metrics.injection_queue_depth() as f64
+    );
+    gauge!(
+        format!("{}_runtime_blocking_threads", key),
+        metrics.num_blocking_threads() as f64
+    );
+    gauge!(
+        format!("{}_runtime_blocking_queue_depth", key),
+        metrics.blocking_queue_depth() as f64
+    );
+
+    for worker in 0..metrics.num_workers() {
+        let label = worker.to_string();
+
+        gauge!(
+            format!("{}_runtime_worker_local_queue_depth", key),
+            metrics.worker_local_queue_depth(worker) as f64,
+            "worker" => label.clone()
+        );
+        gauge!(
+            format!("{}_runtime_worker_mean_poll_seconds", key),
+            metrics.worker_mean_poll_time(worker).as_secs_f64(),
+            "worker" => label.clone()
+        );
+        gauge!(
+            format!("{}_runtime_worker_busy_seconds", key),
+            metrics.worker_total_busy_duration(worker).as_secs_f64(),
+            "worker" => label
+        );
+    }
+}
+
+#[cfg(all(feature = "expose-metrics", not(tokio_unstable)))]
+pub const fn record_metrics() {}
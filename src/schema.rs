@@ -0,0 +1,392 @@
+//! Validation of Discord's responses against bundled schemas, warning early
+//! when Discord changes the shape of a payload, before clients start failing
+//! to deserialize it.
+//!
+//! Schemas only list the fields Discord always sends, so new optional fields
+//! don't raise warnings. Responses are forwarded unchanged either way.
+
+use http::header::CONTENT_ENCODING;
+use hyper::{Body, Response};
+use serde_json::Value;
+use std::{collections::HashSet, env, sync::Mutex};
+use tracing::{debug, warn};
+use twilight_http_ratelimiting::{Method, Path};
+
+/// JSON type of a field.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Kind {
+    Array,
+    Bool,
+    Number,
+    Object,
+    String,
+}
+
+impl Kind {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Array => "array",
+            Self::Bool => "bool",
+            Self::Number => "number",
+            Self::Object => "object",
+            Self::String => "string",
+        }
+    }
+
+    fn of(value: &Value) -> Option<Self> {
+        match value {
+            Value::Array(_) => Some(Self::Array),
+            Value::Bool(_) => Some(Self::Bool),
+            Value::Number(_) => Some(Self::Number),
+            Value::Object(_) => Some(Self::Object),
+            Value::String(_) => Some(Self::String),
+            Value::Null => None,
+        }
+    }
+}
+
+/// A field Discord always sends.
+struct Field {
+    name: &'static str,
+    kind: Kind,
+    nullable: bool,
+}
+
+const fn field(name: &'static str, kind: Kind) -> Field {
+    Field {
+        name,
+        kind,
+        nullable: false,
+    }
+}
+
+const fn nullable(name: &'static str, kind: Kind) -> Field {
+    Field {
+        name,
+        kind,
+        nullable: true,
+    }
+}
+
+struct Schema {
+    /// Name used in `VALIDATE_RESPONSES`, logs and metrics.
+    name: &'static str,
+    fields: &'static [Field],
+}
+
+const USER: Schema = Schema {
+    name: "user",
+    fields: &[
+        field("id", Kind::String),
+        field("username", Kind::String),
+        field("discriminator", Kind::String),
+        nullable("avatar", Kind::String),
+    ],
+};
+
+const CHANNEL: Schema = Schema {
+    name: "channel",
+    fields: &[field("id", Kind::String), field("type", Kind::Number)],
+};
+
+const MESSAGE: Schema = Schema {
+    name: "message",
+    fields: &[
+        field("id", Kind::String),
+        field("channel_id", Kind::String),
+        field("type", Kind::Number),
+        field("author", Kind::Object),
+        field("content", Kind::String),
+        field("timestamp", Kind::String),
+        nullable("edited_timestamp", Kind::String),
+        field("tts", Kind::Bool),
+        field("mention_everyone", Kind::Bool),
+        field("mentions", Kind::Array),
+        field("mention_roles", Kind::Array),
+        field("attachments", Kind::Array),
+        field("embeds", Kind::Array),
+        field("pinned", Kind::Bool),
+    ],
+};
+
+const GUILD: Schema = Schema {
+    name: "guild",
+    fields: &[
+        field("id", Kind::String),
+        field("name", Kind::String),
+        nullable("icon", Kind::String),
+        field("owner_id", Kind::String),
+        field("verification_level", Kind::Number),
+        field("roles", Kind::Array),
+        field("emojis", Kind::Array),
+        field("features", Kind::Array),
+    ],
+};
+
+const MEMBER: Schema = Schema {
+    name: "member",
+    fields: &[
+        field("user", Kind::Object),
+        field("roles", Kind::Array),
+        nullable("joined_at", Kind::String),
+        field("deaf", Kind::Bool),
+        field("mute", Kind::Bool),
+    ],
+};
+
+const GATEWAY: Schema = Schema {
+    name: "gateway",
+    fields: &[field("url", Kind::String)],
+};
+
+const GATEWAY_BOT: Schema = Schema {
+    name: "gateway",
+    fields: &[
+        field("url", Kind::String),
+        field("shards", Kind::Number),
+        field("session_start_limit", Kind::Object),
+    ],
+};
+
+/// Names of all bundled schemas.
+const NAMES: [&str; 6] = ["channel", "gateway", "guild", "member", "message", "user"];
+
+/// Schema of the response to a request, and whether it's a list of them.
+fn schema(method: Method, path: &Path) -> Option<(&'static Schema, bool)> {
+    let schema = match (method, path) {
+        (Method::Get, Path::UsersId) => (&USER, false),
+        (Method::Get, Path::ChannelsId(_)) => (&CHANNEL, false),
+        (Method::Get, Path::ChannelsIdMessages(_)) => (&MESSAGE, true),
+        (Method::Post, Path::ChannelsIdMessages(_))
+        | (Method::Get | Method::Patch, Path::ChannelsIdMessagesId(..)) => (&MESSAGE, false),
+        (Method::Get, Path::GuildsId(_)) => (&GUILD, false),
+        (Method::Get, Path::GuildsIdMembers(_)) => (&MEMBER, true),
+        (Method::Get, Path::GuildsIdMembersId(_)) => (&MEMBER, false),
+        (Method::Get, Path::Gateway) => (&GATEWAY, false),
+        (Method::Get, Path::GatewayBot) => (&GATEWAY_BOT, false),
+        _ => return None,
+    };
+
+    Some(schema)
+}
+
+impl Schema {
+    /// Differences between a payload and the schema.
+    fn check(&self, value: &Value) -> Vec<String> {
+        let object = match value.as_object() {
+            Some(object) => object,
+            None => return vec!["payload is not an object".to_string()],
+        };
+
+        self.fields
+            .iter()
+            .filter_map(|field| match object.get(field.name) {
+                None => Some(format!("`{}` is missing", field.name)),
+                Some(Value::Null) if field.nullable => None,
+                Some(value) => match Kind::of(value) {
+                    Some(kind) if kind == field.kind => None,
+                    kind => Some(format!(
+                        "`{}` is {}, expected {}",
+                        field.name,
+                        kind.map_or("null", Kind::name),
+                        field.kind.name()
+                    )),
+                },
+            })
+            .collect()
+    }
+}
+
+/// Configured via `VALIDATE_RESPONSES`.
+pub struct ResponseValidation {
+    /// Names of the schemas to validate against.
+    schemas: HashSet<&'static str>,
+    /// Differences that were already warned about, logged at debug level
+    /// afterwards.
+    seen: Mutex<HashSet<(&'static str, String)>>,
+}
+
+impl ResponseValidation {
+    /// Load the schemas to validate against from `VALIDATE_RESPONSES`, a
+    /// comma-separated list of schema names or `all`.
+    ///
+    /// Returns `None` if validation is not enabled.
+    pub fn from_env() -> Option<Self> {
+        let value = env::var("VALIDATE_RESPONSES").ok()?;
+
+        Some(Self::new(&value))
+    }
+
+    fn new(value: &str) -> Self {
+        let mut schemas = HashSet::new();
+
+        for name in value
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            if name == "all" {
+                schemas.extend(NAMES);
+            } else if let Some(name) = NAMES.iter().find(|known| **known == name) {
+                schemas.insert(*name);
+            } else {
+                warn!(
+                    "VALIDATE_RESPONSES names unknown schema {:?}, known are {}",
+                    name,
+                    NAMES.join(", ")
+                );
+            }
+        }
+
+        Self {
+            schemas,
+            seen: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Validate a successful JSON response to a request against its schema.
+    ///
+    /// Compressed and empty bodies aren't validated.
+    pub async fn validate(
+        &self,
+        method: Method,
+        path: &Path,
+        response: Response<Body>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let (schema, list) = match schema(method, path) {
+            Some((schema, list)) if self.schemas.contains(schema.name) => (schema, list),
+            _ => return Ok(response),
+        };
+
+        if !response.status().is_success() || response.headers().contains_key(CONTENT_ENCODING) {
+            return Ok(response);
+        }
+
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+
+        // Like the bodies of responses to HEAD requests
+        if body.is_empty() {
+            return Ok(Response::from_parts(parts, body.into()));
+        }
+
+        let problems = match serde_json::from_slice::<Value>(&body) {
+            Ok(Value::Array(items)) if list => {
+                let mut problems = items
+                    .iter()
+                    .flat_map(|item| schema.check(item))
+                    .collect::<Vec<_>>();
+                problems.sort();
+                problems.dedup();
+
+                problems
+            }
+            Ok(_) if list => vec!["payload is not an array".to_string()],
+            Ok(value) => schema.check(&value),
+            Err(_) => vec!["payload is not valid JSON".to_string()],
+        };
+
+        for problem in problems {
+            self.flag(schema.name, path, problem);
+        }
+
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+
+    fn flag(&self, schema: &'static str, path: &Path, problem: String) {
+        #[cfg(feature = "expose-metrics")]
+        metrics::increment_counter!(
+            format!("{}_schema_mismatches_total", crate::METRIC_KEY.as_str()),
+            "schema" => schema
+        );
+
+        let new = self
+            .seen
+            .lock()
+            .expect("seen mismatches poisoned")
+            .insert((schema, problem.clone()));
+
+        if new {
+            warn!(
+                "Response to {:?} doesn't match the {} schema: {}",
+                path, schema, problem
+            );
+        } else {
+            debug!(
+                "Response to {:?} doesn't match the {} schema: {}",
+                path, schema, problem
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{schema, ResponseValidation, MESSAGE, USER};
+    use hyper::{Body, Response};
+    use serde_json::json;
+    use twilight_http_ratelimiting::{Method, Path};
+
+    #[test]
+    fn test_check() {
+        let user = json!({
+            "id": "1",
+            "username": "twilight",
+            "discriminator": "0",
+            "avatar": null,
+            "global_name": "Twilight"
+        });
+        assert!(USER.check(&user).is_empty());
+
+        let changed = json!({"id": 1, "username": "twilight", "avatar": null});
+        assert_eq!(
+            USER.check(&changed),
+            [
+                "`id` is number, expected string",
+                "`discriminator` is missing"
+            ]
+        );
+
+        assert_eq!(
+            MESSAGE.check(&json!({"edited_timestamp": null})).len(),
+            MESSAGE.fields.len() - 1
+        );
+        assert_eq!(USER.check(&json!([])), ["payload is not an object"]);
+    }
+
+    #[test]
+    fn test_schema() {
+        assert!(matches!(
+            schema(Method::Get, &Path::ChannelsIdMessages(1)),
+            Some((schema, true)) if schema.name == "message"
+        ));
+        assert!(matches!(
+            schema(Method::Post, &Path::ChannelsIdMessages(1)),
+            Some((schema, false)) if schema.name == "message"
+        ));
+        assert!(schema(Method::Delete, &Path::ChannelsId(1)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_validate() {
+        let validation = ResponseValidation::new("user, invalid");
+        assert_eq!(validation.schemas.len(), 1);
+
+        let body = json!({"id": "1"}).to_string();
+        let response = validation
+            .validate(
+                Method::Get,
+                &Path::UsersId,
+                Response::new(Body::from(body.clone())),
+            )
+            .await
+            .unwrap();
+
+        // The body is forwarded unchanged
+        let forwarded = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(forwarded, body.as_bytes());
+        assert_eq!(validation.seen.lock().unwrap().len(), 3);
+
+        assert_eq!(ResponseValidation::new("all").schemas.len(), 6);
+    }
+}
@@ -0,0 +1,233 @@
+//! The `selftest` subcommand, checking the ratelimiter against the mock
+//! upstream at high concurrency before a rollout.
+
+use crate::{
+    simulation::{forward, Scripted, Upstream},
+    tenant::Tenant,
+};
+use http::StatusCode;
+use std::{error::Error, fs, sync::Arc};
+use tokio::time::{timeout, Duration, Instant};
+use twilight_http_ratelimiting::Path;
+
+/// Amount of requests sent if not given.
+pub const DEFAULT_REQUESTS: usize = 10_000;
+
+const TENANTS: usize = 4;
+const BUCKETS_PER_TENANT: u64 = 16;
+const LIMIT: u64 = 50;
+const RESET_AFTER: Duration = Duration::from_millis(200);
+const LATENCY: Duration = Duration::from_millis(5);
+
+/// How long a single request may take before it is considered lost.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Maximum growth of the resident memory during the test.
+const MEMORY_BUDGET: u64 = 64 * 1024 * 1024;
+
+/// Result of a self-test run.
+#[derive(Debug)]
+struct Report {
+    requests: usize,
+    /// Requests that received a response in time.
+    completed: usize,
+    /// Requests the upstream counted against a bucket.
+    accepted: u64,
+    /// Requests answered with a scripted shared 429, which are not counted.
+    shared_ratelimited: u64,
+    violations: Vec<String>,
+    elapsed: Duration,
+    /// Growth of the resident memory in bytes, if it could be measured.
+    memory_growth: Option<u64>,
+}
+
+impl Report {
+    fn is_lossless(&self) -> bool {
+        self.completed == self.requests
+            && self.accepted + self.shared_ratelimited == self.requests as u64
+    }
+
+    fn is_memory_bounded(&self) -> bool {
+        self.memory_growth
+            .is_none_or(|growth| growth <= MEMORY_BUDGET)
+    }
+
+    fn passed(&self) -> bool {
+        self.violations.is_empty() && self.is_lossless() && self.is_memory_bounded()
+    }
+}
+
+/// Send requests from several tenants to many buckets concurrently and check
+/// that no bucket is overrun, no request is lost and memory stays bounded.
+pub async fn run(requests: usize) -> Result<(), Box<dyn Error>> {
+    println!(
+        "Sending {} requests from {} tenants to {} buckets each",
+        requests, TENANTS, BUCKETS_PER_TENANT
+    );
+
+    let report = check(requests).await;
+    let verdict = |ok| if ok { "pass" } else { "FAIL" };
+
+    println!(
+        "Completed {} of {} requests in {:?}",
+        report.completed, report.requests, report.elapsed
+    );
+    println!(
+        "[{}] bucket overruns: {}",
+        verdict(report.violations.is_empty()),
+        report.violations.len()
+    );
+
+    for violation in report.violations.iter().take(10) {
+        println!("       {}", violation);
+    }
+
+    println!(
+        "[{}] lost requests: {}",
+        verdict(report.is_lossless()),
+        report.requests - report.completed
+    );
+
+    match report.memory_growth {
+        Some(growth) => println!(
+            "[{}] memory growth: {} KiB (budget {} KiB)",
+            verdict(report.is_memory_bounded()),
+            growth / 1024,
+            MEMORY_BUDGET / 1024
+        ),
+        None => println!("[skip] memory growth: not measurable on this platform"),
+    }
+
+    if !report.passed() {
+        return Err("self-test failed".into());
+    }
+
+    println!("Self-test passed");
+
+    Ok(())
+}
+
+async fn check(requests: usize) -> Report {
+    let memory_before = resident_memory();
+    let start = Instant::now();
+
+    let tenants = (0..TENANTS)
+        .map(|index| {
+            let paths = (0..BUCKETS_PER_TENANT)
+                .map(Path::ChannelsIdMessages)
+                .collect::<Vec<_>>();
+
+            // Every bucket starts with a shared 429 and a server error,
+            // which must neither affect its ratelimit nor get lost
+            let upstream =
+                paths
+                    .iter()
+                    .fold(Upstream::default().latency(LATENCY), |upstream, path| {
+                        upstream.limit(path.clone(), LIMIT, RESET_AFTER).script(
+                            path.clone(),
+                            &[
+                                Scripted::SharedRatelimit {
+                                    reset_after: Duration::from_secs(60),
+                                },
+                                Scripted::ServerError,
+                            ],
+                        )
+                    });
+
+            (
+                Tenant::new(&format!("Bot selftest-{}", index)),
+                Arc::new(upstream),
+                paths,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let handles = (0..requests)
+        .map(|index| {
+            let (tenant, upstream, paths) = &tenants[index % TENANTS];
+            let tenant = tenant.clone();
+            let upstream = Arc::clone(upstream);
+            let path = paths[(index / TENANTS) % paths.len()].clone();
+
+            tokio::spawn(async move {
+                timeout(REQUEST_TIMEOUT, forward(&tenant, &upstream, path)).await
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut completed = 0;
+    let mut shared_ratelimited = 0_u64;
+
+    for handle in handles {
+        if let Ok(Ok(status)) = handle.await {
+            completed += 1;
+
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                shared_ratelimited += 1;
+            }
+        }
+    }
+
+    let elapsed = start.elapsed();
+
+    let mut accepted = 0;
+    let mut violations = Vec::new();
+
+    for (_, upstream, paths) in &tenants {
+        accepted += paths
+            .iter()
+            .map(|path| upstream.accepted(path))
+            .sum::<u64>();
+        violations.extend(upstream.violations());
+    }
+
+    // Overruns are answered with a 429 as well
+    shared_ratelimited = shared_ratelimited.saturating_sub(violations.len() as u64);
+
+    let memory_growth = memory_before
+        .zip(resident_memory())
+        .map(|(before, after)| after.saturating_sub(before));
+
+    Report {
+        requests,
+        completed,
+        accepted,
+        shared_ratelimited,
+        violations,
+        elapsed,
+        memory_growth,
+    }
+}
+
+/// Resident memory of the process in bytes, only available on Linux.
+fn resident_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib = line
+        .trim_start_matches("VmRSS:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+
+    Some(kib * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, BUCKETS_PER_TENANT, TENANTS};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_check() {
+        let report = check(2000).await;
+
+        assert!(report.passed(), "{:?}", report);
+        assert_eq!(report.completed, 2000);
+        // Every bucket answers with one shared 429
+        assert_eq!(
+            report.shared_ratelimited,
+            TENANTS as u64 * BUCKETS_PER_TENANT
+        );
+    }
+}
@@ -0,0 +1,208 @@
+//! Routing of requests to Discord services other than the API, e.g. the CDN,
+//! so one proxy can serve several egress roles.
+//!
+//! Requests are matched by their `Host` header or a path prefix and relayed
+//! as they are, without ratelimiting or adding the default token.
+
+use crate::{
+    dry_run_response,
+    error::RequestError,
+    headers,
+    upstream::{Upstream, UpstreamError},
+    State,
+};
+use http::{header::HOST, Request, Response};
+use hyper::Body;
+use std::env;
+use tracing::{debug, error, warn};
+
+/// What requests a service receives.
+#[derive(Debug, Eq, PartialEq)]
+enum Matcher {
+    /// Requests whose `Host` header has this name, ignoring the port.
+    Host(String),
+    /// Requests whose path starts with this prefix, which is removed.
+    Prefix(String),
+}
+
+struct Service {
+    matcher: Matcher,
+    upstream: Upstream,
+}
+
+/// Configured via `SERVICES`.
+#[derive(Default)]
+pub struct Services {
+    services: Vec<Service>,
+}
+
+impl Services {
+    /// Load the services from a comma-separated list of `host=url` or
+    /// `/prefix=url` entries.
+    pub fn from_env() -> Result<Self, UpstreamError> {
+        match env::var("SERVICES") {
+            Ok(value) => Self::parse(&value),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self, UpstreamError> {
+        let mut services = Vec::new();
+
+        for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (key, url) = match entry.split_once('=') {
+                Some((key, url)) if !key.trim().is_empty() => (key.trim(), url.trim()),
+                _ => {
+                    warn!("Ignoring invalid SERVICES entry {:?}", entry);
+                    continue;
+                }
+            };
+
+            let matcher = if key.starts_with('/') {
+                Matcher::Prefix(key.trim_end_matches('/').to_string())
+            } else {
+                Matcher::Host(key.to_ascii_lowercase())
+            };
+
+            services.push(Service {
+                matcher,
+                upstream: Upstream::new(url)?,
+            });
+        }
+
+        Ok(Self { services })
+    }
+
+    /// Find the service of a request and the path to request from it.
+    pub fn find(&self, request: &Request<Body>) -> Option<(&Upstream, String)> {
+        let path = request.uri().path();
+        let host = request
+            .headers()
+            .get(HOST)
+            .and_then(|value| value.to_str().ok())
+            .map(|host| host.rsplit_once(':').map_or(host, |(name, _)| name));
+
+        self.services.iter().find_map(|service| {
+            let path = match &service.matcher {
+                Matcher::Host(name) => host
+                    .filter(|host| host.eq_ignore_ascii_case(name))
+                    .map(|_| path),
+                Matcher::Prefix(prefix) => path
+                    .strip_prefix(prefix.as_str())
+                    .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+                    .map(|rest| if rest.is_empty() { "/" } else { rest }),
+            }?;
+
+            Some((&service.upstream, path.to_string()))
+        })
+    }
+}
+
+/// Relay a request to a service.
+pub async fn relay(
+    state: &State,
+    upstream: &Upstream,
+    path: &str,
+    request: Request<Body>,
+) -> Result<Response<Body>, RequestError> {
+    let uri = match upstream.uri("", path, request.uri().query()) {
+        Ok(uri) => uri,
+        Err(e) => {
+            error!("Failed to create URI for requesting a service: {:?}", e);
+            return Err(RequestError::InvalidURI { source: e });
+        }
+    };
+
+    debug!("Relaying {} {} to {}", request.method(), path, uri);
+
+    let (mut parts, body) = request.into_parts();
+    let method = parts.method.clone();
+    parts.uri = uri;
+    headers::remove_hop_by_hop(&mut parts.headers);
+    parts.headers.insert(HOST, upstream.host().clone());
+
+    if state.dry_run {
+        return Ok(dry_run_response(&method));
+    }
+
+    let mut response = match state
+        .clients
+        .get(upstream)
+        .request(Request::from_parts(parts, body))
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            error!("Error when requesting a service: {:?}", e);
+            return Err(RequestError::RequestIssue { source: e });
+        }
+    };
+
+    headers::prepare_response(&method, &mut response);
+
+    state
+        .response_size
+        .relay("Service", response)
+        .map_err(|size| RequestError::ResponseTooLarge { size })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Matcher, Services};
+    use http::Request;
+    use hyper::Body;
+
+    #[test]
+    fn test_parse() {
+        let services = Services::parse(
+            "cdn.internal=https://cdn.discordapp.com, /media/=https://media.discordapp.net,invalid",
+        )
+        .unwrap();
+
+        assert_eq!(services.services.len(), 2);
+        assert_eq!(
+            services.services[0].matcher,
+            Matcher::Host("cdn.internal".to_string())
+        );
+        assert_eq!(
+            services.services[1].matcher,
+            Matcher::Prefix("/media".to_string())
+        );
+
+        assert!(Services::parse("cdn.internal=ftp://cdn.discordapp.com").is_err());
+    }
+
+    #[test]
+    fn test_find() {
+        let services = Services::parse(
+            "CDN.internal=https://cdn.discordapp.com,/media=https://media.discordapp.net",
+        )
+        .unwrap();
+
+        let request = |host: &str, path: &str| {
+            Request::builder()
+                .uri(path)
+                .header("host", host)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let cdn = request("cdn.internal:3000", "/attachments/1/2/a.png");
+        let (upstream, path) = services.find(&cdn).unwrap();
+        assert_eq!(upstream.host(), "cdn.discordapp.com");
+        assert_eq!(path, "/attachments/1/2/a.png");
+
+        let media = request("proxy", "/media/attachments/1/2/a.png");
+        let (upstream, path) = services.find(&media).unwrap();
+        assert_eq!(upstream.host(), "media.discordapp.net");
+        assert_eq!(path, "/attachments/1/2/a.png");
+
+        let (_, path) = services.find(&request("proxy", "/media")).unwrap();
+        assert_eq!(path, "/");
+
+        assert!(services.find(&request("proxy", "/mediax/a.png")).is_none());
+        assert!(services
+            .find(&request("proxy", "/api/v10/gateway"))
+            .is_none());
+    }
+}
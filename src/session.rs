@@ -0,0 +1,161 @@
+//! Guard against exhausting the daily session starts of a bot, e.g. because
+//! a crashing shard manager keeps fetching `/gateway/bot` and identifying.
+
+use crate::{parse_env, tenant::Tenant};
+use http::header::CONTENT_ENCODING;
+use hyper::{Body, Response};
+use serde::Deserialize;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+use tracing::{debug, warn};
+use twilight_http_ratelimiting::Path;
+
+/// Session start limit of `/gateway/bot` responses.
+#[derive(Deserialize)]
+struct SessionStartLimit {
+    remaining: u64,
+    /// Milliseconds until the limit resets.
+    reset_after: u64,
+}
+
+#[derive(Deserialize)]
+struct GatewayBot {
+    session_start_limit: SessionStartLimit,
+}
+
+/// Session start limit of a tenant as last reported by Discord.
+#[derive(Default)]
+pub struct SessionStarts {
+    observed: Mutex<Option<(u64, Instant)>>,
+}
+
+impl SessionStarts {
+    fn observe(&self, remaining: u64, reset_after: Duration) {
+        *self.observed.lock().expect("session starts poisoned") =
+            Some((remaining, Instant::now() + reset_after));
+    }
+
+    /// Time until the limit resets if at most `reserve` session starts are
+    /// remaining.
+    fn exhausted(&self, reserve: u64) -> Option<Duration> {
+        let observed = self.observed.lock().expect("session starts poisoned");
+        let (remaining, resets_at) = (*observed)?;
+        let now = Instant::now();
+
+        (remaining <= reserve && resets_at > now).then(|| resets_at - now)
+    }
+}
+
+/// Amount of session starts kept in reserve, read from
+/// `SESSION_START_RESERVE`.
+pub struct SessionGuard {
+    reserve: u64,
+}
+
+impl SessionGuard {
+    /// Returns `None` if the guard is not enabled.
+    pub fn from_env() -> Option<Self> {
+        parse_env("SESSION_START_RESERVE").map(|reserve| Self { reserve })
+    }
+
+    /// Check whether a tenant may fetch `/gateway/bot`.
+    ///
+    /// Returns the time until the session start limit resets otherwise.
+    pub fn check(&self, tenant: &Tenant, path: &Path) -> Result<(), Duration> {
+        if !matches!(path, Path::GatewayBot) {
+            return Ok(());
+        }
+
+        match tenant.sessions.exhausted(self.reserve) {
+            Some(reset_after) => Err(reset_after),
+            None => Ok(()),
+        }
+    }
+
+    /// Track the session start limit of a `/gateway/bot` response.
+    pub async fn record(
+        &self,
+        tenant: &Tenant,
+        path: &Path,
+        response: Response<Body>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        if !matches!(path, Path::GatewayBot)
+            || !response.status().is_success()
+            || response.headers().contains_key(CONTENT_ENCODING)
+        {
+            return Ok(response);
+        }
+
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+
+        match serde_json::from_slice::<GatewayBot>(&body) {
+            Ok(GatewayBot {
+                session_start_limit: limit,
+            }) => {
+                if limit.remaining <= self.reserve {
+                    warn!(
+                        "Tenant {} has {} session starts left, refusing /gateway/bot for {:?}",
+                        tenant.usage.hash(),
+                        limit.remaining,
+                        Duration::from_millis(limit.reset_after)
+                    );
+                }
+
+                tenant
+                    .sessions
+                    .observe(limit.remaining, Duration::from_millis(limit.reset_after));
+            }
+            Err(e) => debug!("Failed to parse session start limit: {}", e),
+        }
+
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SessionGuard;
+    use crate::tenant::Tenant;
+    use hyper::{Body, Response};
+    use tokio::time::{self, Duration};
+    use twilight_http_ratelimiting::Path;
+
+    fn gateway_bot(remaining: u64) -> Response<Body> {
+        Response::new(Body::from(format!(
+            r#"{{"url":"wss://gateway.discord.gg","shards":1,"session_start_limit":{{"total":1000,"remaining":{},"reset_after":60000,"max_concurrency":1}}}}"#,
+            remaining
+        )))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_guard() {
+        let guard = SessionGuard { reserve: 5 };
+        let tenant = Tenant::new("Bot abc");
+
+        assert!(guard.check(&tenant, &Path::GatewayBot).is_ok());
+
+        guard
+            .record(&tenant, &Path::GatewayBot, gateway_bot(6))
+            .await
+            .unwrap();
+        assert!(guard.check(&tenant, &Path::GatewayBot).is_ok());
+
+        let response = guard
+            .record(&tenant, &Path::GatewayBot, gateway_bot(5))
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.starts_with(br#"{"url""#));
+
+        assert_eq!(
+            guard.check(&tenant, &Path::GatewayBot),
+            Err(Duration::from_secs(60))
+        );
+        // Only fetching the gateway is refused
+        assert!(guard.check(&tenant, &Path::Gateway).is_ok());
+
+        time::advance(Duration::from_secs(60)).await;
+        assert!(guard.check(&tenant, &Path::GatewayBot).is_ok());
+    }
+}
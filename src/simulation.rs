@@ -0,0 +1,376 @@
+//! Simulation of the ratelimiter against a scripted mock upstream.
+//!
+//! Requests go through a tenant's ratelimiter the same way
+//! `handle_request` sends them, and the upstream tracks buckets the way
+//! Discord does, recording every request that exceeds a declared limit.
+//! This guards against regressions when the ratelimiting dependency is
+//! updated.
+//!
+//! Tests run with paused time, so bucket resets and upstream latency don't
+//! slow them down and requests are processed in a fixed order. The
+//! `selftest` subcommand runs it in real time at high concurrency.
+
+use crate::{
+    ratelimiter_map::{is_shared_ratelimit, ratelimit_headers},
+    tenant::Tenant,
+};
+use http::{HeaderMap, HeaderValue, StatusCode};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+use tokio::time::{Duration, Instant};
+use twilight_http_ratelimiting::Path;
+
+/// Limit of buckets without a declared one.
+const DEFAULT_LIMIT: Limit = Limit {
+    count: 5,
+    reset_after: Duration::from_millis(100),
+};
+
+/// A limit of `count` requests per `reset_after`.
+#[derive(Clone, Copy, Debug)]
+pub struct Limit {
+    pub count: u64,
+    pub reset_after: Duration,
+}
+
+/// A response sent instead of the regular one.
+#[derive(Clone, Copy, Debug)]
+pub enum Scripted {
+    /// A 429 caused by a resource-wide limit, which doesn't use up the
+    /// bucket.
+    SharedRatelimit { reset_after: Duration },
+    /// A server error, which uses up the bucket like a regular response.
+    ServerError,
+}
+
+/// Current window of a bucket.
+struct Window {
+    started_at: Instant,
+    remaining: u64,
+}
+
+/// A request that was sent although its bucket was exhausted.
+struct Violation {
+    path: Path,
+    /// Time since the bucket's window started.
+    elapsed: Duration,
+}
+
+/// A mock of Discord's bucket accounting.
+#[derive(Default)]
+pub struct Upstream {
+    limits: HashMap<Path, Limit>,
+    /// Time spent before responding to a request.
+    latency: Duration,
+    script: Mutex<HashMap<Path, VecDeque<Scripted>>>,
+    windows: Mutex<HashMap<Path, Window>>,
+    accepted: Mutex<HashMap<Path, u64>>,
+    violations: Mutex<Vec<Violation>>,
+}
+
+impl Upstream {
+    /// Declare the limit of a path's bucket.
+    pub fn limit(mut self, path: Path, count: u64, reset_after: Duration) -> Self {
+        self.limits.insert(path, Limit { count, reset_after });
+
+        self
+    }
+
+    pub const fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+
+        self
+    }
+
+    /// Respond to the next requests to a path with the given responses, in
+    /// order.
+    pub fn script(self, path: Path, responses: &[Scripted]) -> Self {
+        self.script
+            .lock()
+            .expect("script poisoned")
+            .entry(path)
+            .or_default()
+            .extend(responses);
+
+        self
+    }
+
+    /// Amount of requests to a path that were within its limit.
+    pub fn accepted(&self, path: &Path) -> u64 {
+        self.accepted
+            .lock()
+            .expect("accepted poisoned")
+            .get(path)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Descriptions of the requests that exceeded their bucket's limit.
+    pub fn violations(&self) -> Vec<String> {
+        self.violations
+            .lock()
+            .expect("violations poisoned")
+            .iter()
+            .map(|violation| {
+                format!(
+                    "{:?} {:?} into its window",
+                    violation.path, violation.elapsed
+                )
+            })
+            .collect()
+    }
+
+    /// Receive a request, returning the status and headers of the response.
+    pub async fn respond(&self, path: &Path) -> (StatusCode, HeaderMap) {
+        let scripted = self
+            .script
+            .lock()
+            .expect("script poisoned")
+            .get_mut(path)
+            .and_then(VecDeque::pop_front);
+
+        if let Some(Scripted::SharedRatelimit { reset_after }) = scripted {
+            let mut headers = bucket_headers(1, 0, reset_after);
+            headers.insert("x-ratelimit-scope", HeaderValue::from_static("shared"));
+            tokio::time::sleep(self.latency).await;
+
+            return (StatusCode::TOO_MANY_REQUESTS, headers);
+        }
+
+        let now = Instant::now();
+        let limit = self.limits.get(path).copied().unwrap_or(DEFAULT_LIMIT);
+
+        let (status, headers) = {
+            let mut windows = self.windows.lock().expect("windows poisoned");
+            let window = windows.entry(path.clone()).or_insert(Window {
+                started_at: now,
+                remaining: limit.count,
+            });
+
+            if now.duration_since(window.started_at) >= limit.reset_after {
+                *window = Window {
+                    started_at: now,
+                    remaining: limit.count,
+                };
+            }
+
+            let elapsed = now.duration_since(window.started_at);
+            let reset_after = limit.reset_after - elapsed;
+
+            if window.remaining == 0 {
+                self.violations
+                    .lock()
+                    .expect("violations poisoned")
+                    .push(Violation {
+                        path: path.clone(),
+                        elapsed,
+                    });
+
+                let mut headers = bucket_headers(limit.count, 0, reset_after);
+                headers.insert("x-ratelimit-scope", HeaderValue::from_static("user"));
+
+                (StatusCode::TOO_MANY_REQUESTS, headers)
+            } else {
+                window.remaining -= 1;
+                *self
+                    .accepted
+                    .lock()
+                    .expect("accepted poisoned")
+                    .entry(path.clone())
+                    .or_default() += 1;
+
+                let status = match scripted {
+                    Some(Scripted::ServerError) => StatusCode::INTERNAL_SERVER_ERROR,
+                    _ => StatusCode::OK,
+                };
+
+                (
+                    status,
+                    bucket_headers(limit.count, window.remaining, reset_after),
+                )
+            }
+        };
+
+        tokio::time::sleep(self.latency).await;
+
+        (status, headers)
+    }
+}
+
+fn bucket_headers(limit: u64, remaining: u64, reset_after: Duration) -> HeaderMap {
+    let value = |value: String| HeaderValue::from_str(&value).expect("numbers are valid");
+
+    let mut headers = HeaderMap::new();
+    headers.insert("x-ratelimit-limit", value(limit.to_string()));
+    headers.insert("x-ratelimit-remaining", value(remaining.to_string()));
+    headers.insert("x-ratelimit-reset", HeaderValue::from_static("1700000000"));
+    // Rounded up, so the bucket is never reported to reset early
+    let reset_after_ms = reset_after.as_nanos().div_ceil(1_000_000);
+    headers.insert(
+        "x-ratelimit-reset-after",
+        value(format!(
+            "{}.{:03}",
+            reset_after_ms / 1000,
+            reset_after_ms % 1000
+        )),
+    );
+
+    headers
+}
+
+/// Send a request through the tenant's ratelimiter like `handle_request`.
+pub async fn forward(tenant: &Tenant, upstream: &Upstream, path: Path) -> StatusCode {
+    let sender = tenant
+        .ratelimiter
+        .wait_for_ticket(path.clone())
+        .await
+        .expect("ticket is received");
+
+    let (status, headers) = upstream.respond(&path).await;
+
+    let headers = if is_shared_ratelimit(status, &headers) {
+        None
+    } else {
+        ratelimit_headers(&headers)
+    };
+
+    sender.headers(headers).expect("bucket is running");
+
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{forward, Scripted, Upstream};
+    use crate::tenant::Tenant;
+    use http::StatusCode;
+    use std::{iter, sync::Arc};
+    use tokio::time::{Duration, Instant};
+    use twilight_http_ratelimiting::Path;
+
+    /// Send all requests concurrently, returning their statuses in order.
+    async fn run(tenant: &Tenant, upstream: &Arc<Upstream>, paths: Vec<Path>) -> Vec<StatusCode> {
+        let handles = paths
+            .into_iter()
+            .map(|path| {
+                let tenant = tenant.clone();
+                let upstream = Arc::clone(upstream);
+
+                tokio::spawn(async move { forward(&tenant, &upstream, path).await })
+            })
+            .collect::<Vec<_>>();
+
+        let mut statuses = Vec::with_capacity(handles.len());
+
+        for handle in handles {
+            statuses.push(handle.await.expect("request panicked"));
+        }
+
+        statuses
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_single_bucket() {
+        let path = Path::ChannelsIdMessages(1);
+        let upstream = Arc::new(
+            Upstream::default()
+                .limit(path.clone(), 5, Duration::from_millis(50))
+                .latency(Duration::from_millis(20)),
+        );
+        let start = Instant::now();
+
+        let statuses = run(
+            &Tenant::new("Bot sim"),
+            &upstream,
+            iter::repeat_n(path.clone(), 12).collect(),
+        )
+        .await;
+
+        assert!(statuses.iter().all(|status| *status == StatusCode::OK));
+        assert_eq!(upstream.accepted(&path), 12);
+        assert_eq!(upstream.violations(), Vec::<String>::new());
+        // Three windows are needed for twelve requests
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_independent_buckets() {
+        let paths = (1..=3).map(Path::ChannelsIdMessages).collect::<Vec<_>>();
+        let upstream = Arc::new(paths.iter().fold(
+            Upstream::default().latency(Duration::from_millis(5)),
+            |upstream, path| upstream.limit(path.clone(), 2, Duration::from_millis(50)),
+        ));
+
+        let statuses = run(
+            &Tenant::new("Bot sim"),
+            &upstream,
+            paths.iter().cycle().take(18).cloned().collect(),
+        )
+        .await;
+
+        assert!(statuses.iter().all(|status| *status == StatusCode::OK));
+        assert_eq!(upstream.violations(), Vec::<String>::new());
+
+        for path in &paths {
+            assert_eq!(upstream.accepted(path), 6);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tenants_are_separate() {
+        let path = Path::ChannelsIdMessages(1);
+        let first = Arc::new(Upstream::default().limit(path.clone(), 3, Duration::from_millis(50)));
+        let second =
+            Arc::new(Upstream::default().limit(path.clone(), 3, Duration::from_millis(50)));
+        let requests = || iter::repeat_n(path.clone(), 7).collect();
+
+        let first_tenant = Tenant::new("Bot first");
+        let second_tenant = Tenant::new("Bot second");
+
+        tokio::join!(
+            run(&first_tenant, &first, requests()),
+            run(&second_tenant, &second, requests()),
+        );
+
+        assert_eq!(first.violations(), Vec::<String>::new());
+        assert_eq!(second.violations(), Vec::<String>::new());
+        assert_eq!(first.accepted(&path), 7);
+        assert_eq!(second.accepted(&path), 7);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shared_ratelimit() {
+        let path = Path::ChannelsIdMessages(1);
+        let upstream = Arc::new(
+            Upstream::default()
+                .limit(path.clone(), 5, Duration::from_millis(50))
+                .script(
+                    path.clone(),
+                    &[
+                        Scripted::SharedRatelimit {
+                            reset_after: Duration::from_secs(60),
+                        },
+                        Scripted::ServerError,
+                    ],
+                ),
+        );
+        let start = Instant::now();
+
+        let statuses = run(
+            &Tenant::new("Bot sim"),
+            &upstream,
+            iter::repeat_n(path.clone(), 8).collect(),
+        )
+        .await;
+
+        assert_eq!(statuses[0], StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(statuses[1], StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(statuses[2..].iter().all(|status| *status == StatusCode::OK));
+        assert_eq!(upstream.accepted(&path), 7);
+        assert_eq!(upstream.violations(), Vec::<String>::new());
+        // The shared 429's reset doesn't apply to the token's bucket
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,283 @@
+//! Latency objectives per route, tracking the share of requests answered in
+//! time over a rolling hour and how fast the error budget is burned.
+//!
+//! Latency is measured from when the proxy received a request until Discord's
+//! response is relayed, so time spent waiting for ratelimits counts against
+//! the objective.
+
+use crate::sublimit::route_name;
+use serde::Serialize;
+use std::{collections::VecDeque, env, sync::Mutex, time::Duration};
+use tokio::time::Instant;
+use tracing::warn;
+use twilight_http_ratelimiting::Path;
+
+/// Route of an objective applying to all routes without their own.
+const ANY_ROUTE: &str = "*";
+
+/// Minutes covered by the rolling window.
+const WINDOW_MINUTES: u64 = 60;
+
+/// Minutes of the short window for detecting fast burns.
+const SHORT_WINDOW_MINUTES: u64 = 5;
+
+/// Requests of one minute.
+#[derive(Clone, Copy, Default)]
+struct Slot {
+    minute: u64,
+    total: u64,
+    good: u64,
+}
+
+struct Objective {
+    route: String,
+    threshold: Duration,
+    /// Share of requests that have to be faster than the threshold, between 0
+    /// and 1.
+    target: f64,
+    slots: Mutex<VecDeque<Slot>>,
+}
+
+impl Objective {
+    fn record(&self, minute: u64, good: bool) {
+        let mut slots = self.slots.lock().expect("slo poisoned");
+
+        match slots.back_mut() {
+            Some(slot) if slot.minute == minute => {
+                slot.total += 1;
+                slot.good += u64::from(good);
+            }
+            _ => slots.push_back(Slot {
+                minute,
+                total: 1,
+                good: u64::from(good),
+            }),
+        }
+
+        while slots
+            .front()
+            .is_some_and(|slot| slot.minute + WINDOW_MINUTES <= minute)
+        {
+            slots.pop_front();
+        }
+    }
+
+    /// Total and good requests of the last `minutes`, including the current
+    /// one.
+    fn counts(&self, now: u64, minutes: u64) -> (u64, u64) {
+        let slots = self.slots.lock().expect("slo poisoned");
+
+        slots
+            .iter()
+            .filter(|slot| slot.minute + minutes > now)
+            .fold((0, 0), |(total, good), slot| {
+                (total + slot.total, good + slot.good)
+            })
+    }
+
+    /// How many times faster than allowed the error budget is used up, where
+    /// 1 exhausts it exactly at the end of the window.
+    fn burn_rate(&self, now: u64, minutes: u64) -> Option<f64> {
+        let (total, good) = self.counts(now, minutes);
+
+        if total == 0 {
+            return None;
+        }
+
+        let bad = (total - good) as f64 / total as f64;
+
+        Some(bad / (1.0 - self.target))
+    }
+
+    fn summary(&self, now: u64) -> Summary {
+        let (total, good) = self.counts(now, WINDOW_MINUTES);
+
+        Summary {
+            route: self.route.clone(),
+            threshold_ms: self.threshold.as_millis(),
+            target: self.target,
+            requests: total,
+            compliance: (total > 0).then(|| good as f64 / total as f64),
+            burn_rate_5m: self.burn_rate(now, SHORT_WINDOW_MINUTES),
+            burn_rate_1h: self.burn_rate(now, WINDOW_MINUTES),
+        }
+    }
+}
+
+/// State of an objective over the last hour.
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    route: String,
+    threshold_ms: u128,
+    target: f64,
+    requests: u64,
+    compliance: Option<f64>,
+    burn_rate_5m: Option<f64>,
+    burn_rate_1h: Option<f64>,
+}
+
+/// Configured via `LATENCY_SLOS`.
+pub struct Slos {
+    objectives: Vec<Objective>,
+    started: Instant,
+}
+
+impl Slos {
+    /// Load objectives from a comma-separated list of `Route=millis/percent`
+    /// entries, where `Route` is the name of a [`Path`] variant or `*` for all
+    /// other routes.
+    pub fn from_env() -> Self {
+        let objectives = env::var("LATENCY_SLOS")
+            .map(|value| parse_objectives(&value))
+            .unwrap_or_default();
+
+        Self {
+            objectives,
+            started: Instant::now(),
+        }
+    }
+
+    fn objective(&self, path: &Path) -> Option<&Objective> {
+        if self.objectives.is_empty() {
+            return None;
+        }
+
+        let route = route_name(path);
+
+        self.objectives
+            .iter()
+            .find(|objective| objective.route == route)
+            .or_else(|| {
+                self.objectives
+                    .iter()
+                    .find(|objective| objective.route == ANY_ROUTE)
+            })
+    }
+
+    fn minute(&self) -> u64 {
+        self.started.elapsed().as_secs() / 60
+    }
+
+    /// Record the latency of a request to a path.
+    pub fn record(&self, path: &Path, latency: Duration) {
+        let objective = match self.objective(path) {
+            Some(objective) => objective,
+            None => return,
+        };
+
+        let minute = self.minute();
+        objective.record(minute, latency <= objective.threshold);
+
+        #[cfg(feature = "expose-metrics")]
+        {
+            let key = crate::METRIC_KEY.as_str();
+
+            for (window, minutes) in [("5m", SHORT_WINDOW_MINUTES), ("1h", WINDOW_MINUTES)] {
+                if let Some(burn_rate) = objective.burn_rate(minute, minutes) {
+                    metrics::gauge!(
+                        format!("{}_slo_burn_rate", key),
+                        burn_rate,
+                        "route" => objective.route.clone(),
+                        "window" => window
+                    );
+                }
+            }
+        }
+    }
+
+    /// Summaries of all objectives.
+    pub fn summaries(&self) -> Vec<Summary> {
+        let minute = self.minute();
+
+        self.objectives
+            .iter()
+            .map(|objective| objective.summary(minute))
+            .collect()
+    }
+}
+
+fn parse_objectives(value: &str) -> Vec<Objective> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let objective = parse_objective(entry);
+
+            if objective.is_none() {
+                warn!("Ignoring invalid latency SLO {:?}", entry);
+            }
+
+            objective
+        })
+        .collect()
+}
+
+fn parse_objective(entry: &str) -> Option<Objective> {
+    let (route, objective) = entry.split_once('=')?;
+    let (millis, percent) = objective.trim().split_once('/')?;
+    let percent = percent.trim().trim_end_matches('%').parse::<f64>().ok()?;
+
+    if !(percent > 0.0 && percent < 100.0) {
+        return None;
+    }
+
+    Some(Objective {
+        route: route.trim().to_string(),
+        threshold: Duration::from_millis(millis.trim().parse().ok()?),
+        target: percent / 100.0,
+        slots: Mutex::new(VecDeque::new()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_objectives, Slos};
+    use tokio::time::Instant;
+    use twilight_http_ratelimiting::Path;
+
+    #[test]
+    fn test_parse() {
+        let objectives =
+            parse_objectives("ChannelsIdMessages=500/99.9, *=1000/99%, Gateway=1/100, invalid");
+
+        assert_eq!(objectives.len(), 2);
+        assert_eq!(objectives[0].route, "ChannelsIdMessages");
+        assert_eq!(objectives[0].threshold.as_millis(), 500);
+        assert!((objectives[0].target - 0.999).abs() < f64::EPSILON);
+        assert_eq!(objectives[1].route, "*");
+    }
+
+    #[test]
+    fn test_objective() {
+        let slos = Slos {
+            objectives: parse_objectives("ChannelsIdMessages=500/90,*=1000/50"),
+            started: Instant::now(),
+        };
+
+        let messages = slos.objective(&Path::ChannelsIdMessages(1)).unwrap();
+        assert_eq!(messages.route, "ChannelsIdMessages");
+        let other = slos.objective(&Path::Gateway).unwrap();
+        assert_eq!(other.route, "*");
+
+        for _ in 0..8 {
+            messages.record(0, true);
+        }
+
+        messages.record(0, false);
+        messages.record(1, false);
+
+        let summary = messages.summary(1);
+        assert_eq!(summary.requests, 10);
+        assert_eq!(summary.compliance, Some(0.8));
+        // 20% of requests were too slow, with a budget of 10%
+        assert!((summary.burn_rate_1h.unwrap() - 2.0).abs() < 1e-9);
+
+        // Older minutes leave the window
+        messages.record(60, true);
+        let summary = messages.summary(60);
+        assert_eq!(summary.requests, 2);
+        assert_eq!(summary.compliance, Some(0.5));
+        assert!(messages.summary(200).compliance.is_none());
+    }
+}
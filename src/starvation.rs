@@ -0,0 +1,110 @@
+//! Detection of requests waiting unusually long for a ratelimit ticket, e.g.
+//! because a bucket's reset is far in the future or its queue is too long.
+
+use crate::{parse_env, State};
+use tokio::time::{self, Duration};
+use tracing::warn;
+
+#[cfg(feature = "expose-metrics")]
+use std::collections::HashSet;
+
+/// Queue age after which a warning is logged if `STARVATION_THRESHOLD` is not
+/// set.
+const DEFAULT_THRESHOLD: Duration = Duration::from_secs(60);
+
+/// Interval in which the queues are checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Load the threshold from `STARVATION_THRESHOLD`.
+///
+/// Returns `None` if the detection is disabled by setting it to 0.
+pub fn threshold_from_env() -> Option<Duration> {
+    match parse_env::<u64>("STARVATION_THRESHOLD") {
+        Some(0) => None,
+        Some(seconds) => Some(Duration::from_secs(seconds)),
+        None => Some(DEFAULT_THRESHOLD),
+    }
+}
+
+/// Whether the oldest request of a bucket crossed the threshold since the
+/// previous check, so every starving request is only reported once.
+fn crossed(age: Duration, threshold: Duration) -> bool {
+    age >= threshold && age < threshold + CHECK_INTERVAL
+}
+
+/// Check the age of the oldest queued request of every bucket until the
+/// process exits.
+pub async fn run(state: &State, threshold: Duration) {
+    let mut interval = time::interval(CHECK_INTERVAL);
+
+    #[cfg(feature = "expose-metrics")]
+    let mut reported = HashSet::new();
+
+    loop {
+        interval.tick().await;
+
+        #[cfg(feature = "expose-metrics")]
+        let mut current = HashSet::new();
+
+        for tenant in state.ratelimiter_map.tenants() {
+            for (path, age) in tenant.usage.queue_ages() {
+                if crossed(age, threshold) {
+                    warn!(
+                        "Request to {:?} of tenant {} has been queued for {:?}",
+                        path,
+                        tenant.usage.hash(),
+                        age
+                    );
+                }
+
+                #[cfg(feature = "expose-metrics")]
+                {
+                    let labels = (tenant.usage.hash().to_string(), format!("{:?}", path));
+
+                    metrics::gauge!(
+                        format!("{}_oldest_queued_seconds", crate::METRIC_KEY.as_str()),
+                        age.as_secs_f64(),
+                        "tenant" => labels.0.clone(),
+                        "path" => labels.1.clone()
+                    );
+                    current.insert(labels);
+                }
+            }
+        }
+
+        // Buckets whose queue emptied would otherwise keep reporting their
+        // last age
+        #[cfg(feature = "expose-metrics")]
+        {
+            for (tenant, path) in reported.difference(&current) {
+                metrics::gauge!(
+                    format!("{}_oldest_queued_seconds", crate::METRIC_KEY.as_str()),
+                    0.0,
+                    "tenant" => tenant.clone(),
+                    "path" => path.clone()
+                );
+            }
+
+            reported = current;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crossed, CHECK_INTERVAL};
+    use tokio::time::Duration;
+
+    #[test]
+    fn test_crossed() {
+        let threshold = Duration::from_secs(60);
+
+        assert!(!crossed(Duration::from_secs(59), threshold));
+        assert!(crossed(threshold, threshold));
+        assert!(crossed(
+            threshold + CHECK_INTERVAL - Duration::from_millis(1),
+            threshold
+        ));
+        assert!(!crossed(threshold + CHECK_INTERVAL, threshold));
+    }
+}
@@ -0,0 +1,98 @@
+//! Human-readable status page served at `/`, so operators opening the proxy
+//! in a browser see that it is running instead of an invalid path error.
+
+use crate::State;
+use http::{header::CONTENT_TYPE, Method, Request, Response};
+use hyper::Body;
+use std::{fmt::Write, time::Duration};
+
+/// Whether a request is for the status page.
+pub fn is_status_page(request: &Request<Body>) -> bool {
+    request.uri().path() == "/" && matches!(*request.method(), Method::GET | Method::HEAD)
+}
+
+pub fn page(state: &State) -> Response<Body> {
+    let healthy = state.probe.as_ref().is_none_or(|probe| probe.is_ready());
+    let locked_down = state
+        .lockdown
+        .as_ref()
+        .is_some_and(|lockdown| lockdown.remaining().is_some());
+
+    let mut rows = vec![
+        ("Version", env!("CARGO_PKG_VERSION").to_string()),
+        ("Uptime", format_uptime(state.stats.uptime())),
+        (
+            "Health",
+            if locked_down {
+                "locked down after a ban"
+            } else if healthy {
+                "healthy"
+            } else {
+                "unhealthy"
+            }
+            .to_string(),
+        ),
+        (
+            "Traffic",
+            if state.pause.is_paused() {
+                "paused"
+            } else {
+                "flowing"
+            }
+            .to_string(),
+        ),
+        (
+            "Routes under maintenance",
+            state.maintenance.routes().len().to_string(),
+        ),
+        ("Tenants", state.ratelimiter_map.tenants().len().to_string()),
+        ("Requests served", state.stats.requests_served().to_string()),
+    ];
+
+    if state.dry_run {
+        rows.push(("Dry run", "enabled".to_string()));
+    }
+
+    let mut body = String::from(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>twilight-http-proxy</title></head>\n\
+         <body>\n<h1>twilight-http-proxy</h1>\n<table>\n",
+    );
+
+    for (name, value) in rows {
+        let _ = writeln!(body, "<tr><th>{}</th><td>{}</td></tr>", name, value);
+    }
+
+    body.push_str("</table>\n</body>\n</html>\n");
+
+    Response::builder()
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(Body::from(body))
+        .expect("response is valid")
+}
+
+/// Format a duration as days, hours, minutes and seconds, e.g. `1d 2h 3m 4s`.
+fn format_uptime(uptime: Duration) -> String {
+    let seconds = uptime.as_secs();
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{}s", seconds % 60),
+        (0, 0, _) => format!("{}m {}s", minutes, seconds % 60),
+        (0, ..) => format!("{}h {}m {}s", hours, minutes, seconds % 60),
+        _ => format!("{}d {}h {}m {}s", days, hours, minutes, seconds % 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_uptime;
+    use std::time::Duration;
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(Duration::from_secs(5)), "5s");
+        assert_eq!(format_uptime(Duration::from_secs(65)), "1m 5s");
+        assert_eq!(format_uptime(Duration::from_secs(3600)), "1h 0m 0s");
+        assert_eq!(format_uptime(Duration::from_secs(93784)), "1d 2h 3m 4s");
+    }
+}
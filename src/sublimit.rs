@@ -0,0 +1,402 @@
+//! Local pacing for routes with sublimits Discord doesn't advertise in
+//! ratelimit headers.
+
+use crate::parse_env;
+use std::{
+    collections::{HashMap, VecDeque},
+    env, mem,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, warn};
+use twilight_http_ratelimiting::{Method, Path};
+
+/// Maximum amount of paths a [`Pacer`] tracks before pruning finished ones.
+const PRUNE_THRESHOLD: usize = 64;
+
+/// Default of `MAX_SUBLIMIT_WAIT_MS`.
+const DEFAULT_MAX_WAIT: Duration = Duration::from_secs(10);
+
+/// A sublimit of `count` requests per `window` for a method and route.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    method: Method,
+    /// Name of the [`Path`] variant, such as `ChannelsId`.
+    route: String,
+    count: usize,
+    window: Duration,
+    /// JSON body fields of which at least one has to be present for the rule
+    /// to apply. Applies to all requests if `None`.
+    fields: Option<&'static [&'static str]>,
+}
+
+impl Rule {
+    /// Whether the rule only applies to requests with specific body fields.
+    pub const fn fields(&self) -> Option<&'static [&'static str]> {
+        self.fields
+    }
+
+    /// Whether a JSON request body contains any of the fields required by
+    /// this rule.
+    pub fn matches_body(&self, body: &[u8]) -> bool {
+        let fields = match self.fields {
+            Some(fields) => fields,
+            None => return true,
+        };
+
+        match serde_json::from_slice::<serde_json::Map<String, serde_json::Value>>(body) {
+            Ok(object) => fields.iter().any(|field| object.contains_key(*field)),
+            // Let Discord reject invalid bodies without pacing them
+            Err(_) => false,
+        }
+    }
+}
+
+/// Configured sublimit rules.
+pub struct Sublimits {
+    rules: Vec<Rule>,
+    /// Longest time a request is paced, requests that would wait longer are
+    /// rejected.
+    max_wait: Duration,
+}
+
+impl Sublimits {
+    /// Load the default rules, overridden by the `SUBLIMITS` environment
+    /// variable.
+    pub fn from_env() -> Self {
+        let mut rules = default_rules();
+
+        if let Ok(value) = env::var("SUBLIMITS") {
+            for rule in parse_rules(&value) {
+                if let Some(existing) = rules
+                    .iter_mut()
+                    .find(|existing| existing.method == rule.method && existing.route == rule.route)
+                {
+                    existing.count = rule.count;
+                    existing.window = rule.window;
+                } else {
+                    rules.push(rule);
+                }
+            }
+        }
+
+        // A count of 0 disables a rule
+        rules.retain(|rule| rule.count > 0);
+
+        let max_wait =
+            parse_env("MAX_SUBLIMIT_WAIT_MS").map_or(DEFAULT_MAX_WAIT, Duration::from_millis);
+
+        Self { rules, max_wait }
+    }
+
+    /// Longest time a request is paced before it is rejected instead.
+    pub const fn max_wait(&self) -> Duration {
+        self.max_wait
+    }
+
+    /// Find the rule applying to a request.
+    pub fn rule(&self, method: Method, path: &Path) -> Option<&Rule> {
+        let route = route_name(path);
+
+        self.rules
+            .iter()
+            .find(|rule| rule.method == method && rule.route == route)
+    }
+}
+
+/// Name of a [`Path`]'s variant, without its major parameter.
+pub fn route_name(path: &Path) -> String {
+    let mut name = format!("{:?}", path);
+
+    if let Some(index) = name.find('(') {
+        name.truncate(index);
+    }
+
+    name
+}
+
+fn default_rules() -> Vec<Rule> {
+    vec![
+        // Editing a channel's name or topic is limited to 2 per 10 minutes
+        Rule {
+            method: Method::Patch,
+            route: "ChannelsId".to_string(),
+            count: 2,
+            window: Duration::from_secs(600),
+            fields: Some(&["name", "topic"]),
+        },
+        // Adding reactions is limited to roughly 1 per 250ms
+        Rule {
+            method: Method::Put,
+            route: "ChannelsIdMessagesIdReactionsUserIdType".to_string(),
+            count: 1,
+            window: Duration::from_millis(250),
+            fields: None,
+        },
+    ]
+}
+
+/// Parse rules in the format `METHOD Route=count/seconds,...`.
+fn parse_rules(value: &str) -> Vec<Rule> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let rule = parse_rule(entry);
+
+            if rule.is_none() {
+                warn!("Ignoring invalid sublimit {:?}", entry);
+            }
+
+            rule
+        })
+        .collect()
+}
+
+fn parse_rule(entry: &str) -> Option<Rule> {
+    let (route, limit) = entry.split_once('=')?;
+    let (method, route) = route.trim().split_once(' ')?;
+    let (count, seconds) = limit.trim().split_once('/')?;
+
+    let method = match method.to_ascii_uppercase().as_str() {
+        "DELETE" => Method::Delete,
+        "GET" => Method::Get,
+        "PATCH" => Method::Patch,
+        "POST" => Method::Post,
+        "PUT" => Method::Put,
+        _ => return None,
+    };
+
+    let seconds = seconds.trim().parse::<f64>().ok()?;
+
+    if !seconds.is_finite() || seconds < 0.0 {
+        return None;
+    }
+
+    Some(Rule {
+        method,
+        route: route.trim().to_string(),
+        count: count.trim().parse().ok()?,
+        window: Duration::from_secs_f64(seconds),
+        fields: None,
+    })
+}
+
+/// Per-token reservations of sublimited paths.
+#[derive(Default)]
+pub struct Pacer {
+    /// Window of the rule applying to each path and the reserved send times.
+    reservations: Mutex<HashMap<Path, (Duration, VecDeque<Instant>)>>,
+}
+
+/// A request's send time, given back if the request is dropped while waiting
+/// for it, e.g. because the client disconnected.
+struct Reservation<'a> {
+    pacer: &'a Pacer,
+    path: Path,
+    at: Instant,
+}
+
+impl Reservation<'_> {
+    /// Keep the send time, as the request is sent.
+    fn keep(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.pacer.release(self);
+    }
+}
+
+impl Pacer {
+    /// Reserve the next slot for a request and wait until it is reached.
+    ///
+    /// Returns how long the request would have to wait if that's longer than
+    /// `max_wait`, without reserving a slot.
+    pub async fn wait(&self, path: &Path, rule: &Rule, max_wait: Duration) -> Result<(), Duration> {
+        let reservation = self.reserve(path, rule, Instant::now(), max_wait)?;
+
+        if reservation.at > Instant::now() {
+            debug!("Pacing request to {:?} for sublimit", path);
+            sleep_until(reservation.at).await;
+        }
+
+        reservation.keep();
+
+        Ok(())
+    }
+
+    /// Reserve the earliest time at which a request may be sent without
+    /// exceeding the rule's sublimit, unless it's more than `max_wait` away.
+    fn reserve(
+        &self,
+        path: &Path,
+        rule: &Rule,
+        now: Instant,
+        max_wait: Duration,
+    ) -> Result<Reservation<'_>, Duration> {
+        let mut reservations = self.reservations.lock().expect("pacer poisoned");
+
+        if reservations.len() > PRUNE_THRESHOLD {
+            reservations.retain(|_, (window, times)| {
+                times.back().is_some_and(|last| *last + *window > now)
+            });
+        }
+
+        let (window, times) = reservations
+            .entry(path.clone())
+            .or_insert_with(|| (rule.window, VecDeque::new()));
+        *window = rule.window;
+
+        // Only the last `count` reservations within the window affect when
+        // the next request may be sent
+        while times.len() > rule.count
+            || times
+                .front()
+                .is_some_and(|first| *first + rule.window <= now)
+        {
+            times.pop_front();
+        }
+
+        let at = if times.len() == rule.count {
+            (times[0] + rule.window).max(now)
+        } else {
+            now
+        };
+
+        if at - now > max_wait {
+            return Err(at - now);
+        }
+
+        times.push_back(at);
+
+        Ok(Reservation {
+            pacer: self,
+            path: path.clone(),
+            at,
+        })
+    }
+
+    /// Give back the send time of a request that wasn't sent.
+    fn release(&self, reservation: &Reservation<'_>) {
+        let mut reservations = self.reservations.lock().expect("pacer poisoned");
+
+        if let Some((_, times)) = reservations.get_mut(&reservation.path) {
+            if let Some(index) = times.iter().rposition(|at| *at == reservation.at) {
+                times.remove(index);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_rule, Pacer, Rule};
+    use std::time::Duration;
+    use tokio::time::Instant;
+    use twilight_http_ratelimiting::{Method, Path};
+
+    fn rule(count: usize, window: Duration) -> Rule {
+        Rule {
+            method: Method::Patch,
+            route: "ChannelsId".to_string(),
+            count,
+            window,
+            fields: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        assert_eq!(
+            parse_rule("PATCH ChannelsId=2/600"),
+            Some(rule(2, Duration::from_secs(600)))
+        );
+        assert_eq!(
+            parse_rule("patch ChannelsId = 1/0.25").map(|rule| rule.window),
+            Some(Duration::from_millis(250))
+        );
+        assert!(parse_rule("ChannelsId=2/600").is_none());
+        assert!(parse_rule("PATCH ChannelsId=2").is_none());
+        assert!(parse_rule("HEAD ChannelsId=2/600").is_none());
+    }
+
+    #[test]
+    fn test_matches_body() {
+        let mut rule = rule(2, Duration::from_secs(600));
+        rule.fields = Some(&["name", "topic"]);
+
+        assert!(rule.matches_body(br#"{"name":"general"}"#));
+        assert!(!rule.matches_body(br#"{"nsfw":true}"#));
+        assert!(!rule.matches_body(b"not json"));
+    }
+
+    #[test]
+    fn test_reserve() {
+        let pacer = Pacer::default();
+        let rule = rule(2, Duration::from_secs(10));
+        let path = Path::ChannelsId(1);
+        let reserve = |path, now| {
+            let reservation = pacer
+                .reserve(path, &rule, now, Duration::from_secs(60))
+                .unwrap();
+            let at = reservation.at;
+            reservation.keep();
+
+            at
+        };
+        let start = Instant::now();
+
+        assert_eq!(reserve(&path, start), start);
+        assert_eq!(reserve(&path, start), start);
+        // The third request has to wait for the first one to leave the window
+        assert_eq!(reserve(&path, start), start + Duration::from_secs(10));
+        assert_eq!(
+            reserve(&path, start + Duration::from_secs(1)),
+            start + Duration::from_secs(10)
+        );
+        assert_eq!(
+            reserve(&path, start + Duration::from_secs(2)),
+            start + Duration::from_secs(20)
+        );
+
+        // Other paths are not affected
+        assert_eq!(reserve(&Path::ChannelsId(2), start), start);
+
+        // After the window passed requests are sent immediately again
+        let later = start + Duration::from_secs(60);
+        assert_eq!(reserve(&path, later), later);
+    }
+
+    #[test]
+    fn test_release() {
+        let pacer = Pacer::default();
+        let rule = rule(1, Duration::from_secs(10));
+        let path = Path::ChannelsId(1);
+        let start = Instant::now();
+        let max_wait = Duration::from_secs(15);
+
+        pacer.reserve(&path, &rule, start, max_wait).unwrap().keep();
+
+        // Dropped requests don't push later ones further out
+        for _ in 0..3 {
+            let reservation = pacer.reserve(&path, &rule, start, max_wait).unwrap();
+            assert_eq!(reservation.at, start + Duration::from_secs(10));
+        }
+
+        // Requests that would wait too long aren't paced at all
+        pacer.reserve(&path, &rule, start, max_wait).unwrap().keep();
+        assert_eq!(
+            pacer.reserve(&path, &rule, start, max_wait).err(),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(
+            pacer.reservations.lock().unwrap()[&path].1,
+            [start + Duration::from_secs(10)]
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! Tags set by clients to attribute their Discord API usage, e.g. to the team
+//! or feature sending a request.
+//!
+//! Tags are used as labels, so only a limited amount of distinct tags is
+//! tracked and further ones are counted as [`OTHER_TAG`].
+
+use crate::parse_env;
+use http::HeaderMap;
+use serde::Serialize;
+use std::{collections::BTreeMap, sync::Mutex};
+
+/// Header with the tag of a request, not forwarded to Discord.
+pub const TAG_HEADER: &str = "x-proxy-tag";
+
+/// Tag of requests whose tag is invalid or exceeds the limit of tags.
+pub const OTHER_TAG: &str = "other";
+
+/// Amount of distinct tags tracked if `MAX_TAGS` is not set.
+const DEFAULT_MAX_TAGS: usize = 50;
+
+/// Maximum length of a tag.
+const MAX_TAG_LENGTH: usize = 64;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct TagCounts {
+    requests: u64,
+    ratelimited: u64,
+}
+
+/// Requests per tag, configured via `MAX_TAGS`.
+pub struct Tags {
+    max: usize,
+    counts: Mutex<BTreeMap<String, TagCounts>>,
+}
+
+impl Tags {
+    pub fn from_env() -> Self {
+        Self::new(parse_env("MAX_TAGS").unwrap_or(DEFAULT_MAX_TAGS))
+    }
+
+    fn new(max: usize) -> Self {
+        Self {
+            max,
+            counts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Remove the tag header of a request and return its tag.
+    ///
+    /// Tags are lowercased and may only contain ASCII letters, digits, `-`, `_`
+    /// and `.`.
+    pub fn take(&self, headers: &mut HeaderMap) -> Option<String> {
+        let value = headers.remove(TAG_HEADER)?;
+        let tag = value
+            .to_str()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        let valid = !tag.is_empty()
+            && tag.len() <= MAX_TAG_LENGTH
+            && tag
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || b"-_.".contains(&byte));
+
+        if !valid {
+            return Some(OTHER_TAG.to_string());
+        }
+
+        let counts = self.counts.lock().expect("tags poisoned");
+        let known = counts.keys().filter(|known| *known != OTHER_TAG).count();
+
+        if counts.contains_key(&tag) || known < self.max {
+            Some(tag)
+        } else {
+            Some(OTHER_TAG.to_string())
+        }
+    }
+
+    /// Count a request of a tag that Discord responded to.
+    pub fn record(&self, tag: &str, status: u16) {
+        let mut counts = self.counts.lock().expect("tags poisoned");
+        let entry = counts.entry(tag.to_string()).or_default();
+
+        entry.requests += 1;
+
+        if status == 429 {
+            entry.ratelimited += 1;
+        }
+
+        drop(counts);
+
+        #[cfg(feature = "expose-metrics")]
+        metrics::increment_counter!(
+            format!("{}_tagged_requests_total", crate::METRIC_KEY.as_str()),
+            "tag" => tag.to_string(),
+            "status" => status.to_string()
+        );
+    }
+
+    /// Counts of all tags since the proxy started.
+    pub fn counts(&self) -> BTreeMap<String, TagCounts> {
+        self.counts.lock().expect("tags poisoned").clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TagCounts, Tags, OTHER_TAG, TAG_HEADER};
+    use http::HeaderMap;
+
+    fn take(tags: &Tags, value: &str) -> Option<String> {
+        let mut headers = HeaderMap::new();
+        headers.insert(TAG_HEADER, value.parse().unwrap());
+
+        let tag = tags.take(&mut headers);
+        assert!(!headers.contains_key(TAG_HEADER));
+
+        tag
+    }
+
+    #[test]
+    fn test_take() {
+        let tags = Tags::new(2);
+
+        assert_eq!(tags.take(&mut HeaderMap::new()), None);
+        assert_eq!(take(&tags, " Moderation "), Some("moderation".to_string()));
+        assert_eq!(take(&tags, "has spaces"), Some(OTHER_TAG.to_string()));
+        assert_eq!(take(&tags, &"a".repeat(65)), Some(OTHER_TAG.to_string()));
+
+        tags.record("moderation", 200);
+        tags.record("moderation", 429);
+        assert_eq!(take(&tags, "music"), Some("music".to_string()));
+        tags.record("music", 200);
+
+        assert_eq!(take(&tags, "leveling"), Some(OTHER_TAG.to_string()));
+        assert_eq!(take(&tags, "music"), Some("music".to_string()));
+
+        assert_eq!(
+            tags.counts()["moderation"],
+            TagCounts {
+                requests: 2,
+                ratelimited: 1
+            }
+        );
+    }
+}
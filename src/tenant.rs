@@ -0,0 +1,490 @@
+use crate::{
+    backoff::Backoff, global_limit::GlobalWindow, session::SessionStarts, sublimit::Pacer,
+    traffic::Dispatcher,
+};
+use ring::digest::{digest, SHA256};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::time::{Duration, Instant};
+use tracing::warn;
+use twilight_http_ratelimiting::{InMemoryRatelimiter, Path, Ratelimiter};
+
+/// Amount of one-minute slots kept by a [`WindowCounter`].
+const WINDOW_SLOTS: usize = 60;
+
+/// Maximum amount of paths tracked per token. Paths contain IDs, so the least
+/// recently used ones are forgotten beyond it.
+const MAX_PATHS: usize = 1024;
+
+/// Hash a token into the identifier used by the admin API.
+///
+/// This is the first 16 hex characters of the SHA-256 digest of the full
+/// `Authorization` value forwarded to Discord, e.g. `Bot abc.def.ghi`.
+pub fn hash_token(token: &str) -> String {
+    let digest = digest(&SHA256, token.as_bytes());
+
+    digest.as_ref()[..8]
+        .iter()
+        .fold(String::with_capacity(16), |mut acc, byte| {
+            _ = write!(acc, "{:02x}", byte);
+
+            acc
+        })
+}
+
+/// Counts events over the last hour in one-minute resolution.
+struct WindowCounter {
+    /// Pairs of the minute a slot belongs to and its count.
+    slots: [(u64, u64); WINDOW_SLOTS],
+    total: u64,
+}
+
+impl WindowCounter {
+    const fn new() -> Self {
+        Self {
+            slots: [(0, 0); WINDOW_SLOTS],
+            total: 0,
+        }
+    }
+
+    fn record(&mut self, minute: u64) {
+        let slot = &mut self.slots[minute as usize % WINDOW_SLOTS];
+
+        if slot.0 != minute {
+            *slot = (minute, 0);
+        }
+
+        slot.1 += 1;
+        self.total += 1;
+    }
+
+    /// Sum of events in the last `minutes` minutes, including the current one.
+    fn sum(&self, minute: u64, minutes: u64) -> u64 {
+        self.slots
+            .iter()
+            .filter(|(slot_minute, _)| *slot_minute <= minute && minute - slot_minute < minutes)
+            .map(|(_, count)| count)
+            .sum()
+    }
+}
+
+/// Paths that requests have been made to, up to [`MAX_PATHS`].
+#[derive(Default)]
+struct RecentPaths {
+    /// Requests recorded so far, ordering the uses of paths.
+    uses: u64,
+    /// Paths and the number of their last use.
+    paths: HashMap<Path, u64>,
+}
+
+impl RecentPaths {
+    fn touch(&mut self, path: &Path) {
+        self.uses += 1;
+
+        if let Some(used) = self.paths.get_mut(path) {
+            *used = self.uses;
+
+            return;
+        }
+
+        if self.paths.len() >= MAX_PATHS {
+            let oldest = self
+                .paths
+                .iter()
+                .min_by_key(|(_, used)| **used)
+                .map(|(path, _)| path.clone());
+
+            if let Some(oldest) = oldest {
+                self.paths.remove(&oldest);
+            }
+        }
+
+        self.paths.insert(path.clone(), self.uses);
+    }
+}
+
+/// Snapshot of a [`WindowCounter`].
+#[derive(serde::Serialize)]
+pub struct Counts {
+    pub last_minute: u64,
+    pub last_hour: u64,
+    pub total: u64,
+}
+
+/// Request statistics of a single token.
+pub struct Usage {
+    hash: String,
+    created_at: Instant,
+    requests: Mutex<WindowCounter>,
+    ratelimited: Mutex<WindowCounter>,
+    shared_ratelimited: Mutex<WindowCounter>,
+    queued: AtomicUsize,
+    /// Times at which the requests waiting for a ticket of each path were
+    /// queued, with a sequence number telling apart simultaneous ones.
+    waiting: Mutex<HashMap<Path, BTreeSet<(Instant, u64)>>>,
+    next_waiting: AtomicU64,
+    paths: Mutex<RecentPaths>,
+    /// Pair of the current UTC day and the requests made on it.
+    daily: Mutex<(u64, u64)>,
+}
+
+impl Usage {
+    fn new(hash: String) -> Self {
+        Self {
+            hash,
+            created_at: Instant::now(),
+            requests: Mutex::new(WindowCounter::new()),
+            ratelimited: Mutex::new(WindowCounter::new()),
+            shared_ratelimited: Mutex::new(WindowCounter::new()),
+            queued: AtomicUsize::new(0),
+            waiting: Mutex::new(HashMap::new()),
+            next_waiting: AtomicU64::new(0),
+            paths: Mutex::new(RecentPaths::default()),
+            daily: Mutex::new((0, 0)),
+        }
+    }
+
+    fn minute(&self) -> u64 {
+        self.created_at.elapsed().as_secs() / 60
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    /// Record a response received from Discord for the given path.
+    ///
+    /// 429s with a shared scope are counted separately, as they are caused by
+    /// a resource-wide limit rather than the token's own usage.
+    pub fn record(&self, path: &Path, status: u16, shared_scope: bool) {
+        let minute = self.minute();
+
+        self.requests.lock().expect("usage poisoned").record(minute);
+
+        if status == 429 {
+            let counter = if shared_scope {
+                &self.shared_ratelimited
+            } else {
+                &self.ratelimited
+            };
+
+            counter.lock().expect("usage poisoned").record(minute);
+        }
+
+        self.paths.lock().expect("usage poisoned").touch(path);
+    }
+
+    pub fn requests(&self) -> Counts {
+        Self::counts(&self.requests, self.minute())
+    }
+
+    pub fn ratelimited(&self) -> Counts {
+        Self::counts(&self.ratelimited, self.minute())
+    }
+
+    pub fn shared_ratelimited(&self) -> Counts {
+        Self::counts(&self.shared_ratelimited, self.minute())
+    }
+
+    fn counts(counter: &Mutex<WindowCounter>, minute: u64) -> Counts {
+        let counter = counter.lock().expect("usage poisoned");
+
+        Counts {
+            last_minute: counter.sum(minute, 1),
+            last_hour: counter.sum(minute, WINDOW_SLOTS as u64),
+            total: counter.total,
+        }
+    }
+
+    /// Count a request on the given UTC day, unless `limit` requests were
+    /// made on it already.
+    ///
+    /// Returns whether the request was counted.
+    pub fn count_daily(&self, day: u64, limit: u64) -> bool {
+        let mut daily = self.daily.lock().expect("usage poisoned");
+
+        if daily.0 != day {
+            *daily = (day, 0);
+        }
+
+        if daily.1 >= limit {
+            return false;
+        }
+
+        daily.1 += 1;
+
+        true
+    }
+
+    /// Take back a request counted on the given UTC day that wasn't made.
+    pub fn uncount_daily(&self, day: u64) {
+        let mut daily = self.daily.lock().expect("usage poisoned");
+
+        if daily.0 == day {
+            daily.1 = daily.1.saturating_sub(1);
+        }
+    }
+
+    /// Requests made on the given UTC day.
+    pub fn daily(&self, day: u64) -> u64 {
+        let daily = self.daily.lock().expect("usage poisoned");
+
+        if daily.0 == day {
+            daily.1
+        } else {
+            0
+        }
+    }
+
+    /// Amount of requests currently waiting for a ratelimit ticket.
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Mark a request to a path as waiting for a ticket until the guard is
+    /// dropped.
+    pub fn enqueue(&self, path: &Path) -> QueueGuard<'_> {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+
+        let entry = (
+            Instant::now(),
+            self.next_waiting.fetch_add(1, Ordering::Relaxed),
+        );
+
+        self.waiting
+            .lock()
+            .expect("usage poisoned")
+            .entry(path.clone())
+            .or_default()
+            .insert(entry);
+
+        QueueGuard {
+            usage: self,
+            path: path.clone(),
+            entry,
+        }
+    }
+
+    /// Amount of requests waiting for a ticket of a path.
+    pub fn queued(&self, path: &Path) -> usize {
+        self.waiting
+            .lock()
+            .expect("usage poisoned")
+            .get(path)
+            .map_or(0, BTreeSet::len)
+    }
+
+    /// How long the oldest request waiting for a ticket of each path has
+    /// been queued.
+    pub fn queue_ages(&self) -> Vec<(Path, Duration)> {
+        self.waiting
+            .lock()
+            .expect("usage poisoned")
+            .iter()
+            .filter_map(|(path, entries)| {
+                let (queued_at, _) = entries.first()?;
+
+                Some((path.clone(), queued_at.elapsed()))
+            })
+            .collect()
+    }
+
+    /// Paths that requests have been made to recently.
+    pub fn paths(&self) -> Vec<Path> {
+        self.paths
+            .lock()
+            .expect("usage poisoned")
+            .paths
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// Stop tracking a path, e.g. because its bucket expired.
+    pub fn forget_path(&self, path: &Path) {
+        self.paths
+            .lock()
+            .expect("usage poisoned")
+            .paths
+            .remove(path);
+    }
+}
+
+pub struct QueueGuard<'a> {
+    usage: &'a Usage,
+    path: Path,
+    entry: (Instant, u64),
+}
+
+impl Drop for QueueGuard<'_> {
+    fn drop(&mut self) {
+        self.usage.queued.fetch_sub(1, Ordering::Relaxed);
+
+        let mut waiting = self.usage.waiting.lock().expect("usage poisoned");
+
+        if let Some(entries) = waiting.get_mut(&self.path) {
+            entries.remove(&self.entry);
+
+            if entries.is_empty() {
+                waiting.remove(&self.path);
+            }
+        }
+    }
+}
+
+/// A token's ratelimiter together with its usage statistics.
+#[derive(Clone)]
+pub struct Tenant {
+    pub ratelimiter: Arc<dyn Ratelimiter>,
+    pub backoff: Arc<Backoff>,
+    pub dispatcher: Arc<Dispatcher>,
+    pub global_window: Arc<GlobalWindow>,
+    pub pacer: Arc<Pacer>,
+    pub sessions: Arc<SessionStarts>,
+    pub usage: Arc<Usage>,
+}
+
+impl Tenant {
+    pub fn new(token: &str) -> Self {
+        Self::with_ratelimiter(token, Arc::new(InMemoryRatelimiter::new()))
+    }
+
+    pub fn with_ratelimiter(token: &str, ratelimiter: Arc<dyn Ratelimiter>) -> Self {
+        Self {
+            ratelimiter,
+            backoff: Arc::new(Backoff::default()),
+            dispatcher: Arc::new(Dispatcher::default()),
+            global_window: Arc::new(GlobalWindow::default()),
+            pacer: Arc::new(Pacer::default()),
+            sessions: Arc::new(SessionStarts::default()),
+            usage: Arc::new(Usage::new(hash_token(token))),
+        }
+    }
+}
+
+/// Parse per-tenant values in the format `hash=value,hash=value`.
+///
+/// `setting` names the setting in warnings about invalid entries.
+pub fn parse_tenant_values(value: &str, setting: &str) -> HashMap<String, u64> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let parsed = entry.split_once('=').and_then(|(hash, value)| {
+                Some((hash.trim().to_string(), value.trim().parse().ok()?))
+            });
+
+            if parsed.is_none() {
+                warn!("Ignoring invalid {} entry {:?}", setting, entry);
+            }
+
+            parsed
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_token, parse_tenant_values, RecentPaths, Usage, WindowCounter, MAX_PATHS};
+    use tokio::time::{sleep, Duration};
+    use twilight_http_ratelimiting::Path;
+
+    #[test]
+    fn test_parse_tenant_values() {
+        let values = parse_tenant_values("abc=10, def = 20,invalid,ghi=x,", "test");
+
+        assert_eq!(values.len(), 2);
+        assert_eq!(values["abc"], 10);
+        assert_eq!(values["def"], 20);
+    }
+
+    #[test]
+    fn test_hash_token() {
+        let hash = hash_token("Bot abc");
+
+        assert_eq!(hash.len(), 16);
+        assert_eq!(hash, hash_token("Bot abc"));
+        assert_ne!(hash, hash_token("Bot abd"));
+    }
+
+    #[test]
+    fn test_window_counter() {
+        let mut counter = WindowCounter::new();
+
+        counter.record(0);
+        counter.record(0);
+        counter.record(5);
+
+        assert_eq!(counter.sum(5, 1), 1);
+        assert_eq!(counter.sum(5, 60), 3);
+        // Slot 0 is reused for minute 60 and the old value is discarded
+        counter.record(60);
+        assert_eq!(counter.sum(60, 60), 2);
+        assert_eq!(counter.total, 4);
+    }
+
+    #[test]
+    fn test_recent_paths() {
+        let mut recent = RecentPaths::default();
+
+        for id in 0..MAX_PATHS as u64 {
+            recent.touch(&Path::ChannelsId(id));
+        }
+
+        recent.touch(&Path::ChannelsId(0));
+        recent.touch(&Path::ChannelsId(MAX_PATHS as u64));
+
+        // The least recently used path is forgotten
+        assert_eq!(recent.paths.len(), MAX_PATHS);
+        assert!(recent.paths.contains_key(&Path::ChannelsId(0)));
+        assert!(!recent.paths.contains_key(&Path::ChannelsId(1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_usage() {
+        let usage = Usage::new(hash_token("Bot abc"));
+
+        usage.record(&Path::Gateway, 200, false);
+        usage.record(&Path::Gateway, 429, false);
+        usage.record(&Path::Gateway, 429, true);
+
+        sleep(Duration::from_secs(120)).await;
+
+        usage.record(&Path::GatewayBot, 200, false);
+
+        assert_eq!(usage.requests().last_minute, 1);
+        assert_eq!(usage.requests().last_hour, 4);
+        assert_eq!(usage.ratelimited().last_minute, 0);
+        assert_eq!(usage.ratelimited().last_hour, 1);
+        assert_eq!(usage.shared_ratelimited().last_hour, 1);
+        assert_eq!(usage.paths().len(), 2);
+
+        {
+            let _first = usage.enqueue(&Path::Gateway);
+            sleep(Duration::from_secs(5)).await;
+            let _second = usage.enqueue(&Path::Gateway);
+            let _other = usage.enqueue(&Path::GatewayBot);
+            assert_eq!(usage.queue_depth(), 3);
+
+            let mut ages = usage.queue_ages();
+            ages.sort_by_key(|(_, age)| *age);
+            assert_eq!(
+                ages,
+                [
+                    (Path::GatewayBot, Duration::ZERO),
+                    (Path::Gateway, Duration::from_secs(5))
+                ]
+            );
+        }
+
+        assert_eq!(usage.queue_depth(), 0);
+        assert!(usage.queue_ages().is_empty());
+    }
+}
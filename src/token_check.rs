@@ -0,0 +1,179 @@
+//! Opt-in check of the default token on startup, so a revoked or mistyped
+//! token fails the deployment instead of every request proxied with it.
+
+use crate::{upstream::Upstream, State};
+use http::{header::AUTHORIZATION, Request, StatusCode};
+use hyper::{client::connect::Connect, Body, Client};
+use serde::Deserialize;
+use std::{
+    env,
+    error::Error,
+    fmt::{Display, Formatter, Result as FmtResult},
+};
+use tokio::time::{timeout, Duration};
+use tracing::{error, info, warn};
+
+/// How long to wait for Discord to respond.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What to do if Discord rejects the default token.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OnRejected {
+    Exit,
+    Warn,
+}
+
+impl OnRejected {
+    /// Enabled by setting `VERIFY_TOKEN`, to `warn` to keep running if the
+    /// token is rejected.
+    pub fn from_env() -> Option<Self> {
+        env::var("VERIFY_TOKEN").ok().map(|value| {
+            if value.eq_ignore_ascii_case("warn") {
+                Self::Warn
+            } else {
+                Self::Exit
+            }
+        })
+    }
+}
+
+/// The user a token belongs to.
+#[derive(Debug, Deserialize)]
+pub struct User {
+    id: String,
+    username: String,
+}
+
+impl Display for User {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str(&self.username)?;
+        f.write_str(" (")?;
+        f.write_str(&self.id)?;
+
+        f.write_str(")")
+    }
+}
+
+pub enum Verification {
+    Valid(User),
+    /// Discord responded with a `401`.
+    Rejected,
+}
+
+/// Request the user a prefixed token belongs to.
+///
+/// Fails if Discord can't be reached or responds with anything but a `200`
+/// or `401`.
+pub async fn verify<C>(
+    client: &Client<C, Body>,
+    upstream: &Upstream,
+    token: &str,
+) -> Result<Verification, Box<dyn Error>>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    let request = Request::get(upstream.uri("/api/v10", "/users/@me", None)?)
+        .header(AUTHORIZATION, token)
+        .body(Body::empty())?;
+    let response = timeout(TIMEOUT, client.request(request))
+        .await
+        .map_err(|_| "verifying the default token timed out")??;
+
+    match response.status() {
+        StatusCode::OK => {
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+
+            Ok(Verification::Valid(serde_json::from_slice(&body)?))
+        }
+        StatusCode::UNAUTHORIZED => Ok(Verification::Rejected),
+        status => Err(format!("verifying the default token failed with {}", status).into()),
+    }
+}
+
+/// Verify the default token before the proxy starts listening.
+///
+/// Returns the user it belongs to if it could be verified, and fails if it
+/// was rejected and the proxy should exit. Other failures, e.g. if Discord is
+/// unreachable, are only logged.
+pub async fn on_startup(
+    state: &State,
+    on_rejected: OnRejected,
+) -> Result<Option<User>, Box<dyn Error>> {
+    let token = match state.ratelimiter_map.get_or_insert(None) {
+        Some((_, token)) => token,
+        None => {
+            warn!("VERIFY_TOKEN is set, but there is no default token to verify");
+
+            return Ok(None);
+        }
+    };
+
+    if state.dry_run {
+        info!("Not verifying the default token in dry run mode");
+
+        return Ok(None);
+    }
+
+    match verify(state.clients.get(&state.upstream), &state.upstream, &token).await {
+        Ok(Verification::Valid(user)) => Ok(Some(user)),
+        Ok(Verification::Rejected) => match on_rejected {
+            OnRejected::Exit => Err("default token was rejected by Discord".into()),
+            OnRejected::Warn => {
+                error!("Default token was rejected by Discord, all requests using it will fail");
+
+                Ok(None)
+            }
+        },
+        Err(e) => {
+            warn!("Failed to verify the default token: {}", e);
+
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify, Verification};
+    use crate::upstream::Upstream;
+    use http::{header::AUTHORIZATION, Request, Response, StatusCode};
+    use hyper::{
+        server::Server,
+        service::{make_service_fn, service_fn},
+        Body, Client,
+    };
+    use std::convert::Infallible;
+
+    #[tokio::test]
+    async fn test_verify() {
+        let service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                let response = if request.headers()[AUTHORIZATION] == "Bot valid" {
+                    Response::new(Body::from(r#"{"id":"1","username":"proxy"}"#))
+                } else {
+                    let mut response = Response::new(Body::empty());
+                    *response.status_mut() = StatusCode::UNAUTHORIZED;
+
+                    response
+                };
+
+                Ok::<_, Infallible>(response)
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(service);
+        let upstream = Upstream::new(&format!("http://{}", server.local_addr())).unwrap();
+        tokio::spawn(server);
+
+        let client = Client::new();
+
+        match verify(&client, &upstream, "Bot valid").await.unwrap() {
+            Verification::Valid(user) => assert_eq!(user.to_string(), "proxy (1)"),
+            Verification::Rejected => panic!("token is valid"),
+        }
+
+        assert!(matches!(
+            verify(&client, &upstream, "Bot revoked").await.unwrap(),
+            Verification::Rejected
+        ));
+    }
+}
@@ -0,0 +1,96 @@
+//! Default token read from a file given with `DISCORD_TOKEN_FILE`, such as a
+//! Docker or Kubernetes secret.
+//!
+//! The file is watched, so rotating the secret rotates the default token
+//! without a restart.
+
+use crate::State;
+use std::{env, error::Error, fs, io};
+use tokio::time::{self, Duration};
+use tracing::{info, warn};
+
+/// Interval in which the file is checked for a new token.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Read a token, ignoring surrounding whitespace.
+///
+/// Returns `None` if the file is empty.
+pub fn read(path: &str) -> io::Result<Option<String>> {
+    let token = fs::read_to_string(path)?;
+    let token = token.trim();
+
+    Ok(Some(token.to_string()).filter(|token| !token.is_empty()))
+}
+
+/// The default token, read from `DISCORD_TOKEN_FILE` if it is set and from
+/// `DISCORD_TOKEN` otherwise.
+pub fn default_token() -> Result<Option<String>, Box<dyn Error>> {
+    match env::var("DISCORD_TOKEN_FILE") {
+        Ok(path) => {
+            if env::var_os("DISCORD_TOKEN").is_some() {
+                warn!("Both DISCORD_TOKEN and DISCORD_TOKEN_FILE are set, using the file");
+            }
+
+            read(&path).map_err(|e| format!("failed to read token file {}: {}", path, e).into())
+        }
+        Err(_) => Ok(env::var("DISCORD_TOKEN").ok()),
+    }
+}
+
+/// Replace the default token whenever the content of `DISCORD_TOKEN_FILE`
+/// changes.
+///
+/// An empty or unreadable file keeps the current token, as secrets may be
+/// briefly missing while they are rotated.
+pub async fn watch(path: String, state: &State) {
+    let mut current = read(&path).ok().flatten();
+    let mut failing = false;
+    let mut interval = time::interval(POLL_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        match read(&path) {
+            Ok(token) => {
+                failing = false;
+
+                if token.is_some() && token != current {
+                    info!("Default token in {} changed, rotating it", path);
+                    state.ratelimiter_map.set_default_token(token.clone());
+                    current = token;
+                }
+            }
+            Err(e) => {
+                if !failing {
+                    warn!(
+                        "Failed to read token file {}, keeping the token: {}",
+                        path, e
+                    );
+                }
+
+                failing = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read;
+    use std::{env, fs};
+
+    #[test]
+    fn test_read() {
+        let path = env::temp_dir().join(format!("token-{}", fastrand::u64(..)));
+        let path_str = path.to_str().unwrap();
+
+        fs::write(&path, "Bot abc.def.ghi\n").unwrap();
+        assert_eq!(read(path_str).unwrap().as_deref(), Some("Bot abc.def.ghi"));
+
+        fs::write(&path, " \n").unwrap();
+        assert_eq!(read(path_str).unwrap(), None);
+
+        fs::remove_file(&path).unwrap();
+        assert!(read(path_str).is_err());
+    }
+}
@@ -0,0 +1,485 @@
+//! Traffic classes, letting bulk jobs use the capacity interactive requests
+//! leave over.
+//!
+//! Requests waiting for the same bucket are dispatched one at a time, picking
+//! the next one from the classes by weight instead of in arrival order.
+//! Requests with a higher priority go before all requests with a lower one.
+
+use crate::sublimit::route_name;
+use http::HeaderMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+    sync::Mutex,
+};
+use tokio::sync::oneshot::{self, Receiver, Sender};
+use tracing::warn;
+use twilight_http_ratelimiting::Path;
+
+/// Header selecting the class of a request, not forwarded to Discord.
+pub const CLASS_HEADER: &str = "x-proxy-traffic-class";
+
+/// Header selecting the priority of a request, not forwarded to Discord.
+pub const PRIORITY_HEADER: &str = "x-proxy-priority";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Class {
+    Interactive,
+    Bulk,
+}
+
+impl Class {
+    const fn index(self) -> usize {
+        match self {
+            Self::Interactive => 0,
+            Self::Bulk => 1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl Priority {
+    /// Remove the priority header of a request and return its priority.
+    pub fn take(headers: &mut HeaderMap) -> Self {
+        let header = headers.remove(PRIORITY_HEADER);
+
+        match header.as_ref().map(|value| value.as_bytes()) {
+            Some(value) if value.eq_ignore_ascii_case(b"high") => Self::High,
+            Some(value) if value.eq_ignore_ascii_case(b"low") => Self::Low,
+            _ => Self::Normal,
+        }
+    }
+
+    const fn index(self) -> usize {
+        match self {
+            Self::High => 0,
+            Self::Normal => 1,
+            Self::Low => 2,
+        }
+    }
+}
+
+/// Configuration of the traffic classes.
+pub struct TrafficClasses {
+    /// Route names whose requests are bulk unless the header says otherwise.
+    bulk_routes: Vec<String>,
+    /// Dispatch weights of the interactive and bulk class.
+    weights: [u32; 2],
+}
+
+impl TrafficClasses {
+    /// Load the configuration from `BULK_ROUTES` and `DISPATCH_WEIGHTS`.
+    pub fn from_env() -> Self {
+        let bulk_routes = env::var("BULK_ROUTES")
+            .map(|routes| {
+                routes
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|route| !route.is_empty())
+                    .map(ToString::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let weights = match env::var("DISPATCH_WEIGHTS") {
+            Ok(value) => parse_weights(&value).unwrap_or_else(|| {
+                warn!("DISPATCH_WEIGHTS {:?} is invalid, using 1:0", value);
+
+                [1, 0]
+            }),
+            Err(_) => [1, 0],
+        };
+
+        Self {
+            bulk_routes,
+            weights,
+        }
+    }
+
+    /// Remove the class header of a request and return its class.
+    pub fn classify(&self, headers: &mut HeaderMap, path: &Path) -> Class {
+        let header = headers.remove(CLASS_HEADER);
+
+        match header.as_ref().map(|value| value.as_bytes()) {
+            Some(b"bulk") => Class::Bulk,
+            Some(b"interactive") => Class::Interactive,
+            _ if self.bulk_routes.contains(&route_name(path)) => Class::Bulk,
+            _ => Class::Interactive,
+        }
+    }
+}
+
+/// Parse weights in the format `interactive:bulk`.
+fn parse_weights(value: &str) -> Option<[u32; 2]> {
+    let (interactive, bulk) = value.split_once(':')?;
+    let weights = [interactive.trim().parse().ok()?, bulk.trim().parse().ok()?];
+
+    (weights[0] > 0).then_some(weights)
+}
+
+/// Requests of a bucket waiting for their turn.
+#[derive(Default)]
+struct Lane {
+    /// Whether a request of the bucket is currently waiting for its ticket.
+    busy: bool,
+    /// Requests by priority and class.
+    waiting: [[VecDeque<Sender<()>>; 2]; 3],
+    /// Credits of the classes for smooth weighted round-robin.
+    credits: [i64; 2],
+}
+
+impl Lane {
+    /// Pick the priority and class of the next request.
+    fn pick(&mut self, weights: [u32; 2]) -> Option<(usize, usize)> {
+        let priority = self
+            .waiting
+            .iter()
+            .position(|classes| classes.iter().any(|waiting| !waiting.is_empty()))?;
+
+        let candidates = (0..2)
+            .filter(|index| !self.waiting[priority][*index].is_empty())
+            .collect::<Vec<_>>();
+
+        let class = match candidates.as_slice() {
+            [only] => *only,
+            _ => {
+                for (credit, weight) in self.credits.iter_mut().zip(weights) {
+                    *credit += i64::from(weight);
+                }
+
+                // Ties go to interactive requests
+                let picked = if self.credits[1] > self.credits[0] {
+                    1
+                } else {
+                    0
+                };
+                self.credits[picked] -= i64::from(weights[0] + weights[1]);
+
+                picked
+            }
+        };
+
+        Some((priority, class))
+    }
+}
+
+/// Per-token dispatch order of requests waiting for the same bucket.
+#[derive(Default)]
+pub struct Dispatcher {
+    lanes: Mutex<HashMap<Path, Lane>>,
+}
+
+impl Dispatcher {
+    /// Wait for the turn of a request, which lasts until the returned guard
+    /// is dropped.
+    pub async fn acquire<'a>(
+        &'a self,
+        path: &Path,
+        class: Class,
+        priority: Priority,
+        classes: &'a TrafficClasses,
+    ) -> Turn<'a> {
+        let receiver = {
+            let mut lanes = self.lanes.lock().expect("dispatcher poisoned");
+            let lane = lanes.entry(path.clone()).or_default();
+
+            if !lane.busy {
+                lane.busy = true;
+
+                return self.turn(path, classes);
+            }
+
+            let (sender, receiver) = oneshot::channel();
+            lane.waiting[priority.index()][class.index()].push_back(sender);
+
+            receiver
+        };
+
+        let mut waiting = Waiting {
+            dispatcher: self,
+            path,
+            classes,
+            receiver: Some(receiver),
+        };
+
+        // The sender is only dropped together with the dispatcher
+        _ = waiting.receiver.as_mut().expect("not received yet").await;
+        waiting.receiver = None;
+
+        self.turn(path, classes)
+    }
+
+    fn turn<'a>(&'a self, path: &Path, classes: &'a TrafficClasses) -> Turn<'a> {
+        Turn {
+            dispatcher: self,
+            path: path.clone(),
+            classes,
+        }
+    }
+
+    /// Hand the turn to the next waiting request of a bucket.
+    fn release(&self, path: &Path, classes: &TrafficClasses) {
+        let mut lanes = self.lanes.lock().expect("dispatcher poisoned");
+
+        let lane = match lanes.get_mut(path) {
+            Some(lane) => lane,
+            None => return,
+        };
+
+        while let Some((priority, class)) = lane.pick(classes.weights) {
+            let sender = lane.waiting[priority][class]
+                .pop_front()
+                .expect("class is not empty");
+
+            // Requests that gave up while waiting are skipped
+            if sender.send(()).is_ok() {
+                return;
+            }
+        }
+
+        lanes.remove(path);
+    }
+}
+
+/// A request's turn to wait for its ratelimit ticket.
+pub struct Turn<'a> {
+    dispatcher: &'a Dispatcher,
+    path: Path,
+    classes: &'a TrafficClasses,
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        self.dispatcher.release(&self.path, self.classes);
+    }
+}
+
+/// Hands the turn on if a request is dropped right after receiving it.
+struct Waiting<'a> {
+    dispatcher: &'a Dispatcher,
+    path: &'a Path,
+    classes: &'a TrafficClasses,
+    receiver: Option<Receiver<()>>,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if let Some(mut receiver) = self.receiver.take() {
+            receiver.close();
+
+            if receiver.try_recv().is_ok() {
+                self.dispatcher.release(self.path, self.classes);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_weights, Class, Dispatcher, Priority, TrafficClasses};
+    use http::{HeaderMap, HeaderValue};
+    use std::sync::{Arc, Mutex};
+    use tokio::{
+        task,
+        time::{timeout, Duration},
+    };
+    use twilight_http_ratelimiting::Path;
+
+    #[test]
+    fn test_classify() {
+        let classes = TrafficClasses {
+            bulk_routes: vec!["UsersIdChannels".to_string()],
+            weights: [1, 0],
+        };
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            classes.classify(&mut headers, &Path::ChannelsIdMessages(1)),
+            Class::Interactive
+        );
+        assert_eq!(
+            classes.classify(&mut headers, &Path::UsersIdChannels),
+            Class::Bulk
+        );
+
+        headers.insert("x-proxy-traffic-class", HeaderValue::from_static("bulk"));
+        assert_eq!(
+            classes.classify(&mut headers, &Path::ChannelsIdMessages(1)),
+            Class::Bulk
+        );
+        assert!(headers.is_empty());
+
+        headers.insert("x-proxy-priority", HeaderValue::from_static("high"));
+        assert_eq!(Priority::take(&mut headers), Priority::High);
+        assert_eq!(Priority::take(&mut headers), Priority::Normal);
+        headers.insert("x-proxy-priority", HeaderValue::from_static("High"));
+        assert_eq!(Priority::take(&mut headers), Priority::High);
+        headers.insert("x-proxy-priority", HeaderValue::from_static("LOW"));
+        assert_eq!(Priority::take(&mut headers), Priority::Low);
+
+        assert_eq!(parse_weights("10:1"), Some([10, 1]));
+        assert_eq!(parse_weights("0:1"), None);
+        assert_eq!(parse_weights("10"), None);
+    }
+
+    /// Queue requests of normal priority behind a busy bucket and return the
+    /// order in which they were dispatched.
+    async fn dispatch_order(weights: [u32; 2], requests: &[Class]) -> Vec<Class> {
+        let requests = requests
+            .iter()
+            .map(|class| (*class, Priority::Normal))
+            .collect::<Vec<_>>();
+
+        dispatch(weights, &requests)
+            .await
+            .into_iter()
+            .map(|(class, _)| class)
+            .collect()
+    }
+
+    /// Queue requests behind a busy bucket and return the order in which
+    /// they were dispatched.
+    async fn dispatch(weights: [u32; 2], requests: &[(Class, Priority)]) -> Vec<(Class, Priority)> {
+        let classes = Arc::new(TrafficClasses {
+            bulk_routes: Vec::new(),
+            weights,
+        });
+        let dispatcher = Arc::new(Dispatcher::default());
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let path = Path::ChannelsIdMessages(1);
+
+        let first = dispatcher
+            .acquire(&path, Class::Bulk, Priority::Normal, &classes)
+            .await;
+
+        let handles = requests
+            .iter()
+            .map(|request| {
+                let (classes, dispatcher, order, path) = (
+                    Arc::clone(&classes),
+                    Arc::clone(&dispatcher),
+                    Arc::clone(&order),
+                    path.clone(),
+                );
+                let (class, priority) = *request;
+
+                tokio::spawn(async move {
+                    let _turn = dispatcher.acquire(&path, class, priority, &classes).await;
+                    order.lock().unwrap().push((class, priority));
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // Let all requests queue up before releasing the bucket
+        for _ in 0..10 {
+            task::yield_now().await;
+        }
+
+        drop(first);
+
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        let order = order.lock().unwrap();
+
+        order.clone()
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_order() {
+        use Class::{Bulk, Interactive};
+
+        // Interactive requests always go first by default
+        assert_eq!(
+            dispatch_order([1, 0], &[Bulk, Bulk, Interactive, Interactive]).await,
+            [Interactive, Interactive, Bulk, Bulk]
+        );
+
+        // Bulk requests get one of every three turns
+        assert_eq!(
+            dispatch_order(
+                [2, 1],
+                &[
+                    Bulk,
+                    Bulk,
+                    Interactive,
+                    Interactive,
+                    Interactive,
+                    Interactive
+                ]
+            )
+            .await,
+            [
+                Interactive,
+                Bulk,
+                Interactive,
+                Interactive,
+                Bulk,
+                Interactive
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_priorities() {
+        use Class::{Bulk, Interactive};
+        use Priority::{High, Low, Normal};
+
+        // Higher priorities go first, the weights apply within a priority
+        assert_eq!(
+            dispatch(
+                [1, 0],
+                &[
+                    (Interactive, Low),
+                    (Bulk, Normal),
+                    (Bulk, High),
+                    (Interactive, Normal)
+                ]
+            )
+            .await,
+            [
+                (Bulk, High),
+                (Interactive, Normal),
+                (Bulk, Normal),
+                (Interactive, Low)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_abandoned() {
+        let classes = TrafficClasses {
+            bulk_routes: Vec::new(),
+            weights: [1, 0],
+        };
+        let dispatcher = Dispatcher::default();
+        let path = Path::ChannelsIdMessages(1);
+
+        let first = dispatcher
+            .acquire(&path, Class::Interactive, Priority::Normal, &classes)
+            .await;
+
+        // A request that gives up while waiting doesn't block the bucket
+        assert!(timeout(
+            Duration::from_millis(10),
+            dispatcher.acquire(&path, Class::Interactive, Priority::Normal, &classes)
+        )
+        .await
+        .is_err());
+
+        drop(first);
+        drop(
+            dispatcher
+                .acquire(&path, Class::Interactive, Priority::Normal, &classes)
+                .await,
+        );
+        assert!(dispatcher.lanes.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+use http::{
+    uri::{Authority, InvalidUri, Scheme},
+    Error as HttpError, HeaderValue, Uri,
+};
+use std::{
+    error::Error,
+    fmt::{Display, Formatter, Result as FmtResult},
+    str::FromStr,
+};
+
+/// URL requests are forwarded to if `UPSTREAM_URL` is not set.
+pub const DEFAULT_UPSTREAM: &str = "https://discord.com";
+
+#[derive(Debug)]
+pub enum UpstreamError {
+    InvalidUri { source: InvalidUri },
+    MissingHost,
+    UnsupportedScheme,
+}
+
+impl Display for UpstreamError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::InvalidUri { source } => {
+                f.write_str("upstream url is invalid: ")?;
+                source.fmt(f)
+            }
+            Self::MissingHost => f.write_str("upstream url has no host"),
+            Self::UnsupportedScheme => f.write_str("upstream url must use http or https"),
+        }
+    }
+}
+
+impl Error for UpstreamError {}
+
+/// The server requests are forwarded to.
+#[derive(Debug)]
+pub struct Upstream {
+    scheme: Scheme,
+    authority: Authority,
+    /// Path prefix without a trailing slash.
+    prefix: String,
+    /// `Host` header derived from the authority.
+    host: HeaderValue,
+    https: bool,
+}
+
+impl Upstream {
+    pub fn new(url: &str) -> Result<Self, UpstreamError> {
+        let uri = Uri::from_str(url).map_err(|source| UpstreamError::InvalidUri { source })?;
+
+        let https = match uri.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => return Err(UpstreamError::UnsupportedScheme),
+        };
+
+        let authority = uri.authority().ok_or(UpstreamError::MissingHost)?;
+
+        // The authority was validated while parsing the URI, so it is a valid
+        // header value
+        let host =
+            HeaderValue::from_str(authority.as_str()).map_err(|_| UpstreamError::MissingHost)?;
+
+        Ok(Self {
+            scheme: uri.scheme().cloned().unwrap_or(Scheme::HTTPS),
+            authority: authority.clone(),
+            prefix: uri.path().trim_end_matches('/').to_string(),
+            host,
+            https,
+        })
+    }
+
+    /// The `Host` header to send, which also determines the SNI used for TLS.
+    pub const fn host(&self) -> &HeaderValue {
+        &self.host
+    }
+
+    /// The host name, without port.
+    pub fn host_name(&self) -> &str {
+        self.authority.host()
+    }
+
+    /// The port, defaulting to the scheme's.
+    pub fn port(&self) -> u16 {
+        self.authority
+            .port_u16()
+            .unwrap_or(if self.https { 443 } else { 80 })
+    }
+
+    /// Whether the upstream is contacted via TLS.
+    pub const fn is_https(&self) -> bool {
+        self.https
+    }
+
+    /// Build the URI for a normalized API path and optional query.
+    ///
+    /// The scheme and authority are set separately from the path and query,
+    /// so neither can alter the host that is requested. The query is kept
+    /// exactly as it was received.
+    pub fn uri(&self, api: &str, path: &str, query: Option<&str>) -> Result<Uri, HttpError> {
+        let mut path_and_query = String::with_capacity(
+            self.prefix.len() + api.len() + path.len() + query.map_or(0, |query| query.len() + 1),
+        );
+        path_and_query.push_str(&self.prefix);
+        path_and_query.push_str(api);
+        path_and_query.push_str(path);
+
+        if let Some(query) = query {
+            path_and_query.push('?');
+            path_and_query.push_str(query);
+        }
+
+        Uri::builder()
+            .scheme(self.scheme.clone())
+            .authority(self.authority.clone())
+            .path_and_query(path_and_query)
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Upstream, UpstreamError, DEFAULT_UPSTREAM};
+
+    #[test]
+    fn test_default() {
+        let upstream = Upstream::new(DEFAULT_UPSTREAM).unwrap();
+
+        assert!(upstream.is_https());
+        assert_eq!(upstream.host(), "discord.com");
+        assert_eq!(upstream.host_name(), "discord.com");
+        assert_eq!(upstream.port(), 443);
+        assert_eq!(
+            upstream
+                .uri("/api/v10", "/users/@me", Some("with_counts=true"))
+                .unwrap(),
+            "https://discord.com/api/v10/users/@me?with_counts=true"
+        );
+    }
+
+    #[test]
+    fn test_custom() {
+        let upstream = Upstream::new("http://127.0.0.1:8080/recorder/").unwrap();
+
+        assert!(!upstream.is_https());
+        assert_eq!(upstream.host(), "127.0.0.1:8080");
+        assert_eq!(upstream.host_name(), "127.0.0.1");
+        assert_eq!(upstream.port(), 8080);
+        assert_eq!(
+            upstream.uri("/api", "/gateway", None).unwrap(),
+            "http://127.0.0.1:8080/recorder/api/gateway"
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(matches!(
+            Upstream::new("ftp://discord.com"),
+            Err(UpstreamError::UnsupportedScheme)
+        ));
+        assert!(matches!(
+            Upstream::new("/api"),
+            Err(UpstreamError::UnsupportedScheme)
+        ));
+    }
+}
@@ -0,0 +1,268 @@
+//! The `diff-versions` subcommand, replaying captured `GET` requests against
+//! two API versions to audit a migration between them.
+
+use crate::{capture::Exchange, path::normalize_path, replay};
+use hyper::body::{self, Bytes};
+use serde_json::Value;
+use std::error::Error;
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, BufReader},
+};
+
+/// Maximum amount of differences printed per request.
+const MAX_PRINTED: usize = 10;
+
+/// How the responses of the two versions differ.
+#[derive(Debug, Eq, PartialEq)]
+enum Difference {
+    Status(u16, u16),
+    /// JSON pointer of a field only the first version's response has, with
+    /// `*` in place of array indices.
+    OnlyFirst(String),
+    OnlySecond(String),
+    /// JSON pointer of a value that differs, or an empty pointer if the
+    /// bodies aren't JSON and differ.
+    Changed(String),
+}
+
+impl Difference {
+    fn describe(&self, [first, second]: [u8; 2]) -> String {
+        match self {
+            Self::Status(a, b) => format!("status {} in v{}, {} in v{}", a, first, b, second),
+            Self::OnlyFirst(pointer) => format!("`{}` only in v{}", pointer, first),
+            Self::OnlySecond(pointer) => format!("`{}` only in v{}", pointer, second),
+            Self::Changed(pointer) if pointer.is_empty() => "body differs".to_string(),
+            Self::Changed(pointer) => format!("`{}` differs", pointer),
+        }
+    }
+}
+
+/// Replay the `GET` requests of a capture file against two API versions
+/// through a proxy and print how the responses differ.
+///
+/// Requests are sent one after another, first with one version and then with
+/// the other, with the default token as their `Authorization` if set. Other
+/// methods are skipped, as they could change data on Discord. Fails if any
+/// responses differ.
+pub async fn run(file: &str, target: &str, versions: [u8; 2]) -> Result<(), Box<dyn Error>> {
+    let target = target.trim_end_matches('/');
+
+    let token = replay::token()?;
+    let client = replay::client();
+
+    let mut lines = BufReader::new(File::open(file).await?).lines();
+    let (mut compared, mut differed, mut skipped) = (0, 0, 0);
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let exchange: Exchange = serde_json::from_str(&line)?;
+
+        if exchange.method != "GET" {
+            skipped += 1;
+
+            continue;
+        }
+
+        let mut responses = Vec::with_capacity(2);
+
+        for version in versions {
+            let versioned = Exchange {
+                path: versioned_path(&exchange.path, version),
+                ..exchange.clone()
+            };
+            let request = replay::build_request(&versioned, target, token.as_deref())?;
+
+            let response = client.request(request).await?;
+            let status = response.status().as_u16();
+            responses.push((status, body::to_bytes(response.into_body()).await?));
+        }
+
+        compared += 1;
+
+        let differences = compare(&responses[0], &responses[1]);
+
+        if differences.is_empty() {
+            println!("GET {}: same", exchange.path);
+
+            continue;
+        }
+
+        differed += 1;
+        println!("GET {}: {} differences", exchange.path, differences.len());
+
+        for difference in differences.iter().take(MAX_PRINTED) {
+            println!("  {}", difference.describe(versions));
+        }
+
+        if differences.len() > MAX_PRINTED {
+            println!("  and {} more", differences.len() - MAX_PRINTED);
+        }
+    }
+
+    println!(
+        "Compared {} requests, {} differed, {} skipped",
+        compared, differed, skipped
+    );
+
+    if differed > 0 {
+        return Err(format!(
+            "{} responses differ between v{} and v{}",
+            differed, versions[0], versions[1]
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Parse an API version, with or without a leading `v`.
+pub fn parse_version(arg: &str) -> Result<u8, String> {
+    arg.trim_start_matches('v')
+        .parse()
+        .map_err(|_| format!("invalid API version {:?}", arg))
+}
+
+/// A captured path and query with the API version replaced.
+fn versioned_path(path: &str, version: u8) -> String {
+    let (path, query) = match path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path, None),
+    };
+
+    let normalized = normalize_path(path);
+
+    match query {
+        Some(query) => format!("/api/v{}{}?{}", version, normalized.path, query),
+        None => format!("/api/v{}{}", version, normalized.path),
+    }
+}
+
+/// Differences between the statuses and bodies of two responses.
+///
+/// Bodies are only compared if the statuses match.
+fn compare(first: &(u16, Bytes), second: &(u16, Bytes)) -> Vec<Difference> {
+    if first.0 != second.0 {
+        return vec![Difference::Status(first.0, second.0)];
+    }
+
+    let mut differences = Vec::new();
+
+    match (
+        serde_json::from_slice::<Value>(&first.1),
+        serde_json::from_slice::<Value>(&second.1),
+    ) {
+        (Ok(a), Ok(b)) => diff(&a, &b, "", &mut differences),
+        _ if first.1 != second.1 => differences.push(Difference::Changed(String::new())),
+        _ => {}
+    }
+
+    differences
+}
+
+/// Collect the differences between two JSON values.
+///
+/// Array items are compared by index, with differences of all items reported
+/// once.
+fn diff(first: &Value, second: &Value, pointer: &str, differences: &mut Vec<Difference>) {
+    match (first, second) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (key, value) in a {
+                let pointer = format!("{}/{}", pointer, escape(key));
+
+                match b.get(key) {
+                    Some(other) => diff(value, other, &pointer, differences),
+                    None => push(differences, Difference::OnlyFirst(pointer)),
+                }
+            }
+
+            for key in b.keys().filter(|key| !a.contains_key(*key)) {
+                let pointer = format!("{}/{}", pointer, escape(key));
+                push(differences, Difference::OnlySecond(pointer));
+            }
+        }
+        (Value::Array(a), Value::Array(b)) => {
+            if a.len() != b.len() {
+                push(differences, Difference::Changed(pointer.to_string()));
+            }
+
+            let items = format!("{}/*", pointer);
+
+            for (value, other) in a.iter().zip(b) {
+                diff(value, other, &items, differences);
+            }
+        }
+        _ if first != second => push(differences, Difference::Changed(pointer.to_string())),
+        _ => {}
+    }
+}
+
+/// Add a difference, unless an item of the same array already has it.
+fn push(differences: &mut Vec<Difference>, difference: Difference) {
+    if !differences.contains(&difference) {
+        differences.push(difference);
+    }
+}
+
+/// Escape a key for use in a JSON pointer.
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare, parse_version, versioned_path, Difference};
+    use hyper::body::Bytes;
+    use serde_json::json;
+
+    fn response(status: u16, body: &serde_json::Value) -> (u16, Bytes) {
+        (status, Bytes::from(body.to_string()))
+    }
+
+    #[test]
+    fn test_versioned_path() {
+        assert_eq!(
+            versioned_path("/api/v9/channels/1/messages?limit=5", 10),
+            "/api/v10/channels/1/messages?limit=5"
+        );
+        assert_eq!(versioned_path("/api/users/@me", 9), "/api/v9/users/@me");
+        assert_eq!(parse_version("v10").unwrap(), 10);
+        assert!(parse_version("ten").is_err());
+    }
+
+    #[test]
+    fn test_compare() {
+        let v9 = json!([
+            {"id": "1", "author": {"username": "a"}, "a/b": 1},
+            {"id": "2", "author": {"username": "b"}, "a/b": 1}
+        ]);
+        let v10 = json!([
+            {"id": "1", "author": {"username": "a", "global_name": null}},
+            {"id": "2", "author": {"username": "b", "global_name": null}}
+        ]);
+
+        assert_eq!(
+            compare(&response(200, &v9), &response(200, &v10)),
+            [
+                Difference::OnlyFirst("/*/a~1b".to_string()),
+                Difference::OnlySecond("/*/author/global_name".to_string()),
+            ]
+        );
+        assert_eq!(
+            compare(&response(200, &json!([1, 2])), &response(200, &json!([1]))),
+            [Difference::Changed(String::new())]
+        );
+        assert_eq!(
+            compare(&response(200, &v9), &response(404, &v9)),
+            [Difference::Status(200, 404)]
+        );
+        assert!(compare(&response(200, &v9), &response(200, &v9)).is_empty());
+        assert_eq!(
+            compare(&(200, Bytes::from("a")), &(200, Bytes::from("b"))),
+            [Difference::Changed(String::new())]
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! Pre-fork style worker mode for very high connection counts, where a single
+//! Tokio runtime becomes the bottleneck.
+//!
+//! A coordinating parent runs `WORKER_PROCESSES` copies of the proxy, which
+//! share the listening socket with `SO_REUSEPORT`. Workers keep their
+//! ratelimits in Redis, as each would use a token's full buckets otherwise.
+
+use crate::parse_env;
+use hyper::server::conn::AddrIncoming;
+use std::{env, error::Error, net::SocketAddr};
+use tokio::net::TcpSocket;
+
+/// Environment variable telling a process it's a worker, set to its index.
+const WORKER_INDEX: &str = "WORKER_INDEX";
+
+/// Amount of pending connections the listening socket queues.
+const BACKLOG: u32 = 1024;
+
+/// Amount of worker processes to run, if worker mode is enabled and this is
+/// the parent.
+pub fn count() -> Option<usize> {
+    if env::var_os(WORKER_INDEX).is_some() {
+        return None;
+    }
+
+    parse_env("WORKER_PROCESSES").filter(|count| *count > 1)
+}
+
+/// Bind the listening socket, shared with the other workers in worker mode.
+pub fn bind(address: SocketAddr) -> Result<AddrIncoming, Box<dyn Error>> {
+    if env::var_os(WORKER_INDEX).is_none() {
+        return Ok(AddrIncoming::bind(&address)?);
+    }
+
+    let socket = if address.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(true)?;
+    socket.bind(address)?;
+
+    Ok(AddrIncoming::from_listener(socket.listen(BACKLOG)?)?)
+}
+
+/// Ensure workers share their ratelimits.
+fn check_backend() -> Result<(), Box<dyn Error>> {
+    match env::var("RATELIMITER_BACKEND").as_deref() {
+        Ok("redis") => Ok(()),
+        _ => Err("WORKER_PROCESSES needs RATELIMITER_BACKEND=redis".into()),
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn supervise(_: usize) -> Result<(), Box<dyn Error>> {
+    use std::io;
+
+    check_backend()?;
+
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "WORKER_PROCESSES is only supported on Unix",
+    )
+    .into())
+}
+
+/// Run the workers until a shutdown signal is received, restarting workers
+/// that exit on their own.
+///
+/// Workers are started with the same arguments as the parent, and are sent
+/// `SIGTERM` to shut down gracefully.
+#[cfg(unix)]
+pub async fn supervise(count: usize) -> Result<(), Box<dyn Error>> {
+    use tokio::{sync::watch, task::JoinSet};
+    use tracing::info;
+
+    check_backend()?;
+
+    let exe = env::current_exe()?;
+    let (stop, stopping) = watch::channel(false);
+    let mut workers = JoinSet::new();
+
+    for index in 0..count {
+        let child = unix::spawn(&exe, index)?;
+
+        workers.spawn(unix::run(exe.clone(), index, child, stopping.clone()));
+    }
+
+    info!("Started {} worker processes", count);
+
+    crate::shutdown_signal().await;
+    info!("Stopping worker processes");
+    _ = stop.send(true);
+
+    while workers.join_next().await.is_some() {}
+
+    Ok(())
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::WORKER_INDEX;
+    use std::{
+        env, io,
+        path::{Path, PathBuf},
+    };
+    use tokio::{
+        process::{Child, Command},
+        sync::watch::Receiver,
+        time::{sleep, Duration},
+    };
+    use tracing::{error, warn};
+
+    /// Time to wait before restarting a worker that exited, so a worker
+    /// failing on startup isn't restarted in a busy loop.
+    const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+    pub fn spawn(exe: &Path, index: usize) -> io::Result<Child> {
+        Command::new(exe)
+            .args(env::args_os().skip(1))
+            .env(WORKER_INDEX, index.to_string())
+            .spawn()
+    }
+
+    /// Wait for a worker to exit, restarting it until the parent stops.
+    pub async fn run(exe: PathBuf, index: usize, mut child: Child, mut stopping: Receiver<bool>) {
+        loop {
+            tokio::select! {
+                status = child.wait() => {
+                    warn!("Worker {} exited with {:?}, restarting it", index, status);
+                    sleep(RESTART_DELAY).await;
+
+                    if *stopping.borrow() {
+                        return;
+                    }
+
+                    child = match spawn(&exe, index) {
+                        Ok(child) => child,
+                        Err(e) => {
+                            error!("Failed to restart worker {}: {}", index, e);
+
+                            return;
+                        }
+                    };
+                }
+                _ = stopping.changed() => {
+                    terminate(&child);
+                    _ = child.wait().await;
+
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Ask a worker to shut down gracefully.
+    fn terminate(child: &Child) {
+        if let Some(pid) = child.id() {
+            // SAFETY: `kill` has no memory safety requirements, and the
+            // child hasn't been reaped yet, so its pid wasn't reused
+            unsafe {
+                libc::kill(pid as libc::pid_t, libc::SIGTERM);
+            }
+        }
+    }
+}
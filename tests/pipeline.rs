@@ -0,0 +1,1211 @@
+//! End-to-end tests of the full request pipeline.
+//!
+//! Each test boots the proxy binary on an ephemeral port and points it at an
+//! in-process fake Discord server, which records the requests it receives.
+
+use http::{HeaderMap, Method, StatusCode, Version};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Client, Request, Response, Server,
+};
+use std::{
+    convert::Infallible,
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use twilight_http::request::AuditLogReason;
+use twilight_model::{http::attachment::Attachment, id::Id};
+
+/// A request received by the fake Discord server.
+struct Received {
+    method: Method,
+    version: Version,
+    /// Path and query.
+    uri: String,
+    headers: HeaderMap,
+    body: String,
+}
+
+/// Fake Discord server answering every request with a small JSON body and
+/// ratelimit headers.
+struct Discord {
+    addr: SocketAddr,
+    received: Arc<Mutex<Vec<Received>>>,
+}
+
+impl Discord {
+    fn start() -> Self {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Arc::clone(&received);
+
+        let service = make_service_fn(move |_| {
+            let recorder = Arc::clone(&recorder);
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let recorder = Arc::clone(&recorder);
+
+                    async move {
+                        let (parts, body) = request.into_parts();
+                        let body = hyper::body::to_bytes(body).await.unwrap();
+
+                        recorder.lock().unwrap().push(Received {
+                            method: parts.method,
+                            version: parts.version,
+                            uri: parts.uri.to_string(),
+                            headers: parts.headers,
+                            body: String::from_utf8(body.to_vec()).unwrap(),
+                        });
+
+                        Ok::<_, Infallible>(respond())
+                    }
+                }))
+            }
+        });
+
+        let server = Server::try_bind(&([127, 0, 0, 1], 0).into())
+            .unwrap()
+            .serve(service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        Self { addr, received }
+    }
+
+    fn received(&self) -> std::sync::MutexGuard<'_, Vec<Received>> {
+        self.received.lock().unwrap()
+    }
+}
+
+fn respond() -> Response<Body> {
+    Response::builder()
+        .header("content-type", "application/json")
+        .header("keep-alive", "timeout=5")
+        .header("x-fake-discord", "1")
+        .header("x-ratelimit-bucket", "abc")
+        .header("x-ratelimit-limit", "5")
+        .header("x-ratelimit-remaining", "4")
+        .header("x-ratelimit-reset", "1700000000.000")
+        .header("x-ratelimit-reset-after", "1.000")
+        .body(Body::from(r#"{"id":"1"}"#))
+        .unwrap()
+}
+
+/// A running proxy process, killed when dropped.
+struct Proxy {
+    addr: SocketAddr,
+    child: Child,
+}
+
+impl Proxy {
+    async fn start(discord: &Discord, env: &[(&str, &str)]) -> Self {
+        // Reserve an ephemeral port and release it for the proxy to bind
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_twilight-http-proxy"))
+            .env_clear()
+            .env("HOST", "127.0.0.1")
+            .env("PORT", addr.port().to_string())
+            .env("UPSTREAM_URL", format!("http://{}", discord.addr))
+            .env("RUST_LOG", "warn")
+            .envs(env.iter().copied())
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let proxy = Self { addr, child };
+        let start = Instant::now();
+
+        while TcpStream::connect(addr).is_err() {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "proxy did not start listening"
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        proxy
+    }
+
+    async fn send(&self, request: Request<Body>) -> (StatusCode, HeaderMap, String) {
+        let (mut parts, body) = request.into_parts();
+        parts.uri = format!("http://{}{}", self.addr, parts.uri)
+            .parse()
+            .unwrap();
+
+        let response = Client::new()
+            .request(Request::from_parts(parts, body))
+            .await
+            .unwrap();
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+
+        (
+            parts.status,
+            parts.headers,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    async fn get(&self, path: &str) -> (StatusCode, HeaderMap, String) {
+        self.send(Request::get(path).body(Body::empty()).unwrap())
+            .await
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        _ = self.child.kill();
+        _ = self.child.wait();
+    }
+}
+
+#[tokio::test]
+async fn test_forwarding() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(&discord, &[]).await;
+
+    let (status, headers, body) = proxy
+        .send(
+            Request::get("/api/v10/users/@me?with_counts=true")
+                .header("authorization", "Bot user")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, r#"{"id":"1"}"#);
+    assert_eq!(headers["x-fake-discord"], "1");
+    assert_eq!(headers["x-ratelimit-remaining"], "4");
+    assert!(!headers.contains_key("keep-alive"));
+
+    let received = discord.received();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].method, Method::GET);
+    assert_eq!(received[0].uri, "/api/v10/users/@me?with_counts=true");
+    assert_eq!(received[0].headers["authorization"], "Bot user");
+    assert_eq!(
+        received[0].headers["host"],
+        discord.addr.to_string().as_str()
+    );
+}
+
+#[tokio::test]
+async fn test_http_versions() {
+    for (version, expected) in [("http1", Version::HTTP_11), ("http2", Version::HTTP_2)] {
+        let discord = Discord::start();
+        let versions = format!("127.0.0.1={}", version);
+        let proxy = Proxy::start(&discord, &[("UPSTREAM_HTTP_VERSIONS", &versions)]).await;
+
+        let (status, _, _) = proxy
+            .send(
+                Request::get("/api/v10/users/@me")
+                    .header("authorization", "Bot user")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(discord.received()[0].version, expected);
+    }
+}
+
+#[tokio::test]
+async fn test_path_parsing() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(&discord, &[("DISCORD_TOKEN", "default")]).await;
+
+    assert_eq!(
+        proxy.get("//API//V9///channels/1/messages/").await.0,
+        StatusCode::OK
+    );
+    assert_eq!(
+        proxy.get("/api/v10/channels/1/../../users/%40me").await.0,
+        StatusCode::OK
+    );
+    assert_eq!(
+        proxy.get("/api/v10/not-a-route").await.0,
+        StatusCode::NOT_IMPLEMENTED
+    );
+
+    let received = discord.received();
+    let uris = received
+        .iter()
+        .map(|received| received.uri.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(uris, ["/api/v9/channels/1/messages", "/api/v10/users/@me"]);
+}
+
+#[tokio::test]
+async fn test_token_routing() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(&discord, &[("DISCORD_TOKEN", "default")]).await;
+
+    proxy.get("/api/v10/users/@me").await;
+    proxy
+        .send(
+            Request::get("/api/v10/users/@me")
+                .header("authorization", "Bearer user")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+
+    let received = discord.received();
+    assert_eq!(received[0].headers["authorization"], "Bot default");
+    assert_eq!(received[1].headers["authorization"], "Bearer user");
+}
+
+#[tokio::test]
+async fn test_missing_token() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(&discord, &[]).await;
+
+    assert_eq!(
+        proxy.get("/api/v10/users/@me").await.0,
+        StatusCode::UNAUTHORIZED
+    );
+
+    // Webhooks can be executed without a token
+    let (status, ..) = proxy
+        .send(
+            Request::post("/api/v10/webhooks/1/abc")
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"content":"hi"}"#))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let received = discord.received();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].uri, "/api/v10/webhooks/1/abc");
+    assert!(!received[0].headers.contains_key("authorization"));
+}
+
+#[tokio::test]
+async fn test_fail_fast() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(&discord, &[("DISCORD_TOKEN", "default")]).await;
+
+    let digest = ring::digest::digest(&ring::digest::SHA256, b"Bot default");
+    let hash = digest.as_ref()[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+
+    let (status, ..) = proxy
+        .send(
+            Request::post(format!("/__proxy/tenants/{}/buckets/GET/users/@me", hash))
+                .body(Body::from(
+                    r#"{"limit":1,"remaining":0,"reset_after_ms":10000}"#,
+                ))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, headers, body) = proxy
+        .send(
+            Request::get("/api/v10/users/@me")
+                .header("x-ratelimit-precision", "fail-fast")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(headers["x-proxy-fail-fast"], "true");
+    assert_eq!(headers["retry-after"], "10");
+
+    let body = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+    assert_eq!(body["global"], false);
+    assert!(discord.received().is_empty());
+}
+
+#[tokio::test]
+async fn test_queue_timeout() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(
+        &discord,
+        &[("DISCORD_TOKEN", "default"), ("MAX_QUEUE_WAIT_MS", "100")],
+    )
+    .await;
+
+    let digest = ring::digest::digest(&ring::digest::SHA256, b"Bot default");
+    let hash = digest.as_ref()[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+
+    let (status, ..) = proxy
+        .send(
+            Request::post(format!("/__proxy/tenants/{}/buckets/GET/users/@me", hash))
+                .body(Body::from(
+                    r#"{"limit":1,"remaining":0,"reset_after_ms":10000}"#,
+                ))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let start = Instant::now();
+    let (status, headers, _) = proxy.get("/api/v10/users/@me").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    let retry_after = headers["retry-after"]
+        .to_str()
+        .unwrap()
+        .parse::<u64>()
+        .unwrap();
+    assert!((9..=10).contains(&retry_after));
+    assert!(discord.received().is_empty());
+}
+
+#[tokio::test]
+async fn test_queue_limits() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(
+        &discord,
+        &[("DISCORD_TOKEN", "default"), ("MAX_QUEUED_PER_BUCKET", "1")],
+    )
+    .await;
+
+    let digest = ring::digest::digest(&ring::digest::SHA256, b"Bot default");
+    let hash = digest.as_ref()[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+
+    let (status, ..) = proxy
+        .send(
+            Request::post(format!("/__proxy/tenants/{}/buckets/GET/users/@me", hash))
+                .body(Body::from(
+                    r#"{"limit":1,"remaining":0,"reset_after_ms":1000}"#,
+                ))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (first, second) = tokio::join!(
+        proxy.get("/api/v10/users/@me"),
+        proxy.get("/api/v10/users/@me")
+    );
+
+    let mut statuses = [first.0, second.0];
+    statuses.sort();
+    assert_eq!(statuses, [StatusCode::OK, StatusCode::SERVICE_UNAVAILABLE]);
+    assert_eq!(discord.received().len(), 1);
+}
+
+#[tokio::test]
+async fn test_fair_queue() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(
+        &discord,
+        &[("DISCORD_TOKEN", "default"), ("FAIR_SHARE_SLOTS", "1")],
+    )
+    .await;
+
+    let digest = ring::digest::digest(&ring::digest::SHA256, b"Bot default");
+    let hash = digest.as_ref()[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+
+    let (status, ..) = proxy
+        .send(
+            Request::post(format!("/__proxy/tenants/{}/buckets/GET/users/@me", hash))
+                .body(Body::from(
+                    r#"{"limit":1,"remaining":0,"reset_after_ms":2000}"#,
+                ))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // A request waiting for an exhausted bucket doesn't hold the only slot
+    let exhausted = proxy.get("/api/v10/users/@me");
+    let other = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let start = Instant::now();
+        let (status, ..) = proxy
+            .send(
+                Request::get("/api/v10/users/@me")
+                    .header("authorization", "Bot other")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+
+        (status, start.elapsed())
+    };
+
+    let ((first, ..), (second, elapsed)) = tokio::join!(exhausted, other);
+    assert_eq!(first, StatusCode::OK);
+    assert_eq!(second, StatusCode::OK);
+    assert!(elapsed < Duration::from_secs(1));
+
+    let received = discord.received();
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0].headers["authorization"], "Bot other");
+}
+
+#[tokio::test]
+async fn test_bulk_ban() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(&discord, &[("DISCORD_TOKEN", "default")]).await;
+
+    let bulk_ban = |path: &str, body: &'static str| {
+        Request::post(path)
+            .header("x-audit-log-reason", "raid")
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    let (status, ..) = proxy
+        .send(bulk_ban(
+            "/__proxy/bulk-ban/guilds/1",
+            r#"{"user_ids":["a"]}"#,
+        ))
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // API versions before bulk bans ban users one by one
+    let (status, _, body) = proxy
+        .send(bulk_ban(
+            "/__proxy/bulk-ban/api/v9/guilds/1",
+            r#"{"user_ids":["2",3],"delete_message_seconds":60}"#,
+        ))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let report = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+    assert_eq!(report["banned_users"], serde_json::json!(["2", "3"]));
+    assert_eq!(report["failed_users"], serde_json::json!([]));
+
+    let (status, ..) = proxy
+        .send(bulk_ban(
+            "/__proxy/bulk-ban/guilds/1",
+            r#"{"user_ids":["2",3]}"#,
+        ))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let received = discord.received();
+    assert_eq!(received.len(), 3);
+    assert_eq!(received[0].method, Method::PUT);
+    assert_eq!(received[0].uri, "/api/v9/guilds/1/bans/2");
+    assert_eq!(received[0].body, r#"{"delete_message_seconds":60}"#);
+    assert_eq!(received[0].headers["x-audit-log-reason"], "raid");
+    assert_eq!(received[1].uri, "/api/v9/guilds/1/bans/3");
+    assert_eq!(received[2].method, Method::POST);
+    assert_eq!(received[2].uri, "/api/v10/guilds/1/bulk-ban");
+    assert_eq!(received[2].body, r#"{"user_ids":["2","3"]}"#);
+}
+
+#[tokio::test]
+async fn test_paginate() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(&discord, &[("DISCORD_TOKEN", "default")]).await;
+
+    assert_eq!(
+        proxy.get("/__proxy/paginate/guilds/1/roles").await.0,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        proxy
+            .get("/__proxy/paginate/channels/1/messages?limit=0")
+            .await
+            .0,
+        StatusCode::BAD_REQUEST
+    );
+
+    // Responses that aren't lists are relayed as-is
+    let (status, _, body) = proxy
+        .get("/__proxy/paginate/channels/1/messages?limit=150&before=5")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, r#"{"id":"1"}"#);
+
+    let received = discord.received();
+    assert_eq!(received.len(), 1);
+    assert_eq!(
+        received[0].uri,
+        "/api/v10/channels/1/messages?limit=100&before=5"
+    );
+    assert_eq!(received[0].headers["authorization"], "Bot default");
+}
+
+#[tokio::test]
+async fn test_allowed_mentions() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(
+        &discord,
+        &[
+            ("DISCORD_TOKEN", "default"),
+            ("DEFAULT_ALLOWED_MENTIONS", r#"{"parse":[]}"#),
+        ],
+    )
+    .await;
+
+    for body in [
+        r#"{"content":"@everyone"}"#,
+        r#"{"content":"@everyone","allowed_mentions":{"parse":["everyone"]}}"#,
+    ] {
+        let (status, ..) = proxy
+            .send(
+                Request::post("/api/v10/channels/1/messages")
+                    .header("content-type", "application/json")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let received = discord.received();
+    assert_eq!(
+        received[0].body,
+        r#"{"allowed_mentions":{"parse":[]},"content":"@everyone"}"#
+    );
+    assert_eq!(
+        received[0].headers["content-length"],
+        received[0].body.len().to_string()
+    );
+    assert_eq!(
+        received[1].body,
+        r#"{"content":"@everyone","allowed_mentions":{"parse":["everyone"]}}"#
+    );
+}
+
+#[tokio::test]
+async fn test_payload_limits() {
+    let discord = Discord::start();
+    let content = "a".repeat(2001);
+
+    // Buffering a body doesn't enforce the upload limit on its own
+    let proxy = Proxy::start(
+        &discord,
+        &[
+            ("DISCORD_TOKEN", "default"),
+            ("SIGNING_KEY", "secret"),
+            ("MAX_UPLOAD_SIZE", "10"),
+        ],
+    )
+    .await;
+
+    let (status, ..) = proxy
+        .send(
+            Request::post("/api/v10/channels/1/messages")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    serde_json::json!({ "content": "a" }).to_string(),
+                ))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Message limits only apply to messages
+    let proxy = Proxy::start(
+        &discord,
+        &[
+            ("DISCORD_TOKEN", "default"),
+            ("ENFORCE_PAYLOAD_LIMITS", "1"),
+        ],
+    )
+    .await;
+
+    let send = |path: &str| {
+        proxy.send(
+            Request::patch(path)
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    serde_json::json!({ "content": content }).to_string(),
+                ))
+                .unwrap(),
+        )
+    };
+
+    assert_eq!(send("/api/v10/channels/1").await.0, StatusCode::OK);
+    assert_eq!(
+        send("/api/v10/channels/1/messages/2").await.0,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(discord.received().len(), 2);
+}
+
+#[tokio::test]
+async fn test_header_rewriting() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(
+        &discord,
+        &[
+            ("DISCORD_TOKEN", "default"),
+            ("ENCODE_AUDIT_LOG_REASON", "1"),
+        ],
+    )
+    .await;
+
+    proxy
+        .send(
+            Request::delete("/api/v10/channels/1/messages/2")
+                .header("host", "example.com")
+                .header("connection", "x-hop")
+                .header("x-hop", "1")
+                .header("x-audit-log-reason", "spam ✔")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+
+    // Listing the headers the proxy sets doesn't drop them
+    proxy
+        .send(
+            Request::get("/api/v10/users/@me")
+                .header("connection", "authorization, host")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+
+    let received = discord.received();
+    let headers = &received[0].headers;
+    assert_eq!(headers["host"], discord.addr.to_string().as_str());
+    assert!(!headers.contains_key("x-hop"));
+    assert_eq!(headers["x-audit-log-reason"], "spam%20%E2%9C%94");
+
+    let headers = &received[1].headers;
+    assert_eq!(headers["authorization"], "Bot default");
+    assert_eq!(headers["host"], discord.addr.to_string().as_str());
+}
+
+#[tokio::test]
+async fn test_mirroring() {
+    let discord = Discord::start();
+    let mirror = Discord::start();
+    let mirror_url = format!("http://{}/shadow", mirror.addr);
+    let proxy = Proxy::start(
+        &discord,
+        &[
+            ("DISCORD_TOKEN", "default"),
+            ("MIRROR_URL", &mirror_url),
+            ("MIRROR_METHODS", "GET,POST"),
+        ],
+    )
+    .await;
+
+    let (status, ..) = proxy
+        .send(
+            Request::post("/api/v10/channels/1/messages")
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"content":"hi"}"#))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Mirrored requests are sent in the background
+    let start = Instant::now();
+
+    while mirror.received().is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(5), "nothing mirrored");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let received = mirror.received();
+    assert_eq!(received[0].method, Method::POST);
+    assert_eq!(received[0].uri, "/shadow/api/v10/channels/1/messages");
+    assert_eq!(received[0].headers["authorization"], "Bot default");
+    assert_eq!(
+        received[0].headers["host"],
+        mirror.addr.to_string().as_str()
+    );
+    assert_eq!(received[0].body, r#"{"content":"hi"}"#);
+    assert_eq!(discord.received().len(), 1);
+}
+
+#[tokio::test]
+async fn test_mirroring_methods() {
+    let discord = Discord::start();
+    let mirror = Discord::start();
+    let mirror_url = format!("http://{}", mirror.addr);
+    let proxy = Proxy::start(
+        &discord,
+        &[("DISCORD_TOKEN", "default"), ("MIRROR_URL", &mirror_url)],
+    )
+    .await;
+
+    for request in [
+        Request::post("/api/v10/channels/1/messages"),
+        Request::get("/api/v10/channels/1"),
+    ] {
+        let (status, ..) = proxy.send(request.body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let start = Instant::now();
+
+    while mirror.received().is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(5), "nothing mirrored");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // Mutations aren't mirrored by default
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let received = mirror.received();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].method, Method::GET);
+}
+
+#[tokio::test]
+async fn test_canary() {
+    let discord = Discord::start();
+    let canary_discord = Discord::start();
+    let canary = Proxy::start(&canary_discord, &[]).await;
+    let canary_url = format!("http://{}", canary.addr);
+    let proxy = Proxy::start(
+        &discord,
+        &[("CANARY_URL", &canary_url), ("CANARY_PERCENT", "100")],
+    )
+    .await;
+
+    let (status, ..) = proxy
+        .send(
+            Request::post("/api/v10/channels/1/messages?wait=true")
+                .header("authorization", "Bot abc")
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"content":"hi"}"#))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(discord.received().is_empty());
+
+    {
+        let received = canary_discord.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].uri, "/api/v10/channels/1/messages?wait=true");
+        assert_eq!(received[0].headers["authorization"], "Bot abc");
+        assert!(received[0].headers.get("x-proxy-canary").is_none());
+        assert_eq!(received[0].body, r#"{"content":"hi"}"#);
+    }
+
+    // Requests are handled here if the canary is down
+    drop(canary);
+    let (status, ..) = proxy
+        .send(
+            Request::get("/api/v10/users/@me")
+                .header("authorization", "Bot abc")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(discord.received().len(), 1);
+}
+
+#[tokio::test]
+async fn test_dry_run() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(&discord, &[("DISCORD_TOKEN", "default"), ("DRY_RUN", "1")]).await;
+
+    let (status, headers, body) = proxy.get("/api/v10/users/@me").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers["x-proxy-dry-run"], "true");
+    assert_eq!(body, "{}");
+
+    let (status, _, body) = proxy
+        .send(
+            Request::delete("/api/v10/channels/1/messages/2")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert!(body.is_empty());
+
+    assert_eq!(
+        proxy.get("/api/v10/not-a-route").await.0,
+        StatusCode::NOT_IMPLEMENTED
+    );
+    assert!(discord.received().is_empty());
+}
+
+#[tokio::test]
+async fn test_config() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(
+        &discord,
+        &[("DISCORD_TOKEN", "abc.def.ghi"), ("SIGNING_KEY", "secret")],
+    )
+    .await;
+
+    let (status, _, body) = proxy.get("/__proxy/config").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(!body.contains("abc.def.ghi"));
+    assert!(!body.contains("secret"));
+
+    let config = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+    assert!(config["default_token"].is_string());
+
+    let settings = config["settings"].as_array().unwrap();
+    let setting = |name: &str| {
+        settings
+            .iter()
+            .find(|setting| setting["name"] == name)
+            .unwrap()
+            .clone()
+    };
+    assert_eq!(setting("HOST")["value"], "127.0.0.1");
+    assert_eq!(setting("HOST")["default"], false);
+    assert_eq!(setting("MAX_QUERY_LENGTH")["value"], "2048");
+    assert_eq!(setting("MAX_QUERY_LENGTH")["default"], true);
+    assert_eq!(setting("SIGNING_KEY")["value"], "<redacted>");
+
+    assert_eq!(
+        proxy
+            .send(
+                Request::post("/__proxy/config")
+                    .body(Body::empty())
+                    .unwrap()
+            )
+            .await
+            .0,
+        StatusCode::METHOD_NOT_ALLOWED
+    );
+}
+
+#[tokio::test]
+async fn test_admin_token() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(
+        &discord,
+        &[
+            ("ADMIN_TOKEN", "admin secret"),
+            ("OAUTH2_CLIENT_ID", "1"),
+            ("OAUTH2_CLIENT_SECRET", "client secret"),
+        ],
+    )
+    .await;
+
+    assert_eq!(
+        proxy.get("/__proxy/config").await.0,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        proxy.get("/__proxy/oauth2/token").await.0,
+        StatusCode::UNAUTHORIZED
+    );
+
+    let (status, _, body) = proxy
+        .send(
+            Request::get("/__proxy/config")
+                .header("x-proxy-admin-token", "admin secret")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(!body.contains("admin secret"));
+
+    // Health checks don't need the token
+    assert_eq!(proxy.get("/__proxy/live").await.0, StatusCode::OK);
+    assert_eq!(proxy.get("/__proxy/ready").await.0, StatusCode::OK);
+
+    // Without an admin token, the application's token isn't handed out
+    let proxy = Proxy::start(
+        &discord,
+        &[
+            ("OAUTH2_CLIENT_ID", "1"),
+            ("OAUTH2_CLIENT_SECRET", "client secret"),
+        ],
+    )
+    .await;
+
+    assert_eq!(
+        proxy.get("/__proxy/oauth2/token").await.0,
+        StatusCode::NOT_FOUND
+    );
+    assert!(discord.received().is_empty());
+}
+
+#[tokio::test]
+async fn test_tenant_usage() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(&discord, &[("DISCORD_TOKEN", "default")]).await;
+
+    proxy.get("/api/v10/users/@me").await;
+
+    let digest = ring::digest::digest(&ring::digest::SHA256, b"Bot default");
+    let hash = digest.as_ref()[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+
+    let (status, _, body) = proxy.get(&format!("/__proxy/tenants/{}/usage", hash)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let usage = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+    assert_eq!(usage["requests"]["total"], 1);
+    assert_eq!(usage["buckets"][0]["limit"], 5);
+
+    let (status, _, body) = proxy
+        .get(&format!("/__proxy/tenants/{}/estimate/GET/users/@me", hash))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let estimate = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+    assert_eq!(estimate["queued"], 0);
+    assert_eq!(estimate["estimated_wait_ms"], 0);
+
+    let (status, _, body) = proxy
+        .get(&format!("/__proxy/tenants/{}/buckets", hash))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let buckets = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+    assert_eq!(buckets[0]["limit"], 5);
+    assert_eq!(buckets[0]["queued"], 0);
+    assert!(buckets[0]["reset_at_ms"].is_u64());
+
+    let (status, _, _) = proxy.get("/__proxy/tenants/0000/buckets").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_probe() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(
+        &discord,
+        &[("DISCORD_TOKEN", "default"), ("PROBE_INTERVAL", "1")],
+    )
+    .await;
+
+    // The first probe is sent right after startup
+    let start = Instant::now();
+
+    while proxy.get("/__proxy/ready").await.0 != StatusCode::OK {
+        assert!(start.elapsed() < Duration::from_secs(5), "never ready");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let received = discord.received();
+    assert_eq!(received[0].uri, "/api/v10/gateway/bot");
+    assert_eq!(received[0].headers["authorization"], "Bot default");
+}
+
+#[tokio::test]
+async fn test_probe_unreachable() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(
+        &discord,
+        &[
+            ("DISCORD_TOKEN", "default"),
+            ("PROBE_INTERVAL", "1"),
+            ("UPSTREAM_URL", "http://127.0.0.1:1"),
+        ],
+    )
+    .await;
+
+    let start = Instant::now();
+
+    loop {
+        let (status, _, body) = proxy.get("/__proxy/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        let readiness = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+
+        if !readiness["probe"].is_null() {
+            assert_eq!(readiness["probe"]["up"], false);
+            assert!(readiness["probe"]["error"].is_string());
+
+            break;
+        }
+
+        assert!(start.elapsed() < Duration::from_secs(5), "never probed");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_wrong_protocol() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(&discord, &[]).await;
+
+    // Clients configured to use the proxy as an HTTPS proxy tunnel with
+    // CONNECT
+    let mut stream = TcpStream::connect(proxy.addr).unwrap();
+    stream
+        .write_all(b"CONNECT discord.com:443 HTTP/1.1\r\nhost: discord.com:443\r\n\r\n")
+        .unwrap();
+    let mut response = [0; 512];
+    let read = stream.read(&mut response).unwrap();
+    let response = String::from_utf8_lossy(&response[..read]);
+    assert!(response.starts_with("HTTP/1.1 405"));
+    assert!(response.contains("not a forward proxy"));
+
+    // Gateway connections opened through the proxy
+    let mut stream = TcpStream::connect(proxy.addr).unwrap();
+    stream
+        .write_all(
+            b"GET /?v=10&encoding=json HTTP/1.1\r\nhost: gateway.discord.gg\r\n\
+              connection: Upgrade\r\nupgrade: websocket\r\nsec-websocket-version: 13\r\n\
+              sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        )
+        .unwrap();
+    let mut response = [0; 512];
+    let read = stream.read(&mut response).unwrap();
+    let response = String::from_utf8_lossy(&response[..read]);
+    assert!(response.starts_with("HTTP/1.1 400"));
+    assert!(response.contains("only handles REST calls"));
+
+    // TLS handshakes are rejected instead of answered with an HTTP error
+    let mut stream = TcpStream::connect(proxy.addr).unwrap();
+    stream.write_all(&[0x16, 0x03, 0x01, 0x00, 0x05]).unwrap();
+    assert_eq!(stream.read(&mut [0; 16]).unwrap_or(0), 0);
+
+    assert!(discord.received().is_empty());
+}
+
+#[tokio::test]
+async fn test_proxy_protocol() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(&discord, &[("PROXY_PROTOCOL", "1")]).await;
+
+    let mut stream = TcpStream::connect(proxy.addr).unwrap();
+    stream
+        .write_all(b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c")
+        .unwrap();
+    stream
+        .write_all(&[203, 0, 113, 7, 127, 0, 0, 1, 0xC3, 0x50, 0, 80])
+        .unwrap();
+    stream
+        .write_all(b"GET /__proxy/tags HTTP/1.1\r\nhost: proxy\r\n\r\n")
+        .unwrap();
+    let mut response = [0; 512];
+    let read = stream.read(&mut response).unwrap();
+    assert!(String::from_utf8_lossy(&response[..read]).starts_with("HTTP/1.1 200"));
+
+    // Connections without a header are closed
+    let mut stream = TcpStream::connect(proxy.addr).unwrap();
+    stream
+        .write_all(b"GET /__proxy/tags HTTP/1.1\r\nhost: proxy\r\n\r\n")
+        .unwrap();
+    assert_eq!(stream.read(&mut [0; 16]).unwrap_or(0), 0);
+}
+
+/// The proxy mode of twilight-http, the primary consumer of the proxy.
+#[tokio::test]
+async fn test_twilight_http() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(
+        &discord,
+        &[
+            ("ENCODE_AUDIT_LOG_REASON", "1"),
+            ("VALIDATE_JSON", "1"),
+            ("VALIDATE_MULTIPART", "1"),
+        ],
+    )
+    .await;
+
+    // The proxy handles ratelimits, so the client doesn't need a ratelimiter
+    let client = twilight_http::Client::builder()
+        .proxy(proxy.addr.to_string(), true)
+        .ratelimiter(None)
+        .token("user".to_string())
+        .build();
+    let channel = Id::new(1);
+
+    let response = client.current_user().await.unwrap();
+    assert_eq!(response.status().get(), 200);
+
+    client
+        .create_message(channel)
+        .content("hello")
+        .unwrap()
+        .await
+        .unwrap();
+
+    let attachments = [Attachment::from_bytes(
+        "hello.txt".to_string(),
+        b"hello".to_vec(),
+        1,
+    )];
+    client
+        .create_message(channel)
+        .attachments(&attachments)
+        .unwrap()
+        .await
+        .unwrap();
+
+    client
+        .delete_message(channel, Id::new(2))
+        .reason("spam \u{1f5d1}")
+        .unwrap()
+        .await
+        .unwrap();
+
+    client
+        .execute_webhook(Id::new(3), "secret")
+        .content("hello")
+        .unwrap()
+        .await
+        .unwrap();
+
+    let received = discord.received();
+    let requests = received
+        .iter()
+        .map(|received| (received.method.as_str(), received.uri.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        requests,
+        [
+            ("GET", "/api/v10/users/@me"),
+            ("POST", "/api/v10/channels/1/messages"),
+            ("POST", "/api/v10/channels/1/messages"),
+            ("DELETE", "/api/v10/channels/1/messages/2"),
+            ("POST", "/api/v10/webhooks/3/secret?wait=false"),
+        ]
+    );
+
+    assert_eq!(received[0].headers["authorization"], "Bot user");
+    assert_eq!(received[1].body, r#"{"content":"hello"}"#);
+    assert!(received[2].headers["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("multipart/form-data; boundary="));
+    assert!(received[2].body.contains("hello.txt"));
+    // twilight-http already encodes the reason, which must not happen twice
+    assert_eq!(
+        received[3].headers["x-audit-log-reason"],
+        "spam%20%F0%9F%97%91"
+    );
+    assert!(!received[4].headers.contains_key("authorization"));
+}
+
+#[cfg(feature = "expose-metrics")]
+#[tokio::test]
+async fn test_metrics() {
+    let discord = Discord::start();
+    let proxy = Proxy::start(&discord, &[("DISCORD_TOKEN", "default")]).await;
+
+    proxy.get("/api/v10/users/@me").await;
+
+    let (status, _, body) = proxy.get("/metrics").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("twilight_http_proxy"));
+    assert!(body.contains(r#"route="User info""#));
+    assert!(body.contains(r#"status="200 OK""#));
+}